@@ -10,6 +10,7 @@
 
 use std::process::Command;
 use std::fs;
+use std::path::Path;
 
 fn main() {
     let files = fs::read_dir("libfdt-binding").unwrap().into_iter().filter_map(|f| {
@@ -30,4 +31,34 @@ fn main() {
     let output = Command::new("date").arg("+\"%Y-%m-%d %H:%M:%S %Z\"").output().unwrap();
     let build_time = String::from_utf8(output.stdout).unwrap();
     println!("cargo:rustc-env=BUILD_TIME={}", build_time);
+
+    // images with the same build id can be live updated to each other, see live_update_validate
+    let rustc = std::env::var("RUSTC").unwrap_or(String::from("rustc"));
+    let output = Command::new(rustc).arg("--version").output().unwrap();
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase()))
+        .filter(|f| f != "update")
+        .collect();
+    features.sort();
+    println!(
+        "cargo:rustc-env=UPDATE_BUILD_ID={} {} {}",
+        String::from_utf8(output.stdout).unwrap().trim(),
+        std::env::var("PROFILE").unwrap(),
+        features.join(",")
+    );
+
+    // key of the live update images, see tools/live_update_image.py, without it no image is accepted
+    let key_file = std::env::var("UPDATE_KEY_FILE").unwrap_or(String::from("update.key"));
+    let key = fs::read(&key_file).unwrap_or_default();
+    if key.is_empty() {
+        println!(
+            "cargo:warning=no live update key in {}, live update is refused",
+            key_file
+        );
+    }
+    fs::write(
+        Path::new(&std::env::var("OUT_DIR").unwrap()).join("update_key.rs"),
+        format!("const UPDATE_KEY: &[u8] = &{:?};\n", key),
+    )
+    .unwrap();
 }
@@ -15,4 +15,5 @@ global_asm!(include_str!("cache.S"));
 extern "C" {
     pub fn cache_invalidate_d(start: usize, len: usize);
     pub fn cache_clean_invalidate_d(start: usize, len: usize);
+    pub fn cache_sync_i(start: usize, len: usize);
 }
@@ -10,10 +10,12 @@
 
 use core::arch::global_asm;
 use core::fmt::Formatter;
+use core::mem::size_of;
 
 use cortex_a::registers::*;
 
-use crate::arch::{GICD, GicState};
+use crate::arch::{GICD, GicState, timer_arch_get_counter, VmCtxPmu};
+use crate::kernel::{StreamReader, StreamWriter};
 
 global_asm!(include_str!("fpsimd.S"));
 
@@ -106,6 +108,25 @@ impl Aarch64ContextFrame {
             sp: 0,
         }
     }
+
+    pub fn migrate_encode(&self, w: &mut StreamWriter) {
+        for gpr in self.gpr.iter() {
+            w.put_u64(*gpr);
+        }
+        w.put_u64(self.spsr);
+        w.put_u64(self.elr);
+        w.put_u64(self.sp);
+    }
+
+    pub fn migrate_decode(&mut self, r: &mut StreamReader) -> Result<(), ()> {
+        for gpr in self.gpr.iter_mut() {
+            *gpr = r.get_u64()?;
+        }
+        self.spsr = r.get_u64()?;
+        self.elr = r.get_u64()?;
+        self.sp = r.get_u64()?;
+        Ok(())
+    }
 }
 
 #[repr(C)]
@@ -131,6 +152,23 @@ impl VmCtxFpsimd {
         self.fpcr = 0;
         self.fpsimd.iter_mut().for_each(|x| *x = 0);
     }
+
+    pub fn migrate_encode(&self, w: &mut StreamWriter) {
+        for reg in self.fpsimd.iter() {
+            w.put_u64(*reg);
+        }
+        w.put_u32(self.fpsr);
+        w.put_u32(self.fpcr);
+    }
+
+    pub fn migrate_decode(&mut self, r: &mut StreamReader) -> Result<(), ()> {
+        for reg in self.fpsimd.iter_mut() {
+            *reg = r.get_u64()?;
+        }
+        self.fpsr = r.get_u32()?;
+        self.fpcr = r.get_u32()?;
+        Ok(())
+    }
 }
 
 #[repr(C)]
@@ -157,6 +195,10 @@ pub struct GicContext {
 }
 
 impl GicContext {
+    pub fn clear_irqs(&mut self) {
+        self.irq_num = 0;
+    }
+
     pub fn add_irq(&mut self, id: u64) {
         let idx = self.irq_num;
         self.irq_state[idx].id = id;
@@ -183,6 +225,38 @@ impl GicContext {
     pub fn gicv_pmr(&self) -> u32 {
         self.gicv_pmr
     }
+
+    pub fn migrate_encode(&self, w: &mut StreamWriter) {
+        let irq_num = usize::min(self.irq_num, self.irq_state.len());
+        w.put_u32(irq_num as u32);
+        for irq in self.irq_state[..irq_num].iter() {
+            w.put_u64(irq.id);
+            w.put_u8(irq.enable);
+            w.put_u8(irq.pend);
+            w.put_u8(irq.active);
+            w.put_u8(irq.priority);
+            w.put_u8(irq.target);
+        }
+        w.put_u32(self.gicv_ctlr);
+        w.put_u32(self.gicv_pmr);
+    }
+
+    pub fn migrate_decode(&mut self, r: &mut StreamReader) -> Result<(), ()> {
+        self.irq_num = r.get_count(self.irq_state.len())?;
+        for irq in self.irq_state[..self.irq_num].iter_mut() {
+            *irq = GicIrqState {
+                id: r.get_u64()?,
+                enable: r.get_u8()?,
+                pend: r.get_u8()?,
+                active: r.get_u8()?,
+                priority: r.get_u8()?,
+                target: r.get_u8()?,
+            };
+        }
+        self.gicv_ctlr = r.get_u32()?;
+        self.gicv_pmr = r.get_u32()?;
+        Ok(())
+    }
 }
 
 #[repr(C)]
@@ -228,15 +302,16 @@ pub struct VmContext {
 
     // hypervisor context
     pub hcr_el2: u64,
+    pub mdcr_el2: u64,
     cptr_el2: u64,
     hstr_el2: u64,
-    pub pmcr_el0: u64,
     pub vtcr_el2: u64,
 
     // exception
     far_el2: u64,
     hpfar_el2: u64,
     fpsimd: VmCtxFpsimd,
+    pub pmu: VmCtxPmu,
     pub gic_state: GicState,
 }
 
@@ -282,15 +357,16 @@ impl VmContext {
 
             // hypervisor context
             hcr_el2: 0,
+            mdcr_el2: 0,
             cptr_el2: 0,
             hstr_el2: 0,
 
             // exception
-            pmcr_el0: 0,
             vtcr_el2: 0,
             far_el2: 0,
             hpfar_el2: 0,
             fpsimd: VmCtxFpsimd::default(),
+            pmu: VmCtxPmu::default(),
             gic_state: GicState::default(),
         }
     }
@@ -327,11 +403,118 @@ impl VmContext {
         self.tpidr_el1 = 0;
         self.tpidrro_el0 = 0;
         self.hcr_el2 = 0;
+        self.mdcr_el2 = 0;
         self.cptr_el2 = 0;
         self.hstr_el2 = 0;
         self.far_el2 = 0;
         self.hpfar_el2 = 0;
         self.fpsimd.reset();
+        self.pmu.reset();
+    }
+
+    // the saved virtual timer has fired, CNTV_CTL_EL0: ENABLE [0], IMASK [1]
+    pub fn vtimer_fired(&self) -> bool {
+        if self.cntv_ctl_el0 & 0b11 != 0b01 {
+            return false;
+        }
+        let cntvct = (timer_arch_get_counter() as u64).wrapping_sub(self.cntvoff_el2);
+        cntvct >= self.cntv_cval_el0
+    }
+
+    pub fn migrate_encode(&self, w: &mut StreamWriter) {
+        w.put_u64(self.cntvoff_el2);
+        w.put_u64(self.cntp_cval_el0);
+        w.put_u64(self.cntv_cval_el0);
+        w.put_u32(self.cntkctl_el1);
+        w.put_u64(self.cntvct_el0);
+        w.put_u32(self.cntp_ctl_el0);
+        w.put_u32(self.cntv_ctl_el0);
+        w.put_u32(self.cntp_tval_el0);
+        w.put_u32(self.cntv_tval_el0);
+        w.put_u32(self.vpidr_el2);
+        w.put_u64(self.vmpidr_el2);
+        w.put_u64(self.sp_el0);
+        w.put_u64(self.sp_el1);
+        w.put_u64(self.elr_el1);
+        w.put_u32(self.spsr_el1);
+        w.put_u32(self.sctlr_el1);
+        w.put_u64(self.actlr_el1);
+        w.put_u32(self.cpacr_el1);
+        w.put_u64(self.ttbr0_el1);
+        w.put_u64(self.ttbr1_el1);
+        w.put_u64(self.tcr_el1);
+        w.put_u32(self.esr_el1);
+        w.put_u64(self.far_el1);
+        w.put_u64(self.par_el1);
+        w.put_u64(self.mair_el1);
+        w.put_u64(self.amair_el1);
+        w.put_u64(self.vbar_el1);
+        w.put_u32(self.contextidr_el1);
+        w.put_u64(self.tpidr_el0);
+        w.put_u64(self.tpidr_el1);
+        w.put_u64(self.tpidrro_el0);
+        w.put_u64(self.far_el2);
+        w.put_u64(self.hpfar_el2);
+        self.fpsimd.migrate_encode(w);
+    }
+
+    /*
+     * Version 1 also carried hcr/mdcr/cptr/hstr/vtcr_el2 and the gic state of the sender build,
+     * they are read and dropped: the receiver keeps its own and refills the list registers from the vgic.
+     */
+    pub fn migrate_decode(&mut self, r: &mut StreamReader, version: u32) -> Result<(), ()> {
+        self.cntvoff_el2 = r.get_u64()?;
+        self.cntp_cval_el0 = r.get_u64()?;
+        self.cntv_cval_el0 = r.get_u64()?;
+        self.cntkctl_el1 = r.get_u32()?;
+        self.cntvct_el0 = r.get_u64()?;
+        self.cntp_ctl_el0 = r.get_u32()?;
+        self.cntv_ctl_el0 = r.get_u32()?;
+        self.cntp_tval_el0 = r.get_u32()?;
+        self.cntv_tval_el0 = r.get_u32()?;
+        self.vpidr_el2 = r.get_u32()?;
+        self.vmpidr_el2 = r.get_u64()?;
+        self.sp_el0 = r.get_u64()?;
+        self.sp_el1 = r.get_u64()?;
+        self.elr_el1 = r.get_u64()?;
+        self.spsr_el1 = r.get_u32()?;
+        self.sctlr_el1 = r.get_u32()?;
+        self.actlr_el1 = r.get_u64()?;
+        self.cpacr_el1 = r.get_u32()?;
+        self.ttbr0_el1 = r.get_u64()?;
+        self.ttbr1_el1 = r.get_u64()?;
+        self.tcr_el1 = r.get_u64()?;
+        self.esr_el1 = r.get_u32()?;
+        self.far_el1 = r.get_u64()?;
+        self.par_el1 = r.get_u64()?;
+        self.mair_el1 = r.get_u64()?;
+        self.amair_el1 = r.get_u64()?;
+        self.vbar_el1 = r.get_u64()?;
+        self.contextidr_el1 = r.get_u32()?;
+        self.tpidr_el0 = r.get_u64()?;
+        self.tpidr_el1 = r.get_u64()?;
+        self.tpidrro_el0 = r.get_u64()?;
+        if version == 1 {
+            // hcr, mdcr, cptr, hstr and vtcr_el2
+            r.get(5 * size_of::<u64>())?;
+        }
+        self.far_el2 = r.get_u64()?;
+        self.hpfar_el2 = r.get_u64()?;
+        self.fpsimd.migrate_decode(r)?;
+        if version == 1 {
+            GicState::default().migrate_decode(r)?;
+        }
+        Ok(())
+    }
+
+    // the registers the host sets up for a vcpu and the list registers of this build
+    pub fn keep_host_state(&mut self, host: &VmContext) {
+        self.hcr_el2 = host.hcr_el2;
+        self.mdcr_el2 = host.mdcr_el2;
+        self.cptr_el2 = host.cptr_el2;
+        self.hstr_el2 = host.hstr_el2;
+        self.vtcr_el2 = host.vtcr_el2;
+        self.gic_state = host.gic_state;
     }
 
     pub fn ext_regs_store(&mut self) {
@@ -367,9 +550,10 @@ impl VmContext {
         mrs!(self.tpidr_el1, TPIDR_EL1);
         mrs!(self.tpidrro_el0, TPIDRRO_EL0);
 
-        mrs!(self.pmcr_el0, PMCR_EL0);
+        self.pmu.save();
         mrs!(self.vtcr_el2, VTCR_EL2);
         mrs!(self.hcr_el2, HCR_EL2);
+        mrs!(self.mdcr_el2, MDCR_EL2);
         // MRS!(self.cptr_el2, CPTR_EL2);
         // MRS!(self.hstr_el2, HSTR_EL2);
         // MRS!(self.far_el2, FAR_EL2);
@@ -413,9 +597,10 @@ impl VmContext {
         msr!(TPIDR_EL1, self.tpidr_el1);
         msr!(TPIDRRO_EL0, self.tpidrro_el0);
 
-        msr!(PMCR_EL0, self.pmcr_el0);
+        self.pmu.restore();
         msr!(VTCR_EL2, self.vtcr_el2);
         msr!(HCR_EL2, self.hcr_el2);
+        msr!(MDCR_EL2, self.mdcr_el2);
         // MSR!(CPTR_EL2, self.cptr_el2);
         // MSR!(HSTR_EL2, self.hstr_el2);
         // MSR!(FAR_EL2, self.far_el2);
@@ -12,11 +12,13 @@ use core::arch::global_asm;
 
 use tock_registers::interfaces::*;
 
-use crate::arch::{ContextFrameTrait, data_abort_handler, hvc_handler, smc_handler};
+use crate::arch::{ContextFrameTrait, data_abort_handler, hvc_handler, smc_handler, sysreg_handler, wfx_handler};
+use crate::arch::instruction_abort_handler;
 use crate::arch::{gicc_clear_current_irq, gicc_get_current_irq};
 use crate::arch::ContextFrame;
 use crate::kernel::{active_vm_id, current_cpu, FRESH_IRQ_LOGIC_LOCK, FRESH_LOGIC_LOCK, fresh_status, FreshStatus};
-use crate::kernel::interrupt_handler;
+use crate::kernel::{cpu_idle, interrupt_handler, sys_power_stop_guests, vm_fault_record, vm_fault_stop, VmFaultReason};
+use crate::kernel::{trace_enabled, trace_event, TraceKind};
 use crate::lib::time_current_us;
 
 // use crate::lib::time_current_us;
@@ -39,7 +41,7 @@ fn exception_class() -> usize {
 }
 
 #[inline(always)]
-fn exception_far() -> usize {
+pub fn exception_far() -> usize {
     cortex_a::registers::FAR_EL2.get() as usize
 }
 
@@ -219,17 +221,34 @@ extern "C" fn lower_aarch64_synchronous(ctx: *mut ContextFrame) {
         }
     }
     current_cpu().set_ctx(ctx);
+    if let Some(vcpu) = &current_cpu().active_vcpu {
+        vcpu.stats_exit(exception_class());
+    }
+    if trace_enabled(TraceKind::VmExit) {
+        let vcpu = current_cpu().active_vcpu.clone().unwrap();
+        trace_event(
+            TraceKind::VmExit,
+            [vcpu.vm_id(), vcpu.id(), exception_class(), exception_esr()],
+        );
+    }
     match exception_class() {
+        0x01 => {
+            wfx_handler();
+        }
         0x24 => {
             // println!("Core[{}] data_abort_handler", cpu_id());
             data_abort_handler();
         }
+        0x20 if instruction_abort_handler() => {}
         0x17 => {
             smc_handler();
         }
         0x16 => {
             hvc_handler();
         }
+        0x18 => {
+            sysreg_handler();
+        }
         _ => unsafe {
             println!(
                 "x0 {:x}, x1 {:x}, x29 {:x}",
@@ -237,16 +256,20 @@ extern "C" fn lower_aarch64_synchronous(ctx: *mut ContextFrame) {
                 (*ctx).gpr(1),
                 (*ctx).gpr(29)
             );
-            panic!(
-                "core {} vm {}: handler not presents for EC_{} @ipa 0x{:x}, @pc 0x{:x}",
-                current_cpu().id,
-                active_vm_id(),
-                exception_class(),
-                exception_fault_addr(),
-                (*ctx).exception_pc()
+            lower_aarch64_fault(
+                VmFaultReason::UnknownException,
+                format_args!(
+                    "core {} vm {}: handler not presents for EC_{} @ipa 0x{:x}, @pc 0x{:x}",
+                    current_cpu().id,
+                    active_vm_id(),
+                    exception_class(),
+                    exception_fault_addr(),
+                    (*ctx).exception_pc()
+                ),
             );
         },
     }
+    sys_power_stop_guests();
     current_cpu().clear_ctx();
 }
 
@@ -294,7 +317,16 @@ extern "C" fn lower_aarch64_irq(ctx: *mut ContextFrame) {
     // let end = time_current_us();
 
     gicc_clear_current_irq(handled_by_hypervisor);
+    trace_event(TraceKind::IrqEoi, [id, handled_by_hypervisor as usize, 0, 0]);
     current_cpu().clear_ctx();
+    // the scheduler left no vcpu on this core, e.g. in an unused window of SchedulerTP, return to
+    // cpu_idle in EL2h instead of the guest, popping this frame so that the stack does not grow
+    if current_cpu().active_vcpu.is_none() && unsafe { (*ctx).spsr } & 0xc != 0x8 {
+        unsafe {
+            (*ctx).spsr = 0x3c9;
+            (*ctx).set_exception_pc(cpu_idle as usize);
+        }
+    }
     // if current_cpu().active_vcpu.is_some()
     //     && current_cpu().active_vcpu.as_ref().unwrap().vm().is_some()
     //     && active_vm_id() == 2
@@ -305,6 +337,36 @@ extern "C" fn lower_aarch64_irq(ctx: *mut ContextFrame) {
 }
 
 #[no_mangle]
-extern "C" fn lower_aarch64_serror() {
-    panic!("lower aarch64 serror");
+extern "C" fn lower_aarch64_serror(ctx: *mut ContextFrame) {
+    current_cpu().set_ctx(ctx);
+    lower_aarch64_fault(
+        VmFaultReason::SError,
+        format_args!(
+            "core {} vm {}: lower aarch64 serror, esr 0x{:x} @pc 0x{:x}",
+            current_cpu().id,
+            active_vm_id(),
+            exception_esr(),
+            current_cpu().get_elr()
+        ),
+    );
+    current_cpu().clear_ctx();
+}
+
+/*
+ * A guest exception the hypervisor can not handle, only the faulting VM is stopped.
+ * The MVM manages all the others, a fault of it still brings the whole system down.
+ */
+fn lower_aarch64_fault(reason: VmFaultReason, msg: core::fmt::Arguments) {
+    let vm_id = active_vm_id();
+    if vm_id == 0 {
+        panic!("{}", msg);
+    }
+    warn!("{}", msg);
+    // FAR_EL2 and HPFAR_EL2 are not valid for an SError
+    let (far, ipa) = match reason {
+        VmFaultReason::SError => (0, 0),
+        _ => (exception_far(), exception_fault_addr()),
+    };
+    vm_fault_record(vm_id, reason, exception_esr(), far, ipa, current_cpu().get_elr());
+    vm_fault_stop(vm_id);
 }
@@ -18,6 +18,7 @@ use tock_registers::registers::*;
 use crate::board::{Platform, PlatOperation};
 use crate::kernel::current_cpu;
 use crate::kernel::INTERRUPT_NUM_MAX;
+use crate::kernel::StreamReader;
 use crate::lib::{bit_extract, trace};
 
 // GICD BITS
@@ -110,6 +111,7 @@ pub struct GicDesc {
     pub gicc_addr: usize,
     pub gich_addr: usize,
     pub gicv_addr: usize,
+    pub gicr_addr: usize,
     pub maintenance_int_id: usize,
 }
 
@@ -273,7 +275,7 @@ impl GicDistributor {
         ((self.ITARGETSR[idx].get() >> off) & 0xff) as usize
     }
 
-    pub fn set_trgt(&self, int_id: usize, trgt: u8) {
+    pub fn set_trgt(&self, int_id: usize, trgt: usize) {
         let idx = (int_id * 8) / 32;
         let off = (int_id * 8) % 32;
         let mask: u32 = 0b11111111 << off;
@@ -555,6 +557,11 @@ impl GicHypervisorInterface {
     }
 }
 
+/*
+ * ctlr is the GICC_CTLR of the core while the vcpu runs, vmcr is GICH_VMCR as it is:
+ * VMGrp0En [0], VMGrp1En [1], VMAckCtl [2], VMFIQEn [3], VMCBPR [4], VEM [9], VMABP [20:18],
+ * VMBP [23:21], VMPriMask [31:27].
+ */
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct GicState {
@@ -564,6 +571,7 @@ pub struct GicState {
     apr: u32,
     pub lr: [u32; GIC_LIST_REGS_NUM],
     pub ctlr: u32,
+    pub vmcr: u32,
 }
 
 impl GicState {
@@ -575,9 +583,46 @@ impl GicState {
             apr: 0,
             lr: [0; GIC_LIST_REGS_NUM],
             ctlr: 0,
+            vmcr: 0,
         }
     }
 
+    pub fn set_ctlr(&mut self, ctlr: u32) {
+        self.ctlr = ctlr;
+    }
+
+    // GICV_CTLR and GICV_PMR of the vcpu, both are views of the saved GICH_VMCR
+    pub fn gicv_ctlr(&self) -> u32 {
+        self.vmcr & 0x21f
+    }
+
+    pub fn gicv_pmr(&self) -> u32 {
+        ((self.vmcr >> 27) & 0x1f) << 3
+    }
+
+    // state [29:28] of a saved list register, bit 28 is pending
+    pub fn lr_pending(&self) -> bool {
+        self.lr[..gich_lrs_num()].iter().any(|lr| (lr >> 28) & 1 != 0)
+    }
+
+    // a saved list register in the GICv2 layout the vgic works with
+    pub fn saved_lr(&self, idx: usize) -> u32 {
+        self.lr[idx]
+    }
+
+    // only reads over the gic state a version 1 migrate stream carries
+    pub fn migrate_decode(&mut self, r: &mut StreamReader) -> Result<(), ()> {
+        self.hcr = r.get_u32()?;
+        self.apr = r.get_u32()?;
+        self.ctlr = r.get_u32()?;
+        self.vmcr = r.get_u32()?;
+        let count = r.get_count(self.lr.len())?;
+        for lr in self.lr[..count].iter_mut() {
+            *lr = r.get_u32()?;
+        }
+        Ok(())
+    }
+
     pub fn save_state(&mut self) {
         self.hcr = GICH.hcr();
         self.apr = GICH.APR.get();
@@ -599,6 +644,7 @@ impl GicState {
             // println!("GICH_LR[{}] {:x}", i, GICH.lr(i));
         }
         self.ctlr = GICC.CTLR.get();
+        self.vmcr = GICH.VMCR.get();
     }
 
     pub fn restore_state(&self) {
@@ -620,6 +666,7 @@ impl GicState {
             GICH.set_lr(i, self.lr[i]);
         }
         GICC.CTLR.set(self.ctlr);
+        GICH.VMCR.set(self.vmcr);
         // println!("GICC ctlr {:x}", self.ctlr);
     }
 }
@@ -0,0 +1,1088 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+// GICv3 backend. It offers the same interface as the GICv2 one in gic.rs, so vgic and the
+// rest of the hypervisor do not need to know which GIC version the platform has:
+// - GICD is still memory-mapped, but SPIs are routed by affinity (GICD_IROUTER);
+// - SGIs and PPIs live in the per-cpu redistributor (GICR);
+// - GICC and GICH are accessed through ICC_* and ICH_* system registers.
+// List registers are converted from/to the GICv2 layout in GICH.lr()/GICH.set_lr(),
+// so the vgic list register logic is shared by both backends.
+
+use alloc::collections::BTreeSet;
+
+use spin::Mutex;
+use tock_registers::*;
+use tock_registers::interfaces::*;
+use tock_registers::registers::*;
+
+use crate::board::{PLAT_DESC, Platform, PlatOperation, PLATFORM_CPU_NUM_MAX};
+use crate::kernel::current_cpu;
+use crate::kernel::INTERRUPT_NUM_MAX;
+use crate::kernel::StreamReader;
+use crate::lib::bit_extract;
+
+// GICD BITS
+const GICD_CTLR_ENABLE_GRP1NS_BIT: usize = 1 << 1;
+const GICD_CTLR_ARE_NS_BIT: usize = 1 << 4;
+const GICD_CTLR_RWP_BIT: usize = 1 << 31;
+pub const GICD_IROUTER_IRM_BIT: usize = 1 << 31;
+
+// GICR BITS
+const GICR_WAKER_PROCESSOR_SLEEP_BIT: usize = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP_BIT: usize = 1 << 2;
+pub const GICR_TYPER_LAST_BIT: usize = 1 << 4;
+pub const GICR_STRIDE: usize = 0x20000;
+pub const GICR_SGI_BASE_OFF: usize = 0x10000;
+
+// GICC BITS, keep the GICv2 meaning, they are translated into ICH_VMCR_EL2 for vcpus
+pub const GICC_CTLR_EN_BIT: usize = 0x1;
+pub const GICC_CTLR_EOIMODENS_BIT: usize = 1 << 9;
+
+// ICC BITS
+const ICC_SRE_SRE_BIT: usize = 1 << 0;
+const ICC_SRE_ENABLE_BIT: usize = 1 << 3;
+const ICC_CTLR_EOIMODE_BIT: usize = 1 << 1;
+const ICC_IGRPEN1_EN_BIT: usize = 1 << 0;
+
+// ICH BITS
+const ICH_HCR_LRENPIE_BIT: usize = 1 << 2;
+const ICH_VMCR_VENG1_BIT: usize = 1 << 1;
+const ICH_VMCR_VEOIM_BIT: usize = 1 << 9;
+const ICH_VMCR_VPMR_OFF: usize = 24;
+const ICH_LR_PINTID_OFF: usize = 32;
+const ICH_LR_PINTID_LEN: usize = 10;
+const ICH_LR_EOI_BIT: usize = 1 << 41;
+const ICH_LR_PRIO_OFF: usize = 48;
+const ICH_LR_GRP1_BIT: usize = 1 << 60;
+const ICH_LR_HW_BIT: usize = 1 << 61;
+const ICH_LR_STATE_OFF: usize = 62;
+
+pub const GIC_SGIS_NUM: usize = 16;
+const GIC_PPIS_NUM: usize = 16;
+pub const GIC_INTS_MAX: usize = INTERRUPT_NUM_MAX;
+pub const GIC_PRIVINT_NUM: usize = GIC_SGIS_NUM + GIC_PPIS_NUM;
+pub const GIC_SPI_MAX: usize = INTERRUPT_NUM_MAX - GIC_PRIVINT_NUM;
+pub const GIC_PRIO_BITS: usize = 8;
+pub const GIC_TARGET_BITS: usize = 8;
+// GICv3 does not report the source of an SGI to the guest, vgicv3 pends them all from source 0
+pub const GIC_TARGETS_MAX: usize = 1;
+pub const GIC_CONFIG_BITS: usize = 2;
+
+const GIC_INT_REGS_NUM: usize = GIC_INTS_MAX / 32;
+const GIC_PRIO_REGS_NUM: usize = GIC_INTS_MAX * 8 / 32;
+const GIC_CONFIG_REGS_NUM: usize = GIC_INTS_MAX * 2 / 32;
+pub const GIC_SGI_REGS_NUM: usize = GIC_SGIS_NUM * 8 / 32;
+
+// ICH_VTR_EL2.ListRegs is 4 bits wide, keep the same size as GICv2 for GicState layout
+pub const GIC_LIST_REGS_NUM: usize = 64;
+const ICH_LIST_REGS_MAX: usize = 16;
+
+pub const GICD_TYPER_CPUNUM_OFF: usize = 5;
+pub const GICD_TYPER_CPUNUM_MSK: usize = 0b11111;
+
+pub static GIC_LRS_NUM: Mutex<usize> = Mutex::new(0);
+
+static GICD_LOCK: Mutex<()> = Mutex::new(());
+
+pub static INTERRUPT_EN_SET: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+pub fn add_en_interrupt(id: usize) {
+    if id < GIC_PRIVINT_NUM {
+        return;
+    }
+    let mut set = INTERRUPT_EN_SET.lock();
+    set.insert(id);
+}
+
+pub fn show_en_interrupt() {
+    let set = INTERRUPT_EN_SET.lock();
+    print!("en irq set: ");
+    for irq in set.iter() {
+        print!("{} ", irq);
+    }
+    print!("\n");
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum IrqState {
+    IrqSInactive,
+    IrqSPend,
+    IrqSActive,
+    IrqSPendActive,
+}
+
+impl IrqState {
+    pub fn num_to_state(num: usize) -> IrqState {
+        match num {
+            0 => IrqState::IrqSInactive,
+            1 => IrqState::IrqSPend,
+            2 => IrqState::IrqSActive,
+            3 => IrqState::IrqSPendActive,
+            _ => panic!("num_to_state: illegal irq state"),
+        }
+    }
+
+    pub fn to_num(&self) -> usize {
+        match self {
+            IrqState::IrqSInactive => 0,
+            IrqState::IrqSPend => 1,
+            IrqState::IrqSActive => 2,
+            IrqState::IrqSPendActive => 3,
+        }
+    }
+}
+
+pub struct GicDesc {
+    pub gicd_addr: usize,
+    pub gicc_addr: usize,
+    pub gich_addr: usize,
+    pub gicv_addr: usize,
+    pub gicr_addr: usize,
+    pub maintenance_int_id: usize,
+}
+
+// Affinity value (Aff3.Aff2.Aff1.Aff0) of a physical cpu, used by IROUTER and SGI1R.
+pub fn gic_cpu_affinity(cpu_id: usize) -> usize {
+    PLAT_DESC.cpu_desc.core_list[cpu_id].mpidr & 0xff_00ff_ffff
+}
+
+fn gic_affinity_to_cpu(aff: usize) -> Option<usize> {
+    for cpu_id in 0..PLAT_DESC.cpu_desc.num {
+        if gic_cpu_affinity(cpu_id) == aff & 0xff_00ff_ffff {
+            return Some(cpu_id);
+        }
+    }
+    None
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub GicDistributorBlock {
+        (0x0000 => CTLR: ReadWrite<u32>),
+        (0x0004 => TYPER: ReadOnly<u32>),
+        (0x0008 => IIDR: ReadOnly<u32>),
+        (0x000c => reserve0),
+        (0x0080 => IGROUPR: [ReadWrite<u32>; GIC_INT_REGS_NUM]),
+        (0x0100 => ISENABLER: [ReadWrite<u32>; GIC_INT_REGS_NUM]),
+        (0x0180 => ICENABLER: [ReadWrite<u32>; GIC_INT_REGS_NUM]),
+        (0x0200 => ISPENDR: [ReadWrite<u32>; GIC_INT_REGS_NUM]),
+        (0x0280 => ICPENDR: [ReadWrite<u32>; GIC_INT_REGS_NUM]),
+        (0x0300 => ISACTIVER: [ReadWrite<u32>; GIC_INT_REGS_NUM]),
+        (0x0380 => ICACTIVER: [ReadWrite<u32>; GIC_INT_REGS_NUM]),
+        (0x0400 => IPRIORITYR: [ReadWrite<u32>; GIC_PRIO_REGS_NUM]),
+        (0x0800 => reserve1),
+        (0x0c00 => ICFGR: [ReadWrite<u32>; GIC_CONFIG_REGS_NUM]),
+        (0x0d00 => IGRPMODR: [ReadWrite<u32>; GIC_INT_REGS_NUM]),
+        (0x0d80 => reserve2),
+        (0x6000 => IROUTER: [ReadWrite<u64>; GIC_INTS_MAX]),
+        (0x8000 => reserve3),
+        (0x10000 => @END),
+    }
+}
+
+pub struct GicDistributor {
+    base_addr: usize,
+}
+
+impl core::ops::Deref for GicDistributor {
+    type Target = GicDistributorBlock;
+    fn deref(&self) -> &Self::Target {
+        if self.base_addr < 0x1000 {
+            panic!("illegal gicd addr {}", self.base_addr);
+        }
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl GicDistributor {
+    const fn new(base_addr: usize) -> GicDistributor {
+        GicDistributor { base_addr }
+    }
+
+    pub fn ptr(&self) -> *const GicDistributorBlock {
+        self.base_addr as *const GicDistributorBlock
+    }
+
+    fn wait_rwp(&self) {
+        while self.CTLR.get() as usize & GICD_CTLR_RWP_BIT != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn is_enabler(&self, idx: usize) -> u32 {
+        if idx == 0 {
+            return GICR[current_cpu().id].ISENABLER0.get();
+        }
+        self.ISENABLER[idx].get()
+    }
+
+    pub fn is_activer(&self, idx: usize) -> u32 {
+        if idx == 0 {
+            return GICR[current_cpu().id].ISACTIVER0.get();
+        }
+        self.ISACTIVER[idx].get()
+    }
+
+    pub fn is_pender(&self, idx: usize) -> u32 {
+        if idx == 0 {
+            return GICR[current_cpu().id].ISPENDR0.get();
+        }
+        self.ISPENDR[idx].get()
+    }
+
+    // there is no SGI pending register per source in GICv3
+    pub fn cpendsgir(&self, _idx: usize) -> u32 {
+        0
+    }
+
+    pub fn igroup(&self, idx: usize) -> u32 {
+        if idx == 0 {
+            return GICR[current_cpu().id].IGROUPR0.get();
+        }
+        self.IGROUPR[idx].get()
+    }
+
+    pub fn ipriorityr(&self, idx: usize) -> u32 {
+        if idx < GIC_PRIVINT_NUM / 4 {
+            return GICR[current_cpu().id].IPRIORITYR[idx].get();
+        }
+        self.IPRIORITYR[idx].get()
+    }
+
+    // GICD_ITARGETSR is RES0 when affinity routing is enabled
+    pub fn itargetsr(&self, _idx: usize) -> u32 {
+        0
+    }
+
+    pub fn irouter(&self, int_id: usize) -> u64 {
+        self.IROUTER[int_id].get()
+    }
+
+    pub fn ctlr(&self) -> u32 {
+        self.CTLR.get()
+    }
+
+    pub fn icfgr(&self, idx: usize) -> u32 {
+        if idx < GIC_PRIVINT_NUM * GIC_CONFIG_BITS / 32 {
+            return GICR[current_cpu().id].ICFGR[idx].get();
+        }
+        self.ICFGR[idx].get()
+    }
+
+    pub fn ic_enabler(&self, idx: usize) -> u32 {
+        if idx == 0 {
+            return GICR[current_cpu().id].ICENABLER0.get();
+        }
+        self.ICENABLER[idx].get()
+    }
+
+    fn global_init(&self) {
+        let int_num = gic_max_spi();
+
+        self.CTLR.set(0);
+        self.wait_rwp();
+
+        for i in GIC_PRIVINT_NUM / 32..int_num / 32 {
+            self.IGROUPR[i].set(u32::MAX);
+            self.ICENABLER[i].set(u32::MAX);
+            self.ICPENDR[i].set(u32::MAX);
+            self.ICACTIVER[i].set(u32::MAX);
+        }
+
+        for i in GIC_PRIVINT_NUM / 4..int_num * 8 / 32 {
+            self.IPRIORITYR[i].set(u32::MAX);
+        }
+
+        for i in GIC_PRIVINT_NUM..int_num {
+            self.IROUTER[i].set(gic_cpu_affinity(0) as u64);
+        }
+        self.wait_rwp();
+
+        self.CTLR
+            .set((GICD_CTLR_ARE_NS_BIT | GICD_CTLR_ENABLE_GRP1NS_BIT) as u32);
+        self.wait_rwp();
+    }
+
+    fn cpu_init(&self) {
+        GICR[current_cpu().id].init();
+    }
+
+    pub fn send_sgi(&self, cpu_if: usize, sgi_num: usize) {
+        let cpu_id = Platform::cpuif_to_cpuid(cpu_if);
+        let aff = gic_cpu_affinity(cpu_id);
+        // TargetList covers Aff0 RS * 16 .. RS * 16 + 15
+        let aff0 = bit_extract(aff, 0, 8);
+        let sgi1r = (bit_extract(aff, 32, 8) << 48)
+            | ((aff0 >> 4) << 44)
+            | (bit_extract(aff, 16, 8) << 32)
+            | ((sgi_num & 0b1111) << 24)
+            | (bit_extract(aff, 8, 8) << 16)
+            | (1 << (aff0 & 0xf));
+        msr!(ICC_SGI1R_EL1, sgi1r);
+        unsafe {
+            core::arch::asm!("isb");
+        }
+    }
+
+    pub fn prio(&self, int_id: usize) -> usize {
+        if gic_is_priv(int_id) {
+            return GICR[current_cpu().id].prio(int_id);
+        }
+        let idx = (int_id * 8) / 32;
+        let off = (int_id * 8) % 32;
+        ((self.IPRIORITYR[idx].get() >> off) & 0xff) as usize
+    }
+
+    pub fn set_prio(&self, int_id: usize, prio: u8) {
+        if gic_is_priv(int_id) {
+            GICR[current_cpu().id].set_prio(int_id, prio);
+            return;
+        }
+        let idx = (int_id * 8) / 32;
+        let off = (int_id * 8) % 32;
+        let mask: u32 = 0b11111111 << off;
+
+        let lock = GICD_LOCK.lock();
+        let prev = self.IPRIORITYR[idx].get();
+        let value = (prev & !mask) | (((prio as u32) << off) & mask);
+        self.IPRIORITYR[idx].set(value);
+        drop(lock);
+    }
+
+    // Translate IROUTER back into a GICv2 style cpu interface mask.
+    pub fn trgt(&self, int_id: usize) -> usize {
+        if gic_is_priv(int_id) {
+            return 1 << Platform::cpuid_to_cpuif(current_cpu().id);
+        }
+        let route = self.IROUTER[int_id].get() as usize;
+        if route & GICD_IROUTER_IRM_BIT != 0 {
+            return (1 << PLAT_DESC.cpu_desc.num) - 1;
+        }
+        match gic_affinity_to_cpu(route) {
+            Some(cpu_id) => 1 << Platform::cpuid_to_cpuif(cpu_id),
+            None => 0,
+        }
+    }
+
+    // Affinity routing can only target one cpu (or any cpu with IRM), pick the first one in trgt.
+    pub fn set_trgt(&self, int_id: usize, trgt: usize) {
+        if gic_is_priv(int_id) || trgt == 0 {
+            return;
+        }
+        let cpu_id = Platform::cpuif_to_cpuid(trgt.trailing_zeros() as usize);
+        self.set_route(int_id, cpu_id);
+    }
+
+    pub fn set_route(&self, int_id: usize, cpu_id: usize) {
+        if gic_is_priv(int_id) {
+            return;
+        }
+        let lock = GICD_LOCK.lock();
+        self.IROUTER[int_id].set(gic_cpu_affinity(cpu_id) as u64);
+        drop(lock);
+    }
+
+    pub fn set_enable(&self, int_id: usize, en: bool) {
+        if gic_is_priv(int_id) {
+            GICR[current_cpu().id].set_enable(int_id, en);
+            return;
+        }
+        let idx = int_id / 32;
+        let bit = 1 << (int_id % 32);
+
+        let lock = GICD_LOCK.lock();
+        if en {
+            add_en_interrupt(int_id);
+            self.ISENABLER[idx].set(bit);
+        } else {
+            self.ICENABLER[idx].set(bit);
+        }
+        self.wait_rwp();
+        drop(lock);
+    }
+
+    pub fn set_pend(&self, int_id: usize, pend: bool) {
+        if gic_is_priv(int_id) {
+            GICR[current_cpu().id].set_pend(int_id, pend);
+            return;
+        }
+        let lock = GICD_LOCK.lock();
+        let reg_ind = int_id / 32;
+        let mask = 1 << int_id % 32;
+        if pend {
+            self.ISPENDR[reg_ind].set(mask);
+        } else {
+            self.ICPENDR[reg_ind].set(mask);
+        }
+        drop(lock);
+    }
+
+    pub fn set_act(&self, int_id: usize, act: bool) {
+        if gic_is_priv(int_id) {
+            GICR[current_cpu().id].set_act(int_id, act);
+            return;
+        }
+        let reg_ind = int_id / 32;
+        let mask = 1 << int_id % 32;
+
+        let lock = GICD_LOCK.lock();
+        if act {
+            self.ISACTIVER[reg_ind].set(mask);
+        } else {
+            self.ICACTIVER[reg_ind].set(mask);
+        }
+        drop(lock);
+    }
+
+    pub fn set_state(&self, int_id: usize, state: usize) {
+        self.set_act(int_id, (state & 2) != 0);
+        self.set_pend(int_id, (state & 1) != 0);
+    }
+
+    pub fn set_icfgr(&self, int_id: usize, cfg: u8) {
+        if gic_is_priv(int_id) {
+            GICR[current_cpu().id].set_icfgr(int_id, cfg);
+            return;
+        }
+        let lock = GICD_LOCK.lock();
+        let reg_ind = (int_id * GIC_CONFIG_BITS) / 32;
+        let off = (int_id * GIC_CONFIG_BITS) % 32;
+        let mask = 0b11 << off;
+
+        let icfgr = self.ICFGR[reg_ind].get();
+        self.ICFGR[reg_ind].set((icfgr & !mask) | (((cfg as u32) << off) & mask));
+        drop(lock);
+    }
+
+    pub fn typer(&self) -> u32 {
+        self.TYPER.get()
+    }
+
+    pub fn iidr(&self) -> u32 {
+        self.IIDR.get()
+    }
+
+    pub fn state(&self, int_id: usize) -> usize {
+        if gic_is_priv(int_id) {
+            return GICR[current_cpu().id].state(int_id);
+        }
+        let reg_ind = int_id / 32;
+        let mask = 1 << int_id % 32;
+
+        let lock = GICD_LOCK.lock();
+        let pend = if (self.ISPENDR[reg_ind].get() & mask) != 0 {
+            1
+        } else {
+            0
+        };
+        let act = if (self.ISACTIVER[reg_ind].get() & mask) != 0 {
+            2
+        } else {
+            0
+        };
+        drop(lock);
+        return pend | act;
+    }
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    pub GicRedistributorBlock {
+        // RD_base frame
+        (0x0000 => CTLR: ReadWrite<u32>),
+        (0x0004 => IIDR: ReadOnly<u32>),
+        (0x0008 => TYPER: ReadOnly<u64>),
+        (0x0010 => reserve0),
+        (0x0014 => WAKER: ReadWrite<u32>),
+        (0x0018 => reserve1),
+        // SGI_base frame
+        (0x10080 => IGROUPR0: ReadWrite<u32>),
+        (0x10084 => reserve2),
+        (0x10100 => ISENABLER0: ReadWrite<u32>),
+        (0x10104 => reserve3),
+        (0x10180 => ICENABLER0: ReadWrite<u32>),
+        (0x10184 => reserve4),
+        (0x10200 => ISPENDR0: ReadWrite<u32>),
+        (0x10204 => reserve5),
+        (0x10280 => ICPENDR0: ReadWrite<u32>),
+        (0x10284 => reserve6),
+        (0x10300 => ISACTIVER0: ReadWrite<u32>),
+        (0x10304 => reserve7),
+        (0x10380 => ICACTIVER0: ReadWrite<u32>),
+        (0x10384 => reserve8),
+        (0x10400 => IPRIORITYR: [ReadWrite<u32>; GIC_PRIVINT_NUM / 4]),
+        (0x10420 => reserve9),
+        (0x10c00 => ICFGR: [ReadWrite<u32>; GIC_PRIVINT_NUM * GIC_CONFIG_BITS / 32]),
+        (0x10c08 => reserve10),
+        (0x10d00 => IGRPMODR0: ReadWrite<u32>),
+        (0x10d04 => reserve11),
+        (0x20000 => @END),
+    }
+}
+
+pub struct GicRedistributor {
+    base_addr: usize,
+}
+
+impl core::ops::Deref for GicRedistributor {
+    type Target = GicRedistributorBlock;
+    fn deref(&self) -> &Self::Target {
+        if self.base_addr < 0x1000 {
+            panic!("illegal gicr addr {}", self.base_addr);
+        }
+        unsafe { &*self.ptr() }
+    }
+}
+
+impl GicRedistributor {
+    const fn new(base_addr: usize) -> GicRedistributor {
+        GicRedistributor { base_addr }
+    }
+
+    pub fn ptr(&self) -> *const GicRedistributorBlock {
+        self.base_addr as *const GicRedistributorBlock
+    }
+
+    fn init(&self) {
+        let aff = bit_extract(self.TYPER.get() as usize, 32, 32);
+        let cpu_aff = gic_cpu_affinity(current_cpu().id);
+        let cpu_aff = (cpu_aff & 0xff_ffff) | (bit_extract(cpu_aff, 32, 8) << 24);
+        if aff != cpu_aff {
+            warn!(
+                "Core {} redistributor affinity 0x{:x} mismatch with mpidr 0x{:x}",
+                current_cpu().id,
+                aff,
+                cpu_aff
+            );
+        }
+
+        // wake up the redistributor
+        let waker = self.WAKER.get() as usize;
+        self.WAKER.set((waker & !GICR_WAKER_PROCESSOR_SLEEP_BIT) as u32);
+        while self.WAKER.get() as usize & GICR_WAKER_CHILDREN_ASLEEP_BIT != 0 {
+            core::hint::spin_loop();
+        }
+
+        /*
+         * Make sure all private interrupts are group 1, not enabled, non pending,
+         * non active.
+         */
+        self.IGROUPR0.set(u32::MAX);
+        self.IGRPMODR0.set(0);
+        self.ICENABLER0.set(u32::MAX);
+        self.ICPENDR0.set(u32::MAX);
+        self.ICACTIVER0.set(u32::MAX);
+
+        /* All interrupts have lowest priority possible by default */
+        for i in 0..(GIC_PRIVINT_NUM * 8) / 32 {
+            self.IPRIORITYR[i].set(u32::MAX);
+        }
+    }
+
+    pub fn typer(&self) -> u64 {
+        self.TYPER.get()
+    }
+
+    fn prio(&self, int_id: usize) -> usize {
+        let idx = (int_id * 8) / 32;
+        let off = (int_id * 8) % 32;
+        ((self.IPRIORITYR[idx].get() >> off) & 0xff) as usize
+    }
+
+    fn set_prio(&self, int_id: usize, prio: u8) {
+        let idx = (int_id * 8) / 32;
+        let off = (int_id * 8) % 32;
+        let mask: u32 = 0b11111111 << off;
+
+        let prev = self.IPRIORITYR[idx].get();
+        let value = (prev & !mask) | (((prio as u32) << off) & mask);
+        self.IPRIORITYR[idx].set(value);
+    }
+
+    fn set_enable(&self, int_id: usize, en: bool) {
+        let bit = 1 << (int_id % 32);
+        if en {
+            self.ISENABLER0.set(bit);
+        } else {
+            self.ICENABLER0.set(bit);
+        }
+    }
+
+    fn set_pend(&self, int_id: usize, pend: bool) {
+        let bit = 1 << (int_id % 32);
+        if pend {
+            self.ISPENDR0.set(bit);
+        } else {
+            self.ICPENDR0.set(bit);
+        }
+    }
+
+    fn set_act(&self, int_id: usize, act: bool) {
+        let bit = 1 << (int_id % 32);
+        if act {
+            self.ISACTIVER0.set(bit);
+        } else {
+            self.ICACTIVER0.set(bit);
+        }
+    }
+
+    fn set_icfgr(&self, int_id: usize, cfg: u8) {
+        let reg_ind = (int_id * GIC_CONFIG_BITS) / 32;
+        let off = (int_id * GIC_CONFIG_BITS) % 32;
+        let mask = 0b11 << off;
+
+        let icfgr = self.ICFGR[reg_ind].get();
+        self.ICFGR[reg_ind].set((icfgr & !mask) | (((cfg as u32) << off) & mask));
+    }
+
+    fn state(&self, int_id: usize) -> usize {
+        let mask = 1 << (int_id % 32);
+        let pend = if (self.ISPENDR0.get() & mask) != 0 { 1 } else { 0 };
+        let act = if (self.ISACTIVER0.get() & mask) != 0 { 2 } else { 0 };
+        pend | act
+    }
+}
+
+// GICv3 cpu interface, accessed by ICC_*_EL1 system registers.
+pub struct GicCpuInterface;
+
+impl GicCpuInterface {
+    pub const fn new() -> GicCpuInterface {
+        GicCpuInterface
+    }
+
+    fn init(&self) {
+        // enable system register access for EL2 and allow EL1 to use ICC_SRE_EL1
+        let mut sre: usize;
+        mrs!(sre, ICC_SRE_EL2);
+        msr!(ICC_SRE_EL2, sre | ICC_SRE_SRE_BIT | ICC_SRE_ENABLE_BIT);
+        unsafe {
+            core::arch::asm!("isb");
+        }
+
+        for i in 0..gich_lrs_num() {
+            GICH.set_lr(i, 0);
+        }
+
+        msr!(ICC_PMR_EL1, 0xffusize);
+        let mut ctlr: usize;
+        mrs!(ctlr, ICC_CTLR_EL1);
+        msr!(ICC_CTLR_EL1, ctlr | ICC_CTLR_EOIMODE_BIT);
+        msr!(ICC_IGRPEN1_EL1, ICC_IGRPEN1_EN_BIT);
+
+        let hcr_prev = GICH.hcr();
+        GICH.set_hcr(hcr_prev | ICH_HCR_LRENPIE_BIT as u32);
+    }
+
+    pub fn iar(&self) -> u32 {
+        let iar: usize;
+        mrs!(iar, ICC_IAR1_EL1);
+        iar as u32
+    }
+
+    pub fn set_eoir(&self, eoir: u32) {
+        msr!(ICC_EOIR1_EL1, eoir as usize);
+    }
+
+    pub fn set_dir(&self, dir: u32) {
+        msr!(ICC_DIR_EL1, dir as usize);
+    }
+
+    pub fn hppir(&self) -> u32 {
+        let hppir: usize;
+        mrs!(hppir, ICC_HPPIR1_EL1);
+        hppir as u32
+    }
+
+    pub fn rpr(&self) -> u32 {
+        let rpr: usize;
+        mrs!(rpr, ICC_RPR_EL1);
+        rpr as u32
+    }
+
+    pub fn bpr(&self) -> u32 {
+        let bpr: usize;
+        mrs!(bpr, ICC_BPR1_EL1);
+        bpr as u32
+    }
+
+    pub fn abpr(&self) -> u32 {
+        let bpr: usize;
+        mrs!(bpr, ICC_BPR0_EL1);
+        bpr as u32
+    }
+
+    pub fn apr(&self, idx: usize) -> u32 {
+        let apr: usize;
+        match idx {
+            0 => mrs!(apr, ICC_AP1R0_EL1),
+            1 => mrs!(apr, ICC_AP1R1_EL1),
+            2 => mrs!(apr, ICC_AP1R2_EL1),
+            _ => mrs!(apr, ICC_AP1R3_EL1),
+        }
+        apr as u32
+    }
+
+    pub fn nsapr(&self, idx: usize) -> u32 {
+        let apr: usize;
+        match idx {
+            0 => mrs!(apr, ICC_AP0R0_EL1),
+            1 => mrs!(apr, ICC_AP0R1_EL1),
+            2 => mrs!(apr, ICC_AP0R2_EL1),
+            _ => mrs!(apr, ICC_AP0R3_EL1),
+        }
+        apr as u32
+    }
+}
+
+// GICv3 virtual interface control, accessed by ICH_*_EL2 system registers.
+pub struct GicHypervisorInterface;
+
+impl GicHypervisorInterface {
+    const fn new() -> GicHypervisorInterface {
+        GicHypervisorInterface
+    }
+
+    pub fn hcr(&self) -> u32 {
+        let hcr: usize;
+        mrs!(hcr, ICH_HCR_EL2);
+        hcr as u32
+    }
+
+    pub fn set_hcr(&self, hcr: u32) {
+        msr!(ICH_HCR_EL2, hcr as usize);
+    }
+
+    pub fn vtr(&self) -> u32 {
+        let vtr: usize;
+        mrs!(vtr, ICH_VTR_EL2);
+        vtr as u32
+    }
+
+    pub fn vmcr(&self) -> u32 {
+        let vmcr: usize;
+        mrs!(vmcr, ICH_VMCR_EL2);
+        vmcr as u32
+    }
+
+    pub fn set_vmcr(&self, vmcr: u32) {
+        msr!(ICH_VMCR_EL2, vmcr as usize);
+    }
+
+    // ICH_ELRSR_EL2 only covers 16 list registers
+    pub fn elrsr(&self, elsr_idx: usize) -> u32 {
+        if elsr_idx != 0 {
+            return 0;
+        }
+        let elrsr: usize;
+        mrs!(elrsr, ICH_ELRSR_EL2);
+        elrsr as u32
+    }
+
+    pub fn eisr(&self, eisr_idx: usize) -> u32 {
+        if eisr_idx != 0 {
+            return 0;
+        }
+        let eisr: usize;
+        mrs!(eisr, ICH_EISR_EL2);
+        eisr as u32
+    }
+
+    pub fn misr(&self) -> u32 {
+        let misr: usize;
+        mrs!(misr, ICH_MISR_EL2);
+        misr as u32
+    }
+
+    pub fn apr(&self) -> u32 {
+        let apr: usize;
+        mrs!(apr, ICH_AP1R0_EL2);
+        apr as u32
+    }
+
+    pub fn set_apr(&self, apr: u32) {
+        msr!(ICH_AP1R0_EL2, apr as usize);
+    }
+
+    // GICv2 layout list register, see gich_lr_from_v3
+    pub fn lr(&self, lr_idx: usize) -> u32 {
+        gich_lr_from_v3(self.lr_raw(lr_idx))
+    }
+
+    pub fn set_lr(&self, lr_idx: usize, val: u32) {
+        self.set_lr_raw(lr_idx, gich_lr_to_v3(val));
+    }
+
+    pub fn lr_raw(&self, lr_idx: usize) -> u64 {
+        let lr: u64;
+        match lr_idx {
+            0 => mrs!(lr, ICH_LR0_EL2),
+            1 => mrs!(lr, ICH_LR1_EL2),
+            2 => mrs!(lr, ICH_LR2_EL2),
+            3 => mrs!(lr, ICH_LR3_EL2),
+            4 => mrs!(lr, ICH_LR4_EL2),
+            5 => mrs!(lr, ICH_LR5_EL2),
+            6 => mrs!(lr, ICH_LR6_EL2),
+            7 => mrs!(lr, ICH_LR7_EL2),
+            8 => mrs!(lr, ICH_LR8_EL2),
+            9 => mrs!(lr, ICH_LR9_EL2),
+            10 => mrs!(lr, ICH_LR10_EL2),
+            11 => mrs!(lr, ICH_LR11_EL2),
+            12 => mrs!(lr, ICH_LR12_EL2),
+            13 => mrs!(lr, ICH_LR13_EL2),
+            14 => mrs!(lr, ICH_LR14_EL2),
+            15 => mrs!(lr, ICH_LR15_EL2),
+            _ => panic!("illegal ich lr idx {}", lr_idx),
+        }
+        lr
+    }
+
+    pub fn set_lr_raw(&self, lr_idx: usize, val: u64) {
+        match lr_idx {
+            0 => msr!(ICH_LR0_EL2, val),
+            1 => msr!(ICH_LR1_EL2, val),
+            2 => msr!(ICH_LR2_EL2, val),
+            3 => msr!(ICH_LR3_EL2, val),
+            4 => msr!(ICH_LR4_EL2, val),
+            5 => msr!(ICH_LR5_EL2, val),
+            6 => msr!(ICH_LR6_EL2, val),
+            7 => msr!(ICH_LR7_EL2, val),
+            8 => msr!(ICH_LR8_EL2, val),
+            9 => msr!(ICH_LR9_EL2, val),
+            10 => msr!(ICH_LR10_EL2, val),
+            11 => msr!(ICH_LR11_EL2, val),
+            12 => msr!(ICH_LR12_EL2, val),
+            13 => msr!(ICH_LR13_EL2, val),
+            14 => msr!(ICH_LR14_EL2, val),
+            15 => msr!(ICH_LR15_EL2, val),
+            _ => panic!("illegal ich lr idx {}", lr_idx),
+        }
+    }
+}
+
+/*
+ * GICv2 GICH_LR: vID [9:0], pID / cpuid / EOI [19:10], prio [27:23], state [29:28], grp1 [30], hw [31]
+ * GICv3 ICH_LR:  vINTID [31:0], pINTID / EOI [41:32], prio [55:48], group [60], hw [61], state [63:62]
+ * Guests always use group 1 interrupts (ICC_IAR1_EL1), SGI source cpu is not kept in GICv3.
+ */
+fn gich_lr_to_v3(lr: u32) -> u64 {
+    let lr = lr as usize;
+    let hw = lr & (1 << 31) != 0;
+    let mut val = bit_extract(lr, 0, 10);
+    val |= bit_extract(lr, 23, 5) << (ICH_LR_PRIO_OFF + 3);
+    val |= bit_extract(lr, 28, 2) << ICH_LR_STATE_OFF;
+    val |= ICH_LR_GRP1_BIT;
+    if hw {
+        val |= ICH_LR_HW_BIT;
+        val |= bit_extract(lr, 10, 10) << ICH_LR_PINTID_OFF;
+    } else if lr & (1 << 19) != 0 {
+        val |= ICH_LR_EOI_BIT;
+    }
+    val as u64
+}
+
+fn gich_lr_from_v3(lr: u64) -> u32 {
+    let lr = lr as usize;
+    let hw = lr & ICH_LR_HW_BIT != 0;
+    let mut val = bit_extract(lr, 0, 10);
+    val |= bit_extract(lr, ICH_LR_PRIO_OFF + 3, 5) << 23;
+    val |= bit_extract(lr, ICH_LR_STATE_OFF, 2) << 28;
+    val |= 1 << 30;
+    if hw {
+        val |= 1 << 31;
+        val |= bit_extract(lr, ICH_LR_PINTID_OFF, ICH_LR_PINTID_LEN) << 10;
+    } else if lr & ICH_LR_EOI_BIT != 0 {
+        val |= 1 << 19;
+    }
+    val as u32
+}
+
+/*
+ * vmcr is ICH_VMCR_EL2 as it is: VENG0 [0], VENG1 [1], VAckCtl [2], VFIQEn [3], VCBPR [4],
+ * VEOIM [9], VBPR1 [20:18], VBPR0 [23:21], VPMR [31:24].
+ */
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct GicState {
+    pub hcr: u32,
+    eisr: [u32; GIC_LIST_REGS_NUM / 32],
+    elrsr: [u32; GIC_LIST_REGS_NUM / 32],
+    apr: u32,
+    pub lr: [u64; GIC_LIST_REGS_NUM],
+    pub vmcr: u32,
+}
+
+impl GicState {
+    pub fn default() -> GicState {
+        GicState {
+            hcr: 0,
+            eisr: [0; GIC_LIST_REGS_NUM / 32],
+            elrsr: [0; GIC_LIST_REGS_NUM / 32],
+            apr: 0,
+            lr: [0; GIC_LIST_REGS_NUM],
+            vmcr: 0,
+        }
+    }
+
+    // the initial cpu interface of the vcpu, ctlr in the GICv2 GICC_CTLR meaning
+    pub fn set_ctlr(&mut self, ctlr: u32) {
+        let ctlr = ctlr as usize;
+        let mut vmcr = 0xff << ICH_VMCR_VPMR_OFF;
+        if ctlr & GICC_CTLR_EN_BIT != 0 {
+            vmcr |= ICH_VMCR_VENG1_BIT;
+        }
+        if ctlr & GICC_CTLR_EOIMODENS_BIT != 0 {
+            vmcr |= ICH_VMCR_VEOIM_BIT;
+        }
+        self.vmcr = vmcr as u32;
+    }
+
+    // the ctlr and pmr of the vcpu kept by GicContext, taken from the saved ICH_VMCR_EL2
+    pub fn gicv_ctlr(&self) -> u32 {
+        self.vmcr
+    }
+
+    pub fn gicv_pmr(&self) -> u32 {
+        self.vmcr >> ICH_VMCR_VPMR_OFF
+    }
+
+    // state [63:62] of a saved list register, bit 62 is pending
+    pub fn lr_pending(&self) -> bool {
+        self.lr[..gich_lrs_num()]
+            .iter()
+            .any(|lr| (lr >> ICH_LR_STATE_OFF) & 1 != 0)
+    }
+
+    // a saved list register in the GICv2 layout the vgic works with
+    pub fn saved_lr(&self, idx: usize) -> u32 {
+        gich_lr_from_v3(self.lr[idx])
+    }
+
+    // only reads over the gic state a version 1 migrate stream carries
+    pub fn migrate_decode(&mut self, r: &mut StreamReader) -> Result<(), ()> {
+        self.hcr = r.get_u32()?;
+        self.apr = r.get_u32()?;
+        self.vmcr = r.get_u32()?;
+        let count = r.get_count(self.lr.len())?;
+        for lr in self.lr[..count].iter_mut() {
+            *lr = r.get_u64()?;
+        }
+        Ok(())
+    }
+
+    pub fn save_state(&mut self) {
+        self.hcr = GICH.hcr();
+        self.apr = GICH.apr();
+        for i in 0..(GIC_LIST_REGS_NUM / 32) {
+            self.eisr[i] = GICH.eisr(i);
+            self.elrsr[i] = GICH.elrsr(i);
+        }
+        for i in 0..gich_lrs_num() {
+            if self.elrsr[0] & 1 << i == 0 {
+                self.lr[i] = GICH.lr_raw(i);
+            } else {
+                self.lr[i] = 0;
+            }
+        }
+        self.vmcr = GICH.vmcr();
+    }
+
+    pub fn restore_state(&self) {
+        GICH.set_hcr(self.hcr);
+        GICH.set_apr(self.apr);
+
+        for i in 0..gich_lrs_num() {
+            GICH.set_lr_raw(i, self.lr[i]);
+        }
+        GICH.set_vmcr(self.vmcr);
+    }
+}
+
+pub static GICD: GicDistributor = GicDistributor::new(Platform::GICD_BASE + 0x8_0000_0000);
+pub static GICC: GicCpuInterface = GicCpuInterface::new();
+pub static GICH: GicHypervisorInterface = GicHypervisorInterface::new();
+pub static GICR: [GicRedistributor; PLATFORM_CPU_NUM_MAX] = {
+    const BASE: usize = Platform::GICR_BASE + 0x8_0000_0000;
+    let mut gicr = [const { GicRedistributor::new(0) }; PLATFORM_CPU_NUM_MAX];
+    let mut i = 0;
+    while i < PLATFORM_CPU_NUM_MAX {
+        gicr[i] = GicRedistributor::new(BASE + i * GICR_STRIDE);
+        i += 1;
+    }
+    gicr
+};
+
+#[inline(always)]
+pub fn gich_lrs_num() -> usize {
+    let vtr = GICH.vtr();
+    usize::min(((vtr & 0b11111) + 1) as usize, ICH_LIST_REGS_MAX)
+}
+
+#[inline(always)]
+pub fn gic_max_spi() -> usize {
+    let typer = GICD.TYPER.get();
+    let value = typer & 0b11111;
+    usize::min((32 * (value + 1)) as usize, GIC_INTS_MAX)
+}
+
+pub fn gic_glb_init() {
+    set_gic_lrs(gich_lrs_num());
+    GICD.global_init();
+}
+
+pub fn gic_cpu_init() {
+    GICD.cpu_init();
+    GICC.init();
+}
+
+pub fn gic_cpu_reset() {
+    GICC.init();
+}
+
+pub fn gic_is_priv(int_id: usize) -> bool {
+    int_id < GIC_PRIVINT_NUM
+}
+
+pub fn gic_is_sgi(int_id: usize) -> bool {
+    int_id < GIC_SGIS_NUM
+}
+
+pub fn gicc_clear_current_irq(for_hypervisor: bool) {
+    let irq = current_cpu().current_irq as u32;
+    if irq == 0 {
+        return;
+    }
+    let gicc = &GICC;
+    gicc.set_eoir(irq);
+    if for_hypervisor {
+        gicc.set_dir(irq);
+    }
+    let irq = 0;
+    current_cpu().current_irq = irq;
+}
+
+pub fn gicc_get_current_irq() -> (usize, usize) {
+    let iar = GICC.iar();
+    let irq = iar as usize;
+    current_cpu().current_irq = irq;
+    let id = bit_extract(iar as usize, 0, 24);
+    // GICv3 does not report the source cpu of SGIs
+    (id, 0)
+}
+
+pub fn gic_lrs() -> usize {
+    *GIC_LRS_NUM.lock()
+}
+
+pub fn set_gic_lrs(lrs: usize) {
+    let mut gic_lrs = GIC_LRS_NUM.lock();
+    *gic_lrs = lrs;
+}
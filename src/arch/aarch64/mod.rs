@@ -11,7 +11,10 @@
 pub use self::context_frame::*;
 pub use self::cpu::*;
 pub use self::exception::*;
+#[cfg(not(feature = "gicv3"))]
 pub use self::gic::*;
+#[cfg(feature = "gicv3")]
+pub use self::gicv3::*;
 pub use self::interface::*;
 pub use self::interrupt::*;
 pub use self::mmu::*;
@@ -22,10 +25,14 @@ pub use self::smc::*;
 pub use self::cache::*;
 pub use self::smmu::*;
 pub use self::sync::*;
+pub use self::sysreg::*;
 pub use self::timer::*;
 pub use self::tlb::*;
 pub use self::vcpu::*;
 pub use self::vgic::*;
+#[cfg(feature = "gicv3")]
+pub use self::vgicv3::*;
+pub use self::vpmu::*;
 
 #[macro_use]
 mod regs;
@@ -34,7 +41,10 @@ mod cache;
 mod context_frame;
 mod cpu;
 mod exception;
+#[cfg(not(feature = "gicv3"))]
 mod gic;
+#[cfg(feature = "gicv3")]
+mod gicv3;
 mod interface;
 mod interrupt;
 mod mmu;
@@ -43,7 +53,11 @@ mod psci;
 mod smc;
 mod smmu;
 mod sync;
+mod sysreg;
 mod timer;
 mod tlb;
 mod vcpu;
 mod vgic;
+#[cfg(feature = "gicv3")]
+mod vgicv3;
+mod vpmu;
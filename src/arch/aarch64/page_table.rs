@@ -278,6 +278,44 @@ impl PageTable {
         }
     }
 
+    /*
+     * Replace the 2MB block mapping ipa with a table of 512 pages of the same attributes. The block
+     * entry is invalidated in the TLB of the VM by vttbr before the table takes its place
+     * (break-before-make), an access to it meanwhile takes a translation fault.
+     * Returns false if ipa is not mapped by a block.
+     */
+    pub fn split_2mb(&self, ipa: usize, vttbr: usize) -> bool {
+        let directory = Aarch64PageTableEntry::from_pa(self.directory.pa());
+        let l1e = directory.entry(pt_lvl1_idx(ipa));
+        if !l1e.valid() {
+            return false;
+        }
+        let l2e = l1e.entry(pt_lvl2_idx(ipa));
+        if !l2e.valid() || l2e.to_pte() & 0b11 != PTE_BLOCK {
+            return false;
+        }
+        let frame = match crate::kernel::mem_page_alloc() {
+            Ok(frame) => frame,
+            Err(_) => {
+                println!("split_2mb: alloc lv3 page failed");
+                return false;
+            }
+        };
+        let attr = l2e.to_pte() & !0x0000_FFFF_FFFF_F000 & !0b11;
+        let l3 = Aarch64PageTableEntry::from_pa(frame.pa());
+        for i in 0..PTE_PER_PAGE {
+            l3.set_entry(
+                i,
+                Aarch64PageTableEntry::from_pa((l2e.to_pa() + i * PAGE_SIZE) | attr | PTE_PAGE),
+            );
+        }
+        l1e.set_entry(pt_lvl2_idx(ipa), Aarch64PageTableEntry(0));
+        crate::arch::tlb_invalidate_vm_all(vttbr);
+        l1e.set_entry(pt_lvl2_idx(ipa), Aarch64PageTableEntry::make_table(frame.pa()));
+        self.pages.lock().push(frame);
+        true
+    }
+
     pub fn map(&self, ipa: usize, pa: usize, pte: usize) {
         // if ipa >= 0x4_0000_0000 {
         //     println!("map ipa 0x{:x} to pa 0x{:x}", ipa, pa);
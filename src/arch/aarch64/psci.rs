@@ -11,27 +11,39 @@
 use crate::arch::{gic_cpu_init, gicc_clear_current_irq, vcpu_arch_init};
 use crate::kernel::{cpu_idle, current_cpu, ipi_intra_broadcast_msg, Scheduler, timer_enable, Vcpu, VcpuState, Vm};
 use crate::kernel::{active_vm, ipi_send_msg, IpiInnerMsg, IpiPowerMessage, IpiType, PowerEvent};
-use crate::kernel::CpuState;
+use crate::kernel::{vcpu_wfi, CpuState, VcpuPowerState};
 use crate::kernel::IpiMessage;
-use crate::vmm::vmm_reboot;
+use crate::kernel::{sys_power_request, SysPowerEvent};
+use crate::vmm::{vmm_reboot, vmm_shutdown_vm};
 
-use super::smc::smc_call;
+use super::smc::{smc_call, smccc_guest_handler, SMCCC_VERSION};
 
 pub const PSCI_VERSION: usize = 0x84000000;
+pub const PSCI_CPU_SUSPEND: usize = 0x84000001;
+pub const PSCI_CPU_OFF: usize = 0x84000002;
+pub const PSCI_CPU_ON: usize = 0x84000003;
+pub const PSCI_AFFINITY_INFO: usize = 0x84000004;
 pub const PSCI_MIG_INFO_TYPE: usize = 0x84000006;
 pub const PSCI_FEATURES: usize = 0x8400000A;
-// pub const PSCI_CPU_SUSPEND_AARCH64: usize = 0xc4000001;
-// pub const PSCI_CPU_OFF: usize = 0xc4000002;
+pub const PSCI_CPU_SUSPEND_AARCH64: usize = 0xc4000001;
 pub const PSCI_CPU_ON_AARCH64: usize = 0xc4000003;
 pub const PSCI_AFFINITY_INFO_AARCH64: usize = 0xc4000004;
 pub const PSCI_SYSTEM_OFF: usize = 0x84000008;
 pub const PSCI_SYSTEM_RESET: usize = 0x84000009;
+pub const PSCI_SYSTEM_RESET2: usize = 0x84000012;
+pub const PSCI_SYSTEM_RESET2_AARCH64: usize = 0xc4000012;
+
+// PSCI 1.1 presented to the guests
+const PSCI_GUEST_VERSION: usize = (1 << 16) | 1;
+// SYSTEM_RESET2 reset_type: bit 31 means vendor specific, 0 is SYSTEM_WARM_RESET
+const PSCI_RESET2_TYPE_VENDOR: usize = 1 << 31;
+const PSCI_RESET2_SYSTEM_WARM_RESET: usize = 0;
 
 pub const PSCI_E_SUCCESS: usize = 0;
 pub const PSCI_E_NOT_SUPPORTED: usize = usize::MAX;
-
-#[cfg(feature = "tx2")]
-const TEGRA_SIP_GET_ACTMON_CLK_COUNTERS: usize = 0xC2FFFE02;
+pub const PSCI_E_INVALID_PARAMS: usize = usize::MAX - 1;
+pub const PSCI_E_ALREADY_ON: usize = usize::MAX - 3;
+pub const PSCI_E_ON_PENDING: usize = usize::MAX - 4;
 
 pub const PSCI_TOS_NOT_PRESENT_MP: usize = 2;
 
@@ -77,8 +89,97 @@ pub fn power_arch_sys_shutdown() {
     smc_call(PSCI_SYSTEM_OFF, 0, 0, 0);
 }
 
+// the MVM resets the platform through the orderly power sequence, see sys_power_request
 fn psci_guest_sys_reset() {
-    vmm_reboot();
+    let vm = active_vm().unwrap();
+    if vm.id() == 0 {
+        let _ = sys_power_request(SysPowerEvent::Reboot);
+        return;
+    }
+    vmm_reboot(vm);
+}
+
+fn psci_guest_sys_off() {
+    let vm = active_vm().unwrap();
+    if vm.id() == 0 {
+        let _ = sys_power_request(SysPowerEvent::Shutdown);
+        return;
+    }
+    info!("VM[{}] powers itself off", vm.id());
+    vmm_shutdown_vm(vm.id());
+}
+
+fn psci_guest_sys_reset2(reset_type: usize) -> usize {
+    if reset_type & PSCI_RESET2_TYPE_VENDOR != 0 {
+        return PSCI_E_NOT_SUPPORTED;
+    }
+    if reset_type != PSCI_RESET2_SYSTEM_WARM_RESET {
+        return PSCI_E_INVALID_PARAMS;
+    }
+    psci_guest_sys_reset();
+    PSCI_E_SUCCESS
+}
+
+/*
+ * The vcpu leaves the scheduler until another vcpu powers it on again by CPU_ON,
+ * the core goes idle if no other vcpu is left on it.
+ */
+fn psci_guest_cpu_off() {
+    let vcpu = current_cpu().active_vcpu.clone().unwrap();
+    info!(
+        "Core {} (vm {}, vcpu {}) is powered off",
+        current_cpu().id,
+        vcpu.vm_id(),
+        vcpu.id()
+    );
+    vcpu.set_state(VcpuState::VcpuInv);
+    current_cpu().scheduler().sleep(vcpu);
+    if current_cpu().active_vcpu.is_none() {
+        gicc_clear_current_irq(true);
+        cpu_idle();
+    }
+}
+
+/*
+ * Both standby and powerdown states are entered as a WFI. Returning from a powerdown request
+ * as if it was a standby is allowed by PSCI, the guest state is never lost.
+ * The vcpu is woken up as from WFI, by an injected or vgic pending interrupt or its virtual timer.
+ */
+fn psci_guest_cpu_suspend() {
+    let vcpu = current_cpu().active_vcpu.clone().unwrap();
+    vcpu_wfi(vcpu);
+}
+
+fn psci_guest_affinity_info(mpidr: usize, level: usize) -> usize {
+    // only the vcpu level is meaningful to a VM
+    if level != 0 {
+        return PSCI_E_INVALID_PARAMS;
+    }
+    let vm = active_vm().unwrap();
+    match vm.vcpu(mpidr & 0xff) {
+        Some(vcpu) => vcpu.power_state() as usize,
+        None => PSCI_E_INVALID_PARAMS,
+    }
+}
+
+fn psci_guest_features(fid: usize) -> usize {
+    match fid {
+        PSCI_VERSION
+        | PSCI_CPU_OFF
+        | PSCI_CPU_ON
+        | PSCI_CPU_ON_AARCH64
+        | PSCI_AFFINITY_INFO
+        | PSCI_AFFINITY_INFO_AARCH64
+        | PSCI_MIG_INFO_TYPE
+        | PSCI_SYSTEM_OFF
+        | PSCI_SYSTEM_RESET
+        | PSCI_SYSTEM_RESET2
+        | PSCI_SYSTEM_RESET2_AARCH64
+        | PSCI_FEATURES => PSCI_E_SUCCESS,
+        // original power_state format, no OS-initiated mode
+        PSCI_CPU_SUSPEND | PSCI_CPU_SUSPEND_AARCH64 => 0,
+        _ => PSCI_E_NOT_SUPPORTED,
+    }
 }
 
 #[inline(never)]
@@ -90,45 +191,50 @@ pub fn smc_guest_handler(fid: usize, x1: usize, x2: usize, x3: usize) -> bool {
     let r;
     match fid {
         PSCI_VERSION => {
-            r = smc_call(PSCI_VERSION, 0, 0, 0).0;
+            r = PSCI_GUEST_VERSION;
         }
         PSCI_MIG_INFO_TYPE => {
             r = PSCI_TOS_NOT_PRESENT_MP;
         }
-        PSCI_CPU_ON_AARCH64 => {
+        PSCI_CPU_ON | PSCI_CPU_ON_AARCH64 => {
             r = psci_guest_cpu_on(x1, x2, x3);
         }
-        PSCI_AFFINITY_INFO_AARCH64 => {
-            r = 0;
+        PSCI_AFFINITY_INFO | PSCI_AFFINITY_INFO_AARCH64 => {
+            r = psci_guest_affinity_info(x1, x2);
+        }
+        // the calls below may switch to another vcpu, which must not see the return value
+        PSCI_CPU_OFF => {
+            psci_guest_cpu_off();
+            return true;
+        }
+        PSCI_CPU_SUSPEND | PSCI_CPU_SUSPEND_AARCH64 => {
+            current_cpu().set_gpr(0, PSCI_E_SUCCESS);
+            psci_guest_cpu_suspend();
+            return true;
+        }
+        PSCI_SYSTEM_OFF => {
+            psci_guest_sys_off();
+            return true;
         }
         PSCI_SYSTEM_RESET => {
             psci_guest_sys_reset();
             r = 0;
         }
-        #[cfg(feature = "tx2")]
-        TEGRA_SIP_GET_ACTMON_CLK_COUNTERS => {
-            let result = smc_call(fid, x1, x2, x3);
-            r = result.0;
-            // println!("x1 0x{:x}, x2 0x{:x}, x3 0x{:x}", x1, x2, x3);
-            // println!(
-            //     "result.0 0x{:x}, result.1 0x{:x}, result.2 0x{:x}",
-            //     result.0, result.1, result.2
-            // );
-            current_cpu().set_gpr(1, result.1);
-            current_cpu().set_gpr(2, result.2);
+        PSCI_SYSTEM_RESET2 | PSCI_SYSTEM_RESET2_AARCH64 => {
+            r = psci_guest_sys_reset2(x1);
+        }
+        PSCI_FEATURES => {
+            r = psci_guest_features(x1);
         }
-        PSCI_FEATURES => match x1 {
-            PSCI_VERSION | PSCI_CPU_ON_AARCH64 | PSCI_FEATURES => {
-                r = PSCI_E_SUCCESS;
+        _ => match smccc_guest_handler(fid, x1) {
+            Some(val) => {
+                r = val;
             }
-            _ => {
-                r = PSCI_E_NOT_SUPPORTED;
+            None => {
+                // unimplemented!();
+                return false;
             }
         },
-        _ => {
-            // unimplemented!();
-            return false;
-        }
     }
 
     let idx = 0;
@@ -176,6 +282,7 @@ pub fn psci_ipi_handler(msg: &IpiMessage) {
             };
             match power_msg.event {
                 PowerEvent::PsciIpiCpuOn => {
+                    trgt_vcpu.set_power_on_pending(false);
                     if trgt_vcpu.state() as usize != VcpuState::VcpuInv as usize {
                         warn!(
                             "psci_ipi_handler: target VCPU {} in VM {} is already running",
@@ -199,7 +306,10 @@ pub fn psci_ipi_handler(msg: &IpiMessage) {
                     unimplemented!("PowerEvent::PsciIpiCpuOff")
                 }
                 PowerEvent::PsciIpiCpuReset => {
-                    vcpu_arch_init(active_vm().unwrap(), current_cpu().active_vcpu.clone().unwrap());
+                    // a shut down vcpu is reset when the guest powers it on again
+                    if trgt_vcpu.state() as usize != VcpuState::VcpuInv as usize {
+                        vcpu_arch_init(active_vm().unwrap(), current_cpu().active_vcpu.clone().unwrap());
+                    }
                 }
             }
         }
@@ -219,17 +329,24 @@ pub fn psci_guest_cpu_on(mpidr: usize, entry: usize, ctx: usize) -> usize {
 
     if vcpu_id >= vm.cpu_num() || physical_linear_id.is_err() {
         warn!("psci_guest_cpu_on: target vcpu {} not exist", vcpu_id);
-        return usize::MAX - 1;
+        return PSCI_E_INVALID_PARAMS;
     }
     #[cfg(feature = "tx2")]
     {
         let cluster = (mpidr >> 8) & 0xff;
         if vm.id() == 0 && cluster != 1 {
             warn!("psci_guest_cpu_on: L4T only support cluster #1");
-            return usize::MAX - 1;
+            return PSCI_E_INVALID_PARAMS;
         }
     }
 
+    let trgt_vcpu = vm.vcpu(vcpu_id).unwrap();
+    match trgt_vcpu.power_state() {
+        VcpuPowerState::On => return PSCI_E_ALREADY_ON,
+        VcpuPowerState::OnPending => return PSCI_E_ON_PENDING,
+        VcpuPowerState::Off => trgt_vcpu.set_power_on_pending(true),
+    }
+
     let m = IpiPowerMessage {
         src: vm.id(),
         event: PowerEvent::PsciIpiCpuOn,
@@ -239,8 +356,9 @@ pub fn psci_guest_cpu_on(mpidr: usize, entry: usize, ctx: usize) -> usize {
 
     if !ipi_send_msg(physical_linear_id.unwrap(), IpiType::IpiTPower, IpiInnerMsg::Power(m)) {
         warn!("psci_guest_cpu_on: fail to send msg");
-        return usize::MAX - 1;
+        trgt_vcpu.set_power_on_pending(false);
+        return PSCI_E_INVALID_PARAMS;
     }
 
-    0
+    PSCI_E_SUCCESS
 }
@@ -32,3 +32,62 @@ pub fn smc_call(x0: usize, x1: usize, x2: usize, x3: usize) -> (usize, usize, us
     #[cfg(not(target_arch = "aarch64"))]
     unimplemented!();
 }
+
+pub const SMCCC_VERSION: usize = 0x80000000;
+pub const SMCCC_ARCH_FEATURES: usize = 0x80000001;
+pub const SMCCC_ARCH_WORKAROUND_1: usize = 0x80008000;
+pub const SMCCC_ARCH_WORKAROUND_2: usize = 0x80007fff;
+pub const SMCCC_ARCH_WORKAROUND_3: usize = 0x80003fff;
+
+pub const SMCCC_RET_SUCCESS: usize = 0;
+pub const SMCCC_RET_NOT_SUPPORTED: usize = usize::MAX;
+
+// SMCCC 1.1 presented to the guests
+const SMCCC_GUEST_VERSION: usize = (1 << 16) | 1;
+
+// SMCCC_VERSION of the firmware, 1.0 does not implement it
+fn smccc_host_version() -> usize {
+    let r = smc_call(SMCCC_VERSION, 0, 0, 0).0;
+    if (r as i32) < 0 {
+        1 << 16
+    } else {
+        r & 0xffffffff
+    }
+}
+
+/*
+ * Whether the firmware implements a workaround, SMCCC_ARCH_FEATURES is only there since 1.1.
+ * A negative value is passed back to the guest as it is, NOT_REQUIRED means the core is not affected.
+ */
+fn smccc_host_workaround(fid: usize) -> usize {
+    if smccc_host_version() < SMCCC_GUEST_VERSION {
+        return SMCCC_RET_NOT_SUPPORTED;
+    }
+    let r = smc_call(SMCCC_ARCH_FEATURES, fid, 0, 0).0;
+    (r as i32) as isize as usize
+}
+
+fn smccc_guest_arch_features(fid: usize) -> usize {
+    match fid {
+        SMCCC_VERSION | SMCCC_ARCH_FEATURES => SMCCC_RET_SUCCESS,
+        SMCCC_ARCH_WORKAROUND_1 | SMCCC_ARCH_WORKAROUND_3 => smccc_host_workaround(fid),
+        // WORKAROUND_2 is not offered: the SSBD state belongs to the physical core and is shared by
+        // all the VMs on it, it stays as the firmware sets it
+        _ => SMCCC_RET_NOT_SUPPORTED,
+    }
+}
+
+/* SMCCC arch calls of a guest, returns None if fid is not one of them */
+pub fn smccc_guest_handler(fid: usize, x1: usize) -> Option<usize> {
+    match fid {
+        SMCCC_VERSION => Some(SMCCC_GUEST_VERSION),
+        SMCCC_ARCH_FEATURES => Some(smccc_guest_arch_features(x1)),
+        SMCCC_ARCH_WORKAROUND_1 | SMCCC_ARCH_WORKAROUND_3 => {
+            // the mitigation is done by the firmware on the physical core, it has no return value
+            smc_call(fid, 0, 0, 0);
+            Some(SMCCC_RET_SUCCESS)
+        }
+        SMCCC_ARCH_WORKAROUND_2 => Some(SMCCC_RET_NOT_SUPPORTED),
+        _ => None,
+    }
+}
@@ -13,14 +13,108 @@ use crate::arch::{
     exception_data_abort_access_reg_width, exception_data_abort_access_width, exception_data_abort_handleable,
     exception_data_abort_is_permission_fault, exception_data_abort_is_translate_fault, exception_iss,
 };
-use crate::arch::{exception_esr, exception_fault_addr};
+use crate::arch::{exception_esr, exception_far, exception_fault_addr};
 use crate::arch::exception_next_instruction_step;
-use crate::arch::smc_guest_handler;
+use crate::arch::{smc_call, smc_guest_handler, SMCCC_RET_NOT_SUPPORTED};
+use crate::arch::{sysreg_emu, SysregAccess, SYSREG_ENC_MSK};
+use crate::config::VmSmcPolicy;
 use crate::device::{emu_handler, EmuContext};
-use crate::kernel::{active_vm, current_cpu, hvc_guest_handler, migrate_data_abort_handler};
+use crate::kernel::{active_vm, active_vm_id, current_cpu, hvc_guest_handler, migrate_data_abort_handler};
+use crate::kernel::{dirty_log_translate_fault, migrate_postcopy_fault};
+use crate::kernel::{vcpu_wfe, vcpu_wfi, vm_fault_record, vm_fault_stop, VmFaultReason};
+use crate::lib::bit_extract;
 
 pub const HVC_RETURN_REG: usize = 0;
 
+// ESR_EL1 of an exception injected to the guest, EC + 1 of an abort means taken from EL1 itself
+const ESR_EC_UNKNOWN: usize = 0x0;
+const ESR_EC_IABT_LOW: usize = 0x20;
+const ESR_EC_DABT_LOW: usize = 0x24;
+const ESR_IL: usize = 1 << 25;
+const ESR_ISS_WNR: usize = 1 << 6;
+// FSC: synchronous external abort, not on translation table walk
+const ESR_ISS_FSC_SEA: usize = 0x10;
+// HCR_EL2.RW [31]: EL1 of the guest is AArch64
+const HCR_EL2_RW: u64 = 1 << 31;
+
+/*
+ * The EL1 system registers of the guest are live here, the exception entry to EL1 is done by hand:
+ * ESR/FAR/ELR/SPSR_EL1 are filled and the guest resumes at its vector with DAIF masked.
+ * Only an AArch64 EL1 is supported, an AArch32 EL0 below it takes the exception the same way
+ * but through the vector of lower EL using AArch32, the ESR format does not change.
+ */
+fn guest_inject_sync(esr: usize, far: Option<usize>) {
+    let hcr: u64;
+    mrs!(hcr, HCR_EL2);
+    let spsr = current_cpu().get_spsr();
+    let elr = current_cpu().get_elr();
+    // SPSR.M[4]: AArch32, M[3:0]: 0b0000 EL0t, 0b0100 EL1t, 0b0101 EL1h
+    let vector_offset = match (spsr & 0x10 != 0, spsr & 0xf) {
+        (false, 0b0101) => 0x200,
+        (false, 0b0100) => 0x0,
+        (false, 0b0000) => 0x400,
+        // AArch32 User mode
+        (true, 0b0000) if hcr & HCR_EL2_RW != 0 => 0x600,
+        _ => {
+            warn!(
+                "guest_inject_sync: VM[{}] spsr 0x{:x} hcr 0x{:x} is not an AArch64 EL1 guest, stop it",
+                active_vm_id(),
+                spsr,
+                hcr
+            );
+            vm_fault_stop(active_vm_id());
+            return;
+        }
+    };
+
+    let vbar: u64;
+    mrs!(vbar, VBAR_EL1);
+    msr!(ESR_EL1, esr);
+    if let Some(far) = far {
+        msr!(FAR_EL1, far);
+    }
+    msr!(ELR_EL1, elr);
+    msr!(SPSR_EL1, spsr);
+    current_cpu().set_elr(vbar as usize + vector_offset);
+    // EL1h, D A I F masked
+    current_cpu().set_spsr(0x3c5);
+}
+
+fn guest_at_el1() -> bool {
+    matches!(current_cpu().get_spsr() & 0x1f, 0b00100 | 0b00101)
+}
+
+/* Make the guest take a synchronous external abort at `far`, as a bus error on real hardware */
+pub fn guest_inject_sea(is_data: bool, far: usize) {
+    let same_el = guest_at_el1() as usize;
+    let mut esr = ESR_IL | ESR_ISS_FSC_SEA;
+    if is_data {
+        esr |= (ESR_EC_DABT_LOW + same_el) << 26;
+        esr |= exception_iss() & ESR_ISS_WNR;
+    } else {
+        esr |= (ESR_EC_IABT_LOW + same_el) << 26;
+    }
+    guest_inject_sync(esr, Some(far));
+}
+
+/* Make the instruction at the current elr UNDEFINED to the guest, elr must not be advanced */
+pub fn guest_inject_undef() {
+    guest_inject_sync((ESR_EC_UNKNOWN << 26) | ESR_IL, None);
+}
+
+// the abort can not be emulated, report it back to the guest instead of bringing down the hypervisor
+fn data_abort_inject_sea(ipa: usize) {
+    vm_fault_record(
+        active_vm_id(),
+        VmFaultReason::DataAbort,
+        exception_esr(),
+        exception_far(),
+        ipa,
+        current_cpu().get_elr(),
+    );
+    guest_inject_sea(true, exception_far());
+}
+
 pub fn data_abort_handler() {
     // let time0 = time_current_us();
     let emu_ctx = EmuContext {
@@ -34,12 +128,14 @@ pub fn data_abort_handler() {
     let elr = current_cpu().get_elr();
 
     if !exception_data_abort_handleable() {
-        panic!(
+        warn!(
             "Core {} data abort not handleable 0x{:x}, esr 0x{:x}",
             current_cpu().id,
             exception_fault_addr(),
             exception_esr()
         );
+        data_abort_inject_sea(emu_ctx.address);
+        return;
     }
 
     if !exception_data_abort_is_translate_fault() {
@@ -62,13 +158,20 @@ pub fn data_abort_handler() {
             // println!("migrate_data_abort_handler: {}us", time1 - time0);
             return;
         } else {
-            panic!(
+            warn!(
                 "Core {} data abort is not translate fault 0x{:x}",
                 current_cpu().id,
                 exception_fault_addr(),
             );
+            data_abort_inject_sea(emu_ctx.address);
+            return;
         }
     }
+    // a page not received yet by post-copy migration, or a block split for dirty logging meanwhile,
+    // the access is retried
+    if migrate_postcopy_fault(emu_ctx.address) || dirty_log_translate_fault(emu_ctx.address) {
+        return;
+    }
     if !emu_handler(&emu_ctx) {
         active_vm().unwrap().show_pagetable(emu_ctx.address);
         println!(
@@ -82,29 +185,73 @@ pub fn data_abort_handler() {
             current_cpu().get_gpr(emu_ctx.reg),
             exception_esr()
         );
-        panic!(
+        warn!(
             "data_abort_handler: Failed to handler emul device request, ipa 0x{:x} elr 0x{:x}",
             emu_ctx.address, elr
         );
+        data_abort_inject_sea(emu_ctx.address);
+        return;
     }
     let val = elr + exception_next_instruction_step();
     current_cpu().set_elr(val);
 }
 
+/*
+ * Only the instruction fetch from a page not received yet by post-copy migration is handled,
+ * returns false for the others. IFSC is encoded the same as DFSC.
+ */
+pub fn instruction_abort_handler() -> bool {
+    exception_data_abort_is_translate_fault()
+        && (migrate_postcopy_fault(exception_fault_addr()) || dirty_log_translate_fault(exception_fault_addr()))
+}
+
+// ISS of EC 0x01: TI [1:0], 0b00 WFI, 0b01 WFE, 0b10 WFIT, 0b11 WFET
+pub fn wfx_handler() {
+    let elr = current_cpu().get_elr();
+    current_cpu().set_elr(elr + exception_next_instruction_step());
+
+    let vcpu = current_cpu().active_vcpu.clone().unwrap();
+    if exception_iss() & 0b11 == 0 {
+        vcpu_wfi(vcpu);
+    } else {
+        // the timeout of WFIT is not emulated, take it as a hint like WFE
+        vcpu_wfe(vcpu);
+    }
+}
+
+/*
+ * A forwarded call passes only x1-x3 to the firmware and returns only x0-x3, as smc_call does.
+ * The SMC64 calls of SMCCC 1.2 taking or returning more registers (up to x17) are truncated, the
+ * ranges holding them should not be forwarded.
+ */
 pub fn smc_handler() {
     let fid = current_cpu().get_gpr(0);
     let x1 = current_cpu().get_gpr(1);
     let x2 = current_cpu().get_gpr(2);
     let x3 = current_cpu().get_gpr(3);
+    // step over the smc first, the handler may switch to another vcpu
+    let elr = current_cpu().get_elr();
+    current_cpu().set_elr(elr + exception_next_instruction_step());
 
-    if !smc_guest_handler(fid, x1, x2, x3) {
-        warn!("smc_handler: unknown fid 0x{:x}", fid);
-        current_cpu().set_gpr(0, 0);
+    match active_vm().unwrap().config().smc_policy(fid) {
+        VmSmcPolicy::Deny => {
+            warn!("smc_handler: VM[{}] denied fid 0x{:x}", active_vm_id(), fid);
+            current_cpu().set_gpr(0, SMCCC_RET_NOT_SUPPORTED);
+        }
+        VmSmcPolicy::Forward => {
+            let (r0, r1, r2, r3) = smc_call(fid, x1, x2, x3);
+            current_cpu().set_gpr(0, r0);
+            current_cpu().set_gpr(1, r1);
+            current_cpu().set_gpr(2, r2);
+            current_cpu().set_gpr(3, r3);
+        }
+        VmSmcPolicy::Emulate => {
+            if !smc_guest_handler(fid, x1, x2, x3) {
+                warn!("smc_handler: unknown fid 0x{:x}", fid);
+                current_cpu().set_gpr(0, SMCCC_RET_NOT_SUPPORTED);
+            }
+        }
     }
-
-    let elr = current_cpu().get_elr();
-    let val = elr + exception_next_instruction_step();
-    current_cpu().set_elr(val);
 }
 
 pub fn hvc_handler() {
@@ -139,3 +286,35 @@ pub fn hvc_handler() {
     //     timer_arch_get_frequency()
     // );
 }
+
+pub fn sysreg_handler() {
+    let iss = exception_iss();
+    let reg = bit_extract(iss, 5, 5);
+    let access = SysregAccess {
+        enc: iss & SYSREG_ENC_MSK,
+        write: iss & 1 == 0,
+        // Rt 31 is xzr
+        val: if reg == 31 { 0 } else { current_cpu().get_gpr(reg) },
+    };
+
+    match sysreg_emu(&access) {
+        Ok(val) => {
+            if !access.write && reg != 31 {
+                current_cpu().set_gpr(reg, val);
+            }
+            let elr = current_cpu().get_elr();
+            current_cpu().set_elr(elr + exception_next_instruction_step());
+        }
+        Err(_) => {
+            // a guest could trap here at will, keep it off the console
+            debug!(
+                "sysreg_handler: core {} VM[{}] undefined {} of sysreg iss 0x{:x}",
+                current_cpu().id,
+                active_vm_id(),
+                if access.write { "write" } else { "read" },
+                iss
+            );
+            guest_inject_undef();
+        }
+    }
+}
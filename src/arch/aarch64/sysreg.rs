@@ -0,0 +1,348 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+// Emulation of the system registers and SYS instructions trapped with EC 0x18, see sysreg_handler.
+
+use crate::arch::{cache_clean_invalidate_d, vpmu_sysreg_access};
+use crate::config::CPU_ID_REG_NUM;
+use crate::kernel::{active_vm, current_cpu};
+use crate::lib::bit_extract;
+
+// ISS of EC 0x18: Op0 [21:20], Op2 [19:17], Op1 [16:14], CRn [13:10], Rt [9:5], CRm [4:1], Direction [0]
+pub const SYSREG_ENC_MSK: usize = 0x3ffc1e;
+const SYSREG_ENC_CRM_MSK: usize = 0xf << 1;
+const SYSREG_ENC_OP2_MSK: usize = 0x7 << 17;
+
+pub const fn sysreg_enc(op0: usize, op1: usize, crn: usize, crm: usize, op2: usize) -> usize {
+    (op0 << 20) | (op2 << 17) | (op1 << 14) | (crn << 10) | (crm << 1)
+}
+
+// MDCR_EL2: HPMN [4:0], TPMCR [5], TPM [6], TDA [9], TDOSA [10], TDRA [11]
+const MDCR_EL2_HPMN_MSK: usize = 0x1f;
+const MDCR_EL2_TPMCR: usize = 1 << 5;
+const MDCR_EL2_TPM: usize = 1 << 6;
+const MDCR_EL2_TDA: usize = 1 << 9;
+const MDCR_EL2_TDOSA: usize = 1 << 10;
+const MDCR_EL2_TDRA: usize = 1 << 11;
+
+// OSLSR_EL1.OSLM 0b10: OS lock implemented
+const OSLSR_EL1_OSLM_IMPL: usize = 1 << 3;
+
+pub struct SysregAccess {
+    // Op0, Op1, CRn, CRm and Op2 of the access
+    pub enc: usize,
+    pub write: bool,
+    // the value to write, unused for a read
+    pub val: usize,
+}
+
+struct SysregEmu {
+    enc: usize,
+    // bits of the encoding to match, so that a register array takes one entry
+    mask: usize,
+    // returns the value read, Err means UNDEF
+    handler: fn(&SysregAccess) -> Result<usize, ()>,
+}
+
+static SYSREG_EMU_TABLE: [SysregEmu; 20] = [
+    // group 3 ID registers (HCR_EL2.TID3)
+    SysregEmu {
+        enc: sysreg_enc(3, 0, 0, 0, 0),
+        mask: SYSREG_ENC_MSK & !SYSREG_ENC_CRM_MSK & !SYSREG_ENC_OP2_MSK,
+        handler: sysreg_id_access,
+    },
+    // DBGBVR<n>_EL1, DBGBCR<n>_EL1, DBGWVR<n>_EL1, DBGWCR<n>_EL1 (MDCR_EL2.TDA)
+    SysregEmu {
+        enc: sysreg_enc(2, 0, 0, 0, 4),
+        mask: SYSREG_ENC_MSK & !SYSREG_ENC_CRM_MSK & !(0b11 << 17),
+        handler: sysreg_raz_wi,
+    },
+    // MDCCINT_EL1
+    SysregEmu {
+        enc: sysreg_enc(2, 0, 0, 2, 0),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_raz_wi,
+    },
+    // MDSCR_EL1
+    SysregEmu {
+        enc: sysreg_enc(2, 0, 0, 2, 2),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_raz_wi,
+    },
+    // MDCCSR_EL0
+    SysregEmu {
+        enc: sysreg_enc(2, 3, 0, 1, 0),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_raz_wi,
+    },
+    // MDRAR_EL1 (MDCR_EL2.TDRA)
+    SysregEmu {
+        enc: sysreg_enc(2, 0, 1, 0, 0),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_raz_wi,
+    },
+    // OSLAR_EL1 (MDCR_EL2.TDOSA)
+    SysregEmu {
+        enc: sysreg_enc(2, 0, 1, 0, 4),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_raz_wi,
+    },
+    // OSLSR_EL1
+    SysregEmu {
+        enc: sysreg_enc(2, 0, 1, 1, 4),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_oslsr_access,
+    },
+    // OSDLR_EL1
+    SysregEmu {
+        enc: sysreg_enc(2, 0, 1, 3, 4),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_raz_wi,
+    },
+    // DBGPRCR_EL1
+    SysregEmu {
+        enc: sysreg_enc(2, 0, 1, 4, 4),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_raz_wi,
+    },
+    // DBGCLAIMSET_EL1, DBGCLAIMCLR_EL1
+    SysregEmu {
+        enc: sysreg_enc(2, 0, 7, 8, 6),
+        mask: SYSREG_ENC_MSK & !(1 << 1),
+        handler: sysreg_raz_wi,
+    },
+    // DBGAUTHSTATUS_EL1
+    SysregEmu {
+        enc: sysreg_enc(2, 0, 7, 14, 6),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_raz_wi,
+    },
+    // PMCR_EL0 ... PMOVSSET_EL0 (MDCR_EL2.TPM), see vpmu
+    SysregEmu {
+        enc: sysreg_enc(3, 3, 9, 12, 0),
+        mask: SYSREG_ENC_MSK & !(0b11 << 1) & !SYSREG_ENC_OP2_MSK,
+        handler: vpmu_sysreg_access,
+    },
+    // PMEVCNTR<n>_EL0, PMEVTYPER<n>_EL0, PMCCFILTR_EL0
+    SysregEmu {
+        enc: sysreg_enc(3, 3, 14, 8, 0),
+        mask: SYSREG_ENC_MSK & !(0b111 << 1) & !SYSREG_ENC_OP2_MSK,
+        handler: vpmu_sysreg_access,
+    },
+    // PMINTENSET_EL1
+    SysregEmu {
+        enc: sysreg_enc(3, 0, 9, 14, 1),
+        mask: SYSREG_ENC_MSK,
+        handler: vpmu_sysreg_access,
+    },
+    // PMINTENCLR_EL1
+    SysregEmu {
+        enc: sysreg_enc(3, 0, 9, 14, 2),
+        mask: SYSREG_ENC_MSK,
+        handler: vpmu_sysreg_access,
+    },
+    // DC ISW (HCR_EL2.TSW)
+    SysregEmu {
+        enc: sysreg_enc(1, 0, 7, 6, 2),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_dc_sw,
+    },
+    // DC CSW
+    SysregEmu {
+        enc: sysreg_enc(1, 0, 7, 10, 2),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_dc_sw,
+    },
+    // DC CISW
+    SysregEmu {
+        enc: sysreg_enc(1, 0, 7, 14, 2),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_dc_sw,
+    },
+    // ICC_SGI1R_EL1 (ICH_HCR_EL2.TC of a GICv3 guest)
+    SysregEmu {
+        enc: sysreg_enc(3, 0, 12, 11, 5),
+        mask: SYSREG_ENC_MSK,
+        handler: sysreg_icc_sgi1r_access,
+    },
+];
+
+/* Returns the value read, Err if the register is not emulated and the access is UNDEF */
+pub fn sysreg_emu(access: &SysregAccess) -> Result<usize, ()> {
+    match SYSREG_EMU_TABLE.iter().find(|x| access.enc & x.mask == x.enc) {
+        Some(emu) => (emu.handler)(access),
+        None => Err(()),
+    }
+}
+
+fn sysreg_raz_wi(_access: &SysregAccess) -> Result<usize, ()> {
+    Ok(0)
+}
+
+fn sysreg_oslsr_access(access: &SysregAccess) -> Result<usize, ()> {
+    if access.write {
+        return Err(());
+    }
+    // OSLAR_EL1 is WI, the lock is never set
+    Ok(OSLSR_EL1_OSLM_IMPL)
+}
+
+/*
+ * Set/way cache maintenance can not be confined to one VM, so it becomes a clean and invalidate of
+ * the whole VM memory. Guests walk all the sets and ways of a level in a loop, the memory is flushed
+ * at the first operation of the loop, the one with set 0 and way 0.
+ */
+fn sysreg_dc_sw(access: &SysregAccess) -> Result<usize, ()> {
+    if access.val & !0xf == 0 {
+        let vm = active_vm().unwrap();
+        for idx in 0..vm.mem_region_num() {
+            unsafe {
+                cache_clean_invalidate_d(vm.pa_start(idx), vm.pa_length(idx));
+            }
+        }
+    }
+    Ok(0)
+}
+
+fn sysreg_icc_sgi1r_access(access: &SysregAccess) -> Result<usize, ()> {
+    if !access.write {
+        return Err(());
+    }
+    #[cfg(feature = "gicv3")]
+    {
+        if let Some(vcpu) = &current_cpu().active_vcpu {
+            vcpu.stats_ipi();
+        }
+        crate::arch::vgic_icc_sgi1r_access(access.val);
+        Ok(0)
+    }
+    #[cfg(not(feature = "gicv3"))]
+    Err(())
+}
+
+// CRm and Op2 of ID_AA64*_EL1, in the order of VmCpuConfig.id_regs
+const CPU_ID_REG_ENC: [(usize, usize); CPU_ID_REG_NUM] = [
+    (4, 0),
+    (4, 1),
+    (4, 4),
+    (5, 0),
+    (5, 1),
+    (6, 0),
+    (6, 1),
+    (6, 2),
+    (7, 0),
+    (7, 1),
+    (7, 2),
+];
+
+/*
+ * Signed 4-bit fields of each ID register in CPU_ID_REG_ENC, 0xf of them means not implemented:
+ * FP and AdvSIMD of ID_AA64PFR0, DoubleLock of ID_AA64DFR0, TGran64 and TGran4 of ID_AA64MMFR0.
+ */
+const CPU_ID_REG_SIGNED: [usize; CPU_ID_REG_NUM] =
+    [(1 << 4) | (1 << 5), 0, 0, 1 << 9, 0, 0, 0, 0, (1 << 6) | (1 << 7), 0, 0];
+
+// value of a 4-bit field of ID register idx, sign extended if it is a signed one
+pub fn cpu_id_reg_field(idx: usize, reg: usize, field: usize) -> isize {
+    let val = ((reg >> (field * 4)) & 0xf) as isize;
+    if CPU_ID_REG_SIGNED[idx] & (1 << field) != 0 && val >= 8 {
+        val - 16
+    } else {
+        val
+    }
+}
+
+fn sysreg_id_access(access: &SysregAccess) -> Result<usize, ()> {
+    if access.write {
+        return Err(());
+    }
+    let crm = bit_extract(access.enc, 1, 4);
+    let op2 = bit_extract(access.enc, 17, 3);
+    let host = id_reg_read(crm, op2);
+    match CPU_ID_REG_ENC.iter().position(|x| *x == (crm, op2)) {
+        Some(idx) => {
+            let id_reg = active_vm().unwrap().config().cpu_id_reg(idx);
+            Ok((host & !id_reg.mask) | id_reg.value)
+        }
+        None => Ok(host),
+    }
+}
+
+pub fn cpu_id_reg_host(idx: usize) -> usize {
+    let (crm, op2) = CPU_ID_REG_ENC[idx];
+    id_reg_read(crm, op2)
+}
+
+// group 3 ID registers of this core, the unallocated ones are RAZ
+fn id_reg_read(crm: usize, op2: usize) -> usize {
+    let val: u64;
+    match (crm, op2) {
+        // ID_PFR0, ID_PFR1, ID_DFR0, ID_AFR0, ID_MMFR0-3
+        (1, 0) => mrs!(val, S3_0_C0_C1_0),
+        (1, 1) => mrs!(val, S3_0_C0_C1_1),
+        (1, 2) => mrs!(val, S3_0_C0_C1_2),
+        (1, 3) => mrs!(val, S3_0_C0_C1_3),
+        (1, 4) => mrs!(val, S3_0_C0_C1_4),
+        (1, 5) => mrs!(val, S3_0_C0_C1_5),
+        (1, 6) => mrs!(val, S3_0_C0_C1_6),
+        (1, 7) => mrs!(val, S3_0_C0_C1_7),
+        // ID_ISAR0-5, ID_MMFR4, ID_ISAR6
+        (2, 0) => mrs!(val, S3_0_C0_C2_0),
+        (2, 1) => mrs!(val, S3_0_C0_C2_1),
+        (2, 2) => mrs!(val, S3_0_C0_C2_2),
+        (2, 3) => mrs!(val, S3_0_C0_C2_3),
+        (2, 4) => mrs!(val, S3_0_C0_C2_4),
+        (2, 5) => mrs!(val, S3_0_C0_C2_5),
+        (2, 6) => mrs!(val, S3_0_C0_C2_6),
+        (2, 7) => mrs!(val, S3_0_C0_C2_7),
+        // MVFR0-2, ID_PFR2, ID_DFR1, ID_MMFR5
+        (3, 0) => mrs!(val, S3_0_C0_C3_0),
+        (3, 1) => mrs!(val, S3_0_C0_C3_1),
+        (3, 2) => mrs!(val, S3_0_C0_C3_2),
+        (3, 4) => mrs!(val, S3_0_C0_C3_4),
+        (3, 5) => mrs!(val, S3_0_C0_C3_5),
+        (3, 6) => mrs!(val, S3_0_C0_C3_6),
+        // ID_AA64PFR0, ID_AA64PFR1, ID_AA64ZFR0
+        (4, 0) => mrs!(val, S3_0_C0_C4_0),
+        (4, 1) => mrs!(val, S3_0_C0_C4_1),
+        (4, 4) => mrs!(val, S3_0_C0_C4_4),
+        // ID_AA64DFR0, ID_AA64DFR1, ID_AA64AFR0, ID_AA64AFR1
+        (5, 0) => mrs!(val, S3_0_C0_C5_0),
+        (5, 1) => mrs!(val, S3_0_C0_C5_1),
+        (5, 4) => mrs!(val, S3_0_C0_C5_4),
+        (5, 5) => mrs!(val, S3_0_C0_C5_5),
+        // ID_AA64ISAR0-2
+        (6, 0) => mrs!(val, S3_0_C0_C6_0),
+        (6, 1) => mrs!(val, S3_0_C0_C6_1),
+        (6, 2) => mrs!(val, S3_0_C0_C6_2),
+        // ID_AA64MMFR0-2
+        (7, 0) => mrs!(val, S3_0_C0_C7_0),
+        (7, 1) => mrs!(val, S3_0_C0_C7_1),
+        (7, 2) => mrs!(val, S3_0_C0_C7_2),
+        _ => val = 0,
+    }
+    val as usize
+}
+
+/*
+ * MDCR_EL2 of a vcpu: the debug registers and the PMU are always emulated, the PMU is RAZ/WI if the
+ * cpu model of the VM hides it, see vpmu_enabled.
+ */
+pub fn sysreg_vm_mdcr() -> u64 {
+    let mdcr: u64;
+    mrs!(mdcr, MDCR_EL2);
+    let val = (mdcr as usize & MDCR_EL2_HPMN_MSK)
+        | MDCR_EL2_TPMCR
+        | MDCR_EL2_TPM
+        | MDCR_EL2_TDA
+        | MDCR_EL2_TDOSA
+        | MDCR_EL2_TDRA;
+    val as u64
+}
@@ -15,3 +15,21 @@ pub fn tlb_invalidate_guest_all() {
         asm!("dsb ish", "tlbi vmalls12e1is", "dsb ish", "isb");
     }
 }
+
+// invalidate the stage 1 & 2 entries of the VM with vttbr, which may not be the one running on this core
+pub fn tlb_invalidate_vm_all(vttbr: usize) {
+    unsafe {
+        asm!(
+            "mrs {0}, VTTBR_EL2",
+            "msr VTTBR_EL2, {1}",
+            "isb",
+            "dsb ish",
+            "tlbi vmalls12e1is",
+            "dsb ish",
+            "msr VTTBR_EL2, {0}",
+            "isb",
+            out(reg) _,
+            in(reg) vttbr,
+        );
+    }
+}
@@ -15,7 +15,7 @@ use alloc::vec::Vec;
 use spin::Mutex;
 
 use crate::{arch::GICH, kernel::IpiInitcMessage};
-use crate::board::{PLATFORM_CPU_NUM_MAX, Platform, PlatOperation};
+use crate::board::{PLATFORM_CPU_NUM_MAX, PLATFORM_VCPU_NUM_MAX, Platform, PlatOperation};
 use crate::device::EmuContext;
 use crate::device::EmuDevs;
 use crate::kernel::{
@@ -23,10 +23,16 @@ use crate::kernel::{
 };
 use crate::kernel::{active_vm, active_vm_id, active_vm_ncpu};
 use crate::kernel::{ipi_intra_broadcast_msg, ipi_send_msg, IpiInnerMsg, IpiMessage, IpiType};
-use crate::kernel::{InitcEvent, Vcpu, Vm, vm};
+use crate::kernel::{InitcEvent, Vcpu, VcpuState, Vm, vm};
+use crate::kernel::{vcpu_wfi_wakeup, vm_health_vcpu_wfi};
 use crate::lib::{bit_extract, bit_get, bit_set, bitmap_find_nth, ptr_read_write};
 
+#[cfg(not(feature = "gicv3"))]
 use super::gic::*;
+#[cfg(feature = "gicv3")]
+use super::gicv3::*;
+#[cfg(feature = "gicv3")]
+use super::vgicv3::{VGIC_PIDR2_ARCH_GICV3, VGIC_REG_OFFSET_PIDR2};
 
 #[derive(Clone)]
 struct VgicInt {
@@ -90,9 +96,9 @@ impl VgicInt {
             //     "restore vgic int {} migrate target data {:x}",
             //     int_data.id, int_data.targets
             // );
-            for vcpuid in 0..PLATFORM_CPU_NUM_MAX {
+            for vcpuid in 0..PLATFORM_VCPU_NUM_MAX {
                 if (1 << vcpuid) & int_data.targets != 0 {
-                    pcpu_targets |= (1 << vcpuid_map[&vcpuid]) as u8;
+                    pcpu_targets |= 1 << vcpuid_map[&vcpuid];
                 }
             }
             pcpu_targets
@@ -145,7 +151,7 @@ impl VgicInt {
             let mut vcpu_targets = 0;
             for pcpuid in 0..PLATFORM_CPU_NUM_MAX {
                 if (1 << pcpuid) & inner.targets != 0 {
-                    vcpu_targets |= (1 << cpuid_map[&pcpuid]) as u8;
+                    vcpu_targets |= 1 << cpuid_map[&pcpuid];
                 }
             }
             vcpu_targets
@@ -243,7 +249,7 @@ impl VgicInt {
         vgic_int.lr = lr;
     }
 
-    fn set_targets(&self, targets: u8) {
+    fn set_targets(&self, targets: usize) {
         let mut vgic_int = self.inner.lock();
         // if vgic_int.targets == 1 && vgic_int.id == 48 {
         //     panic!("set targets to {}", targets);
@@ -312,7 +318,7 @@ impl VgicInt {
         vgic_int.prio
     }
 
-    fn targets(&self) -> u8 {
+    fn targets(&self) -> usize {
         let vgic_int = self.inner.lock();
         vgic_int.targets
     }
@@ -397,7 +403,8 @@ struct VgicIntInner {
     enabled: bool,
     state: IrqState,
     prio: u8,
-    targets: u8,
+    // mask of physical cpus
+    targets: usize,
     cfg: u8,
 
     in_pend: bool,
@@ -432,7 +439,7 @@ impl VgicIntInner {
             enabled,
             state: IrqState::IrqSInactive,
             prio: 0xff,
-            targets: targets as u8,
+            targets,
             cfg: 0,
             in_pend: false,
             in_act: false,
@@ -522,7 +529,7 @@ impl VgicCpuPriv {
         for (idx, int) in self.act_list.iter().enumerate() {
             cpu_priv_data.act_list[idx] = int.id() as usize;
         }
-        cpu_priv_data.act_num = self.pend_list.len();
+        cpu_priv_data.pend_num = self.pend_list.len();
         for (idx, int) in self.pend_list.iter().enumerate() {
             cpu_priv_data.pend_list[idx] = int.id() as usize;
         }
@@ -563,20 +570,20 @@ impl Vgic {
             // act list
             for act_idx in 0..vgic_data.cpu_priv[idx].act_num {
                 let id = vgic_data.cpu_priv[idx].act_list[act_idx];
-                let interrupt = if id >= GIC_SPI_MAX {
-                    cpu_priv_list[idx].interrupts[id - GIC_SPI_MAX].clone()
+                let interrupt = if id < GIC_PRIVINT_NUM {
+                    cpu_priv_list[idx].interrupts[id].clone()
                 } else {
-                    vgicd.interrupts[id].clone()
+                    vgicd.interrupts[id - GIC_PRIVINT_NUM].clone()
                 };
                 cpu_priv_list[idx].act_list.push_back(interrupt);
             }
             // pend list
             for pend_idx in 0..vgic_data.cpu_priv[idx].pend_num {
                 let id = vgic_data.cpu_priv[idx].pend_list[pend_idx];
-                let interrupt = if id >= GIC_SPI_MAX {
-                    cpu_priv_list[idx].interrupts[id - GIC_SPI_MAX].clone()
+                let interrupt = if id < GIC_PRIVINT_NUM {
+                    cpu_priv_list[idx].interrupts[id].clone()
                 } else {
-                    vgicd.interrupts[id].clone()
+                    vgicd.interrupts[id - GIC_PRIVINT_NUM].clone()
                 };
                 cpu_priv_list[idx].pend_list.push_back(interrupt);
             }
@@ -782,6 +789,25 @@ impl Vgic {
         vgicd.ctlr = ctlr;
     }
 
+    // the vcpu has a pending interrupt, in the list registers or waiting for one
+    pub fn vcpu_has_pending(&self, vcpu: Vcpu) -> bool {
+        if let Some(active_vcpu) = &current_cpu().active_vcpu {
+            if active_vcpu.vm_id() == vcpu.vm_id() {
+                for i in 0..gic_lrs() {
+                    // state [29:28], bit 28 is pending
+                    if (GICH.elrsr(i / 32) & (1 << (i % 32))) == 0 && (GICH.lr(i) >> 28) & 1 != 0 {
+                        return true;
+                    }
+                }
+            } else if vcpu.gic_lr_pending() {
+                return true;
+            }
+        } else if vcpu.gic_lr_pending() {
+            return true;
+        }
+        !self.cpu_priv.lock()[vcpu.id()].pend_list.is_empty()
+    }
+
     pub fn vgicd_ctlr(&self) -> u32 {
         let vgicd = self.vgicd.lock();
         vgicd.ctlr
@@ -1116,7 +1142,7 @@ impl Vgic {
                         event: InitcEvent::VgicdSetEn,
                         vm_id: vcpu_vm_id,
                         int_id: interrupt.id(),
-                        val: en as u8,
+                        val: en as usize,
                     };
                     if !ipi_send_msg(int_phys_id, IpiType::IpiTIntc, IpiInnerMsg::Initc(ipi_msg)) {
                         println!(
@@ -1175,7 +1201,7 @@ impl Vgic {
                     event: InitcEvent::VgicdSetPend,
                     vm_id,
                     int_id: interrupt.id(),
-                    val: pend as u8,
+                    val: pend as usize,
                 };
                 match interrupt.owner() {
                     Some(owner) => {
@@ -1229,7 +1255,7 @@ impl Vgic {
                     event: InitcEvent::VgicdSetPend,
                     vm_id,
                     int_id: interrupt.id(),
-                    val: act as u8,
+                    val: act as usize,
                 };
                 let phys_id = interrupt.owner_phys_id().unwrap();
                 if !ipi_send_msg(phys_id, IpiType::IpiTIntc, IpiInnerMsg::Initc(m)) {
@@ -1258,7 +1284,7 @@ impl Vgic {
                     event: InitcEvent::VgicdSetCfg,
                     vm_id: vcpu.vm_id(),
                     int_id: interrupt.id(),
-                    val: cfg,
+                    val: cfg as usize,
                 };
                 if !ipi_send_msg(
                     interrupt.owner_phys_id().unwrap(),
@@ -1365,7 +1391,7 @@ impl Vgic {
                     event: InitcEvent::VgicdSetPrio,
                     vm_id,
                     int_id: interrupt.id(),
-                    val: prio,
+                    val: prio as usize,
                 };
                 if !ipi_send_msg(
                     interrupt.owner_phys_id().unwrap(),
@@ -1388,7 +1414,7 @@ impl Vgic {
         return interrupt_option.unwrap().prio();
     }
 
-    fn set_trgt(&self, vcpu: Vcpu, int_id: usize, trgt: u8) {
+    fn set_trgt(&self, vcpu: Vcpu, int_id: usize, trgt: usize) {
         let interrupt_option = self.get_int(vcpu.clone(), int_id);
         if let Some(interrupt) = interrupt_option {
             let interrupt_lock = interrupt.lock.lock();
@@ -1396,13 +1422,13 @@ impl Vgic {
                 if interrupt.targets() != trgt {
                     interrupt.set_targets(trgt);
                     let mut ptrgt = 0;
-                    for cpuid in 0..8 {
-                        if bit_get(trgt as usize, cpuid) != 0 {
+                    for cpuid in 0..PLATFORM_CPU_NUM_MAX {
+                        if bit_get(trgt, cpuid) != 0 {
                             ptrgt = bit_set(ptrgt, Platform::cpuid_to_cpuif(cpuid))
                         }
                     }
                     if interrupt.hw() {
-                        GICD.set_trgt(interrupt.id() as usize, ptrgt as u8);
+                        GICD.set_trgt(interrupt.id() as usize, ptrgt);
                     }
                     if vgic_get_state(interrupt.clone()) != 0 {
                         self.route(vcpu.clone(), interrupt.clone());
@@ -1433,7 +1459,7 @@ impl Vgic {
         }
     }
 
-    fn get_trgt(&self, vcpu: Vcpu, int_id: usize) -> u8 {
+    fn get_trgt(&self, vcpu: Vcpu, int_id: usize) -> usize {
         let interrupt_option = self.get_int(vcpu, int_id);
         return interrupt_option.unwrap().targets();
     }
@@ -1460,7 +1486,11 @@ impl Vgic {
         if emu_ctx.write {
             let prev_ctlr = self.vgicd_ctlr();
             let idx = emu_ctx.reg;
+            #[cfg(not(feature = "gicv3"))]
             self.set_vgicd_ctlr(current_cpu().get_gpr(idx) as u32 & 0x1);
+            // EnableGrp1 (bit 0) and EnableGrp1A (bit 1) both enable the guest's group 1 interrupts
+            #[cfg(feature = "gicv3")]
+            self.set_vgicd_ctlr(((current_cpu().get_gpr(idx) | (current_cpu().get_gpr(idx) >> 1)) & 0x1) as u32);
             if prev_ctlr ^ self.vgicd_ctlr() != 0 {
                 let enable = self.vgicd_ctlr() != 0;
                 let hcr = GICH.hcr();
@@ -1474,13 +1504,16 @@ impl Vgic {
                     event: InitcEvent::VgicdGichEn,
                     vm_id: active_vm_id(),
                     int_id: 0,
-                    val: enable as u8,
+                    val: enable as usize,
                 };
                 ipi_intra_broadcast_msg(active_vm().unwrap(), IpiType::IpiTIntc, IpiInnerMsg::Initc(m));
             }
         } else {
             let idx = emu_ctx.reg;
             let val = self.vgicd_ctlr() as usize;
+            // affinity routing is always enabled (ARE_NS, bit 4)
+            #[cfg(feature = "gicv3")]
+            let val = val | (val << 1) | (1 << 4);
             current_cpu().set_gpr(idx, val);
         }
     }
@@ -1782,6 +1815,9 @@ impl Vgic {
 
         if bit_extract(emu_ctx.address, 0, 12) == bit_extract(Platform::GICD_BASE + 0x0f00, 0, 12) {
             if emu_ctx.write {
+                if let Some(vcpu) = &current_cpu().active_vcpu {
+                    vcpu.stats_ipi();
+                }
                 let sgir_trglstflt = bit_extract(val, 24, 2);
                 let mut trgtlist = 0;
                 // println!("addr {:x}, sgir trglst flt {}, vtrgt {}", emu_ctx.address, sgir_trglstflt, bit_extract(val, 16, 8));
@@ -1801,13 +1837,13 @@ impl Vgic {
                     _ => {}
                 }
 
-                for i in 0..8 {
+                for i in 0..PLATFORM_CPU_NUM_MAX {
                     if trgtlist & (1 << i) != 0 {
                         let m = IpiInitcMessage {
                             event: InitcEvent::VgicdSetPend,
                             vm_id: active_vm_id(),
                             int_id: (bit_extract(val, 0, 8) | (active_vcpu_id() << 10)) as u16,
-                            val: true as u8,
+                            val: true as usize,
                         };
                         if !ipi_send_msg(i, IpiType::IpiTIntc, IpiInnerMsg::Initc(m)) {
                             println!(
@@ -1883,15 +1919,15 @@ impl Vgic {
                 self.set_trgt(
                     current_cpu().active_vcpu.clone().unwrap(),
                     first_int + i,
-                    bit_extract(val, GIC_TARGET_BITS * i, GIC_TARGET_BITS) as u8,
+                    bit_extract(val, GIC_TARGET_BITS * i, GIC_TARGET_BITS),
                 );
             }
         } else {
             // println!("read, first_int {}, width {}", first_int, emu_ctx.width);
             for i in 0..emu_ctx.width {
                 // println!("{}", self.get_trgt(active_vcpu().unwrap(), first_int + i));
-                val |= (self.get_trgt(current_cpu().active_vcpu.clone().unwrap(), first_int + i) as usize)
-                    << (GIC_TARGET_BITS * i);
+                val |=
+                    self.get_trgt(current_cpu().active_vcpu.clone().unwrap(), first_int + i) << (GIC_TARGET_BITS * i);
             }
             // println!("after read val {}", val);
             val = vgic_target_translate(active_vm().unwrap(), val as u32, false) as usize;
@@ -1900,6 +1936,53 @@ impl Vgic {
         }
     }
 
+    #[cfg(feature = "gicv3")]
+    fn emu_irouter_access(&self, emu_ctx: &EmuContext) {
+        let offset = (emu_ctx.address & 0xffff) - VGICD_REG_OFFSET_IROUTER;
+        let int_id = offset / 8;
+        let vm = active_vm().unwrap();
+        let vcpu = current_cpu().active_vcpu.clone().unwrap();
+
+        // the upper word only holds Aff3, private interrupts are not routed by IROUTER
+        if offset & 0x7 != 0 || int_id < GIC_PRIVINT_NUM {
+            if !emu_ctx.write {
+                current_cpu().set_gpr(emu_ctx.reg, 0);
+            }
+            return;
+        }
+
+        if emu_ctx.write {
+            let val = current_cpu().get_gpr(emu_ctx.reg);
+            let aff0 = bit_extract(val, 0, 8);
+            let vtrgt = if val & GICD_IROUTER_IRM_BIT != 0 {
+                (1 << vm.cpu_num()) - 1
+            } else if bit_extract(val, 8, 16) != 0 || bit_extract(val, 32, 8) != 0 || aff0 >= vm.cpu_num() {
+                warn!(
+                    "emu_irouter_access: vm[{}] route int {} to illegal affinity 0x{:x}",
+                    vm.id(),
+                    int_id,
+                    val
+                );
+                return;
+            } else {
+                1 << aff0
+            };
+            let ptrgt = vm.vcpu_to_pcpu_mask(vtrgt, vm.cpu_num());
+            self.set_trgt(vcpu, int_id, ptrgt);
+        } else {
+            let ptrgt = self.get_trgt(vcpu, int_id);
+            let vtrgt = vm.pcpu_to_vcpu_mask(ptrgt, PLATFORM_CPU_NUM_MAX);
+            let val = if vtrgt.count_ones() > 1 {
+                GICD_IROUTER_IRM_BIT
+            } else if vtrgt == 0 {
+                0
+            } else {
+                vtrgt.trailing_zeros() as usize
+            };
+            current_cpu().set_gpr(emu_ctx.reg, val);
+        }
+    }
+
     fn handle_trapped_eoir(&self, vcpu: Vcpu) {
         // if current_cpu().id == 2 {
         //     for i in 0..4 {
@@ -2051,16 +2134,16 @@ impl Vgic {
     }
 }
 
-fn vgic_target_translate(vm: Vm, trgt: u32, v2p: bool) -> u32 {
+pub(crate) fn vgic_target_translate(vm: Vm, trgt: u32, v2p: bool) -> u32 {
     let from = trgt.to_le_bytes();
 
     let mut result = 0;
     for (idx, val) in from
         .map(|x| {
             if v2p {
-                vm.vcpu_to_pcpu_mask(x as usize, 8) as u32
+                vm.vcpu_to_pcpu_mask(x as usize, GIC_TARGET_BITS) as u32
             } else {
-                vm.pcpu_to_vcpu_mask(x as usize, 8) as u32
+                vm.pcpu_to_vcpu_mask(x as usize, GIC_TARGET_BITS) as u32
             }
         })
         .iter()
@@ -2241,8 +2324,34 @@ const VGICD_REG_OFFSET_PREFIX_ISACTIVER: usize = 0x6;
 const VGICD_REG_OFFSET_PREFIX_ICACTIVER: usize = 0x7;
 const VGICD_REG_OFFSET_PREFIX_ICFGR: usize = 0x18;
 const VGICD_REG_OFFSET_PREFIX_SGIR: usize = 0x1e;
+#[cfg(feature = "gicv3")]
+const VGICD_REG_OFFSET_IROUTER: usize = 0x6000;
+#[cfg(feature = "gicv3")]
+const VGICD_REG_OFFSET_IROUTER_END: usize = 0x7fe0;
 
 pub fn emu_intc_handler(_emu_dev_id: usize, emu_ctx: &EmuContext) -> bool {
+    // GICv3 distributor takes 64KB, IROUTER and ID registers live above the GICv2 4KB frame
+    #[cfg(feature = "gicv3")]
+    {
+        let gicd_offset = emu_ctx.address & 0xffff;
+        if gicd_offset >= VGICD_REG_OFFSET_IROUTER && gicd_offset < VGICD_REG_OFFSET_IROUTER_END {
+            if emu_ctx.width != 8 && emu_ctx.width != 4 {
+                return false;
+            }
+            active_vm().unwrap().vgic().emu_irouter_access(emu_ctx);
+            return true;
+        } else if gicd_offset >= 0x1000 {
+            if !emu_ctx.write {
+                let val = if gicd_offset == VGIC_REG_OFFSET_PIDR2 {
+                    VGIC_PIDR2_ARCH_GICV3
+                } else {
+                    0
+                };
+                current_cpu().set_gpr(emu_ctx.reg, val);
+            }
+            return true;
+        }
+    }
     let offset = emu_ctx.address & 0xfff;
     if emu_ctx.width > 4 {
         return false;
@@ -2385,6 +2494,7 @@ pub fn vgic_ipi_handler(msg: &IpiMessage) {
     let vm_id;
     let int_id;
     let val;
+    let mut wakeup = false;
     match &msg.ipi_message {
         IpiInnerMsg::Initc(intc) => {
             vm_id = intc.vm_id;
@@ -2440,9 +2550,10 @@ pub fn vgic_ipi_handler(msg: &IpiMessage) {
             }
             InitcEvent::VgicdSetPend => {
                 vgic.set_pend(trgt_vcpu.clone(), int_id as usize, val != 0);
+                wakeup = val != 0 && matches!(trgt_vcpu.state(), VcpuState::VcpuBlk);
             }
             InitcEvent::VgicdSetPrio => {
-                vgic.set_prio(trgt_vcpu.clone(), int_id as usize, val);
+                vgic.set_prio(trgt_vcpu.clone(), int_id as usize, val as u8);
             }
             InitcEvent::VgicdSetTrgt => {
                 vgic.set_trgt(trgt_vcpu.clone(), int_id as usize, val);
@@ -2465,7 +2576,12 @@ pub fn vgic_ipi_handler(msg: &IpiMessage) {
             }
         }
     }
-    save_vcpu_gic(current_cpu().active_vcpu.clone(), trgt_vcpu);
+    save_vcpu_gic(current_cpu().active_vcpu.clone(), trgt_vcpu.clone());
+    // the gic state of the target is saved, it could be scheduled now
+    if wakeup {
+        vm_health_vcpu_wfi(vm_id, trgt_vcpu.id(), false);
+        vcpu_wfi_wakeup(trgt_vcpu);
+    }
 }
 
 pub fn emu_intc_init(vm: Vm, emu_dev_id: usize) {
@@ -2477,6 +2593,11 @@ pub fn emu_intc_init(vm: Vm, emu_dev_id: usize) {
     let mut vgicd = vgic.vgicd.lock();
     vgicd.typer = (GICD.typer() & GICD_TYPER_CPUNUM_MSK as u32)
         | (((vm.cpu_num() - 1) << GICD_TYPER_CPUNUM_OFF) & GICD_TYPER_CPUNUM_MSK) as u32;
+    // IDbits, no LPI and MBI support
+    #[cfg(feature = "gicv3")]
+    {
+        vgicd.typer |= GICD.typer() & (0b11111 << 19);
+    }
     vgicd.iidr = GICD.iidr();
 
     for i in 0..GIC_SPI_MAX {
@@ -0,0 +1,190 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+// Virtual GICv3 for guests. The distributor and the interrupt state are shared with vgic.rs,
+// here are the parts only a GICv3 guest sees: the redistributor frames and ICC_SGI1R_EL1.
+
+use crate::device::EmuContext;
+use crate::kernel::{active_vcpu_id, active_vm, active_vm_id, current_cpu};
+use crate::kernel::{ipi_send_msg, InitcEvent, IpiInitcMessage, IpiInnerMsg, IpiType};
+use crate::lib::bit_extract;
+
+use super::gicv3::*;
+use super::vgic::emu_intc_handler;
+
+const VGICR_REG_OFFSET_CTLR: usize = 0x0;
+const VGICR_REG_OFFSET_IIDR: usize = 0x4;
+const VGICR_REG_OFFSET_TYPER: usize = 0x8;
+const VGICR_REG_OFFSET_TYPER_HI: usize = 0xc;
+const VGICR_REG_OFFSET_WAKER: usize = 0x14;
+pub const VGIC_REG_OFFSET_PIDR2: usize = 0xffe8;
+
+// ArchRev [7:4] of GICD_PIDR2 / GICR_PIDR2
+pub const VGIC_PIDR2_ARCH_GICV3: usize = 0x3 << 4;
+
+const VGICR_SGI_OFFSET_IGROUPR0: usize = 0x080;
+const VGICR_SGI_OFFSET_IGRPMODR0: usize = 0xd00;
+
+fn vgicr_typer(vcpu_id: usize, ncpu: usize) -> usize {
+    // Aff0 of a vcpu is its id, see reset_vmpidr
+    let mut typer = (vcpu_id << 32) | (vcpu_id << 8);
+    if vcpu_id + 1 == ncpu {
+        typer |= GICR_TYPER_LAST_BIT;
+    }
+    typer
+}
+
+fn vgicr_rd_access(vcpu_id: usize, offset: usize, emu_ctx: &EmuContext) {
+    if emu_ctx.write {
+        // CTLR (no LPI) and WAKER are WI, a virtual redistributor never sleeps
+        return;
+    }
+    let vm = active_vm().unwrap();
+    let val = match offset {
+        VGICR_REG_OFFSET_CTLR | VGICR_REG_OFFSET_WAKER => 0,
+        VGICR_REG_OFFSET_IIDR => GICD.iidr() as usize,
+        VGICR_REG_OFFSET_TYPER => {
+            let typer = vgicr_typer(vcpu_id, vm.cpu_num());
+            if emu_ctx.width == 8 {
+                typer
+            } else {
+                typer & 0xffff_ffff
+            }
+        }
+        VGICR_REG_OFFSET_TYPER_HI => vgicr_typer(vcpu_id, vm.cpu_num()) >> 32,
+        VGIC_REG_OFFSET_PIDR2 => VGIC_PIDR2_ARCH_GICV3,
+        _ => 0,
+    };
+    current_cpu().set_gpr(emu_ctx.reg, val);
+}
+
+// SGI_base frame has the same layout as GICD for interrupt 0..31.
+fn vgicr_sgi_access(offset: usize, emu_ctx: &EmuContext) -> bool {
+    let is_priv_reg = match offset {
+        // IGROUPR0 and IGRPMODR0, guests only own group 1 interrupts
+        VGICR_SGI_OFFSET_IGROUPR0 | VGICR_SGI_OFFSET_IGRPMODR0 => {
+            if !emu_ctx.write {
+                let val = if offset == VGICR_SGI_OFFSET_IGROUPR0 {
+                    u32::MAX as usize
+                } else {
+                    0
+                };
+                current_cpu().set_gpr(emu_ctx.reg, val);
+            }
+            return true;
+        }
+        // ISENABLER0 ICENABLER0 ISPENDR0 ICPENDR0 ISACTIVER0 ICACTIVER0
+        0x100 | 0x180 | 0x200 | 0x280 | 0x300 | 0x380 => true,
+        // IPRIORITYR0-7
+        0x400..=0x41f => true,
+        // ICFGR0-1
+        0xc00 | 0xc04 => true,
+        _ => false,
+    };
+
+    if !is_priv_reg {
+        if !emu_ctx.write {
+            current_cpu().set_gpr(emu_ctx.reg, 0);
+        }
+        return true;
+    }
+    let gicd_ctx = EmuContext {
+        address: offset,
+        width: emu_ctx.width,
+        write: emu_ctx.write,
+        sign_ext: emu_ctx.sign_ext,
+        reg: emu_ctx.reg,
+        reg_width: emu_ctx.reg_width,
+    };
+    emu_intc_handler(0, &gicd_ctx)
+}
+
+pub fn emu_vgicr_handler(emu_dev_id: usize, emu_ctx: &EmuContext) -> bool {
+    let vm = match active_vm() {
+        None => {
+            panic!("emu_vgicr_handler: vm is None");
+        }
+        Some(x) => x,
+    };
+    if emu_ctx.width > 8 || emu_ctx.address & (emu_ctx.width - 1) != 0 {
+        return false;
+    }
+
+    let base = vm.config().emulated_device_list()[emu_dev_id].base_ipa;
+    let vcpu_id = (emu_ctx.address - base) / GICR_STRIDE;
+    let offset = (emu_ctx.address - base) % GICR_STRIDE;
+    if vcpu_id >= vm.cpu_num() {
+        return false;
+    }
+
+    if offset < GICR_SGI_BASE_OFF {
+        vgicr_rd_access(vcpu_id, offset, emu_ctx);
+        return true;
+    }
+
+    // the sgi frame of another vcpu is RAZ/WI, the interrupt state is handled by the vcpu itself
+    if vcpu_id != active_vcpu_id() {
+        if !emu_ctx.write {
+            current_cpu().set_gpr(emu_ctx.reg, 0);
+        }
+        return true;
+    }
+    vgicr_sgi_access(offset - GICR_SGI_BASE_OFF, emu_ctx)
+}
+
+/*
+ * ICC_SGI1R_EL1:
+ * TargetList [15:0], Aff1 [23:16], INTID [27:24], Aff2 [39:32], IRM [40], RS [47:44], Aff3 [55:48]
+ * vcpus only have Aff0, which is the vcpu id, so a non-zero Aff1/Aff2/Aff3 targets no vcpu. TargetList
+ * covers the vcpus RS * 16 .. RS * 16 + 15. The source of an SGI is not visible to a GICv3 guest,
+ * it is pended from source 0.
+ */
+pub fn vgic_icc_sgi1r_access(val: usize) {
+    let vm = match active_vm() {
+        Some(vm) => vm,
+        None => {
+            panic!("vgic_icc_sgi1r_access: current vcpu.vm is none");
+        }
+    };
+    let sgi = bit_extract(val, 24, 4);
+    let irm = bit_extract(val, 40, 1) != 0;
+    if !irm && (bit_extract(val, 16, 8) != 0 || bit_extract(val, 32, 8) != 0 || bit_extract(val, 48, 8) != 0) {
+        return;
+    }
+    let rs = bit_extract(val, 44, 4);
+    let target_list = bit_extract(val, 0, 16);
+
+    for vcpu_id in 0..vm.cpu_num() {
+        let target = if irm {
+            vcpu_id != active_vcpu_id()
+        } else {
+            vcpu_id >> 4 == rs && target_list & (1 << (vcpu_id & 0xf)) != 0
+        };
+        if !target {
+            continue;
+        }
+        let pcpu_id = match vm.vcpuid_to_pcpuid(vcpu_id) {
+            Ok(pcpu_id) => pcpu_id,
+            Err(_) => continue,
+        };
+        let m = IpiInitcMessage {
+            event: InitcEvent::VgicdSetPend,
+            vm_id: active_vm_id(),
+            int_id: sgi as u16,
+            val: true as usize,
+        };
+        if !ipi_send_msg(pcpu_id, IpiType::IpiTIntc, IpiInnerMsg::Initc(m)) {
+            println!(
+                "vgic_icc_sgi1r_access: Failed to send ipi message, target {} type {}",
+                pcpu_id, 0
+            );
+        }
+    }
+}
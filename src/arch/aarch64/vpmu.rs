@@ -0,0 +1,288 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+/*
+ * Virtual PMU. The PMU registers are trapped (MDCR_EL2.TPM, TPMCR) and emulated on the hardware
+ * counters, which belong to the running vcpu: its counters are restored when it is scheduled in and
+ * saved and stopped when it is scheduled out. A guest sees the event counters below MDCR_EL2.HPMN
+ * and the cycle counter, and never counts at EL2, the filters of its counters are written with NSH
+ * cleared. The overflow interrupt is the PPI INTERRUPT_IRQ_GUEST_PMU, linked to the vcpu like the
+ * virtual timer.
+ */
+
+use crate::arch::{cpu_id_reg_host, SysregAccess};
+use crate::config::{CPU_ID_REG_DFR0, VmConfigEntry};
+use crate::kernel::{active_vm, StreamReader, StreamWriter};
+use crate::lib::bit_extract;
+
+pub const VPMU_COUNTER_MAX: usize = 31;
+// the cycle counter in PMCNTENSET and the like, and the index of PMCCFILTR_EL0 in PMSELR_EL0
+const VPMU_CYCLE_COUNTER: usize = 31;
+
+// PMCR_EL0: E [0], P [1], C [2], D [3], X [4], DP [5], LC [6], LP [7], N [15:11]
+const PMCR_E: usize = 1 << 0;
+const PMCR_P: usize = 1 << 1;
+const PMCR_WR_MSK: usize = 0xff;
+const PMCR_N_OFF: usize = 11;
+const PMCR_N_MSK: usize = 0x1f << PMCR_N_OFF;
+// PMEVTYPER<n>_EL0, PMCCFILTR_EL0: NSH [27], count at EL2
+const PMEVTYPER_NSH: usize = 1 << 27;
+// PMUSERENR_EL0: EN [0], SW [1], CR [2], ER [3]
+const PMUSERENR_MSK: usize = 0xf;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct VmCtxPmu {
+    pub pmcr_el0: u64,
+    pmselr_el0: u64,
+    pmuserenr_el0: u64,
+    pmcntenset_el0: u64,
+    pmintenset_el1: u64,
+    pmovsset_el0: u64,
+    pmccntr_el0: u64,
+    pmccfiltr_el0: u64,
+    pmevcntr_el0: [u64; VPMU_COUNTER_MAX],
+    pmevtyper_el0: [u64; VPMU_COUNTER_MAX],
+}
+
+impl VmCtxPmu {
+    pub fn default() -> VmCtxPmu {
+        VmCtxPmu {
+            pmcr_el0: 0,
+            pmselr_el0: 0,
+            pmuserenr_el0: 0,
+            pmcntenset_el0: 0,
+            pmintenset_el1: 0,
+            pmovsset_el0: 0,
+            pmccntr_el0: 0,
+            pmccfiltr_el0: 0,
+            pmevcntr_el0: [0; VPMU_COUNTER_MAX],
+            pmevtyper_el0: [0; VPMU_COUNTER_MAX],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = VmCtxPmu::default();
+    }
+
+    // all the counters are sent, the HPMN of the receiver may differ, it restores those below its own
+    pub fn migrate_encode(&self, w: &mut StreamWriter) {
+        w.put_u64(self.pmcr_el0);
+        w.put_u64(self.pmselr_el0);
+        w.put_u64(self.pmuserenr_el0);
+        w.put_u64(self.pmcntenset_el0);
+        w.put_u64(self.pmintenset_el1);
+        w.put_u64(self.pmovsset_el0);
+        w.put_u64(self.pmccntr_el0);
+        w.put_u64(self.pmccfiltr_el0);
+        w.put_u32(VPMU_COUNTER_MAX as u32);
+        for n in 0..VPMU_COUNTER_MAX {
+            w.put_u64(self.pmevcntr_el0[n]);
+            w.put_u64(self.pmevtyper_el0[n]);
+        }
+    }
+
+    pub fn migrate_decode(&mut self, r: &mut StreamReader) -> Result<(), ()> {
+        self.pmcr_el0 = r.get_u64()?;
+        self.pmselr_el0 = r.get_u64()?;
+        self.pmuserenr_el0 = r.get_u64()?;
+        self.pmcntenset_el0 = r.get_u64()?;
+        self.pmintenset_el1 = r.get_u64()?;
+        self.pmovsset_el0 = r.get_u64()?;
+        self.pmccntr_el0 = r.get_u64()?;
+        self.pmccfiltr_el0 = r.get_u64()?;
+        let num = r.get_count(VPMU_COUNTER_MAX)?;
+        for n in 0..num {
+            self.pmevcntr_el0[n] = r.get_u64()?;
+            self.pmevtyper_el0[n] = r.get_u64()?;
+        }
+        Ok(())
+    }
+
+    // save the counters of the vcpu, and stop them so that nothing counts for it until restore
+    pub fn save(&mut self) {
+        if !pmu_implemented() {
+            return;
+        }
+        let mask = vpmu_counter_mask();
+        mrs!(self.pmcr_el0, PMCR_EL0);
+        msr!(PMCR_EL0, self.pmcr_el0 & !(PMCR_E as u64));
+        isb();
+        mrs!(self.pmselr_el0, PMSELR_EL0);
+        mrs!(self.pmuserenr_el0, PMUSERENR_EL0);
+        mrs!(self.pmcntenset_el0, PMCNTENSET_EL0);
+        mrs!(self.pmintenset_el1, PMINTENSET_EL1);
+        mrs!(self.pmovsset_el0, PMOVSSET_EL0);
+        mrs!(self.pmccntr_el0, PMCCNTR_EL0);
+        mrs!(self.pmccfiltr_el0, PMCCFILTR_EL0);
+        for n in 0..vpmu_counter_num() {
+            msr!(PMSELR_EL0, n);
+            isb();
+            mrs!(self.pmevcntr_el0[n], PMXEVCNTR_EL0);
+            mrs!(self.pmevtyper_el0[n], PMXEVTYPER_EL0);
+        }
+        // a pending overflow of the vcpu must not raise the PPI for the next one
+        msr!(PMCNTENCLR_EL0, mask);
+        msr!(PMINTENCLR_EL1, mask);
+        msr!(PMOVSCLR_EL0, mask);
+    }
+
+    pub fn restore(&self) {
+        if !pmu_implemented() {
+            return;
+        }
+        let mask = vpmu_counter_mask() as u64;
+        msr!(PMCR_EL0, self.pmcr_el0 & !(PMCR_E as u64));
+        isb();
+        for n in 0..vpmu_counter_num() {
+            msr!(PMSELR_EL0, n);
+            isb();
+            msr!(PMXEVTYPER_EL0, self.pmevtyper_el0[n]);
+            msr!(PMXEVCNTR_EL0, self.pmevcntr_el0[n]);
+        }
+        msr!(PMSELR_EL0, self.pmselr_el0);
+        msr!(PMUSERENR_EL0, self.pmuserenr_el0);
+        msr!(PMCCFILTR_EL0, self.pmccfiltr_el0);
+        msr!(PMCCNTR_EL0, self.pmccntr_el0);
+        msr!(PMCNTENCLR_EL0, mask & !self.pmcntenset_el0);
+        msr!(PMCNTENSET_EL0, self.pmcntenset_el0 & mask);
+        msr!(PMOVSCLR_EL0, mask & !self.pmovsset_el0);
+        msr!(PMOVSSET_EL0, self.pmovsset_el0 & mask);
+        msr!(PMINTENCLR_EL1, mask & !self.pmintenset_el1);
+        msr!(PMINTENSET_EL1, self.pmintenset_el1 & mask);
+        isb();
+        msr!(PMCR_EL0, self.pmcr_el0);
+    }
+}
+
+fn isb() {
+    unsafe { core::arch::asm!("isb") };
+}
+
+// ID_AA64DFR0_EL1.PMUVer [11:8], 0xf is an IMPLEMENTATION DEFINED PMU
+fn pmu_implemented() -> bool {
+    !matches!(bit_extract(cpu_id_reg_host(CPU_ID_REG_DFR0), 8, 4), 0 | 0xf)
+}
+
+// the PMU of a VM is hidden if its cpu model sets PMUVer to 0
+pub fn vpmu_enabled(config: &VmConfigEntry) -> bool {
+    let dfr0 = config.cpu_id_reg(CPU_ID_REG_DFR0);
+    let hidden = bit_extract(dfr0.mask, 8, 4) == 0xf && bit_extract(dfr0.value, 8, 4) == 0;
+    pmu_implemented() && !hidden
+}
+
+// the event counters of the guests, MDCR_EL2.HPMN, the same for all vcpus
+fn vpmu_counter_num() -> usize {
+    let mdcr: u64;
+    mrs!(mdcr, MDCR_EL2);
+    usize::min(mdcr as usize & 0x1f, VPMU_COUNTER_MAX)
+}
+
+fn vpmu_counter_mask() -> usize {
+    (1 << VPMU_CYCLE_COUNTER) | ((1 << vpmu_counter_num()) - 1)
+}
+
+// access a PMU register of the vcpu, the value written and read is under mask
+macro_rules! vpmu_reg_access {
+    ($access: expr, $reg: ident, $mask: expr) => {{
+        if $access.write {
+            msr!($reg, $access.val & $mask);
+            Ok(0)
+        } else {
+            let val: u64;
+            mrs!(val, $reg);
+            Ok(val as usize & $mask)
+        }
+    }};
+}
+
+/*
+ * The PMU registers trapped with EC 0x18, they are emulated at EL2, where all the counters are
+ * reachable, so the counters of the guest are checked against MDCR_EL2.HPMN here. RAZ/WI if the VM
+ * has no PMU.
+ */
+pub fn vpmu_sysreg_access(access: &SysregAccess) -> Result<usize, ()> {
+    if !vpmu_enabled(&active_vm().unwrap().config()) {
+        return Ok(0);
+    }
+    let op1 = bit_extract(access.enc, 14, 3);
+    let crn = bit_extract(access.enc, 10, 4);
+    let crm = bit_extract(access.enc, 1, 4);
+    let op2 = bit_extract(access.enc, 17, 3);
+    let mask = vpmu_counter_mask();
+    match (op1, crn, crm, op2) {
+        (3, 9, 12, 0) => vpmu_pmcr_access(access),
+        (3, 9, 12, 1) => vpmu_reg_access!(access, PMCNTENSET_EL0, mask),
+        (3, 9, 12, 2) => vpmu_reg_access!(access, PMCNTENCLR_EL0, mask),
+        (3, 9, 12, 3) => vpmu_reg_access!(access, PMOVSCLR_EL0, mask),
+        (3, 9, 12, 4) if access.write => vpmu_reg_access!(access, PMSWINC_EL0, mask & !(1 << VPMU_CYCLE_COUNTER)),
+        (3, 9, 12, 5) => vpmu_reg_access!(access, PMSELR_EL0, 0x1f),
+        (3, 9, 12, 6) if !access.write => vpmu_reg_access!(access, PMCEID0_EL0, usize::MAX),
+        (3, 9, 12, 7) if !access.write => vpmu_reg_access!(access, PMCEID1_EL0, usize::MAX),
+        (3, 9, 13, 0) => vpmu_reg_access!(access, PMCCNTR_EL0, usize::MAX),
+        (3, 9, 13, 1) | (3, 9, 13, 2) => {
+            let sel: u64;
+            mrs!(sel, PMSELR_EL0);
+            vpmu_counter_access(access, sel as usize & 0x1f, op2 == 1)
+        }
+        (3, 9, 14, 0) => vpmu_reg_access!(access, PMUSERENR_EL0, PMUSERENR_MSK),
+        (3, 9, 14, 3) => vpmu_reg_access!(access, PMOVSSET_EL0, mask),
+        (0, 9, 14, 1) => vpmu_reg_access!(access, PMINTENSET_EL1, mask),
+        (0, 9, 14, 2) => vpmu_reg_access!(access, PMINTENCLR_EL1, mask),
+        // PMEVCNTR<n>_EL0, PMEVTYPER<n>_EL0, n is CRm [1:0] : Op2
+        (3, 14, 8..=11, _) => vpmu_counter_access(access, ((crm & 0b11) << 3) | op2, false),
+        (3, 14, 12..=15, _) => vpmu_counter_access(access, ((crm & 0b11) << 3) | op2, true),
+        _ => Err(()),
+    }
+}
+
+fn vpmu_pmcr_access(access: &SysregAccess) -> Result<usize, ()> {
+    let num = vpmu_counter_num();
+    if access.write {
+        // PMCR_EL0.P written at EL2 would reset the counters above HPMN too
+        if access.val & PMCR_P != 0 {
+            let sel: u64;
+            mrs!(sel, PMSELR_EL0);
+            for n in 0..num {
+                msr!(PMSELR_EL0, n);
+                isb();
+                msr!(PMXEVCNTR_EL0, 0_u64);
+            }
+            msr!(PMSELR_EL0, sel);
+        }
+        msr!(PMCR_EL0, access.val & PMCR_WR_MSK & !PMCR_P);
+        Ok(0)
+    } else {
+        let pmcr: u64;
+        mrs!(pmcr, PMCR_EL0);
+        Ok((pmcr as usize & !PMCR_N_MSK) | (num << PMCR_N_OFF))
+    }
+}
+
+// event counter n, or its filter if typer, the filter of the cycle counter is n 31
+fn vpmu_counter_access(access: &SysregAccess, n: usize, typer: bool) -> Result<usize, ()> {
+    if typer && n == VPMU_CYCLE_COUNTER {
+        return vpmu_reg_access!(access, PMCCFILTR_EL0, !PMEVTYPER_NSH);
+    }
+    if n >= vpmu_counter_num() {
+        return Err(());
+    }
+    let sel: u64;
+    mrs!(sel, PMSELR_EL0);
+    msr!(PMSELR_EL0, n);
+    isb();
+    let res = if typer {
+        vpmu_reg_access!(access, PMXEVTYPER_EL0, !PMEVTYPER_NSH)
+    } else {
+        vpmu_reg_access!(access, PMXEVCNTR_EL0, usize::MAX)
+    };
+    msr!(PMSELR_EL0, sel);
+    res
+}
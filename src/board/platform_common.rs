@@ -17,9 +17,24 @@ pub const PLATFORM_VCPU_NUM_MAX: usize = 8;
 #[repr(C)]
 pub enum SchedRule {
     RoundRobin,
+    RealTime,
+    TimePartition,
     None,
 }
 
+/*
+ * Scheduler of the cores after core 0, which runs the MVM, feature sched-rt selects SchedulerRT and
+ * sched-tp selects SchedulerTP.
+ */
+#[cfg(all(feature = "sched-rt", feature = "sched-tp"))]
+compile_error!("features sched-rt and sched-tp both select the scheduler of the guest cores, enable only one");
+#[cfg(feature = "sched-rt")]
+pub const GUEST_CORE_SCHED: SchedRule = SchedRule::RealTime;
+#[cfg(feature = "sched-tp")]
+pub const GUEST_CORE_SCHED: SchedRule = SchedRule::TimePartition;
+#[cfg(not(any(feature = "sched-rt", feature = "sched-tp")))]
+pub const GUEST_CORE_SCHED: SchedRule = SchedRule::RoundRobin;
+
 #[repr(C)]
 pub struct PlatMemRegion {
     pub base: usize,
@@ -75,6 +90,8 @@ pub trait PlatOperation {
     const GICC_BASE: usize;
     const GICH_BASE: usize;
     const GICV_BASE: usize;
+    // redistributor, only offered by GICv3 platforms
+    const GICR_BASE: usize = 0;
 
     const DISK_PARTITION_0_START: usize = usize::MAX;
     const DISK_PARTITION_1_START: usize = usize::MAX;
@@ -16,6 +16,7 @@ use crate::board::{
     PlatMemRegion,
 };
 use crate::board::SchedRule::RoundRobin;
+use crate::board::GUEST_CORE_SCHED;
 use crate::device::ARM_CORTEX_A57;
 use crate::driver::{read, write};
 
@@ -35,6 +36,7 @@ impl PlatOperation for QemuPlatform {
     const GICC_BASE: usize = 0x08010000;
     const GICH_BASE: usize = 0x08030000;
     const GICV_BASE: usize = 0x08040000;
+    const GICR_BASE: usize = 0x080a0000;
 
     const SHARE_MEM_BASE: usize = 0x7_0000_0000;
 
@@ -81,17 +83,17 @@ pub static PLAT_DESC: PlatformConfig = PlatformConfig {
             PlatCpuCoreConfig {
                 name: ARM_CORTEX_A57,
                 mpidr: 1,
-                sched: RoundRobin,
+                sched: GUEST_CORE_SCHED,
             },
             PlatCpuCoreConfig {
                 name: ARM_CORTEX_A57,
                 mpidr: 2,
-                sched: RoundRobin,
+                sched: GUEST_CORE_SCHED,
             },
             PlatCpuCoreConfig {
                 name: ARM_CORTEX_A57,
                 mpidr: 3,
-                sched: RoundRobin,
+                sched: GUEST_CORE_SCHED,
             },
         ],
     },
@@ -115,6 +117,7 @@ pub static PLAT_DESC: PlatformConfig = PlatformConfig {
             gicc_addr: Platform::GICC_BASE,
             gich_addr: Platform::GICH_BASE,
             gicv_addr: Platform::GICV_BASE,
+            gicr_addr: Platform::GICR_BASE,
             maintenance_int_id: 25,
         },
         smmu_desc: SmmuDesc {
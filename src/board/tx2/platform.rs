@@ -15,6 +15,7 @@ use crate::board::{
     PlatMemRegion,
 };
 use crate::board::SchedRule::RoundRobin;
+use crate::board::GUEST_CORE_SCHED;
 use crate::device::ARM_CORTEX_A57;
 #[allow(unused_imports)]
 use crate::device::ARM_NVIDIA_DENVER;
@@ -81,17 +82,17 @@ pub static PLAT_DESC: PlatformConfig = PlatformConfig {
             PlatCpuCoreConfig {
                 name: ARM_CORTEX_A57,
                 mpidr: 0x80000101,
-                sched: RoundRobin,
+                sched: GUEST_CORE_SCHED,
             },
             PlatCpuCoreConfig {
                 name: ARM_CORTEX_A57,
                 mpidr: 0x80000102,
-                sched: RoundRobin,
+                sched: GUEST_CORE_SCHED,
             },
             PlatCpuCoreConfig {
                 name: ARM_CORTEX_A57,
                 mpidr: 0x80000103,
-                sched: RoundRobin,
+                sched: GUEST_CORE_SCHED,
             },
         ],
     },
@@ -118,6 +119,7 @@ pub static PLAT_DESC: PlatformConfig = PlatformConfig {
             gicc_addr: Platform::GICC_BASE,
             gich_addr: Platform::GICH_BASE,
             gicv_addr: Platform::GICV_BASE,
+            gicr_addr: Platform::GICR_BASE,
             maintenance_int_id: 25,
         },
         smmu_desc: SmmuDesc {
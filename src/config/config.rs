@@ -8,15 +8,19 @@
 // MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use alloc::collections::BTreeMap;
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::mem::{align_of, size_of};
 
 use spin::Mutex;
 
 // use crate::board::*;
+use crate::arch::{cpu_id_reg_field, cpu_id_reg_host, PAGE_SIZE};
+use crate::board::PLATFORM_CPU_NUM_MAX;
 use crate::device::{EmuDeviceType, mediated_blk_free, mediated_blk_request};
-use crate::kernel::{active_vm, vm, Vm, vm_ipa2pa, VM_NUM_MAX, VmType};
+use crate::kernel::{active_vm, vm, Vm, vm_if_get_state, vm_ipa2pa, VM_NUM_MAX, VmState, VmType};
 use crate::lib::{BitAlloc, BitAlloc16, memcpy_safe};
 use crate::vmm::vmm_init_gvm;
 
@@ -31,15 +35,20 @@ pub enum DtbDevType {
     DevSerial = 0,
     DevGicd = 1,
     DevGicc = 2,
+    DevGicr = 3,
 }
 
 impl DtbDevType {
-    pub fn from_usize(value: usize) -> DtbDevType {
+    pub fn from_usize(value: usize) -> Result<DtbDevType, ()> {
         match value {
-            0 => DtbDevType::DevSerial,
-            1 => DtbDevType::DevGicd,
-            2 => DtbDevType::DevGicc,
-            _ => panic!("Unknown DtbDevType value: {}", value),
+            0 => Ok(DtbDevType::DevSerial),
+            1 => Ok(DtbDevType::DevGicd),
+            2 => Ok(DtbDevType::DevGicc),
+            3 => Ok(DtbDevType::DevGicr),
+            _ => {
+                println!("Unknown DtbDevType value: {}", value);
+                Err(())
+            }
         }
     }
 }
@@ -98,6 +107,7 @@ impl VmPassthroughDeviceConfig {
     }
 }
 
+#[repr(C)]
 #[derive(Clone, Copy, Debug, Eq)]
 pub struct VmRegion {
     pub ipa_start: usize,
@@ -172,11 +182,38 @@ impl VmImageConfig {
     }
 }
 
+/*
+ * ID registers of the VM cpu model, in order:
+ * ID_AA64PFR0/PFR1/ZFR0/DFR0/DFR1/ISAR0/ISAR1/ISAR2/MMFR0/MMFR1/MMFR2_EL1
+ */
+pub const CPU_ID_REG_NUM: usize = 11;
+pub const CPU_ID_REG_DFR0: usize = 3;
+
+// the 4-bit fields under mask are taken from value instead of the host
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct VmCpuIdReg {
+    pub mask: usize,
+    pub value: usize,
+}
+
+impl VmCpuIdReg {
+    pub const fn default() -> VmCpuIdReg {
+        VmCpuIdReg { mask: 0, value: 0 }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct VmCpuConfig {
     pub num: usize,
     pub allocate_bitmap: u32,
     pub master: i32,
+    // real-time parameters used by SchedulerRT, in us, 0 budget means best effort
+    pub budget: usize,
+    pub period: usize,
+    // smaller value means higher priority
+    pub priority: usize,
+    pub id_regs: [VmCpuIdReg; CPU_ID_REG_NUM],
 }
 
 impl VmCpuConfig {
@@ -185,10 +222,137 @@ impl VmCpuConfig {
             num: 0,
             allocate_bitmap: 0,
             master: 0,
+            budget: 0,
+            period: 0,
+            priority: 0,
+            id_regs: [VmCpuIdReg::default(); CPU_ID_REG_NUM],
+        }
+    }
+}
+
+// What to do when a VM is declared dead, see kernel/health.rs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VmHealthPolicy {
+    Ignore = 0,
+    Notify = 1,
+    Reboot = 2,
+    RebootBackoff = 3,
+    Shutdown = 4,
+}
+
+impl VmHealthPolicy {
+    pub fn from_usize(value: usize) -> VmHealthPolicy {
+        match value {
+            0 => VmHealthPolicy::Ignore,
+            1 => VmHealthPolicy::Notify,
+            2 => VmHealthPolicy::Reboot,
+            3 => VmHealthPolicy::RebootBackoff,
+            4 => VmHealthPolicy::Shutdown,
+            _ => panic!("Unknown VmHealthPolicy value: {}", value),
         }
     }
 }
 
+#[derive(Clone, Copy)]
+pub struct VmHealthConfig {
+    pub policy: VmHealthPolicy,
+    // missed heartbeats before the VM is dead
+    pub keep_alive_miss: usize,
+    // ms all vcpus of the VM could stay in WFI, 0 disables the check
+    pub wfi_timeout: usize,
+}
+
+impl VmHealthConfig {
+    pub const fn default() -> VmHealthConfig {
+        VmHealthConfig {
+            policy: VmHealthPolicy::Ignore,
+            keep_alive_miss: 3,
+            wfi_timeout: 0,
+        }
+    }
+}
+
+// How a guest SMC is served, see smc_handler
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VmSmcPolicy {
+    // fail with NOT_SUPPORTED
+    Deny = 0,
+    // pass through to the firmware, x1-x3 only, see smc_handler
+    Forward = 1,
+    // handled by the hypervisor (PSCI, SMCCC)
+    Emulate = 2,
+}
+
+impl VmSmcPolicy {
+    pub fn from_usize(value: usize) -> VmSmcPolicy {
+        match value {
+            0 => VmSmcPolicy::Deny,
+            1 => VmSmcPolicy::Forward,
+            2 => VmSmcPolicy::Emulate,
+            _ => panic!("Unknown VmSmcPolicy value: {}", value),
+        }
+    }
+}
+
+// function IDs from start to end, both included
+#[derive(Clone, Copy, Debug)]
+pub struct VmSmcRange {
+    pub start: usize,
+    pub end: usize,
+    pub policy: VmSmcPolicy,
+}
+
+pub const SMC_RANGE_MAX_NUM: usize = 16;
+// SMCCC function IDs are 32 bits, the firmware ignores the upper half of x0
+const SMC_FID_MASK: usize = 0xffff_ffff;
+// the Arm architecture calls (SMCCC) and PSCI, in SMC32 and SMC64, served by the hypervisor only
+const SMC_ARCH_FID_RANGES: [(usize, usize); 4] = [
+    (0x8000_0000, 0x8000_ffff),
+    (0xc000_0000, 0xc000_ffff),
+    (0x8400_0000, 0x8400_001f),
+    (0xc400_0000, 0xc400_001f),
+];
+
+#[derive(Clone)]
+pub struct VmSmcConfig {
+    pub ranges: Vec<VmSmcRange>,
+}
+
+impl VmSmcConfig {
+    pub const fn default() -> VmSmcConfig {
+        VmSmcConfig { ranges: Vec::new() }
+    }
+
+    // the first range covering fid decides, calls out of all ranges are emulated
+    pub fn policy(&self, fid: usize) -> VmSmcPolicy {
+        let fid = fid & SMC_FID_MASK;
+        match self.ranges.iter().find(|x| fid >= x.start && fid <= x.end) {
+            Some(range) => range.policy,
+            None => VmSmcPolicy::Emulate,
+        }
+    }
+
+    // a forwarded range would hand the vcpus and the system power to the firmware, it can not cover PSCI or SMCCC
+    pub fn add(&mut self, range: VmSmcRange) -> Result<(), ()> {
+        if self.ranges.len() >= SMC_RANGE_MAX_NUM || range.start > range.end || range.end > SMC_FID_MASK {
+            return Err(());
+        }
+        if range.policy == VmSmcPolicy::Forward
+            && SMC_ARCH_FID_RANGES
+                .iter()
+                .any(|(start, end)| range.start <= *end && *start <= range.end)
+        {
+            return Err(());
+        }
+        self.ranges.push(range);
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct AddrRegions {
     pub ipa: usize,
@@ -231,6 +395,8 @@ pub struct VmConfigEntry {
     pub vm_emu_dev_confg: Arc<Mutex<VmEmulatedDeviceConfigList>>,
     pub vm_pt_dev_confg: Arc<Mutex<VmPassthroughDeviceConfig>>,
     pub vm_dtb_devs: Arc<Mutex<VMDtbDevConfigList>>,
+    pub health: Arc<Mutex<VmHealthConfig>>,
+    pub smc: Arc<Mutex<VmSmcConfig>>,
 }
 
 impl VmConfigEntry {
@@ -248,6 +414,8 @@ impl VmConfigEntry {
             vm_emu_dev_confg: Arc::new(Mutex::new(VmEmulatedDeviceConfigList::default())),
             vm_pt_dev_confg: Arc::new(Mutex::new(VmPassthroughDeviceConfig::default())),
             vm_dtb_devs: Arc::new(Mutex::new(VMDtbDevConfigList::default())),
+            health: Arc::new(Mutex::new(VmHealthConfig::default())),
+            smc: Arc::new(Mutex::new(VmSmcConfig::default())),
         }
     }
 
@@ -274,6 +442,8 @@ impl VmConfigEntry {
             vm_emu_dev_confg: Arc::new(Mutex::new(VmEmulatedDeviceConfigList::default())),
             vm_pt_dev_confg: Arc::new(Mutex::new(VmPassthroughDeviceConfig::default())),
             vm_dtb_devs: Arc::new(Mutex::new(VMDtbDevConfigList::default())),
+            health: Arc::new(Mutex::new(VmHealthConfig::default())),
+            smc: Arc::new(Mutex::new(VmSmcConfig::default())),
         }
     }
 
@@ -371,6 +541,72 @@ impl VmConfigEntry {
         cpu_cfg.master = master as i32;
     }
 
+    pub fn cpu_budget(&self) -> usize {
+        let cpu_cfg = self.cpu.lock();
+        cpu_cfg.budget
+    }
+
+    pub fn cpu_period(&self) -> usize {
+        let cpu_cfg = self.cpu.lock();
+        cpu_cfg.period
+    }
+
+    pub fn cpu_priority(&self) -> usize {
+        let cpu_cfg = self.cpu.lock();
+        cpu_cfg.priority
+    }
+
+    pub fn set_cpu_rt_cfg(&self, budget: usize, period: usize, priority: usize) {
+        let mut cpu_cfg = self.cpu.lock();
+        cpu_cfg.budget = budget;
+        cpu_cfg.period = period;
+        cpu_cfg.priority = priority;
+    }
+
+    pub fn cpu_id_reg(&self, idx: usize) -> VmCpuIdReg {
+        let cpu_cfg = self.cpu.lock();
+        cpu_cfg.id_regs[idx]
+    }
+
+    pub fn set_cpu_id_reg(&self, idx: usize, mask: usize, value: usize) {
+        let mut cpu_cfg = self.cpu.lock();
+        cpu_cfg.id_regs[idx] = VmCpuIdReg {
+            mask,
+            value: value & mask,
+        };
+    }
+
+    pub fn health_cfg(&self) -> VmHealthConfig {
+        *self.health.lock()
+    }
+
+    pub fn smc_policy(&self, fid: usize) -> VmSmcPolicy {
+        let smc_cfg = self.smc.lock();
+        smc_cfg.policy(fid)
+    }
+
+    pub fn add_smc_range(&self, range: VmSmcRange) -> Result<(), ()> {
+        let mut smc_cfg = self.smc.lock();
+        smc_cfg.add(range)
+    }
+
+    pub fn clear_smc_range(&self) {
+        let mut smc_cfg = self.smc.lock();
+        smc_cfg.clear();
+    }
+
+    pub fn smc_ranges(&self) -> Vec<VmSmcRange> {
+        let smc_cfg = self.smc.lock();
+        smc_cfg.ranges.clone()
+    }
+
+    pub fn set_health_cfg(&self, policy: VmHealthPolicy, keep_alive_miss: usize, wfi_timeout: usize) {
+        let mut health_cfg = self.health.lock();
+        health_cfg.policy = policy;
+        health_cfg.keep_alive_miss = keep_alive_miss;
+        health_cfg.wfi_timeout = wfi_timeout;
+    }
+
     pub fn emulated_device_list(&self) -> Vec<VmEmulatedDeviceConfig> {
         let emu_dev_cfg = self.vm_emu_dev_confg.lock();
         emu_dev_cfg.emu_dev_list.clone()
@@ -452,6 +688,35 @@ impl VmConfigEntry {
         }
         0
     }
+
+    pub fn gicr_addr(&self) -> usize {
+        let dtb_devs = self.vm_dtb_devs.lock();
+        for dev in &dtb_devs.dtb_device_list {
+            match dev.dev_type {
+                DtbDevType::DevGicr => {
+                    return dev.addr_region.ipa;
+                }
+                _ => {}
+            }
+        }
+        0
+    }
+}
+
+// A window of the major frame, offset and length are in us
+#[derive(Clone, Copy, Debug)]
+pub struct SchedWindowConfig {
+    pub vm_id: usize,
+    pub offset: usize,
+    pub length: usize,
+}
+
+#[derive(Clone)]
+pub struct SchedPartitionConfig {
+    pub major_frame: usize,
+    pub windows: Vec<SchedWindowConfig>,
+    // the MVM runs in the time no window of a runnable VM covers, otherwise the cpu idles
+    pub spare_to_mvm: bool,
 }
 
 #[derive(Clone)]
@@ -460,6 +725,8 @@ pub struct VmConfigTable {
     pub vm_bitmap: BitAlloc16,
     pub vm_num: usize,
     pub entries: Vec<VmConfigEntry>,
+    // time partitioning plan of each physical cpu, indexed by cpu id
+    pub sched_partitions: BTreeMap<usize, SchedPartitionConfig>,
 }
 
 impl VmConfigTable {
@@ -469,6 +736,7 @@ impl VmConfigTable {
             vm_bitmap: BitAlloc16::default(),
             vm_num: 0,
             entries: Vec::new(),
+            sched_partitions: BTreeMap::new(),
         }
     }
 
@@ -700,6 +968,299 @@ pub fn vm_cfg_set_cpu(vmid: usize, num: usize, allocate_bitmap: usize, master: u
     Ok(0)
 }
 
+/* Set real-time scheduling parameters of VM's vcpus, budget and period are in us */
+pub fn vm_cfg_set_cpu_rt(vmid: usize, budget: usize, period: usize, priority: usize) -> Result<usize, ()> {
+    let vm_cfg = match vm_cfg_entry(vmid) {
+        Some(vm_cfg) => vm_cfg,
+        None => return Err(()),
+    };
+    // SchedulerRT takes the parameters when the vcpus are added to it
+    match vm_if_get_state(vmid) {
+        VmState::VmActive | VmState::VmPaused => {
+            println!("vm_cfg_set_cpu_rt: VM[{}] is running", vmid);
+            return Err(());
+        }
+        _ => {}
+    }
+
+    if budget != 0 && period == 0 {
+        println!("vm_cfg_set_cpu_rt: VM[{}] budget {} without a period", vmid, budget);
+        return Err(());
+    }
+    if budget > period {
+        println!(
+            "vm_cfg_set_cpu_rt: VM[{}] budget {} is larger than period {}",
+            vmid, budget, period
+        );
+        return Err(());
+    }
+    vm_cfg.set_cpu_rt_cfg(budget, period, priority);
+
+    println!(
+        "\nVM[{}] vm_cfg_set_cpu_rt: budget {}us period {}us priority {}",
+        vmid,
+        vm_cfg.cpu_budget(),
+        vm_cfg.cpu_period(),
+        vm_cfg.cpu_priority()
+    );
+
+    Ok(0)
+}
+
+// the first masked field of ID register idx which is not whole or higher than the host one
+fn vm_cfg_cpu_id_reg_check(idx: usize, mask: usize, value: usize) -> Result<(), usize> {
+    let host = cpu_id_reg_host(idx);
+    for field in 0..16 {
+        let field_mask = (mask >> (field * 4)) & 0xf;
+        if field_mask == 0 {
+            continue;
+        }
+        if field_mask != 0xf || cpu_id_reg_field(idx, value, field) > cpu_id_reg_field(idx, host, field) {
+            return Err(field);
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Override the fields of ID register idx (see CPU_ID_REG_NUM) seen by VM, a 0 mask restores the host
+ * value. Every masked field is a whole 4-bit field and can not be higher than the host one, so a
+ * cpu model common to several hosts takes the lowest value of each field. Signed fields compare
+ * as signed, e.g. 0xf (not implemented) of FP is lower than 0.
+ */
+pub fn vm_cfg_set_cpu_id_reg(vmid: usize, idx: usize, mask: usize, value: usize) -> Result<usize, ()> {
+    let vm_cfg = match vm_cfg_entry(vmid) {
+        Some(vm_cfg) => vm_cfg,
+        None => return Err(()),
+    };
+    if idx >= CPU_ID_REG_NUM {
+        println!("vm_cfg_set_cpu_id_reg: VM[{}] illegal id reg {}", vmid, idx);
+        return Err(());
+    }
+    match vm_if_get_state(vmid) {
+        VmState::VmActive | VmState::VmPaused => {
+            println!("vm_cfg_set_cpu_id_reg: VM[{}] is running", vmid);
+            return Err(());
+        }
+        _ => {}
+    }
+
+    if let Err(field) = vm_cfg_cpu_id_reg_check(idx, mask, value) {
+        println!(
+            "vm_cfg_set_cpu_id_reg: VM[{}] id reg {} field {} mask {:x} value {:x}, host {:x}",
+            vmid,
+            idx,
+            field,
+            mask,
+            value,
+            cpu_id_reg_host(idx)
+        );
+        return Err(());
+    }
+    vm_cfg.set_cpu_id_reg(idx, mask, value);
+
+    println!(
+        "\nVM[{}] vm_cfg_set_cpu_id_reg: id reg {} mask {:x} value {:x}",
+        vmid, idx, mask, value
+    );
+
+    Ok(0)
+}
+
+/* Set the health policy of VM, wfi_timeout is in ms */
+pub fn vm_cfg_set_health(vmid: usize, policy: usize, keep_alive_miss: usize, wfi_timeout: usize) -> Result<usize, ()> {
+    let vm_cfg = match vm_cfg_entry(vmid) {
+        Some(vm_cfg) => vm_cfg,
+        None => return Err(()),
+    };
+    if policy > VmHealthPolicy::Shutdown as usize || keep_alive_miss == 0 {
+        println!(
+            "vm_cfg_set_health: VM[{}] illegal policy {} keep_alive_miss {}",
+            vmid, policy, keep_alive_miss
+        );
+        return Err(());
+    }
+
+    vm_cfg.set_health_cfg(VmHealthPolicy::from_usize(policy), keep_alive_miss, wfi_timeout);
+
+    println!(
+        "\nVM[{}] vm_cfg_set_health: policy {:?} keep_alive_miss {} wfi_timeout {}ms",
+        vmid,
+        vm_cfg.health_cfg().policy,
+        keep_alive_miss,
+        wfi_timeout
+    );
+    Ok(0)
+}
+
+/* Add a range of SMC function IDs with its policy to VM, an earlier range takes precedence */
+pub fn vm_cfg_add_smc_range(vmid: usize, start: usize, end: usize, policy: usize) -> Result<usize, ()> {
+    let vm_cfg = match vm_cfg_entry(vmid) {
+        Some(vm_cfg) => vm_cfg,
+        None => return Err(()),
+    };
+    if policy > VmSmcPolicy::Emulate as usize {
+        println!("vm_cfg_add_smc_range: VM[{}] illegal policy {}", vmid, policy);
+        return Err(());
+    }
+
+    let range = VmSmcRange {
+        start,
+        end,
+        policy: VmSmcPolicy::from_usize(policy),
+    };
+    if vm_cfg.add_smc_range(range).is_err() {
+        println!(
+            "vm_cfg_add_smc_range: VM[{}] illegal range 0x{:x}-0x{:x} policy {:?}",
+            vmid, start, end, range.policy
+        );
+        return Err(());
+    }
+
+    println!(
+        "\nVM[{}] vm_cfg_add_smc_range: fid 0x{:x}-0x{:x} policy {:?}",
+        vmid, start, end, range.policy
+    );
+    Ok(0)
+}
+
+/* Remove every SMC range of VM, all calls are emulated again */
+pub fn vm_cfg_clear_smc_range(vmid: usize) -> Result<usize, ()> {
+    let vm_cfg = match vm_cfg_entry(vmid) {
+        Some(vm_cfg) => vm_cfg,
+        None => return Err(()),
+    };
+    vm_cfg.clear_smc_range();
+    println!("\nVM[{}] vm_cfg_clear_smc_range", vmid);
+    Ok(0)
+}
+
+/*
+ * List the SMC ranges of VM in the order they are looked up, at most max of them are written to
+ * list_ipa of the MVM as (start, end, policy) usize triples. Returns the number of ranges.
+ */
+pub fn vm_cfg_list_smc_range(vmid: usize, list_ipa: usize, max: usize) -> Result<usize, ()> {
+    let vm_cfg = match vm_cfg_entry(vmid) {
+        Some(vm_cfg) => vm_cfg,
+        None => return Err(()),
+    };
+    let ranges = vm_cfg.smc_ranges();
+    let mut list = Vec::new();
+    for range in ranges.iter().take(max) {
+        list.push(range.start);
+        list.push(range.end);
+        list.push(range.policy as usize);
+    }
+
+    let mvm = active_vm().unwrap();
+    let src = list.as_ptr() as usize;
+    let size = list.len() * size_of::<usize>();
+    let mut offset = 0;
+    while offset < size {
+        let len = usize::min(PAGE_SIZE - (list_ipa + offset) % PAGE_SIZE, size - offset);
+        let pa = vm_ipa2pa(mvm.clone(), list_ipa + offset);
+        if pa == 0 {
+            println!("vm_cfg_list_smc_range: illegal ipa {:x}", list_ipa + offset);
+            return Err(());
+        }
+        memcpy_safe(pa as *const u8, (src + offset) as *const u8, len);
+        offset += len;
+    }
+    Ok(ranges.len())
+}
+
+// the time partitioning plan of cpu_id, major_frame 0 means no plan
+pub fn vm_cfg_sched_partition(cpu_id: usize) -> SchedPartitionConfig {
+    let vm_config = DEF_VM_CONFIG_TABLE.lock();
+    match vm_config.sched_partitions.get(&cpu_id) {
+        Some(partition) => partition.clone(),
+        None => SchedPartitionConfig {
+            major_frame: 0,
+            windows: Vec::new(),
+            spare_to_mvm: false,
+        },
+    }
+}
+
+/*
+ * Set the major frame of a physical cpu in us, this clears all windows of the cpu.
+ * With spare_to_mvm set, the MVM runs in the spare time of the plan, otherwise the cpu idles.
+ */
+pub fn vm_cfg_set_sched_major_frame(cpu_id: usize, major_frame: usize, spare_to_mvm: usize) -> Result<usize, ()> {
+    if cpu_id >= PLATFORM_CPU_NUM_MAX {
+        println!("vm_cfg_set_sched_major_frame: illegal cpu id {}", cpu_id);
+        return Err(());
+    }
+    let mut vm_config = DEF_VM_CONFIG_TABLE.lock();
+    if major_frame == 0 {
+        vm_config.sched_partitions.remove(&cpu_id);
+    } else {
+        vm_config.sched_partitions.insert(
+            cpu_id,
+            SchedPartitionConfig {
+                major_frame,
+                windows: Vec::new(),
+                spare_to_mvm: spare_to_mvm != 0,
+            },
+        );
+    }
+    println!(
+        "\nCore[{}] vm_cfg_set_sched_major_frame: major frame {}us, spare time to MVM {}",
+        cpu_id,
+        major_frame,
+        spare_to_mvm != 0
+    );
+    Ok(0)
+}
+
+/* Add a window for VM on a physical cpu, windows must fit in the major frame and must not overlap */
+pub fn vm_cfg_add_sched_window(cpu_id: usize, vm_id: usize, offset: usize, length: usize) -> Result<usize, ()> {
+    let mut vm_config = DEF_VM_CONFIG_TABLE.lock();
+    let partition = match vm_config.sched_partitions.get_mut(&cpu_id) {
+        Some(partition) => partition,
+        None => {
+            println!("vm_cfg_add_sched_window: Core[{}] has no major frame", cpu_id);
+            return Err(());
+        }
+    };
+    let end = match offset.checked_add(length) {
+        Some(end) if length != 0 && end <= partition.major_frame => end,
+        _ => {
+            println!(
+                "vm_cfg_add_sched_window: window at {} length {} is out of major frame {}",
+                offset, length, partition.major_frame
+            );
+            return Err(());
+        }
+    };
+    for window in partition.windows.iter() {
+        if offset < window.offset + window.length && window.offset < end {
+            println!(
+                "vm_cfg_add_sched_window: window [{}, {}) overlaps VM[{}] window [{}, {})",
+                offset,
+                end,
+                window.vm_id,
+                window.offset,
+                window.offset + window.length
+            );
+            return Err(());
+        }
+    }
+    let idx = partition
+        .windows
+        .iter()
+        .position(|x| x.offset > offset)
+        .unwrap_or(partition.windows.len());
+    partition
+        .windows
+        .insert(idx, SchedWindowConfig { vm_id, offset, length });
+    println!(
+        "\nCore[{}] vm_cfg_add_sched_window: VM[{}] window [{}, {})",
+        cpu_id, vm_id, offset, end
+    );
+    Ok(0)
+}
+
 /* Add emulated device config for VM */
 pub fn vm_cfg_add_emu_dev(
     vmid: usize,
@@ -761,7 +1322,7 @@ pub fn vm_cfg_add_emu_dev(
         emu_type
     );
 
-    let emu_dev_type = EmuDeviceType::from_usize(emu_type);
+    let emu_dev_type = EmuDeviceType::from_usize(emu_type)?;
     let emu_dev_cfg = VmEmulatedDeviceConfig {
         name: Some(name_str.trim_end_matches(char::from(0)).to_string()),
         base_ipa,
@@ -772,7 +1333,7 @@ pub fn vm_cfg_add_emu_dev(
             EmuDeviceType::EmuDeviceTVirtioBlkMediated => EmuDeviceType::EmuDeviceTVirtioBlk,
             _ => emu_dev_type.clone(),
         },
-        mediated: match EmuDeviceType::from_usize(emu_type) {
+        mediated: match emu_dev_type {
             EmuDeviceType::EmuDeviceTVirtioBlkMediated => true,
             _ => false,
         },
@@ -962,7 +1523,7 @@ pub fn vm_cfg_add_dtb_dev(
     // Get DTB device config list.
     let vm_dtb_dev = VmDtbDevConfig {
         name: dtb_dev_name_str.trim_end_matches(char::from(0)).to_string(),
-        dev_type: DtbDevType::from_usize(dev_type),
+        dev_type: DtbDevType::from_usize(dev_type)?,
         irqs: dtb_irq_list,
         addr_region: AddrRegions {
             ipa: addr_region_ipa,
@@ -1065,3 +1626,618 @@ pub fn vm_cfg_upload_kernel_image(
     dst.copy_from_slice(src);
     Ok(0)
 }
+
+/*
+ * Layout of the VM config shared with MVM by HVC_VMM_GET_VM_DEF_CFG/GET_VM_CFG/SET_VM_CFG.
+ * `version` and `size` are checked on SET_VM_CFG, bump VM_CFG_SHARE_VERSION when the layout changes.
+ */
+pub const VM_CFG_SHARE_VERSION: usize = 4;
+const CMDLINE_MAX_LEN: usize = 256;
+const MEM_REGION_MAX_NUM: usize = 16;
+const STREAMS_ID_MAX_NUM: usize = 0x40;
+const DTB_DEV_MAX_NUM: usize = 16;
+const DTB_DEV_IRQ_MAX_NUM: usize = 16;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VmCfgShareImage {
+    kernel_load_ipa: usize,
+    kernel_load_pa: usize,
+    kernel_entry_point: usize,
+    device_tree_load_ipa: usize,
+    ramdisk_load_ipa: usize,
+    // 0 means no mediated block
+    mediated_block: usize,
+    mediated_block_index: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VmCfgShareEmuDev {
+    name: [u8; NAME_MAX_LEN],
+    base_ipa: usize,
+    length: usize,
+    irq_id: usize,
+    cfg_num: usize,
+    cfg_list: [usize; CFG_MAX_NUM],
+    emu_type: usize,
+    mediated: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VmCfgSharePtRegion {
+    ipa: usize,
+    pa: usize,
+    length: usize,
+    dev_property: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VmCfgShareDtbDev {
+    name: [u8; NAME_MAX_LEN],
+    dev_type: usize,
+    irq_num: usize,
+    irqs: [usize; DTB_DEV_IRQ_MAX_NUM],
+    ipa: usize,
+    length: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VmCfgShareSmcRange {
+    start: usize,
+    end: usize,
+    policy: usize,
+}
+
+#[repr(C)]
+struct VmCfgShare {
+    version: usize,
+    size: usize,
+    // id, name, os_type and cmdline are read only
+    id: usize,
+    name: [u8; NAME_MAX_LEN],
+    os_type: usize,
+    cmdline: [u8; CMDLINE_MAX_LEN],
+    cpu_num: usize,
+    cpu_allocate_bitmap: usize,
+    cpu_master: usize,
+    cpu_budget: usize,
+    cpu_period: usize,
+    cpu_priority: usize,
+    cpu_id_regs: [VmCpuIdReg; CPU_ID_REG_NUM],
+    image: VmCfgShareImage,
+    mem_region_num: usize,
+    mem_regions: [VmRegion; MEM_REGION_MAX_NUM],
+    emu_dev_num: usize,
+    emu_devs: [VmCfgShareEmuDev; EMULATED_DEV_MAX_NUM],
+    pt_region_num: usize,
+    pt_regions: [VmCfgSharePtRegion; PASSTHROUGH_DEV_MAX_NUM],
+    pt_irq_num: usize,
+    pt_irqs: [usize; IRQ_MAX_NUM],
+    pt_streams_id_num: usize,
+    pt_streams_ids: [usize; STREAMS_ID_MAX_NUM],
+    dtb_dev_num: usize,
+    dtb_devs: [VmCfgShareDtbDev; DTB_DEV_MAX_NUM],
+    health_policy: usize,
+    health_keep_alive_miss: usize,
+    health_wfi_timeout: usize,
+    smc_range_num: usize,
+    smc_ranges: [VmCfgShareSmcRange; SMC_RANGE_MAX_NUM],
+}
+
+fn vm_cfg_share_mem(cfg_ipa: usize) -> Option<&'static mut VmCfgShare> {
+    // the struct takes more than a page, it has to lie in one memory region of the MVM
+    let mvm = active_vm().unwrap();
+    let cfg_pa = vm_ipa2pa(mvm.clone(), cfg_ipa);
+    let last = size_of::<VmCfgShare>() - 1;
+    if cfg_pa == 0 || cfg_ipa % align_of::<VmCfgShare>() != 0 || vm_ipa2pa(mvm, cfg_ipa + last) != cfg_pa + last {
+        println!("illegal vm cfg ipa {:x}", cfg_ipa);
+        return None;
+    }
+    Some(unsafe { &mut *(cfg_pa as *mut VmCfgShare) })
+}
+
+// copy str into a '\0' terminated buffer, truncate it if too long
+fn vm_cfg_str_to_buf(src: &str, buf: &mut [u8]) {
+    let len = usize::min(src.len(), buf.len() - 1);
+    buf[..len].copy_from_slice(&src.as_bytes()[..len]);
+    buf[len..].fill(0);
+}
+
+fn vm_cfg_buf_to_string(buf: &[u8]) -> String {
+    let len = buf.iter().position(|x| *x == 0).unwrap_or(buf.len());
+    match String::from_utf8(buf[..len].to_vec()) {
+        Ok(str) => str,
+        Err(error) => {
+            println!("error: {:?} in parsing the name {:?}", error, &buf[..len]);
+            String::from("unknown")
+        }
+    }
+}
+
+fn vm_cfg_dump(vm_cfg: &VmConfigEntry, share: &mut VmCfgShare) -> Result<usize, ()> {
+    let mem_regions = vm_cfg.memory_region();
+    let emu_devs = vm_cfg.emulated_device_list();
+    let pt_regions = vm_cfg.passthrough_device_regions();
+    let pt_irqs = vm_cfg.passthrough_device_irqs();
+    let pt_streams_ids = vm_cfg.passthrough_device_stread_ids();
+    let dtb_devs = vm_cfg.dtb_device_list();
+    if mem_regions.len() > MEM_REGION_MAX_NUM
+        || emu_devs.len() > EMULATED_DEV_MAX_NUM
+        || pt_regions.len() > PASSTHROUGH_DEV_MAX_NUM
+        || pt_irqs.len() > IRQ_MAX_NUM
+        || pt_streams_ids.len() > STREAMS_ID_MAX_NUM
+        || dtb_devs.len() > DTB_DEV_MAX_NUM
+    {
+        println!("vm_cfg_dump: VM[{}] config does not fit in the share mem", vm_cfg.id());
+        return Err(());
+    }
+
+    share.version = VM_CFG_SHARE_VERSION;
+    share.size = size_of::<VmCfgShare>();
+    share.id = vm_cfg.id();
+    vm_cfg_str_to_buf(&vm_cfg.vm_name(), &mut share.name);
+    share.os_type = vm_cfg.os_type as usize;
+    vm_cfg_str_to_buf(&vm_cfg.cmdline, &mut share.cmdline);
+
+    {
+        let cpu_cfg = vm_cfg.cpu.lock();
+        share.cpu_num = cpu_cfg.num;
+        share.cpu_allocate_bitmap = cpu_cfg.allocate_bitmap as usize;
+        share.cpu_master = cpu_cfg.master as usize;
+        share.cpu_budget = cpu_cfg.budget;
+        share.cpu_period = cpu_cfg.period;
+        share.cpu_priority = cpu_cfg.priority;
+        share.cpu_id_regs = cpu_cfg.id_regs;
+    }
+    {
+        let img_cfg = vm_cfg.image.lock();
+        share.image = VmCfgShareImage {
+            kernel_load_ipa: img_cfg.kernel_load_ipa,
+            kernel_load_pa: img_cfg.kernel_load_pa,
+            kernel_entry_point: img_cfg.kernel_entry_point,
+            device_tree_load_ipa: img_cfg.device_tree_load_ipa,
+            ramdisk_load_ipa: img_cfg.ramdisk_load_ipa,
+            mediated_block: img_cfg.mediated_block_index.is_some() as usize,
+            mediated_block_index: img_cfg.mediated_block_index.unwrap_or(0),
+        };
+    }
+
+    share.mem_region_num = mem_regions.len();
+    share.mem_regions[..mem_regions.len()].copy_from_slice(&mem_regions);
+
+    share.emu_dev_num = emu_devs.len();
+    for (idx, emu_dev) in emu_devs.iter().enumerate() {
+        let share_dev = &mut share.emu_devs[idx];
+        vm_cfg_str_to_buf(emu_dev.name.as_deref().unwrap_or(""), &mut share_dev.name);
+        share_dev.base_ipa = emu_dev.base_ipa;
+        share_dev.length = emu_dev.length;
+        share_dev.irq_id = emu_dev.irq_id;
+        share_dev.cfg_num = usize::min(emu_dev.cfg_list.len(), CFG_MAX_NUM);
+        share_dev.cfg_list.fill(0);
+        share_dev.cfg_list[..share_dev.cfg_num].copy_from_slice(&emu_dev.cfg_list[..share_dev.cfg_num]);
+        // a mediated virtio blk is added as EmuDeviceTVirtioBlkMediated, see vm_cfg_add_emu_dev
+        share_dev.emu_type = if emu_dev.mediated {
+            EmuDeviceType::EmuDeviceTVirtioBlkMediated as usize
+        } else {
+            emu_dev.emu_type as usize
+        };
+        share_dev.mediated = emu_dev.mediated as usize;
+    }
+
+    share.pt_region_num = pt_regions.len();
+    for (idx, region) in pt_regions.iter().enumerate() {
+        share.pt_regions[idx] = VmCfgSharePtRegion {
+            ipa: region.ipa,
+            pa: region.pa,
+            length: region.length,
+            dev_property: region.dev_property as usize,
+        };
+    }
+    share.pt_irq_num = pt_irqs.len();
+    share.pt_irqs[..pt_irqs.len()].copy_from_slice(&pt_irqs);
+    share.pt_streams_id_num = pt_streams_ids.len();
+    share.pt_streams_ids[..pt_streams_ids.len()].copy_from_slice(&pt_streams_ids);
+
+    share.dtb_dev_num = dtb_devs.len();
+    for (idx, dtb_dev) in dtb_devs.iter().enumerate() {
+        let share_dev = &mut share.dtb_devs[idx];
+        vm_cfg_str_to_buf(&dtb_dev.name, &mut share_dev.name);
+        share_dev.dev_type = dtb_dev.dev_type as usize;
+        share_dev.irq_num = usize::min(dtb_dev.irqs.len(), DTB_DEV_IRQ_MAX_NUM);
+        share_dev.irqs.fill(0);
+        share_dev.irqs[..share_dev.irq_num].copy_from_slice(&dtb_dev.irqs[..share_dev.irq_num]);
+        share_dev.ipa = dtb_dev.addr_region.ipa;
+        share_dev.length = dtb_dev.addr_region.length;
+    }
+
+    let health_cfg = vm_cfg.health_cfg();
+    share.health_policy = health_cfg.policy as usize;
+    share.health_keep_alive_miss = health_cfg.keep_alive_miss;
+    share.health_wfi_timeout = health_cfg.wfi_timeout;
+
+    let smc_ranges = vm_cfg.smc_ranges();
+    share.smc_range_num = smc_ranges.len();
+    for (idx, range) in smc_ranges.iter().enumerate() {
+        share.smc_ranges[idx] = VmCfgShareSmcRange {
+            start: range.start,
+            end: range.end,
+            policy: range.policy as usize,
+        };
+    }
+    Ok(0)
+}
+
+/* Dump the config a new VM starts with, MVM could use it as a template */
+pub fn vm_cfg_get_def_cfg(cfg_ipa: usize) -> Result<usize, ()> {
+    let share = match vm_cfg_share_mem(cfg_ipa) {
+        Some(share) => share,
+        None => return Err(()),
+    };
+    vm_cfg_dump(&VmConfigEntry::default(), share)
+}
+
+/* Dump the config of VM[vmid] */
+pub fn vm_cfg_get_cfg(vmid: usize, cfg_ipa: usize) -> Result<usize, ()> {
+    let vm_cfg = match vm_cfg_entry(vmid) {
+        Some(vm_cfg) => vm_cfg,
+        None => return Err(()),
+    };
+    let share = match vm_cfg_share_mem(cfg_ipa) {
+        Some(share) => share,
+        None => return Err(()),
+    };
+    vm_cfg_dump(&vm_cfg, share)
+}
+
+/*
+ * Update the config of a stopped VM in place.
+ * Once the VM is set up, its memory, cpus and devices are allocated, only the scheduling
+ * parameters, the image info and the health policy could be changed.
+ */
+pub fn vm_cfg_set_cfg(vmid: usize, cfg_ipa: usize) -> Result<usize, ()> {
+    if vmid == 0 {
+        println!("vm_cfg_set_cfg: MVM config can not be modified");
+        return Err(());
+    }
+    let mut vm_cfg = match vm_cfg_entry(vmid) {
+        Some(vm_cfg) => vm_cfg,
+        None => return Err(()),
+    };
+    match vm_if_get_state(vmid) {
+        VmState::VmActive | VmState::VmPaused => {
+            println!("vm_cfg_set_cfg: VM[{}] is running, stop it first", vmid);
+            return Err(());
+        }
+        _ => {}
+    }
+    let share = match vm_cfg_share_mem(cfg_ipa) {
+        Some(share) => share,
+        None => return Err(()),
+    };
+    if share.version != VM_CFG_SHARE_VERSION || share.size != size_of::<VmCfgShare>() {
+        println!(
+            "vm_cfg_set_cfg: unsupported vm cfg version {} size {}, expect version {} size {}",
+            share.version,
+            share.size,
+            VM_CFG_SHARE_VERSION,
+            size_of::<VmCfgShare>()
+        );
+        return Err(());
+    }
+    if share.mem_region_num > MEM_REGION_MAX_NUM
+        || share.emu_dev_num > EMULATED_DEV_MAX_NUM
+        || share.pt_region_num > PASSTHROUGH_DEV_MAX_NUM
+        || share.pt_irq_num > IRQ_MAX_NUM
+        || share.pt_streams_id_num > STREAMS_ID_MAX_NUM
+        || share.dtb_dev_num > DTB_DEV_MAX_NUM
+        || share.smc_range_num > SMC_RANGE_MAX_NUM
+    {
+        println!("vm_cfg_set_cfg: VM[{}] illegal device num", vmid);
+        return Err(());
+    }
+    if share.health_policy > VmHealthPolicy::Shutdown as usize || share.health_keep_alive_miss == 0 {
+        println!(
+            "vm_cfg_set_cfg: VM[{}] illegal health policy {} keep_alive_miss {}",
+            vmid, share.health_policy, share.health_keep_alive_miss
+        );
+        return Err(());
+    }
+    if share.cpu_budget > share.cpu_period {
+        println!(
+            "vm_cfg_set_cfg: VM[{}] budget {} is larger than period {}",
+            vmid, share.cpu_budget, share.cpu_period
+        );
+        return Err(());
+    }
+    for (idx, id_reg) in share.cpu_id_regs.iter().enumerate() {
+        if let Err(field) = vm_cfg_cpu_id_reg_check(idx, id_reg.mask, id_reg.value) {
+            println!(
+                "vm_cfg_set_cfg: VM[{}] id reg {} field {} mask {:x} value {:x}, host {:x}",
+                vmid,
+                idx,
+                field,
+                id_reg.mask,
+                id_reg.value,
+                cpu_id_reg_host(idx)
+            );
+            return Err(());
+        }
+    }
+    let mem_regions = share.mem_regions[..share.mem_region_num].to_vec();
+    let mut emu_dev_list = Vec::new();
+    let mut mediated = false;
+    for share_dev in share.emu_devs[..share.emu_dev_num].iter() {
+        let emu_dev_type = EmuDeviceType::from_usize(share_dev.emu_type)?;
+        let is_mediated = emu_dev_type == EmuDeviceType::EmuDeviceTVirtioBlkMediated;
+        mediated |= is_mediated;
+        emu_dev_list.push(VmEmulatedDeviceConfig {
+            name: Some(vm_cfg_buf_to_string(&share_dev.name)),
+            base_ipa: share_dev.base_ipa,
+            length: share_dev.length,
+            irq_id: share_dev.irq_id,
+            cfg_list: share_dev.cfg_list[..usize::min(share_dev.cfg_num, CFG_MAX_NUM)].to_vec(),
+            emu_type: if is_mediated {
+                EmuDeviceType::EmuDeviceTVirtioBlk
+            } else {
+                emu_dev_type
+            },
+            mediated: is_mediated,
+        });
+    }
+    let mut dtb_dev_list = Vec::new();
+    for share_dev in share.dtb_devs[..share.dtb_dev_num].iter() {
+        dtb_dev_list.push(VmDtbDevConfig {
+            name: vm_cfg_buf_to_string(&share_dev.name),
+            dev_type: DtbDevType::from_usize(share_dev.dev_type)?,
+            irqs: share_dev.irqs[..usize::min(share_dev.irq_num, DTB_DEV_IRQ_MAX_NUM)].to_vec(),
+            addr_region: AddrRegions {
+                ipa: share_dev.ipa,
+                length: share_dev.length,
+            },
+        });
+    }
+
+    let mut pt_regions = Vec::new();
+    for region in share.pt_regions[..share.pt_region_num].iter() {
+        pt_regions.push(PassthroughRegion {
+            ipa: region.ipa,
+            pa: region.pa,
+            length: region.length,
+            dev_property: region.dev_property != 0,
+        });
+    }
+    let pt_irqs = share.pt_irqs[..share.pt_irq_num].to_vec();
+    let pt_streams_ids = share.pt_streams_ids[..share.pt_streams_id_num].to_vec();
+
+    // checked as vm_cfg_add_smc_range does, one by one in the order they are looked up
+    let mut smc_cfg = VmSmcConfig::default();
+    for range in share.smc_ranges[..share.smc_range_num].iter() {
+        if range.policy > VmSmcPolicy::Emulate as usize
+            || smc_cfg
+                .add(VmSmcRange {
+                    start: range.start,
+                    end: range.end,
+                    policy: VmSmcPolicy::from_usize(range.policy),
+                })
+                .is_err()
+        {
+            println!(
+                "vm_cfg_set_cfg: VM[{}] illegal smc range 0x{:x}-0x{:x} policy {}",
+                vmid, range.start, range.end, range.policy
+            );
+            return Err(());
+        }
+    }
+
+    if vm(vmid).is_some() {
+        let emu_dev_eq = |x: &VmEmulatedDeviceConfig, y: &VmEmulatedDeviceConfig| {
+            x.base_ipa == y.base_ipa && x.length == y.length && x.emu_type == y.emu_type && x.mediated == y.mediated
+        };
+        let dtb_dev_eq = |x: &VmDtbDevConfig, y: &VmDtbDevConfig| {
+            x.dev_type == y.dev_type && x.addr_region.ipa == y.addr_region.ipa && x.irqs == y.irqs
+        };
+        let old_emu_devs = vm_cfg.emulated_device_list();
+        let old_dtb_devs = vm_cfg.dtb_device_list();
+        if mem_regions != vm_cfg.memory_region()
+            || share.cpu_num != vm_cfg.cpu_num()
+            || share.cpu_allocate_bitmap as u32 != vm_cfg.cpu_allocated_bitmap()
+            || share.cpu_master != vm_cfg.cpu_master()
+            || emu_dev_list.len() != old_emu_devs.len()
+            || !emu_dev_list
+                .iter()
+                .zip(old_emu_devs.iter())
+                .all(|(x, y)| emu_dev_eq(x, y))
+            || dtb_dev_list.len() != old_dtb_devs.len()
+            || !dtb_dev_list
+                .iter()
+                .zip(old_dtb_devs.iter())
+                .all(|(x, y)| dtb_dev_eq(x, y))
+            || pt_regions != vm_cfg.passthrough_device_regions()
+            || pt_irqs != vm_cfg.passthrough_device_irqs()
+            || pt_streams_ids != vm_cfg.passthrough_device_stread_ids()
+        {
+            println!(
+                "vm_cfg_set_cfg: VM[{}] is set up, its memory, cpus and devices can not be changed",
+                vmid
+            );
+            return Err(());
+        }
+    }
+
+    // Keep the mediated block if VM still uses one.
+    match (vm_cfg.mediated_block_index(), mediated) {
+        (None, true) => match mediated_blk_request() {
+            Ok(idx) => vm_cfg.set_mediated_block_index(idx),
+            Err(_) => {
+                println!("no more medaited blk for vm {}", vmid);
+                return Err(());
+            }
+        },
+        (Some(idx), false) => {
+            mediated_blk_free(idx);
+            vm_cfg.image.lock().mediated_block_index = None;
+        }
+        _ => {}
+    }
+
+    {
+        let mut cpu_cfg = vm_cfg.cpu.lock();
+        cpu_cfg.num = usize::min(share.cpu_num, share.cpu_allocate_bitmap.count_ones() as usize);
+        cpu_cfg.allocate_bitmap = share.cpu_allocate_bitmap as u32;
+        cpu_cfg.master = share.cpu_master as i32;
+        cpu_cfg.budget = share.cpu_budget;
+        cpu_cfg.period = share.cpu_period;
+        cpu_cfg.priority = share.cpu_priority;
+        cpu_cfg.id_regs = share.cpu_id_regs;
+    }
+    {
+        let mut img_cfg = vm_cfg.image.lock();
+        img_cfg.kernel_load_ipa = share.image.kernel_load_ipa;
+        img_cfg.kernel_entry_point = share.image.kernel_entry_point;
+        img_cfg.device_tree_load_ipa = share.image.device_tree_load_ipa;
+        img_cfg.ramdisk_load_ipa = share.image.ramdisk_load_ipa;
+    }
+    vm_cfg.memory.lock().region = mem_regions;
+    vm_cfg.vm_emu_dev_confg.lock().emu_dev_list = emu_dev_list;
+    {
+        let mut pt_dev_cfg = vm_cfg.vm_pt_dev_confg.lock();
+        pt_dev_cfg.regions = pt_regions;
+        pt_dev_cfg.irqs = pt_irqs;
+        pt_dev_cfg.streams_ids = pt_streams_ids;
+    }
+    vm_cfg.vm_dtb_devs.lock().dtb_device_list = dtb_dev_list;
+    vm_cfg.set_health_cfg(
+        VmHealthPolicy::from_usize(share.health_policy),
+        share.health_keep_alive_miss,
+        share.health_wfi_timeout,
+    );
+    *vm_cfg.smc.lock() = smc_cfg;
+
+    println!(
+        "\nVM[{}] vm_cfg_set_cfg: {} memory regions, {} emulated devices, {} passthrough regions, {} dtb devices",
+        vmid, share.mem_region_num, share.emu_dev_num, share.pt_region_num, share.dtb_dev_num
+    );
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn smc_range(start: usize, end: usize, policy: VmSmcPolicy) -> VmSmcRange {
+        VmSmcRange { start, end, policy }
+    }
+
+    fn smc_cfg(ranges: &[(usize, usize, VmSmcPolicy)]) -> VmSmcConfig {
+        let mut smc_cfg = VmSmcConfig::default();
+        for (start, end, policy) in ranges {
+            smc_cfg.add(smc_range(*start, *end, *policy)).unwrap();
+        }
+        smc_cfg
+    }
+
+    #[test]
+    fn smc_policy_default() {
+        let smc_cfg = smc_cfg(&[]);
+        assert_eq!(smc_cfg.policy(0x8400_0000), VmSmcPolicy::Emulate);
+    }
+
+    #[test]
+    fn smc_policy_bounds() {
+        let smc_cfg = smc_cfg(&[(0xc200_0000, 0xc200_ffff, VmSmcPolicy::Forward)]);
+        assert_eq!(smc_cfg.policy(0xc1ff_ffff), VmSmcPolicy::Emulate);
+        assert_eq!(smc_cfg.policy(0xc200_0000), VmSmcPolicy::Forward);
+        assert_eq!(smc_cfg.policy(0xc200_ffff), VmSmcPolicy::Forward);
+        assert_eq!(smc_cfg.policy(0xc201_0000), VmSmcPolicy::Emulate);
+    }
+
+    #[test]
+    fn smc_policy_overlap() {
+        // an earlier range takes precedence over the later ones covering the same fid
+        let smc_cfg = smc_cfg(&[
+            (0xc200_0100, 0xc200_01ff, VmSmcPolicy::Deny),
+            (0xc200_0000, 0xc200_ffff, VmSmcPolicy::Forward),
+            (0xc200_0000, 0xc2ff_ffff, VmSmcPolicy::Deny),
+        ]);
+        assert_eq!(smc_cfg.policy(0xc200_0000), VmSmcPolicy::Forward);
+        assert_eq!(smc_cfg.policy(0xc200_0100), VmSmcPolicy::Deny);
+        assert_eq!(smc_cfg.policy(0xc200_01ff), VmSmcPolicy::Deny);
+        assert_eq!(smc_cfg.policy(0xc200_0200), VmSmcPolicy::Forward);
+        assert_eq!(smc_cfg.policy(0xc201_0000), VmSmcPolicy::Deny);
+        assert_eq!(smc_cfg.policy(0xc300_0000), VmSmcPolicy::Emulate);
+    }
+
+    #[test]
+    fn smc_policy_fid_width() {
+        let smc_cfg = smc_cfg(&[(0xc200_0000, 0xc200_ffff, VmSmcPolicy::Forward)]);
+        assert_eq!(smc_cfg.policy(0x1_c200_0000), VmSmcPolicy::Forward);
+        assert_eq!(smc_cfg.policy(0x1_8400_0000), VmSmcPolicy::Emulate);
+    }
+
+    #[test]
+    fn smc_policy_clear() {
+        let vm_cfg = VmConfigEntry::default();
+        vm_cfg
+            .add_smc_range(smc_range(0, 0xffff_ffff, VmSmcPolicy::Deny))
+            .unwrap();
+        vm_cfg
+            .add_smc_range(smc_range(0xc200_0000, 0xc200_ffff, VmSmcPolicy::Forward))
+            .unwrap();
+        assert_eq!(vm_cfg.smc_policy(0x8400_0000), VmSmcPolicy::Deny);
+        assert_eq!(vm_cfg.smc_policy(0xc200_0000), VmSmcPolicy::Deny);
+        vm_cfg.clear_smc_range();
+        assert!(vm_cfg.smc_ranges().is_empty());
+        assert_eq!(vm_cfg.smc_policy(0x8400_0000), VmSmcPolicy::Emulate);
+        assert_eq!(vm_cfg.smc_policy(0xc200_0000), VmSmcPolicy::Emulate);
+        // ranges added after a clear are looked up again
+        vm_cfg
+            .add_smc_range(smc_range(0xc200_0000, 0xc200_ffff, VmSmcPolicy::Forward))
+            .unwrap();
+        assert_eq!(vm_cfg.smc_policy(0xc200_0000), VmSmcPolicy::Forward);
+    }
+
+    #[test]
+    fn smc_range_forward_arch() {
+        let mut smc_cfg = VmSmcConfig::default();
+        // PSCI and the SMCCC architecture calls stay with the hypervisor
+        assert!(smc_cfg
+            .add(smc_range(0x8400_0000, 0x8400_0000, VmSmcPolicy::Forward))
+            .is_err());
+        assert!(smc_cfg
+            .add(smc_range(0xc400_001f, 0xc400_0020, VmSmcPolicy::Forward))
+            .is_err());
+        assert!(smc_cfg
+            .add(smc_range(0x8000_8000, 0x8000_8000, VmSmcPolicy::Forward))
+            .is_err());
+        assert!(smc_cfg.add(smc_range(0, 0xffff_ffff, VmSmcPolicy::Forward)).is_err());
+        assert!(smc_cfg.add(smc_range(0, 0x1_0000_0000, VmSmcPolicy::Deny)).is_err());
+        assert!(smc_cfg.add(smc_range(2, 1, VmSmcPolicy::Deny)).is_err());
+        assert!(smc_cfg.ranges.is_empty());
+        // the other services of the standard secure owner may be forwarded
+        assert!(smc_cfg
+            .add(smc_range(0x8400_0020, 0x8400_ffff, VmSmcPolicy::Forward))
+            .is_ok());
+        // PSCI may be denied
+        assert!(smc_cfg
+            .add(smc_range(0x8400_0000, 0x8400_001f, VmSmcPolicy::Deny))
+            .is_ok());
+        assert_eq!(smc_cfg.policy(0x8400_0020), VmSmcPolicy::Forward);
+        assert_eq!(smc_cfg.policy(0x8400_0000), VmSmcPolicy::Deny);
+    }
+
+    #[test]
+    fn smc_range_max_num() {
+        let mut smc_cfg = VmSmcConfig::default();
+        for fid in 0..SMC_RANGE_MAX_NUM {
+            smc_cfg.add(smc_range(fid, fid, VmSmcPolicy::Deny)).unwrap();
+        }
+        // the ranges have to fit in VmCfgShare
+        assert!(smc_cfg.add(smc_range(0x100, 0x100, VmSmcPolicy::Deny)).is_err());
+        smc_cfg.clear();
+        assert!(smc_cfg.add(smc_range(0x100, 0x100, VmSmcPolicy::Deny)).is_ok());
+    }
+}
@@ -21,7 +21,8 @@ use crate::kernel::VmType;
 
 use super::{
     PassthroughRegion, vm_cfg_set_config_name, VmConfigEntry, VmCpuConfig, VMDtbDevConfigList, VmEmulatedDeviceConfig,
-    VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig, VmRegion,
+    VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig, VmRegion, VmHealthConfig,
+    VmSmcConfig, VmCpuIdReg, CPU_ID_REG_NUM,
 };
 
 #[rustfmt::skip]
@@ -193,10 +194,16 @@ pub fn mvm_config_init() {
             num: 1,
             allocate_bitmap: 0b0001,
             master: 0,
+            budget: 0,
+            period: 0,
+            priority: 0,
+            id_regs: [VmCpuIdReg::default(); CPU_ID_REG_NUM],
         })),
         vm_emu_dev_confg: Arc::new(Mutex::new(VmEmulatedDeviceConfigList { emu_dev_list: emu_dev_config })),
         vm_pt_dev_confg: Arc::new(Mutex::new(pt_dev_config)),
         vm_dtb_devs: Arc::new(Mutex::new(VMDtbDevConfigList::default())),
+        health: Arc::new(Mutex::new(VmHealthConfig::default())),
+        smc: Arc::new(Mutex::new(VmSmcConfig::default())),
     };
     let _ = vm_cfg_add_vm_entry(mvm_config_entry);
 }
@@ -21,7 +21,7 @@ use crate::kernel::{HVC_IRQ, VmType};
 use super::{
     VmConfigEntry, VmCpuConfig, VmEmulatedDeviceConfig, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig,
     VmRegion, vm_cfg_set_config_name, PassthroughRegion, vm_cfg_add_vm_entry, VmEmulatedDeviceConfigList,
-    VMDtbDevConfigList,
+    VMDtbDevConfigList, VmHealthConfig, VmSmcConfig, VmCpuIdReg, CPU_ID_REG_NUM,
 };
 
 #[rustfmt::skip]
@@ -29,11 +29,16 @@ pub fn mvm_config_init() {
     vm_cfg_set_config_name("qemu-default");
 
     // vm0 emu
-    let emu_dev_config = vec![
+    #[cfg(not(feature = "gicv3"))]
+    let vgicd_length = 0x1000;
+    #[cfg(feature = "gicv3")]
+    let vgicd_length = 0x10000;
+    #[allow(unused_mut)]
+    let mut emu_dev_config = vec![
         VmEmulatedDeviceConfig {
             name: Some(String::from("vgicd")),
             base_ipa: Platform::GICD_BASE,
-            length: 0x1000,
+            length: vgicd_length,
             irq_id: 0,
             cfg_list: Vec::new(),
             emu_type: EmuDeviceType::EmuDeviceTGicd,
@@ -67,15 +72,29 @@ pub fn mvm_config_init() {
             mediated: false,
         }
     ];
+    #[cfg(feature = "gicv3")]
+    emu_dev_config.push(VmEmulatedDeviceConfig {
+        name: Some(String::from("vgicr")),
+        base_ipa: Platform::GICR_BASE,
+        length: crate::arch::GICR_STRIDE * PLAT_DESC.cpu_desc.num,
+        irq_id: 0,
+        cfg_list: Vec::new(),
+        emu_type: EmuDeviceType::EmuDeviceTGicr,
+        mediated: false,
+    });
 
     // vm0 passthrough
     let mut pt_dev_config: VmPassthroughDeviceConfig = VmPassthroughDeviceConfig::default();
     pt_dev_config.regions = vec![
         PassthroughRegion { ipa: Platform::UART_0_ADDR, pa: Platform::UART_0_ADDR, length: 0x1000, dev_property: true },
-        PassthroughRegion { ipa: Platform::GICC_BASE, pa: Platform::GICV_BASE, length: 0x2000, dev_property: true },
         // pass-througn virtio blk/net
         PassthroughRegion { ipa: 0x0a003000, pa: 0x0a003000, length: 0x1000, dev_property: true },
     ];
+    // GICv3 guests use the system register cpu interface, there is no GICV to map
+    #[cfg(not(feature = "gicv3"))]
+    pt_dev_config.regions.push(
+        PassthroughRegion { ipa: Platform::GICC_BASE, pa: Platform::GICV_BASE, length: 0x2000, dev_property: true },
+    );
     pt_dev_config.irqs = vec![33, 27, 32 + 0x28, 32 + 0x29];
     pt_dev_config.streams_ids = vec![];
     // pt_dev_config.push(VmPassthroughDeviceConfig {
@@ -132,6 +151,10 @@ pub fn mvm_config_init() {
             num: 4,
             allocate_bitmap: 0b1111,
             master: -1,
+            budget: 0,
+            period: 0,
+            priority: 0,
+            id_regs: [VmCpuIdReg::default(); CPU_ID_REG_NUM],
         })),
         memory: Arc::new(Mutex::new(VmMemoryConfig {
             region: vm_region,
@@ -139,6 +162,8 @@ pub fn mvm_config_init() {
         vm_emu_dev_confg: Arc::new(Mutex::new(VmEmulatedDeviceConfigList { emu_dev_list: emu_dev_config })),
         vm_pt_dev_confg: Arc::new(Mutex::new(pt_dev_config)),
         vm_dtb_devs: Arc::new(Mutex::new(VMDtbDevConfigList::default())),
+        health: Arc::new(Mutex::new(VmHealthConfig::default())),
+        smc: Arc::new(Mutex::new(VmSmcConfig::default())),
     };
     let _ = vm_cfg_add_vm_entry(mvm_config_entry);
 }
@@ -21,9 +21,13 @@ use crate::kernel::{HVC_IRQ, INTERRUPT_IRQ_GUEST_TIMER, VmType};
 
 use super::{
     PassthroughRegion, vm_cfg_set_config_name, VmConfigEntry, VmCpuConfig, VMDtbDevConfigList, VmEmulatedDeviceConfig,
-    VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig, VmRegion,
+    VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig, VmRegion, VmHealthConfig,
+    VmCpuIdReg, CPU_ID_REG_NUM, VmSmcConfig, VmSmcPolicy, VmSmcRange,
 };
 
+// Tegra SiP call of L4T, served by the firmware
+const TEGRA_SIP_GET_ACTMON_CLK_COUNTERS: usize = 0xC2FFFE02;
+
 #[rustfmt::skip]
 pub fn mvm_config_init() {
     println!("mvm_config_init() init config for VM0, which is manager VM");
@@ -253,10 +257,22 @@ pub fn mvm_config_init() {
             num: 1,
             allocate_bitmap: 0b0001,
             master: 0,
+            budget: 0,
+            period: 0,
+            priority: 0,
+            id_regs: [VmCpuIdReg::default(); CPU_ID_REG_NUM],
         })),
         vm_emu_dev_confg: Arc::new(Mutex::new(VmEmulatedDeviceConfigList { emu_dev_list: emu_dev_config })),
         vm_pt_dev_confg: Arc::new(Mutex::new(pt_dev_config)),
         vm_dtb_devs: Arc::new(Mutex::new(VMDtbDevConfigList::default())),
+        health: Arc::new(Mutex::new(VmHealthConfig::default())),
+        smc: Arc::new(Mutex::new(VmSmcConfig {
+            ranges: vec![VmSmcRange {
+                start: TEGRA_SIP_GET_ACTMON_CLK_COUNTERS,
+                end: TEGRA_SIP_GET_ACTMON_CLK_COUNTERS,
+                policy: VmSmcPolicy::Forward,
+            }],
+        })),
     };
     let _ = vm_cfg_add_vm_entry(mvm_config_entry);
 }
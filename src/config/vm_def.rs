@@ -22,7 +22,7 @@ use crate::kernel::{INTERRUPT_IRQ_GUEST_TIMER, VmType};
 use super::{
     PassthroughRegion, VmConfigEntry, VmCpuConfig, VMDtbDevConfigList, VmEmulatedDeviceConfig,
     VmEmulatedDeviceConfigList, VmImageConfig, VmMemoryConfig, VmPassthroughDeviceConfig, VmRegion, VmDtbDevConfig,
-    AddrRegions, DtbDevType,
+    AddrRegions, DtbDevType, VmHealthConfig, VmSmcConfig, VmCpuIdReg, CPU_ID_REG_NUM,
 };
 
 pub fn init_tmp_config_for_bma1() {
@@ -92,6 +92,10 @@ pub fn init_tmp_config_for_bma1() {
             num: 1,
             allocate_bitmap: 0b0010,
             master: 1,
+            budget: 0,
+            period: 0,
+            priority: 0,
+            id_regs: [VmCpuIdReg::default(); CPU_ID_REG_NUM],
         })),
         vm_emu_dev_confg: Arc::new(Mutex::new(VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
@@ -100,6 +104,8 @@ pub fn init_tmp_config_for_bma1() {
         vm_dtb_devs: Arc::new(Mutex::new(VMDtbDevConfigList {
             dtb_device_list: vec![],
         })),
+        health: Arc::new(Mutex::new(VmHealthConfig::default())),
+        smc: Arc::new(Mutex::new(VmSmcConfig::default())),
         cmdline: String::from(""),
     };
     let _ = vm_cfg_add_vm_entry(bma_config);
@@ -172,6 +178,10 @@ pub fn init_tmp_config_for_bma2() {
             num: 1,
             allocate_bitmap: 0b0100,
             master: 2,
+            budget: 0,
+            period: 0,
+            priority: 0,
+            id_regs: [VmCpuIdReg::default(); CPU_ID_REG_NUM],
         })),
         vm_emu_dev_confg: Arc::new(Mutex::new(VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
@@ -180,6 +190,8 @@ pub fn init_tmp_config_for_bma2() {
         vm_dtb_devs: Arc::new(Mutex::new(VMDtbDevConfigList {
             dtb_device_list: vec![],
         })),
+        health: Arc::new(Mutex::new(VmHealthConfig::default())),
+        smc: Arc::new(Mutex::new(VmSmcConfig::default())),
         cmdline: String::from(""),
     };
     let _ = vm_cfg_add_vm_entry(bma_config);
@@ -316,6 +328,10 @@ pub fn init_tmp_config_for_vm1() {
             num: 1,
             allocate_bitmap: 0b0010,
             master: 1,
+            budget: 0,
+            period: 0,
+            priority: 0,
+            id_regs: [VmCpuIdReg::default(); CPU_ID_REG_NUM],
         })),
         vm_emu_dev_confg: Arc::new(Mutex::new(VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
@@ -324,6 +340,8 @@ pub fn init_tmp_config_for_vm1() {
         vm_dtb_devs: Arc::new(Mutex::new(VMDtbDevConfigList {
             dtb_device_list: vm_dtb_devs,
         })),
+        health: Arc::new(Mutex::new(VmHealthConfig::default())),
+        smc: Arc::new(Mutex::new(VmSmcConfig::default())),
     };
     println!("generate tmp_config for vm1");
     let _ = vm_cfg_add_vm_entry(vm1_config);
@@ -448,6 +466,10 @@ pub fn init_tmp_config_for_vm2() {
             num: 1,
             allocate_bitmap: 0b0100,
             master: 2,
+            budget: 0,
+            period: 0,
+            priority: 0,
+            id_regs: [VmCpuIdReg::default(); CPU_ID_REG_NUM],
         })),
         vm_emu_dev_confg: Arc::new(Mutex::new(VmEmulatedDeviceConfigList {
             emu_dev_list: emu_dev_config,
@@ -456,6 +478,8 @@ pub fn init_tmp_config_for_vm2() {
         vm_dtb_devs: Arc::new(Mutex::new(VMDtbDevConfigList {
             dtb_device_list: vm_dtb_devs,
         })),
+        health: Arc::new(Mutex::new(VmHealthConfig::default())),
+        smc: Arc::new(Mutex::new(VmSmcConfig::default())),
     };
     let _ = vm_cfg_add_vm_entry(vm2_config);
 }
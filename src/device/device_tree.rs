@@ -12,9 +12,11 @@ use alloc::vec::Vec;
 
 use vm_fdt::{Error, FdtWriter, FdtWriterResult};
 
+use crate::arch::vpmu_enabled;
 use crate::config::{DtbDevType, VmDtbDevConfig};
 use crate::config::VmConfigEntry;
 use crate::device::EmuDeviceType;
+use crate::kernel::INTERRUPT_IRQ_GUEST_PMU;
 use crate::SYSTEM_FDT;
 use crate::vmm::CPIO_RAMDISK;
 
@@ -144,7 +146,10 @@ pub fn init_vm0_dtb(dtb: *mut fdt::myctypes::c_void) {
         // pass through the only one uart on qemu-system-aarch64
         // assert_eq!(fdt_remove_node(dtb, "/pl011@9000000\0".as_ptr()), 0);
 
+        #[cfg(not(feature = "gicv3"))]
         assert_eq!(fdt_remove_node(dtb, "/intc@8000000/v2m@8020000\0".as_ptr()), 0);
+        #[cfg(feature = "gicv3")]
+        assert_eq!(fdt_remove_node(dtb, "/intc@8000000/its@8080000\0".as_ptr()), 0);
         assert_eq!(fdt_remove_node(dtb, "/flash@0\0".as_ptr()), 0);
 
         let len = fdt_size(dtb) as usize;
@@ -172,6 +177,9 @@ pub fn create_fdt(config: VmConfigEntry) -> Result<Vec<u8>, Error> {
 
     create_memory_node(&mut fdt, config.clone())?;
     create_timer_node(&mut fdt, 0x8)?;
+    if vpmu_enabled(&config) {
+        create_pmu_node(&mut fdt, INTERRUPT_IRQ_GUEST_PMU)?;
+    }
     // todo: fix create_chosen_node size
     create_chosen_node(&mut fdt, &config.cmdline, config.ramdisk_load_ipa(), CPIO_RAMDISK.len())?;
     create_cpu_node(&mut fdt, config.clone())?;
@@ -184,7 +192,10 @@ pub fn create_fdt(config: VmConfigEntry) -> Result<Vec<u8>, Error> {
     //     }
     //     None => {}
     // }
+    #[cfg(not(feature = "gicv3"))]
     create_gic_node(&mut fdt, config.gicc_addr(), config.gicd_addr())?;
+    #[cfg(feature = "gicv3")]
+    create_gicv3_node(&mut fdt, config.gicd_addr(), config.gicr_addr(), config.cpu_num())?;
 
     for emu_cfg in config.emulated_device_list() {
         match emu_cfg.emu_type {
@@ -258,6 +269,14 @@ fn create_timer_node(fdt: &mut FdtWriter, trigger_lvl: u32) -> FdtWriterResult<(
     Ok(())
 }
 
+fn create_pmu_node(fdt: &mut FdtWriter, irq: usize) -> FdtWriterResult<()> {
+    let pmu = fdt.begin_node("pmu")?;
+    fdt.property_string("compatible", "arm,armv8-pmuv3")?;
+    fdt.property_array_u32("interrupts", &[0x1, irq as u32 - 16, 0x4])?;
+    fdt.end_node(pmu)?;
+    Ok(())
+}
+
 fn create_cpu_node(fdt: &mut FdtWriter, config: VmConfigEntry) -> FdtWriterResult<()> {
     let cpus = fdt.begin_node("cpus")?;
     fdt.property_u32("#size-cells", 0)?;
@@ -309,6 +328,7 @@ fn create_chosen_node(fdt: &mut FdtWriter, cmdline: &str, ipa: usize, size: usiz
     Ok(())
 }
 
+#[cfg(not(feature = "gicv3"))]
 fn create_gic_node(fdt: &mut FdtWriter, gicc_addr: usize, gicd_addr: usize) -> FdtWriterResult<()> {
     let gic_name = format!("interrupt-controller@{:x}", gicd_addr);
     let gic = fdt.begin_node(&gic_name)?;
@@ -323,6 +343,30 @@ fn create_gic_node(fdt: &mut FdtWriter, gicc_addr: usize, gicd_addr: usize) -> F
     Ok(())
 }
 
+#[cfg(feature = "gicv3")]
+fn create_gicv3_node(fdt: &mut FdtWriter, gicd_addr: usize, gicr_addr: usize, cpu_num: usize) -> FdtWriterResult<()> {
+    let gic_name = format!("interrupt-controller@{:x}", gicd_addr);
+    let gic = fdt.begin_node(&gic_name)?;
+
+    fdt.property_u32("phandle", 0x8001)?;
+    fdt.property_array_u64(
+        "reg",
+        &[
+            gicd_addr as u64,
+            0x10000,
+            gicr_addr as u64,
+            (crate::arch::GICR_STRIDE * cpu_num) as u64,
+        ],
+    )?;
+    fdt.property_string("compatible", "arm,gic-v3")?;
+    fdt.property_u32("#redistributor-regions", 0x1)?;
+    fdt.property_u32("#interrupt-cells", 0x03)?;
+    fdt.property_null("interrupt-controller")?;
+    fdt.end_node(gic)?;
+
+    Ok(())
+}
+
 fn create_virtio_node(fdt: &mut FdtWriter, name: &str, irq: usize, address: usize) -> FdtWriterResult<()> {
     let virtio = fdt.begin_node(name)?;
     fdt.property_null("dma-coherent")?;
@@ -19,7 +19,7 @@ use crate::device::{
     virtio_blk_notify_handler, virtio_console_notify_handler, virtio_mediated_blk_notify_handler,
     virtio_net_notify_handler, VirtioMmio,
 };
-use crate::kernel::current_cpu;
+use crate::kernel::{current_cpu, trace_event, TraceKind};
 use crate::lib::in_range;
 
 pub const EMU_DEV_NUM_MAX: usize = 32;
@@ -106,6 +106,7 @@ pub enum EmuDeviceType {
     EmuDeviceTShyper = 6,
     EmuDeviceTVirtioBlkMediated = 7,
     EmuDeviceTIOMMU = 8,
+    EmuDeviceTGicr = 9,
 }
 
 impl Display for EmuDeviceType {
@@ -120,6 +121,7 @@ impl Display for EmuDeviceType {
             EmuDeviceType::EmuDeviceTShyper => write!(f, "device shyper"),
             EmuDeviceType::EmuDeviceTVirtioBlkMediated => write!(f, "medaited virtio block"),
             EmuDeviceType::EmuDeviceTIOMMU => write!(f, "IOMMU"),
+            EmuDeviceType::EmuDeviceTGicr => write!(f, "interrupt controller redistributor"),
         }
     }
 }
@@ -128,6 +130,7 @@ impl EmuDeviceType {
     pub fn removable(&self) -> bool {
         match *self {
             EmuDeviceType::EmuDeviceTGicd
+            | EmuDeviceType::EmuDeviceTGicr
             | EmuDeviceType::EmuDeviceTGPPT
             | EmuDeviceType::EmuDeviceTVirtioBlk
             | EmuDeviceType::EmuDeviceTVirtioNet
@@ -138,18 +141,22 @@ impl EmuDeviceType {
 }
 
 impl EmuDeviceType {
-    pub fn from_usize(value: usize) -> EmuDeviceType {
+    pub fn from_usize(value: usize) -> Result<EmuDeviceType, ()> {
         match value {
-            0 => EmuDeviceType::EmuDeviceTConsole,
-            1 => EmuDeviceType::EmuDeviceTGicd,
-            2 => EmuDeviceType::EmuDeviceTGPPT,
-            3 => EmuDeviceType::EmuDeviceTVirtioBlk,
-            4 => EmuDeviceType::EmuDeviceTVirtioNet,
-            5 => EmuDeviceType::EmuDeviceTVirtioConsole,
-            6 => EmuDeviceType::EmuDeviceTShyper,
-            7 => EmuDeviceType::EmuDeviceTVirtioBlkMediated,
-            8 => EmuDeviceType::EmuDeviceTIOMMU,
-            _ => panic!("Unknown  EmuDeviceType value: {}", value),
+            0 => Ok(EmuDeviceType::EmuDeviceTConsole),
+            1 => Ok(EmuDeviceType::EmuDeviceTGicd),
+            2 => Ok(EmuDeviceType::EmuDeviceTGPPT),
+            3 => Ok(EmuDeviceType::EmuDeviceTVirtioBlk),
+            4 => Ok(EmuDeviceType::EmuDeviceTVirtioNet),
+            5 => Ok(EmuDeviceType::EmuDeviceTVirtioConsole),
+            6 => Ok(EmuDeviceType::EmuDeviceTShyper),
+            7 => Ok(EmuDeviceType::EmuDeviceTVirtioBlkMediated),
+            8 => Ok(EmuDeviceType::EmuDeviceTIOMMU),
+            9 => Ok(EmuDeviceType::EmuDeviceTGicr),
+            _ => {
+                println!("Unknown EmuDeviceType value: {}", value);
+                Err(())
+            }
         }
     }
 }
@@ -169,7 +176,12 @@ pub fn emu_handler(emu_ctx: &EmuContext) -> bool {
             // }
             let handler = emu_dev.handler;
             let id = emu_dev.id;
+            let vm_id = emu_dev.vm_id;
             drop(emu_devs_list);
+            if let Some(vm) = active_vcpu.vm() {
+                vm.stats_mmio(id);
+            }
+            trace_event(TraceKind::MmioEmu, [vm_id, ipa, emu_ctx.width, emu_ctx.write as usize]);
             return handler(id, emu_ctx);
         }
     }
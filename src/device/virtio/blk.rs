@@ -537,6 +537,7 @@ pub fn virtio_blk_notify_handler(vq: Virtq, blk: VirtioMmio, vm: Vm) -> bool {
 
     // let mediated = blk.mediated();
     let dev = blk.dev();
+    let dev_id = blk.id();
     let req = match dev.req() {
         super::DevReq::BlkReq(blk_req) => blk_req,
         _ => {
@@ -644,6 +645,7 @@ pub fn virtio_blk_notify_handler(vq: Virtq, blk: VirtioMmio, vm: Vm) -> bool {
             next_desc_idx = vq.desc_next(next_desc_idx) as usize;
         }
         req_node.iov_total = req_node.iov_sum_up;
+        vm.stats_virtio_req(dev_id, req_node.iov_total);
         req.add_req_node(req_node, vm.clone());
 
         process_count += 1;
@@ -160,6 +160,7 @@ pub fn virtio_console_notify_handler(vq: Virtq, console: VirtioMmio, vm: Vm) ->
 
     let tx_iov = VirtioIov::default();
     let dev = console.dev();
+    let dev_id = console.id();
 
     let (trgt_vmid, trgt_console_ipa) = match dev.desc() {
         DevDesc::ConsoleDesc(desc) => desc.target_console(),
@@ -192,6 +193,7 @@ pub fn virtio_console_notify_handler(vq: Virtq, console: VirtioMmio, vm: Vm) ->
             idx = vq.desc_next(idx) as usize;
         }
 
+        vm.stats_virtio_req(dev_id, len);
         if !virtio_console_recv(trgt_vmid, trgt_console_ipa, tx_iov.clone(), len) {
             println!("virtio_console_notify_handler: failed send");
             // return false;
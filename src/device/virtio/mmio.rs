@@ -25,6 +25,7 @@ use crate::device::VIRTQ_READY;
 use crate::kernel::{current_cpu, ipi_send_msg, IpiInnerMsg, IpiIntInjectMsg, IpiType, VirtioMmioData, vm_ipa2pa, VmPa};
 use crate::kernel::{active_vm, active_vm_id};
 use crate::kernel::Vm;
+use crate::kernel::{StreamReader, StreamWriter};
 
 pub const VIRTIO_F_VERSION_1: usize = 1 << 32;
 pub const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
@@ -93,6 +94,39 @@ impl VirtMmioRegs {
         }
     }
 
+    pub fn migrate_encode(&self, w: &mut StreamWriter) {
+        w.put_u32(self.magic);
+        w.put_u32(self.version);
+        w.put_u32(self.device_id);
+        w.put_u32(self.vendor_id);
+        w.put_u32(self.dev_feature);
+        w.put_u32(self.dev_feature_sel);
+        w.put_u32(self.drv_feature);
+        w.put_u32(self.drv_feature_sel);
+        w.put_u32(self.q_sel);
+        w.put_u32(self.q_num_max);
+        w.put_u32(self.irt_stat);
+        w.put_u32(self.irt_ack);
+        w.put_u32(self.dev_stat);
+    }
+
+    pub fn migrate_decode(&mut self, r: &mut StreamReader) -> Result<(), ()> {
+        self.magic = r.get_u32()?;
+        self.version = r.get_u32()?;
+        self.device_id = r.get_u32()?;
+        self.vendor_id = r.get_u32()?;
+        self.dev_feature = r.get_u32()?;
+        self.dev_feature_sel = r.get_u32()?;
+        self.drv_feature = r.get_u32()?;
+        self.drv_feature_sel = r.get_u32()?;
+        self.q_sel = r.get_u32()?;
+        self.q_num_max = r.get_u32()?;
+        self.irt_stat = r.get_u32()?;
+        self.irt_ack = r.get_u32()?;
+        self.dev_stat = r.get_u32()?;
+        Ok(())
+    }
+
     pub fn init(&mut self, id: VirtioDeviceType) {
         self.magic = 0x74726976;
         self.version = 0x2;
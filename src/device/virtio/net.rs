@@ -285,6 +285,7 @@ pub fn virtio_net_notify_handler(vq: Virtq, nic: VirtioMmio, vm: Vm) -> bool {
 
     let tx_iov = VirtioIov::default();
     let mut vms_to_notify = 0;
+    let dev_id = nic.id();
 
     let mut next_desc_idx_opt = vq.pop_avail_desc_idx(vq.avail_idx());
 
@@ -308,6 +309,7 @@ pub fn virtio_net_notify_handler(vq: Virtq, nic: VirtioMmio, vm: Vm) -> bool {
             idx = vq.desc_next(idx) as usize;
         }
 
+        vm.stats_virtio_req(dev_id, len);
         let trgt_vmid_map = ethernet_transmit(tx_iov.clone(), len).1;
         if trgt_vmid_map != 0 {
             vms_to_notify |= trgt_vmid_map;
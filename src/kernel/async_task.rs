@@ -23,7 +23,7 @@ use crate::device::{
     BlkIov, mediated_blk_read, mediated_blk_write, virtio_blk_notify_handler, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
     VirtioMmio, Virtq,
 };
-use crate::kernel::{active_vm_id, ipi_send_msg, IpiInnerMsg, IpiMediatedMsg, IpiType, vm};
+use crate::kernel::{active_vm_id, ipi_send_msg, IpiInnerMsg, IpiMediatedMsg, IpiType, trace_event, TraceKind, vm};
 use crate::lib::{memcpy_safe, sleep, trace};
 
 pub static TASK_IPI_COUNT: Mutex<usize> = Mutex::new(0);
@@ -338,6 +338,11 @@ pub fn set_front_io_task_state(state: AsyncTaskState) {
     }
 }
 
+// whether there is mediated block I/O waiting for the MVM
+pub fn async_task_pending() -> bool {
+    !ASYNC_IPI_TASK_LIST.lock().is_empty() || !ASYNC_IO_TASK_LIST.lock().is_empty()
+}
+
 pub fn add_async_task(task: AsyncTask, ipi: bool) {
     // println!("add {} task", if ipi { "ipi" } else { "blk io" });
     let mut ipi_list = ASYNC_IPI_TASK_LIST.lock();
@@ -413,9 +418,11 @@ pub fn async_task_exe() {
         }
         drop(ipi_list);
         drop(io_list);
+        trace_event(TraceKind::AsyncStart, [task.src_vmid, ipi as usize, 0, 0]);
         if task.handle() || (ipi && active_vm_id() == 0) {
             // task finish
             finish_async_task(ipi);
+            trace_event(TraceKind::AsyncFinish, [task.src_vmid, ipi as usize, 0, 0]);
         } else {
             // wait for notify
             set_async_exe_status(AsyncExeStatus::Pending);
@@ -18,9 +18,9 @@ use crate::arch::ContextFrameTrait;
 // use core::ops::{Deref, DerefMut};
 use crate::arch::cpu_interrupt_unmask;
 use crate::board::PLATFORM_CPU_NUM_MAX;
-use crate::kernel::{SchedType, Vcpu, VcpuArray, VcpuState, Vm, Scheduler};
-use crate::kernel::IpiMessage;
-use crate::lib::trace;
+use crate::kernel::{live_update_vcpu_scheduled, SchedType, Vcpu, VcpuArray, VcpuState, Vm, Scheduler};
+use crate::kernel::{IpiMessage, trace_enabled, trace_event, TraceKind};
+use crate::lib::{time_current_us, trace};
 
 pub const CPU_MASTER: usize = 0;
 pub const CPU_STACK_SIZE: usize = PAGE_SIZE * 128;
@@ -197,18 +197,40 @@ impl Cpu {
         }
     }
 
+    pub fn set_spsr(&self, val: usize) {
+        match self.ctx {
+            Some(ctx_addr) => {
+                if trace() && ctx_addr < 0x1000 {
+                    panic!("illegal ctx addr {:x}", ctx_addr);
+                }
+                let ctx = ctx_addr as *mut ContextFrame;
+                unsafe { (*ctx).spsr = val as u64 }
+            }
+            None => {}
+        }
+    }
+
     pub fn set_active_vcpu(&mut self, active_vcpu: Option<Vcpu>) {
+        // the run time of a vcpu is from here to the next switch of the core
+        let now = time_current_us();
+        if let Some(prev_vcpu) = &self.active_vcpu {
+            prev_vcpu.stats_sched_out(now);
+        }
         self.active_vcpu = active_vcpu.clone();
         match active_vcpu {
             None => {}
             Some(vcpu) => {
                 vcpu.set_state(VcpuState::VcpuAct);
+                vcpu.stats_sched_in(now);
             }
         }
     }
 
     pub fn schedule_to(&mut self, next_vcpu: Vcpu) {
         if let Some(prev_vcpu) = &self.active_vcpu {
+            if trace_enabled(TraceKind::SchedOut) {
+                trace_event(TraceKind::SchedOut, [prev_vcpu.vm_id(), prev_vcpu.id(), 0, 0]);
+            }
             if prev_vcpu.vm_id() != next_vcpu.vm_id() {
                 // println!(
                 //     "next vm{} vcpu {}, prev vm{} vcpu {}",
@@ -225,6 +247,10 @@ impl Cpu {
         //      because context restore while inject pending interrupt for VM
         //      and will judge if current active vcpu
         self.set_active_vcpu(Some(next_vcpu.clone()));
+        live_update_vcpu_scheduled(&next_vcpu);
+        if trace_enabled(TraceKind::SchedIn) {
+            trace_event(TraceKind::SchedIn, [next_vcpu.vm_id(), next_vcpu.id(), 0, 0]);
+        }
         next_vcpu.context_vm_restore();
         // restore vm's Stage2 MMU context
         let vttbr = (next_vcpu.vm_id() << 48) | next_vcpu.vm_pt_dir();
@@ -233,12 +259,18 @@ impl Cpu {
         unsafe {
             core::arch::asm!("msr VTTBR_EL2, {0}", "isb", in(reg) vttbr);
         }
+        // a forced reboot queued while the vcpu was not running
+        if next_vcpu.take_reboot_pending() {
+            crate::vmm::vmm_reboot(next_vcpu.vm().unwrap());
+        }
     }
 
-    pub fn scheduler(&mut self) -> &mut impl Scheduler {
+    pub fn scheduler(&mut self) -> &mut dyn Scheduler {
         match &mut self.sched {
             SchedType::None => panic!("scheduler is None"),
             SchedType::SchedRR(rr) => rr,
+            SchedType::SchedRT(rt) => rt,
+            SchedType::SchedTP(tp) => tp,
         }
     }
 
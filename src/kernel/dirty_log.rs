@@ -0,0 +1,275 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::arch::{LVL2_SHIFT, PAGE_SIZE, PTE_S2_FIELD_AP_RO, PTE_S2_FIELD_AP_RW};
+use crate::kernel::{active_vm, migrate_progress_stage, vm, vm_ipa2pa, MigrateStage, Vm};
+use crate::kernel::{live_update_entry, LiveUpdatable, LiveUpdateEntry, LiveUpdateFrom, LiveUpdateStage};
+use crate::lib::{time_current_us, FlexBitmap};
+
+const BLOCK_SIZE: usize = 1 << LVL2_SHIFT;
+
+/*
+ * Dirty page logging of a VM on behalf of the MVM, for incremental snapshots, checkpoints or
+ * memory usage analysis, without a migration. The logged memory is write protected in stage-2,
+ * the first write to a page takes a permission fault which sets the page dirty and gives write
+ * access back. HVC_VMM_DIRTY_LOG_FETCH hands the bitmap over and protects the dirty pages again.
+ * A 2MB block is split into pages at its first write fault, so that only the page written is
+ * set dirty; the blocks crossing the edges of the logged range are split at start.
+ * Bit i of the bitmap is page i of the logged range, counted over the memory regions of the VM in
+ * order, the same as the migration bitmap when the whole VM is logged.
+ */
+#[derive(Clone)]
+struct DirtyLog {
+    // (ipa, page num, first bit) of the logged part of each memory region
+    spans: Vec<(usize, usize, usize)>,
+    bitmap: FlexBitmap,
+    faults: usize,
+    start: usize,
+}
+
+impl DirtyLog {
+    fn ipa_bit(&self, ipa: usize) -> Option<usize> {
+        for (start, num, first) in self.spans.iter() {
+            if ipa >= *start && ipa < *start + *num * PAGE_SIZE {
+                return Some(*first + (ipa - *start) / PAGE_SIZE);
+            }
+        }
+        None
+    }
+
+    fn bit_ipa(&self, bit: usize) -> Option<usize> {
+        for (start, num, first) in self.spans.iter() {
+            if bit >= *first && bit < *first + *num {
+                return Some(*start + (bit - *first) * PAGE_SIZE);
+            }
+        }
+        None
+    }
+}
+
+static DIRTY_LOG_LIST: Mutex<BTreeMap<usize, DirtyLog>> = Mutex::new(BTreeMap::new());
+
+impl LiveUpdatable for Mutex<BTreeMap<usize, DirtyLog>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        let mut list = self.lock();
+        for (vm_id, log) in src.lock().iter() {
+            list.insert(*vm_id, log.clone());
+        }
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().clear();
+    }
+}
+
+pub static DIRTY_LOG_UPDATE: LiveUpdateEntry =
+    live_update_entry("DIRTY_LOG_LIST", LiveUpdateStage::Fresh, false, 1, &DIRTY_LOG_LIST);
+
+/*
+ * Start dirty logging of the memory of the VM in [ipa, ipa + len), or all of it if len is 0.
+ * Returns the number of pages logged, which is the number of bits of the bitmap.
+ */
+pub fn dirty_log_start(vm_id: usize, ipa: usize, len: usize) -> Result<usize, ()> {
+    let vm = match vm(vm_id) {
+        Some(vm) if vm_id != 0 => vm,
+        _ => {
+            println!("dirty_log_start: VM[{}] can not be logged", vm_id);
+            return Err(());
+        }
+    };
+    if ipa % PAGE_SIZE != 0 || len % PAGE_SIZE != 0 {
+        println!("dirty_log_start: ipa {:x} len {:x} not page aligned", ipa, len);
+        return Err(());
+    }
+    let ipa_end = match ipa.checked_add(len) {
+        Some(ipa_end) => ipa_end,
+        None => {
+            println!("dirty_log_start: ipa {:x} len {:x} overflows", ipa, len);
+            return Err(());
+        }
+    };
+    let mut list = DIRTY_LOG_LIST.lock();
+    if list.contains_key(&vm_id) {
+        println!("dirty_log_start: VM[{}] is logged already", vm_id);
+        return Err(());
+    }
+    let mut spans = Vec::new();
+    let mut page_num = 0;
+    for idx in 0..vm.region_num() {
+        let region_start = vm.pa_start(idx).wrapping_add(vm.pa_offset(idx));
+        let region_end = region_start + vm.pa_length(idx);
+        let (start, end) = match len {
+            0 => (region_start, region_end),
+            _ => (usize::max(ipa, region_start), usize::min(ipa_end, region_end)),
+        };
+        if start < end {
+            spans.push((start, (end - start) / PAGE_SIZE, page_num));
+            page_num += (end - start) / PAGE_SIZE;
+        }
+    }
+    if page_num == 0 {
+        println!(
+            "dirty_log_start: VM[{}] has no memory in {:x} len {:x}",
+            vm_id, ipa, len
+        );
+        return Err(());
+    }
+
+    // write faults wait for the list lock, they see the VM logged once its memory is protected
+    for (start, num, _) in spans.iter() {
+        let end = *start + *num * PAGE_SIZE;
+        if *start % BLOCK_SIZE != 0 {
+            vm.pt_split_2mb(*start);
+        }
+        if end % BLOCK_SIZE != 0 {
+            vm.pt_split_2mb(end);
+        }
+        vm.pt_range_access_permission(*start, *num * PAGE_SIZE, PTE_S2_FIELD_AP_RO);
+    }
+    vm.tlb_invalidate();
+    list.insert(
+        vm_id,
+        DirtyLog {
+            spans,
+            bitmap: FlexBitmap::new(page_num),
+            faults: 0,
+            start: time_current_us(),
+        },
+    );
+    info!("VM[{}] dirty logging started, {} pages", vm_id, page_num);
+    Ok(page_num)
+}
+
+pub fn dirty_log_stop(vm_id: usize) -> Result<usize, ()> {
+    let mut list = DIRTY_LOG_LIST.lock();
+    let log = match list.remove(&vm_id) {
+        Some(log) => log,
+        None => {
+            println!("dirty_log_stop: VM[{}] is not logged", vm_id);
+            return Err(());
+        }
+    };
+    // a migration in progress keeps tracking the writes to the memory
+    if let (Some(vm), MigrateStage::Idle) = (vm(vm_id), migrate_progress_stage(vm_id)) {
+        // the stale read only entries in the TLB fault once more at most, see dirty_log_write_fault
+        for (start, num, _) in log.spans.iter() {
+            vm.pt_range_access_permission(*start, *num * PAGE_SIZE, PTE_S2_FIELD_AP_RW);
+        }
+    }
+    info!(
+        "VM[{}] dirty logging stopped, {} write faults in {}us",
+        vm_id,
+        log.faults,
+        time_current_us() - log.start
+    );
+    Ok(0)
+}
+
+/*
+ * Copy the bitmap to bitmap_ipa of the MVM, which holds (pages + 63) / 64 words, then clear it and
+ * protect the dirty pages again. Returns the number of dirty pages.
+ */
+pub fn dirty_log_fetch(vm_id: usize, bitmap_ipa: usize) -> Result<usize, ()> {
+    let vm = match vm(vm_id) {
+        Some(vm) => vm,
+        None => {
+            println!("dirty_log_fetch: VM[{}] not exist", vm_id);
+            return Err(());
+        }
+    };
+    let mvm = active_vm().unwrap();
+    let mut list = DIRTY_LOG_LIST.lock();
+    let log = match list.get_mut(&vm_id) {
+        Some(log) => log,
+        None => {
+            println!("dirty_log_fetch: VM[{}] is not logged", vm_id);
+            return Err(());
+        }
+    };
+    for (word_idx, word) in log.bitmap.slice().iter().enumerate() {
+        let word_pa = vm_ipa2pa(mvm.clone(), bitmap_ipa + word_idx * 8);
+        if word_pa == 0 {
+            println!("dirty_log_fetch: illegal ipa {:x}", bitmap_ipa + word_idx * 8);
+            return Err(());
+        }
+        unsafe {
+            *(word_pa as *mut usize) = *word;
+        }
+    }
+
+    // the list lock is held, a write to a page protected here is set dirty for the next fetch
+    let mut dirty = 0;
+    for (word_idx, word) in log.bitmap.slice().iter().enumerate() {
+        let mut word = *word;
+        while word != 0 {
+            let bit = word_idx * 64 + word.trailing_zeros() as usize;
+            word &= word - 1;
+            // the bitmap has no bit past the logged pages
+            if let Some(ipa) = log.bit_ipa(bit) {
+                vm.pt_set_access_permission(ipa, PTE_S2_FIELD_AP_RO);
+                dirty += 1;
+            }
+        }
+    }
+    log.bitmap.clear();
+    vm.tlb_invalidate();
+    Ok(dirty)
+}
+
+/*
+ * Give write access to the page of the active VM at ipa back on a permission fault, the page is
+ * set dirty if it is logged. Returns the pa and length mapped, as Vm::pt_set_access_permission.
+ */
+pub fn dirty_log_write_fault(vm: &Vm, ipa: usize) -> (usize, usize) {
+    let mut list = DIRTY_LOG_LIST.lock();
+    let log = match list.get_mut(&vm.id()) {
+        Some(log) if log.ipa_bit(ipa).is_some() => log,
+        _ => return vm.pt_set_access_permission(ipa, PTE_S2_FIELD_AP_RW),
+    };
+    // a block is left whole only if the split fails, all of its pages are dirty then
+    vm.pt_split_2mb(ipa);
+    let (pa, len) = vm.pt_set_access_permission(ipa, PTE_S2_FIELD_AP_RW);
+    let base = ipa & !(usize::max(len, PAGE_SIZE) - 1);
+    for page in 0..len / PAGE_SIZE {
+        if let Some(bit) = log.ipa_bit(base + page * PAGE_SIZE) {
+            log.bitmap.set(bit, true);
+        }
+    }
+    log.faults += 1;
+    (pa, len)
+}
+
+pub fn dirty_log_logged(vm_id: usize, ipa: usize) -> bool {
+    match DIRTY_LOG_LIST.lock().get(&vm_id) {
+        Some(log) => log.ipa_bit(ipa).is_some(),
+        None => false,
+    }
+}
+
+/*
+ * Called on a stage-2 translation fault of the active VM. A logged block may be in the middle of
+ * its split, see PageTable::split_2mb. The split is done under the list lock, so the faulting
+ * access finds the pages when it is retried.
+ */
+pub fn dirty_log_translate_fault(ipa: usize) -> bool {
+    match active_vm() {
+        Some(vm) => dirty_log_logged(vm.id(), ipa),
+        None => false,
+    }
+}
+
+pub fn dirty_log_remove(vm_id: usize) {
+    DIRTY_LOG_LIST.lock().remove(&vm_id);
+}
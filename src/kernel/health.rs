@@ -0,0 +1,383 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::mem::{align_of, size_of};
+
+use spin::Mutex;
+
+use crate::board::PLATFORM_VCPU_NUM_MAX;
+use crate::config::{vm_cfg_entry, VmHealthPolicy};
+use crate::kernel::{active_vm, hvc_send_msg_to_vm, ivc_keep_alive_check, vm, vm_ipa2pa, HvcGuestMsg, HvcHealthMsg};
+use crate::kernel::{HVC_VMM, HVC_VMM_VM_HEALTH};
+use crate::kernel::{live_update_decode_with, live_update_encode_with, live_update_entry_codec, StreamReader, StreamWriter};
+use crate::kernel::{LiveUpdatable, LiveUpdateEntry, LiveUpdateFrom, LiveUpdateStage, VM_NUM_MAX};
+use crate::lib::time_current_us;
+use crate::vmm::{vmm_force_reboot_vm, vmm_shutdown_vm};
+
+#[derive(Clone, Copy, Debug)]
+pub enum VmHealthEvent {
+    KeepAliveLost = 0,
+    Exception = 1,
+    WfiTimeout = 2,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum VmFaultReason {
+    DataAbort = 0,
+    UnknownException = 1,
+    SError = 2,
+}
+
+/* The last fault of a VM, read by the MVM through HVC_VMM_GET_VM_FAULT */
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct VmFaultInfo {
+    pub reason: usize,
+    pub esr: usize,
+    pub far: usize,
+    pub ipa: usize,
+    pub pc: usize,
+    // faults since the VM was created
+    pub count: usize,
+}
+
+// reboot delay of VmHealthPolicy::RebootBackoff doubles from base to max, in us
+const HEALTH_BACKOFF_BASE_US: usize = 1_000_000;
+const HEALTH_BACKOFF_MAX_US: usize = 64_000_000;
+
+/*
+ * A VM is declared dead when it misses its heartbeats, hits an unhandled exception, or all of
+ * its vcpus stay in WFI longer than the configured timeout. The action is taken once per death,
+ * a rebooted VM is watched again from scratch.
+ */
+#[derive(Clone)]
+struct VmHealth {
+    dead: bool,
+    restarts: usize,
+    last_restart: usize,
+    reboot_at: Option<usize>,
+    fault: Option<VmFaultInfo>,
+    // vcpu id -> the time it entered WFI
+    wfi_since: BTreeMap<usize, usize>,
+}
+
+impl VmHealth {
+    const fn default() -> VmHealth {
+        VmHealth {
+            dead: false,
+            restarts: 0,
+            last_restart: 0,
+            reboot_at: None,
+            fault: None,
+            wfi_since: BTreeMap::new(),
+        }
+    }
+
+    // version 1 of VM_HEALTH_LIST
+    fn encode(&self, w: &mut StreamWriter) {
+        w.put_bool(self.dead);
+        w.put_usize(self.restarts);
+        w.put_usize(self.last_restart);
+        w.put_bool(self.reboot_at.is_some());
+        w.put_usize(self.reboot_at.unwrap_or(0));
+        w.put_bool(self.fault.is_some());
+        let fault = self.fault.unwrap_or_default();
+        for val in [fault.reason, fault.esr, fault.far, fault.ipa, fault.pc, fault.count] {
+            w.put_usize(val);
+        }
+        w.put_u32(self.wfi_since.len() as u32);
+        for (vcpu_id, since) in self.wfi_since.iter() {
+            w.put_usize(*vcpu_id);
+            w.put_usize(*since);
+        }
+    }
+
+    fn decode(r: &mut StreamReader, version: usize) -> Result<VmHealth, ()> {
+        if version != 1 {
+            return Err(());
+        }
+        let dead = r.get_bool()?;
+        let restarts = r.get_usize()?;
+        let last_restart = r.get_usize()?;
+        let reboot = r.get_bool()?;
+        let reboot_at = r.get_usize()?;
+        let fault = r.get_bool()?;
+        let fault_info = VmFaultInfo {
+            reason: r.get_usize()?,
+            esr: r.get_usize()?,
+            far: r.get_usize()?,
+            ipa: r.get_usize()?,
+            pc: r.get_usize()?,
+            count: r.get_usize()?,
+        };
+        let mut health = VmHealth {
+            dead,
+            restarts,
+            last_restart,
+            reboot_at: if reboot { Some(reboot_at) } else { None },
+            fault: if fault { Some(fault_info) } else { None },
+            wfi_since: BTreeMap::new(),
+        };
+        for _ in 0..r.get_count(PLATFORM_VCPU_NUM_MAX)? {
+            let vcpu_id = r.get_usize()?;
+            health.wfi_since.insert(vcpu_id, r.get_usize()?);
+        }
+        Ok(health)
+    }
+}
+
+static VM_HEALTH_LIST: Mutex<BTreeMap<usize, VmHealth>> = Mutex::new(BTreeMap::new());
+
+impl LiveUpdatable for Mutex<BTreeMap<usize, VmHealth>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        let mut list = self.lock();
+        for (vm_id, health) in src.lock().iter() {
+            list.insert(*vm_id, health.clone());
+        }
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().clear();
+    }
+}
+
+// the health of the VMs one by one, see LiveUpdateEncode
+fn vm_health_encode(state: *const (), buf: usize, cap: usize) -> Result<usize, ()> {
+    live_update_encode_with(state, buf, cap, |list: &Mutex<BTreeMap<usize, VmHealth>>, w| {
+        let list = list.lock();
+        w.put_u32(list.len() as u32);
+        for (vm_id, health) in list.iter() {
+            w.put_usize(*vm_id);
+            health.encode(w);
+        }
+    })
+}
+
+fn vm_health_decode(state: *const (), data: &[u8], version: usize) -> Result<(), ()> {
+    live_update_decode_with(state, data, |list: &Mutex<BTreeMap<usize, VmHealth>>, r| {
+        let mut list = list.lock();
+        for _ in 0..r.get_count(VM_NUM_MAX)? {
+            let vm_id = r.get_usize()?;
+            list.insert(vm_id, VmHealth::decode(r, version)?);
+        }
+        Ok(())
+    })
+}
+
+pub static VM_HEALTH_UPDATE: LiveUpdateEntry = live_update_entry_codec(
+    "VM_HEALTH_LIST",
+    LiveUpdateStage::Fresh,
+    false,
+    1,
+    &VM_HEALTH_LIST,
+    vm_health_encode,
+    vm_health_decode,
+);
+
+fn vm_health_reboot(vm_id: usize) {
+    info!("VM[{}] health monitor reboots it", vm_id);
+    vmm_force_reboot_vm(vm_id);
+}
+
+/* Returns true if the VM has been stopped or reset by its policy */
+pub fn vm_health_report(vm_id: usize, event: VmHealthEvent) -> bool {
+    let policy = match vm_cfg_entry(vm_id) {
+        Some(vm_cfg) => vm_cfg.health_cfg().policy,
+        None => return false,
+    };
+    let now = time_current_us();
+    let (delay, restarts) = {
+        let mut health_list = VM_HEALTH_LIST.lock();
+        let health = health_list.entry(vm_id).or_insert(VmHealth::default());
+        if health.dead {
+            return false;
+        }
+        warn!("VM[{}] is dead: {:?}, policy {:?}", vm_id, event, policy);
+        if policy == VmHealthPolicy::Ignore {
+            return false;
+        }
+        health.dead = true;
+        health.wfi_since.clear();
+        let delay = match policy {
+            VmHealthPolicy::Reboot => 0,
+            VmHealthPolicy::RebootBackoff => {
+                // a VM survived the longest backoff starts over
+                if now - health.last_restart > HEALTH_BACKOFF_MAX_US {
+                    health.restarts = 0;
+                }
+                usize::min(
+                    HEALTH_BACKOFF_BASE_US << usize::min(health.restarts, 6),
+                    HEALTH_BACKOFF_MAX_US,
+                )
+            }
+            _ => 0,
+        };
+        if policy == VmHealthPolicy::Reboot || policy == VmHealthPolicy::RebootBackoff {
+            health.restarts += 1;
+            health.reboot_at = Some(now + delay);
+        }
+        (delay, health.restarts)
+    };
+
+    if vm_id != 0 {
+        let msg = HvcHealthMsg {
+            fid: HVC_VMM,
+            event: HVC_VMM_VM_HEALTH,
+            vm_id,
+            reason: event as usize,
+            action: policy as usize,
+            restarts,
+        };
+        if !hvc_send_msg_to_vm(0, &HvcGuestMsg::Health(msg)) {
+            println!("vm_health_report: failed to notify VM 0");
+        }
+    }
+
+    match policy {
+        VmHealthPolicy::Shutdown => {
+            vmm_shutdown_vm(vm_id);
+            true
+        }
+        VmHealthPolicy::Reboot | VmHealthPolicy::RebootBackoff if delay == 0 => {
+            if let Some(health) = VM_HEALTH_LIST.lock().get_mut(&vm_id) {
+                health.reboot_at = None;
+            }
+            vm_health_reboot(vm_id);
+            true
+        }
+        _ => false,
+    }
+}
+
+pub fn vm_fault_record(vm_id: usize, reason: VmFaultReason, esr: usize, far: usize, ipa: usize, pc: usize) {
+    warn!(
+        "VM[{}] fault {:?}: esr 0x{:x} far 0x{:x} ipa 0x{:x} pc 0x{:x}",
+        vm_id, reason, esr, far, ipa, pc
+    );
+    let mut health_list = VM_HEALTH_LIST.lock();
+    let health = health_list.entry(vm_id).or_insert(VmHealth::default());
+    let count = match &health.fault {
+        Some(fault) => fault.count + 1,
+        None => 1,
+    };
+    health.fault = Some(VmFaultInfo {
+        reason: reason as usize,
+        esr,
+        far,
+        ipa,
+        pc,
+        count,
+    });
+}
+
+/*
+ * Stop a VM after a fault it can not survive. The health policy may reboot it, otherwise it is
+ * shut down, and stays there until the MVM boots it again or a delayed reboot is due.
+ */
+pub fn vm_fault_stop(vm_id: usize) {
+    if !vm_health_report(vm_id, VmHealthEvent::Exception) {
+        vmm_shutdown_vm(vm_id);
+    }
+}
+
+pub fn vm_fault_get(vm_id: usize, info_ipa: usize) -> Result<usize, ()> {
+    if vm(vm_id).is_none() {
+        println!("vm_fault_get: VM[{}] not exist", vm_id);
+        return Err(());
+    }
+    // the struct may cross a page, it has to lie in one memory region of the MVM
+    let mvm = active_vm().unwrap();
+    let info_pa = vm_ipa2pa(mvm.clone(), info_ipa);
+    let last = size_of::<VmFaultInfo>() - 1;
+    if info_pa == 0 || info_ipa % align_of::<VmFaultInfo>() != 0 || vm_ipa2pa(mvm, info_ipa + last) != info_pa + last {
+        println!("vm_fault_get: illegal ipa {:x}", info_ipa);
+        return Err(());
+    }
+    let info = match VM_HEALTH_LIST.lock().get(&vm_id) {
+        Some(health) => health.fault.unwrap_or_default(),
+        None => VmFaultInfo::default(),
+    };
+    unsafe {
+        *(info_pa as *mut VmFaultInfo) = info;
+    }
+    Ok(info.count)
+}
+
+/* Called when a vcpu of vm[vm_id] enters (idle = true) or leaves WFI */
+pub fn vm_health_vcpu_wfi(vm_id: usize, vcpu_id: usize, idle: bool) {
+    let mut health_list = VM_HEALTH_LIST.lock();
+    if idle {
+        let health = health_list.entry(vm_id).or_insert(VmHealth::default());
+        health.wfi_since.entry(vcpu_id).or_insert(time_current_us());
+    } else if let Some(health) = health_list.get_mut(&vm_id) {
+        health.wfi_since.remove(&vcpu_id);
+    }
+}
+
+// a rebooted VM is alive again
+pub fn vm_health_clear(vm_id: usize) {
+    if let Some(health) = VM_HEALTH_LIST.lock().get_mut(&vm_id) {
+        health.dead = false;
+        health.last_restart = time_current_us();
+        health.reboot_at = None;
+        health.wfi_since.clear();
+    }
+}
+
+pub fn vm_health_remove(vm_id: usize) {
+    VM_HEALTH_LIST.lock().remove(&vm_id);
+}
+
+// called periodically by the timer of core 0, see TimerKeep::Monitor
+pub fn vm_health_check() {
+    ivc_keep_alive_check();
+
+    let now = time_current_us();
+    let mut reboot_list = Vec::new();
+    let mut wfi_list = Vec::new();
+    {
+        let mut health_list = match VM_HEALTH_LIST.try_lock() {
+            Some(list) => list,
+            None => return,
+        };
+        for (vm_id, health) in health_list.iter_mut() {
+            match health.reboot_at {
+                Some(reboot_at) if reboot_at <= now => {
+                    health.reboot_at = None;
+                    reboot_list.push(*vm_id);
+                }
+                _ => {}
+            }
+            if !health.dead && !health.wfi_since.is_empty() {
+                let idle_since = health.wfi_since.values().max().unwrap();
+                wfi_list.push((*vm_id, health.wfi_since.len(), now.saturating_sub(*idle_since)));
+            }
+        }
+    }
+
+    for (vm_id, idle_num, idle_time) in wfi_list {
+        let vm = match vm(vm_id) {
+            Some(vm) => vm,
+            None => continue,
+        };
+        if idle_num < vm.cpu_num() {
+            continue;
+        }
+        let wfi_timeout = vm.config().health_cfg().wfi_timeout;
+        if wfi_timeout != 0 && idle_time > wfi_timeout.saturating_mul(1000) {
+            vm_health_report(vm_id, VmHealthEvent::WfiTimeout);
+        }
+    }
+    for vm_id in reboot_list {
+        vm_health_reboot(vm_id);
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use spin::Mutex;
+
+use crate::arch::{cache_sync_i, PAGE_SIZE};
+use crate::board::PLAT_DESC;
+use crate::kernel::{active_vm, current_cpu, ipi_send_msg, IpiInnerMsg, IpiMessage, IpiType, vm_ipa2pa};
+use crate::kernel::{update_build_str, update_image_id, update_key, UPDATE_BUILD_LEN};
+use crate::lib::{barrier, HmacSha256, mac_eq, memcpy_safe, SHA256_LEN};
+
+// "SHYPHPAT"
+const HOT_PATCH_MAGIC: usize = 0x5441_5048_5059_4853;
+const HOT_PATCH_VERSION: usize = 2;
+const HOT_PATCH_SIZE_MAX: usize = 0x100000;
+const HOT_PATCH_MAC_OFFSET: usize = 3 * size_of::<usize>();
+// b imm26
+const INSN_B: u32 = 0x1400_0000;
+const INSN_B_RANGE: isize = 1 << 27;
+
+/*
+ * Header of a hot patch object, built by tools/hot_patch_object.py against a signed image. The
+ * mac is the HMAC-SHA256 with the update key of the object with the mac field zeroed, the same key
+ * the images are signed with, see live_update. The header is followed by
+ * func_num HotPatchFunc, the code of the new functions is at code_offset. The code is position
+ * independent, it reaches the functions and globals of the image by their absolute addresses.
+ */
+#[repr(C)]
+struct HotPatchHeader {
+    magic: usize,
+    version: usize,
+    size: usize,
+    mac: [u8; SHA256_LEN],
+    build: [u8; UPDATE_BUILD_LEN],
+    // leading word of the mac of the image the patch is built against, see update_image_id
+    image_checksum: usize,
+    func_num: usize,
+    code_offset: usize,
+    code_size: usize,
+}
+
+#[repr(C)]
+struct HotPatchFunc {
+    old_addr: usize,
+    // offset of the new function in the code
+    new_offset: usize,
+    // the first instruction of the old function, to be sure of the address
+    old_insn: usize,
+}
+
+/*
+ * An applied patch. The first instruction of each old function is a branch to the new one, the
+ * rest of the old function is left as it is. A core may be in the middle of an old function when
+ * it is patched, it goes on with the old code and takes the new one at the next call.
+ */
+struct HotPatch {
+    id: usize,
+    // the object as uploaded, the new functions run from it
+    object: Vec<usize>,
+    // (old addr, old insn, branch)
+    funcs: Vec<(usize, u32, u32)>,
+}
+
+struct HotPatchList {
+    next_id: usize,
+    patches: Vec<HotPatch>,
+}
+
+static HOT_PATCH_LIST: Mutex<HotPatchList> = Mutex::new(HotPatchList {
+    next_id: 1,
+    patches: Vec::new(),
+});
+
+// busy counts as applied, a core waiting for the lock would never join hot_patch_write
+pub fn hot_patch_applied() -> bool {
+    match HOT_PATCH_LIST.try_lock() {
+        Some(list) => !list.patches.is_empty(),
+        None => true,
+    }
+}
+
+// copy the object at ipa of the MVM, page by page, it is not contiguous in pa
+fn hot_patch_copy(ipa: usize, size: usize) -> Result<Vec<usize>, ()> {
+    let mvm = active_vm().unwrap();
+    let mut object = vec![0; (size + size_of::<usize>() - 1) / size_of::<usize>()];
+    let dst = object.as_mut_ptr() as usize;
+    let mut offset = 0;
+    while offset < size {
+        let len = usize::min(PAGE_SIZE - (ipa + offset) % PAGE_SIZE, size - offset);
+        let pa = vm_ipa2pa(mvm.clone(), ipa + offset);
+        if pa == 0 {
+            println!("hot_patch_copy: illegal ipa {:x}", ipa + offset);
+            return Err(());
+        }
+        memcpy_safe((dst + offset) as *const u8, pa as *const u8, len);
+        offset += len;
+    }
+    Ok(object)
+}
+
+fn hot_patch_validate(object: &[usize], size: usize) -> Result<(), ()> {
+    let header = unsafe { &*(object.as_ptr() as *const HotPatchHeader) };
+    if header.magic != HOT_PATCH_MAGIC || header.version != HOT_PATCH_VERSION || header.size != size {
+        println!(
+            "hot_patch_validate: illegal header, magic {:x} version {} size {:x}",
+            header.magic, header.version, header.size
+        );
+        return Err(());
+    }
+    if update_key().is_empty() {
+        println!("hot_patch_validate: no update key is provisioned");
+        return Err(());
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(object.as_ptr() as *const u8, size) };
+    let mut mac = HmacSha256::new(update_key());
+    mac.update(&bytes[..HOT_PATCH_MAC_OFFSET]);
+    mac.update(&[0; SHA256_LEN]);
+    mac.update(&bytes[HOT_PATCH_MAC_OFFSET + SHA256_LEN..]);
+    if !mac_eq(&mac.finish(), &header.mac) {
+        println!("hot_patch_validate: object is not signed with the update key");
+        return Err(());
+    }
+    let (build, image_checksum) = update_image_id();
+    if header.build[..] != *build || header.image_checksum != image_checksum {
+        println!(
+            "hot_patch_validate: built against \"{}\" checksum {:x}, not \"{}\" checksum {:x}",
+            update_build_str(&header.build),
+            header.image_checksum,
+            update_build_str(build),
+            image_checksum
+        );
+        return Err(());
+    }
+    // bounded by size first, the sums below can not overflow then
+    if header.func_num == 0
+        || header.func_num > size / size_of::<HotPatchFunc>()
+        || header.code_offset > size
+        || header.code_size > size - header.code_offset
+        || size_of::<HotPatchHeader>() + header.func_num * size_of::<HotPatchFunc>() > header.code_offset
+        || header.code_offset % 4 != 0
+    {
+        println!(
+            "hot_patch_validate: illegal layout, {} funcs, code {:x} size {:x}",
+            header.func_num, header.code_offset, header.code_size
+        );
+        return Err(());
+    }
+    Ok(())
+}
+
+fn hot_patch_funcs(object: &[usize]) -> &[HotPatchFunc] {
+    let header = unsafe { &*(object.as_ptr() as *const HotPatchHeader) };
+    unsafe {
+        core::slice::from_raw_parts(
+            (object.as_ptr() as usize + size_of::<HotPatchHeader>()) as *const HotPatchFunc,
+            header.func_num,
+        )
+    }
+}
+
+/*
+ * Write the instructions while all the other cores wait in the hot patch ipi, so that none of
+ * them fetches a half written function entry.
+ */
+fn hot_patch_write(insns: &[(usize, u32)]) {
+    for cpu_id in 0..PLAT_DESC.cpu_desc.num {
+        if cpu_id != current_cpu().id {
+            ipi_send_msg(cpu_id, IpiType::IpiTHotPatch, IpiInnerMsg::None);
+        }
+    }
+    barrier();
+    for (addr, insn) in insns {
+        unsafe {
+            core::ptr::write_volatile(*addr as *mut u32, *insn);
+            cache_sync_i(*addr, size_of::<u32>());
+        }
+    }
+    barrier();
+}
+
+pub fn hot_patch_ipi_handler(_msg: &IpiMessage) {
+    barrier();
+    barrier();
+    // the instructions fetched before the patch are discarded
+    unsafe { core::arch::asm!("isb") };
+}
+
+/*
+ * Apply the patch object of size at ipa of the MVM. Returns the id of the patch for
+ * hot_patch_revert.
+ */
+pub fn hot_patch_apply(ipa: usize, size: usize) -> Result<usize, ()> {
+    extern "C" {
+        fn _image_start();
+        fn _bss_begin();
+    }
+    if size < size_of::<HotPatchHeader>() || size > HOT_PATCH_SIZE_MAX {
+        println!("hot_patch_apply: illegal size {:x}", size);
+        return Err(());
+    }
+    // the other cores wait in hot_patch_write, another hvc waiting for the lock would never join them
+    let mut list = match HOT_PATCH_LIST.try_lock() {
+        Some(list) => list,
+        None => {
+            println!("hot_patch_apply: another patch is in progress");
+            return Err(());
+        }
+    };
+    let object = hot_patch_copy(ipa, size)?;
+    hot_patch_validate(&object, size)?;
+
+    let header = unsafe { &*(object.as_ptr() as *const HotPatchHeader) };
+    let code = object.as_ptr() as usize + header.code_offset;
+    let mut funcs = Vec::new();
+    for func in hot_patch_funcs(&object) {
+        let old_addr = func.old_addr;
+        if old_addr % 4 != 0 || old_addr < _image_start as usize || old_addr >= _bss_begin as usize {
+            println!("hot_patch_apply: illegal function {:x}", old_addr);
+            return Err(());
+        }
+        if func.new_offset % 4 != 0 || func.new_offset >= header.code_size {
+            println!("hot_patch_apply: illegal new function offset {:x}", func.new_offset);
+            return Err(());
+        }
+        if let Some(patch) = list
+            .patches
+            .iter()
+            .find(|patch| patch.funcs.iter().any(|(addr, _, _)| *addr == old_addr))
+        {
+            println!("hot_patch_apply: function {:x} is patched by {}", old_addr, patch.id);
+            return Err(());
+        }
+        let old_insn = unsafe { *(old_addr as *const u32) };
+        if old_insn as usize != func.old_insn {
+            println!(
+                "hot_patch_apply: function {:x} begins with {:08x}, not {:08x}",
+                old_addr, old_insn, func.old_insn
+            );
+            return Err(());
+        }
+        let offset = (code + func.new_offset) as isize - old_addr as isize;
+        if offset < -INSN_B_RANGE || offset >= INSN_B_RANGE {
+            println!("hot_patch_apply: function {:x} out of branch range", old_addr);
+            return Err(());
+        }
+        funcs.push((old_addr, old_insn, INSN_B | ((offset >> 2) as u32 & 0x3ff_ffff)));
+    }
+
+    unsafe { cache_sync_i(code, header.code_size) };
+    let insns: Vec<(usize, u32)> = funcs.iter().map(|(addr, _, branch)| (*addr, *branch)).collect();
+    hot_patch_write(&insns);
+
+    let id = list.next_id;
+    list.next_id += 1;
+    info!("hot patch {} applied, {} functions", id, funcs.len());
+    list.patches.push(HotPatch { id, object, funcs });
+    Ok(id)
+}
+
+pub fn hot_patch_revert(id: usize) -> Result<usize, ()> {
+    let mut list = match HOT_PATCH_LIST.try_lock() {
+        Some(list) => list,
+        None => {
+            println!("hot_patch_revert: another patch is in progress");
+            return Err(());
+        }
+    };
+    let idx = match list.patches.iter().position(|patch| patch.id == id) {
+        Some(idx) => idx,
+        None => {
+            println!("hot_patch_revert: patch {} not exist", id);
+            return Err(());
+        }
+    };
+    let patch = list.patches.remove(idx);
+    let insns: Vec<(usize, u32)> = patch
+        .funcs
+        .iter()
+        .map(|(addr, old_insn, _)| (*addr, *old_insn))
+        .collect();
+    hot_patch_write(&insns);
+    info!("hot patch {} reverted", id);
+    // a core may be in one of the new functions still, keep them
+    core::mem::forget(patch.object);
+    Ok(0)
+}
@@ -18,16 +18,28 @@ use crate::arch::gicc_clear_current_irq;
 use crate::config::*;
 use crate::device::{mediated_blk_notify_handler, mediated_dev_append};
 use crate::kernel::{
-    active_vm, active_vm_id, current_cpu, DIRTY_MEM_THRESHOLD, interrupt_vm_inject, ipi_register, ipi_send_msg,
-    IpiHvcMsg, IpiInnerMsg, IpiMessage, IpiType, ivc_update_mq, map_migrate_vm_mem, mem_heap_region_reserve,
-    migrate_finish_ipi_handler, migrate_ready, Scheduler, send_migrate_memcpy_msg, unmap_migrate_vm_mem,
-    UPDATE_IMG_BASE_ADDR, update_request, vcpu_idle, vm, vm_if_copy_mem_map, vm_if_dirty_mem_map, vm_if_get_cpu_id,
-    vm_if_ivc_arg, vm_if_ivc_arg_ptr, vm_if_mem_map_dirty_sum, vm_if_mem_map_page_num, vm_if_set_ivc_arg_ptr,
-    VM_NUM_MAX, VMData,
+    active_vm, active_vm_id, current_cpu, interrupt_vm_inject, ipi_register, ipi_send_msg, IpiHvcMsg, IpiInnerMsg,
+    IpiMessage, IpiType, ivc_ack, ivc_broadcast_msg, ivc_init_keep_alive, ivc_keep_alive, ivc_send_msg, ivc_update_mq,
+    live_update_validate, map_migrate_vm_mem, mem_heap_region_reserve, migrate_finish_ipi_handler, migrate_ready,
+    Scheduler, send_migrate_memcpy_msg, unmap_migrate_vm_mem, UPDATE_IMG_BASE_ADDR, update_request, vcpu_idle, vm,
+    vm_if_copy_mem_map, vm_if_dirty_mem_map, vm_if_get_cpu_id, vm_if_ivc_arg, vm_if_ivc_arg_ptr,
+    vm_if_mem_map_page_num, vm_if_set_ivc_arg_ptr, vm_fault_get, VM_NUM_MAX, migrate_stream_size, sys_power_request,
+    SysPowerEvent,
 };
-use crate::lib::{func_barrier, memcpy_safe, round_up, set_barrier_num, trace};
+use crate::kernel::{migrate_cancel, migrate_progress_converged, migrate_progress_finish, migrate_progress_get};
+use crate::kernel::{migrate_progress_round, migrate_set_max_downtime, vcpu_run};
+use crate::kernel::{dirty_log_fetch, dirty_log_start, dirty_log_stop};
+use crate::kernel::{hot_patch_applied, hot_patch_apply, hot_patch_revert};
+use crate::kernel::{trace_map, trace_set_kind, trace_set_mask, TraceKind, vm_stats_get};
+use crate::kernel::{migrate_postcopy_allowed, migrate_postcopy_fill, migrate_postcopy_init, migrate_progress_postcopy};
+use crate::kernel::{migrate_compress_apply, migrate_compress_dirty, migrate_compress_exit, migrate_compress_receive_init};
+use crate::kernel::{
+    vm_snapshot_boot, vm_snapshot_restore, vm_snapshot_save, vm_snapshot_save_done, vm_snapshot_save_ipi_handler,
+};
+use crate::lib::{func_barrier, memcpy_safe, set_barrier_num, trace};
 use crate::lib::unilib::*;
-use crate::vmm::{get_vm_id, vmm_boot_vm, vmm_list_vm, vmm_migrate_boot, vmm_reboot_vm, vmm_remove_vm};
+use crate::vmm::{get_vm_id, vmm_boot_vm, vmm_list_vm, vmm_migrate_boot, vmm_reboot_vm, vmm_remove_vm, vmm_shutdown_vm};
+use crate::vmm::{vmm_pause_vm, vmm_resume_vm};
 
 pub static VM_STATE_FLAG: Mutex<usize> = Mutex::new(0);
 
@@ -44,6 +56,9 @@ pub const VM_CONTEXT_RECEIVE: usize = 2;
 pub const MIGRATE_SEND: usize = 3;
 pub const MIGRATE_RECEIVE: usize = 4;
 pub const LIVE_UPDATE_IMG: usize = 5;
+// optional, see migrate_compress
+pub const MIGRATE_ZERO_BITMAP: usize = 6;
+pub const MIGRATE_XBZRLE: usize = 7;
 
 // hvc_fid
 pub const HVC_SYS: usize = 0;
@@ -58,6 +73,9 @@ pub const HVC_SYS_REBOOT: usize = 0;
 pub const HVC_SYS_SHUTDOWN: usize = 1;
 pub const HVC_SYS_UPDATE: usize = 3;
 pub const HVC_SYS_TEST: usize = 4;
+// function-level patch of the running image, see hot_patch
+pub const HVC_SYS_HOT_PATCH: usize = 5;
+pub const HVC_SYS_HOT_PATCH_REVERT: usize = 6;
 
 // hvc_vmm_event
 pub const HVC_VMM_LIST_VM: usize = 0;
@@ -80,6 +98,37 @@ pub const HVC_VMM_MIGRATE_FINISH: usize = 13;
 pub const HVC_VMM_MIGRATE_INIT_VM: usize = 14;
 pub const HVC_VMM_MIGRATE_VM_BOOT: usize = 15;
 pub const HVC_VMM_VM_REMOVE: usize = 16;
+// notification to MVM only, see HvcHealthMsg
+pub const HVC_VMM_VM_HEALTH: usize = 17;
+pub const HVC_VMM_GET_VM_FAULT: usize = 18;
+pub const HVC_VMM_PAUSE_VM: usize = 19;
+pub const HVC_VMM_RESUME_VM: usize = 20;
+pub const HVC_VMM_SNAPSHOT_SAVE: usize = 21;
+pub const HVC_VMM_SNAPSHOT_SAVE_DONE: usize = 22;
+pub const HVC_VMM_SNAPSHOT_RESTORE: usize = 23;
+pub const HVC_VMM_SNAPSHOT_BOOT: usize = 24;
+// for receiver: apply zero pages and xbzrle records of a round
+pub const HVC_VMM_MIGRATE_APPLY: usize = 25;
+// for sender: abort the migration and resume the VM
+pub const HVC_VMM_MIGRATE_CANCEL: usize = 26;
+pub const HVC_VMM_MIGRATE_PROGRESS: usize = 27;
+pub const HVC_VMM_MIGRATE_SET_DOWNTIME: usize = 28;
+// for sender: stop the VM now and serve the rest of its memory on demand
+pub const HVC_VMM_MIGRATE_POSTCOPY: usize = 29;
+// for receiver: boot the VM with the pages not received yet, and fill them
+pub const HVC_VMM_MIGRATE_POSTCOPY_BOOT: usize = 30;
+pub const HVC_VMM_MIGRATE_PAGE_FILL: usize = 31;
+// dirty page logging without migration, see dirty_log
+pub const HVC_VMM_DIRTY_LOG_START: usize = 32;
+pub const HVC_VMM_DIRTY_LOG_STOP: usize = 33;
+pub const HVC_VMM_DIRTY_LOG_FETCH: usize = 34;
+// per cpu trace rings, see trace_ring
+pub const HVC_VMM_TRACE_SET_MASK: usize = 35;
+pub const HVC_VMM_TRACE_MAP: usize = 36;
+// copy HvcVmStats of a VM to the MVM
+pub const HVC_VMM_VM_STATS: usize = 37;
+// ipi only: save the gic state of a vcpu of a VM being saved, see vm_snapshot_save
+pub const HVC_VMM_SNAPSHOT_SAVE_GIC: usize = 38;
 
 // hvc_ivc_event
 pub const HVC_IVC_UPDATE_MQ: usize = 0;
@@ -122,6 +171,14 @@ pub const HVC_CONFIG_PASSTHROUGH_DEVICE_IRQS: usize = 6;
 pub const HVC_CONFIG_PASSTHROUGH_DEVICE_STREAMS_IDS: usize = 7;
 pub const HVC_CONFIG_DTB_DEVICE: usize = 8;
 pub const HVC_CONFIG_UPLOAD_KERNEL_IMAGE: usize = 9;
+pub const HVC_CONFIG_CPU_RT: usize = 10;
+pub const HVC_CONFIG_SCHED_MAJOR_FRAME: usize = 11;
+pub const HVC_CONFIG_SCHED_WINDOW: usize = 12;
+pub const HVC_CONFIG_HEALTH: usize = 13;
+pub const HVC_CONFIG_CPU_ID_REG: usize = 14;
+pub const HVC_CONFIG_SMC_RANGE: usize = 15;
+pub const HVC_CONFIG_SMC_RANGE_CLEAR: usize = 16;
+pub const HVC_CONFIG_SMC_RANGE_LIST: usize = 17;
 
 #[cfg(feature = "tx2")]
 pub const HVC_IRQ: usize = 32 + 0x20;
@@ -136,6 +193,8 @@ pub enum HvcGuestMsg {
     Manage(HvcManageMsg),
     Migrate(HvcMigrateMsg),
     UniLib(HvcUniLibMsg),
+    Ivc(HvcIvcMsg),
+    Health(HvcHealthMsg),
 }
 
 #[repr(C)]
@@ -154,6 +213,8 @@ pub struct HvcManageMsg {
 pub const MIGRATE_START: usize = 0;
 pub const MIGRATE_COPY: usize = 1;
 pub const MIGRATE_FINISH: usize = 2;
+// for receiver in post-copy: page_num is the index of the page the VM is waiting for
+pub const MIGRATE_PAGE_REQUEST: usize = 3;
 
 #[repr(C)]
 pub struct HvcMigrateMsg {
@@ -174,6 +235,31 @@ pub struct HvcUniLibMsg {
     pub arg_3: usize,
 }
 
+// reason is VmHealthEvent, action is VmHealthPolicy
+#[repr(C)]
+pub struct HvcHealthMsg {
+    pub fid: usize,
+    pub event: usize,
+    pub vm_id: usize,
+    pub reason: usize,
+    pub action: usize,
+    pub restarts: usize,
+}
+
+// max payload of an inter-VM message, HvcIvcMsg must fit in a slot of the hvc message ring
+pub const IVC_MSG_MAX_LEN: usize = 128;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct HvcIvcMsg {
+    pub fid: usize,
+    pub event: usize,
+    pub src_vmid: usize,
+    pub msg_id: usize,
+    pub len: usize,
+    pub data: [u8; IVC_MSG_MAX_LEN],
+}
+
 pub fn add_share_mem(mem_type: usize, base: usize) {
     let mut list = SHARE_MEM_LIST.lock();
     list.insert(mem_type, base);
@@ -189,6 +275,10 @@ pub fn get_share_mem(mem_type: usize) -> usize {
     }
 }
 
+pub fn find_share_mem(mem_type: usize) -> Option<usize> {
+    SHARE_MEM_LIST.lock().get(&mem_type).copied()
+}
+
 pub fn hvc_guest_handler(
     hvc_type: usize,
     event: usize,
@@ -201,9 +291,9 @@ pub fn hvc_guest_handler(
     x6: usize,
 ) -> Result<usize, ()> {
     match hvc_type {
-        HVC_SYS => hvc_sys_handler(event, x0),
-        HVC_VMM => hvc_vmm_handler(event, x0, x1),
-        HVC_IVC => hvc_ivc_handler(event, x0, x1),
+        HVC_SYS => hvc_sys_handler(event, x0, x1),
+        HVC_VMM => hvc_vmm_handler(event, x0, x1, x2, x3),
+        HVC_IVC => hvc_ivc_handler(event, x0, x1, x2),
         HVC_MEDIATED => hvc_mediated_handler(event, x0, x1),
         HVC_CONFIG => hvc_config_handler(event, x0, x1, x2, x3, x4, x5, x6),
         HVC_UNILIB => hvc_unilib_handler(event, x0, x1, x2),
@@ -235,6 +325,51 @@ fn hvc_config_handler(
         HVC_CONFIG_PASSTHROUGH_DEVICE_STREAMS_IDS => vm_cfg_add_passthrough_device_streams_ids(x0, x1, x2),
         HVC_CONFIG_DTB_DEVICE => vm_cfg_add_dtb_dev(x0, x1, x2, x3, x4, x5, x6),
         HVC_CONFIG_UPLOAD_KERNEL_IMAGE => vm_cfg_upload_kernel_image(x0, x1, x2, x3, x4),
+        HVC_CONFIG_CPU_RT => {
+            if active_vm_id() != 0 {
+                println!("hvc_config_handler: VM[{}] can not set rt parameters", active_vm_id());
+                return Err(());
+            }
+            vm_cfg_set_cpu_rt(x0, x1, x2, x3)
+        }
+        HVC_CONFIG_SCHED_MAJOR_FRAME | HVC_CONFIG_SCHED_WINDOW => {
+            if active_vm_id() != 0 {
+                println!("hvc_config_handler: VM[{}] can not plan the partitions", active_vm_id());
+                return Err(());
+            }
+            match event {
+                HVC_CONFIG_SCHED_MAJOR_FRAME => vm_cfg_set_sched_major_frame(x0, x1, x2),
+                _ => vm_cfg_add_sched_window(x0, x1, x2, x3),
+            }
+        }
+        HVC_CONFIG_HEALTH => {
+            if active_vm_id() != 0 {
+                println!("hvc_config_handler: VM[{}] can not set health policies", active_vm_id());
+                return Err(());
+            }
+            vm_cfg_set_health(x0, x1, x2, x3)
+        }
+        HVC_CONFIG_CPU_ID_REG => {
+            if active_vm_id() != 0 {
+                println!("hvc_config_handler: VM[{}] can not set cpu id regs", active_vm_id());
+                return Err(());
+            }
+            vm_cfg_set_cpu_id_reg(x0, x1, x2, x3)
+        }
+        HVC_CONFIG_SMC_RANGE | HVC_CONFIG_SMC_RANGE_CLEAR | HVC_CONFIG_SMC_RANGE_LIST => {
+            if active_vm_id() != 0 {
+                println!(
+                    "hvc_config_handler: VM[{}] can not configure SMC ranges",
+                    active_vm_id()
+                );
+                return Err(());
+            }
+            match event {
+                HVC_CONFIG_SMC_RANGE => vm_cfg_add_smc_range(x0, x1, x2, x3),
+                HVC_CONFIG_SMC_RANGE_CLEAR => vm_cfg_clear_smc_range(x0),
+                _ => vm_cfg_list_smc_range(x0, x1, x2),
+            }
+        }
         _ => {
             println!("hvc_config_handler unknown event {}", event);
             Err(())
@@ -242,9 +377,30 @@ fn hvc_config_handler(
     }
 }
 
-fn hvc_sys_handler(event: usize, x0: usize) -> Result<usize, ()> {
+fn hvc_sys_handler(event: usize, x0: usize, x1: usize) -> Result<usize, ()> {
     match event {
+        HVC_SYS_REBOOT | HVC_SYS_SHUTDOWN => {
+            if active_vm_id() != 0 {
+                println!("hvc_sys_handler: VM[{}] can not power the system", active_vm_id());
+                return Err(());
+            }
+            if event == HVC_SYS_REBOOT {
+                sys_power_request(SysPowerEvent::Reboot)
+            } else {
+                sys_power_request(SysPowerEvent::Shutdown)
+            }
+        }
         HVC_SYS_UPDATE => {
+            if active_vm_id() != 0 {
+                println!("hvc_sys_handler: VM[{}] can not update the hypervisor", active_vm_id());
+                return Err(());
+            }
+            // the patched text is not carried over
+            if hot_patch_applied() {
+                println!("hvc_sys_handler: revert the hot patches before live update");
+                return Err(());
+            }
+            live_update_validate(UPDATE_IMG_BASE_ADDR, x0)?;
             mem_heap_region_reserve(UPDATE_IMG_BASE_ADDR, x0);
             update_request();
             Ok(0)
@@ -254,11 +410,22 @@ fn hvc_sys_handler(event: usize, x0: usize) -> Result<usize, ()> {
             crate::device::virtio_net_announce(vm);
             Ok(0)
         }
+        HVC_SYS_HOT_PATCH | HVC_SYS_HOT_PATCH_REVERT => {
+            if active_vm_id() != 0 {
+                println!("hvc_sys_handler: VM[{}] can not patch the hypervisor", active_vm_id());
+                return Err(());
+            }
+            if event == HVC_SYS_HOT_PATCH {
+                hot_patch_apply(x0, x1)
+            } else {
+                hot_patch_revert(x0)
+            }
+        }
         _ => Err(()),
     }
 }
 
-fn hvc_vmm_handler(event: usize, x0: usize, _x1: usize) -> Result<usize, ()> {
+fn hvc_vmm_handler(event: usize, x0: usize, x1: usize, x2: usize, x3: usize) -> Result<usize, ()> {
     match event {
         HVC_VMM_LIST_VM => vmm_list_vm(x0),
         HVC_VMM_GET_VM_STATE => {
@@ -269,12 +436,27 @@ fn hvc_vmm_handler(event: usize, x0: usize, _x1: usize) -> Result<usize, ()> {
             Ok(HVC_FINISH)
         }
         HVC_VMM_SHUTDOWN_VM => {
-            todo!();
+            vmm_shutdown_vm(x0);
+            Ok(HVC_FINISH)
         }
         HVC_VMM_REBOOT_VM => {
             vmm_reboot_vm(x0);
             Ok(HVC_FINISH)
         }
+        HVC_VMM_GET_VM_DEF_CFG | HVC_VMM_GET_VM_CFG | HVC_VMM_SET_VM_CFG => {
+            if active_vm_id() != 0 {
+                println!(
+                    "hvc_vmm_handler: VM[{}] can not access the config of a VM",
+                    active_vm_id()
+                );
+                return Err(());
+            }
+            match event {
+                HVC_VMM_GET_VM_DEF_CFG => vm_cfg_get_def_cfg(x0),
+                HVC_VMM_GET_VM_CFG => vm_cfg_get_cfg(x0, x1),
+                _ => vm_cfg_set_cfg(x0, x1),
+            }
+        }
         HVC_VMM_GET_VM_ID => {
             get_vm_id(x0);
             Ok(HVC_FINISH)
@@ -315,16 +497,9 @@ fn hvc_vmm_handler(event: usize, x0: usize, _x1: usize) -> Result<usize, ()> {
             Ok(HVC_FINISH)
         }
         HVC_VMM_MIGRATE_MEMCPY => {
-            let dirty_mem_num = vm_if_mem_map_dirty_sum(x0);
             // let cpu_trgt = vm_if_get_cpu_id(x0);
-            if dirty_mem_num < DIRTY_MEM_THRESHOLD {
-                // Idle live vm, copy dirty mem and vm register struct
-                let trgt_vm = vm(x0).unwrap();
-                set_barrier_num(trgt_vm.cpu_num());
-                for vcpu_id in 0..trgt_vm.cpu_num() {
-                    let pcpu_id = trgt_vm.vcpuid_to_pcpuid(vcpu_id).unwrap();
-                    send_hvc_ipi(0, x0, HVC_VMM, HVC_VMM_MIGRATE_FINISH, pcpu_id);
-                }
+            if migrate_progress_converged(x0) {
+                mvm_migrate_stop(x0);
             } else {
                 mvm_migrate_memory(x0);
                 // send_hvc_ipi(0, x0, HVC_VMM, HVC_VMM_MIGRATE_MEMCPY, cpu_trgt);
@@ -337,35 +512,22 @@ fn hvc_vmm_handler(event: usize, x0: usize, _x1: usize) -> Result<usize, ()> {
             let vm = vm(x0).unwrap();
             map_migrate_vm_mem(vm.clone(), get_share_mem(MIGRATE_RECEIVE));
             vm.context_vm_migrate_init();
+            migrate_compress_receive_init(x0);
             Ok(HVC_FINISH)
         }
-        HVC_VMM_MIGRATE_VM_BOOT => {
-            let mvm = vm(0).unwrap();
-            let vm = vm(x0).unwrap();
-
-            let size = size_of::<VMData>();
-            mvm.pt_unmap_range(get_share_mem(VM_CONTEXT_RECEIVE), round_up(size, PAGE_SIZE), true);
-            unmap_migrate_vm_mem(vm.clone(), get_share_mem(MIGRATE_RECEIVE));
-
-            vm.context_vm_migrate_restore();
-            for vcpu_id in 0..vm.cpu_num() {
-                let cpu_trgt = vm.vcpuid_to_pcpuid(vcpu_id).unwrap();
-                // send ipi to target vcpu, copy data and boot vm (in ipi copy gic data)
-                send_hvc_ipi(0, x0, HVC_VMM, HVC_VMM_MIGRATE_VM_BOOT, cpu_trgt);
-            }
-            Ok(HVC_FINISH)
-        }
+        HVC_VMM_MIGRATE_VM_BOOT => mvm_migrate_vm_boot(x0),
         HVC_VMM_MIGRATE_FINISH => {
             let mvm = vm(0).unwrap();
             let trgt_vm = vm(x0).unwrap();
-            let size = size_of::<VMData>();
-            mvm.pt_unmap_range(get_share_mem(VM_CONTEXT_SEND), round_up(size, PAGE_SIZE), true);
+            mvm.pt_unmap_range(get_share_mem(VM_CONTEXT_SEND), migrate_stream_size(), true);
             mvm.pt_unmap_range(
                 get_share_mem(MIGRATE_BITMAP),
                 PAGE_SIZE * vm_if_mem_map_page_num(x0),
                 true,
             );
             unmap_migrate_vm_mem(trgt_vm, get_share_mem(MIGRATE_SEND));
+            migrate_compress_exit(x0);
+            migrate_progress_finish(x0);
             vmm_remove_vm(x0);
             *VM_STATE_FLAG.lock() = 0;
             Ok(HVC_FINISH)
@@ -375,6 +537,114 @@ fn hvc_vmm_handler(event: usize, x0: usize, _x1: usize) -> Result<usize, ()> {
             *VM_STATE_FLAG.lock() = 0;
             Ok(HVC_FINISH)
         }
+        HVC_VMM_GET_VM_FAULT => {
+            if active_vm_id() != 0 {
+                println!(
+                    "hvc_vmm_handler: VM[{}] can not read the faults of a VM",
+                    active_vm_id()
+                );
+                return Err(());
+            }
+            vm_fault_get(x0, x1)
+        }
+        HVC_VMM_PAUSE_VM | HVC_VMM_RESUME_VM => {
+            if active_vm_id() != 0 {
+                println!("hvc_vmm_handler: VM[{}] can not pause or resume a VM", active_vm_id());
+                return Err(());
+            }
+            if event == HVC_VMM_PAUSE_VM {
+                vmm_pause_vm(x0)
+            } else {
+                vmm_resume_vm(x0)
+            }
+        }
+        HVC_VMM_SNAPSHOT_SAVE | HVC_VMM_SNAPSHOT_SAVE_DONE | HVC_VMM_SNAPSHOT_RESTORE | HVC_VMM_SNAPSHOT_BOOT => {
+            if active_vm_id() != 0 {
+                println!(
+                    "hvc_vmm_handler: VM[{}] can not take or load a snapshot",
+                    active_vm_id()
+                );
+                return Err(());
+            }
+            match event {
+                HVC_VMM_SNAPSHOT_SAVE => vm_snapshot_save(x0, x1),
+                HVC_VMM_SNAPSHOT_SAVE_DONE => vm_snapshot_save_done(x0),
+                HVC_VMM_SNAPSHOT_RESTORE => vm_snapshot_restore(x0, x1),
+                _ => vm_snapshot_boot(x0),
+            }
+        }
+        HVC_VMM_MIGRATE_APPLY => {
+            if active_vm_id() != 0 {
+                println!("hvc_vmm_handler: VM[{}] can not control a migration", active_vm_id());
+                return Err(());
+            }
+            migrate_compress_apply(x0)
+        }
+        HVC_VMM_MIGRATE_CANCEL | HVC_VMM_MIGRATE_PROGRESS | HVC_VMM_MIGRATE_SET_DOWNTIME => {
+            if active_vm_id() != 0 {
+                println!("hvc_vmm_handler: VM[{}] can not control a migration", active_vm_id());
+                return Err(());
+            }
+            match event {
+                HVC_VMM_MIGRATE_CANCEL => migrate_cancel(x0),
+                HVC_VMM_MIGRATE_PROGRESS => migrate_progress_get(x0, x1),
+                _ => migrate_set_max_downtime(x0, x1),
+            }
+        }
+        HVC_VMM_MIGRATE_POSTCOPY | HVC_VMM_MIGRATE_POSTCOPY_BOOT | HVC_VMM_MIGRATE_PAGE_FILL => {
+            if active_vm_id() != 0 {
+                println!("hvc_vmm_handler: VM[{}] can not control a migration", active_vm_id());
+                return Err(());
+            }
+            match event {
+                HVC_VMM_MIGRATE_POSTCOPY => {
+                    match vm(x0) {
+                        Some(vm) if migrate_postcopy_allowed(&vm) => {}
+                        _ => return Err(()),
+                    }
+                    if !migrate_progress_postcopy(x0) {
+                        println!("hvc_vmm: VM[{}] is not in pre-copy", x0);
+                        return Err(());
+                    }
+                    mvm_migrate_stop(x0);
+                    Ok(HVC_FINISH)
+                }
+                HVC_VMM_MIGRATE_POSTCOPY_BOOT => {
+                    migrate_postcopy_init(x0, x1)?;
+                    mvm_migrate_vm_boot(x0)
+                }
+                _ => migrate_postcopy_fill(x0, x1, x2, x3),
+            }
+        }
+        HVC_VMM_DIRTY_LOG_START | HVC_VMM_DIRTY_LOG_STOP | HVC_VMM_DIRTY_LOG_FETCH => {
+            if active_vm_id() != 0 {
+                println!("hvc_vmm_handler: VM[{}] can not log dirty pages", active_vm_id());
+                return Err(());
+            }
+            match event {
+                HVC_VMM_DIRTY_LOG_START => dirty_log_start(x0, x1, x2),
+                HVC_VMM_DIRTY_LOG_STOP => dirty_log_stop(x0),
+                _ => dirty_log_fetch(x0, x1),
+            }
+        }
+        HVC_VMM_TRACE_VMEXIT | HVC_VMM_TRACE_SET_MASK | HVC_VMM_TRACE_MAP => {
+            if active_vm_id() != 0 {
+                println!("hvc_vmm_handler: VM[{}] can not control tracing", active_vm_id());
+                return Err(());
+            }
+            match event {
+                HVC_VMM_TRACE_VMEXIT => trace_set_kind(TraceKind::VmExit, x0 != 0),
+                HVC_VMM_TRACE_SET_MASK => trace_set_mask(x0),
+                _ => trace_map(),
+            }
+        }
+        HVC_VMM_VM_STATS => {
+            if active_vm_id() != 0 {
+                println!("hvc_vmm_handler: VM[{}] can not read the stats", active_vm_id());
+                return Err(());
+            }
+            vm_stats_get(x0, x1)
+        }
         _ => {
             println!("hvc_vmm unknown event {}", event);
             Err(())
@@ -382,7 +652,7 @@ fn hvc_vmm_handler(event: usize, x0: usize, _x1: usize) -> Result<usize, ()> {
     }
 }
 
-fn hvc_ivc_handler(event: usize, x0: usize, x1: usize) -> Result<usize, ()> {
+fn hvc_ivc_handler(event: usize, x0: usize, x1: usize, x2: usize) -> Result<usize, ()> {
     match event {
         HVC_IVC_UPDATE_MQ => {
             if ivc_update_mq(x0, x1) {
@@ -391,6 +661,11 @@ fn hvc_ivc_handler(event: usize, x0: usize, x1: usize) -> Result<usize, ()> {
                 Err(())
             }
         }
+        HVC_IVC_SEND_MSG => ivc_send_msg(x0, x1, x2),
+        HVC_IVC_BROADCAST_MSG => ivc_broadcast_msg(x0, x1),
+        HVC_IVC_ACK => ivc_ack(x0),
+        HVC_IVC_INIT_KEEP_ALIVE => ivc_init_keep_alive(x0, x1),
+        HVC_IVC_KEEP_ALIVE => ivc_keep_alive(),
         HVC_IVC_SHARE_MEM => {
             let vm = active_vm().unwrap();
             let base = vm.share_mem_base();
@@ -505,6 +780,22 @@ pub fn hvc_send_msg_to_vm(vm_id: usize, guest_msg: &HvcGuestMsg) -> bool {
             );
             (msg.fid, msg.event)
         }
+        HvcGuestMsg::Ivc(msg) => {
+            memcpy_safe(
+                target_addr as *const u8,
+                msg as *const _ as *const u8,
+                size_of::<HvcIvcMsg>(),
+            );
+            (msg.fid, msg.event)
+        }
+        HvcGuestMsg::Health(msg) => {
+            memcpy_safe(
+                target_addr as *const u8,
+                msg as *const _ as *const u8,
+                size_of::<HvcHealthMsg>(),
+            );
+            (msg.fid, msg.event)
+        }
     };
 
     let cpu_trgt = vm_if_get_cpu_id(vm_id);
@@ -564,10 +855,17 @@ pub fn hvc_ipi_handler(msg: &IpiMessage) {
                     hvc_guest_notify(msg.trgt_vmid);
                 }
                 HVC_VMM => match msg.event {
-                    HVC_VMM_MIGRATE_START => {
+                    HVC_VMM_MIGRATE_START | HVC_VMM_VM_HEALTH => {
                         // in mvm
                         hvc_guest_notify(msg.trgt_vmid);
                     }
+                    HVC_VMM_SNAPSHOT_SAVE => {
+                        // VM0 receives it when the image is ready
+                        hvc_guest_notify(msg.trgt_vmid);
+                    }
+                    HVC_VMM_SNAPSHOT_SAVE_GIC => {
+                        vm_snapshot_save_ipi_handler(msg.trgt_vmid);
+                    }
                     HVC_VMM_MIGRATE_FINISH => {
                         // 被迁移VM收到该ipi标志vcpu_idle，VM0收到该ipi标志最后一次内存拷贝
                         if current_cpu().id == 0 {
@@ -627,6 +925,16 @@ pub fn hvc_ipi_handler(msg: &IpiMessage) {
                         }
                         vmm_migrate_boot();
                     }
+                    HVC_VMM_MIGRATE_CANCEL => {
+                        // the vcpu stopped for the last copy is still the active one of this core
+                        match current_cpu().active_vcpu.clone() {
+                            Some(vcpu) if vcpu.vm_id() == msg.trgt_vmid => {
+                                gicc_clear_current_irq(true);
+                                vcpu_run(false);
+                            }
+                            _ => {}
+                        }
+                    }
                     _ => {}
                 },
                 HVC_CONFIG => match msg.event {
@@ -637,7 +945,7 @@ pub fn hvc_ipi_handler(msg: &IpiMessage) {
                         todo!();
                     }
                 },
-                HVC_UNILIB => {
+                HVC_UNILIB | HVC_IVC => {
                     hvc_guest_notify(msg.trgt_vmid);
                 }
                 _ => {
@@ -656,10 +964,42 @@ fn mvm_migrate_memory(trgt_vmid: usize) {
     let vm = vm(trgt_vmid);
     vm.as_ref().unwrap().pt_read_only();
     // tlb_invalidate_guest_all();
+    migrate_progress_round(trgt_vmid);
     vm_if_copy_mem_map(trgt_vmid);
+    migrate_compress_dirty(trgt_vmid);
     send_migrate_memcpy_msg(trgt_vmid);
 }
 
+// stop the vcpus for the last copy, see HVC_VMM_MIGRATE_FINISH of hvc_ipi_handler
+fn mvm_migrate_stop(trgt_vmid: usize) {
+    // Idle live vm, copy dirty mem and vm register struct
+    let trgt_vm = vm(trgt_vmid).unwrap();
+    set_barrier_num(trgt_vm.cpu_num());
+    for vcpu_id in 0..trgt_vm.cpu_num() {
+        let pcpu_id = trgt_vm.vcpuid_to_pcpuid(vcpu_id).unwrap();
+        send_hvc_ipi(0, trgt_vmid, HVC_VMM, HVC_VMM_MIGRATE_FINISH, pcpu_id);
+    }
+}
+
+fn mvm_migrate_vm_boot(trgt_vmid: usize) -> Result<usize, ()> {
+    let mvm = vm(0).unwrap();
+    let vm = vm(trgt_vmid).unwrap();
+
+    mvm.pt_unmap_range(get_share_mem(VM_CONTEXT_RECEIVE), migrate_stream_size(), true);
+    unmap_migrate_vm_mem(vm.clone(), get_share_mem(MIGRATE_RECEIVE));
+
+    // the stream is checked against the compression mode of the receiver before it is dropped
+    let restored = vm.context_vm_migrate_restore();
+    migrate_compress_exit(trgt_vmid);
+    restored?;
+    for vcpu_id in 0..vm.cpu_num() {
+        let cpu_trgt = vm.vcpuid_to_pcpuid(vcpu_id).unwrap();
+        // send ipi to target vcpu, copy data and boot vm (in ipi copy gic data)
+        send_hvc_ipi(0, trgt_vmid, HVC_VMM, HVC_VMM_MIGRATE_VM_BOOT, cpu_trgt);
+    }
+    Ok(HVC_FINISH)
+}
+
 pub fn hvc_init() {
     if !ipi_register(IpiType::IpiTHvc, hvc_ipi_handler) {
         panic!("hvc_init: failed to register hvc ipi {}", IpiType::IpiTHvc as usize)
@@ -14,7 +14,11 @@ use spin::Mutex;
 
 use crate::arch::{interrupt_arch_ipi_send, interrupt_arch_vm_inject};
 use crate::arch::{GIC_PRIVINT_NUM, interrupt_arch_vm_register};
-use crate::kernel::{current_cpu, hyper_fresh_ipi_handler, ipi_irq_handler, IpiInnerMsg, IpiMessage, Vcpu, VcpuState};
+use crate::kernel::{
+    current_cpu, hot_patch_ipi_handler, hyper_fresh_ipi_handler, ipi_irq_handler, IpiInnerMsg, IpiMessage, Vcpu,
+    VcpuState,
+};
+use crate::kernel::{trace_enabled, trace_event, TraceKind, vcpu_wfi_wakeup, vm_health_vcpu_wfi};
 use crate::kernel::{ipi_register, IpiType, Vm};
 use crate::lib::{BitAlloc, BitAlloc256, BitAlloc4K, BitMap};
 use crate::vmm::vmm_ipi_handler;
@@ -22,6 +26,8 @@ use crate::vmm::vmm_ipi_handler;
 pub const INTERRUPT_NUM_MAX: usize = 1024;
 pub const INTERRUPT_IRQ_HYPERVISOR_TIMER: usize = 26;
 pub const INTERRUPT_IRQ_GUEST_TIMER: usize = 27;
+// overflow PPI of the PMU, the one of SBSA, a guest with a vPMU takes it as its own
+pub const INTERRUPT_IRQ_GUEST_PMU: usize = 23;
 pub const INTERRUPT_IRQ_IPI: usize = 1;
 
 pub static INTERRUPT_HYPER_BITMAP: Mutex<BitMap<BitAlloc256>> = Mutex::new(BitAlloc4K::default());
@@ -106,6 +112,9 @@ pub fn interrupt_init() {
         if !ipi_register(IpiType::IpiTHyperFresh, hyper_fresh_ipi_handler) {
             panic!("interrupt_init: failed to register ipi hyper fresh");
         }
+        if !ipi_register(IpiType::IpiTHotPatch, hot_patch_ipi_handler) {
+            panic!("interrupt_init: failed to register ipi hot patch");
+        }
 
         println!("Interrupt init ok");
     }
@@ -155,7 +164,15 @@ pub fn interrupt_vm_inject(vm: Vm, vcpu: Vcpu, int_id: usize, _source: usize) {
         );
         return;
     }
-    interrupt_arch_vm_inject(vm, vcpu, int_id);
+    let vm_id = vm.id();
+    if trace_enabled(TraceKind::IrqInject) {
+        trace_event(TraceKind::IrqInject, [vm_id, vcpu.id(), int_id, 0]);
+    }
+    vcpu.stats_irq_inject();
+    interrupt_arch_vm_inject(vm, vcpu.clone(), int_id);
+    // the vcpu is not idle any more once it has an interrupt to take
+    vm_health_vcpu_wfi(vm_id, vcpu.id(), false);
+    vcpu_wfi_wakeup(vcpu);
 }
 
 pub fn interrupt_handler(int_id: usize, src: usize) -> bool {
@@ -15,7 +15,7 @@ use spin::Mutex;
 use crate::arch::INTERRUPT_IRQ_IPI;
 use crate::board::PLAT_DESC;
 use crate::device::{VirtioMmio, Virtq};
-use crate::kernel::{CPU_IF_LIST, current_cpu, interrupt_cpu_ipi_send};
+use crate::kernel::{CPU_IF_LIST, current_cpu, interrupt_cpu_ipi_send, trace_event, TraceKind};
 use crate::vmm::VmmEvent;
 
 use super::Vm;
@@ -45,7 +45,7 @@ pub struct IpiInitcMessage {
     pub event: InitcEvent,
     pub vm_id: usize,
     pub int_id: u16,
-    pub val: u8,
+    pub val: usize,
 }
 
 /*
@@ -115,6 +115,7 @@ pub enum IpiType {
     IpiTVMM = 5,
     IpiTMediatedDev = 6,
     IpiTIntInject = 8,
+    IpiTHotPatch = 9,
 }
 
 #[derive(Clone)]
@@ -163,6 +164,7 @@ pub fn ipi_irq_handler() {
     while !msg.is_none() {
         let ipi_msg = msg.unwrap();
         let ipi_type = ipi_msg.ipi_type as usize;
+        trace_event(TraceKind::IpiRecv, [ipi_type, 0, 0, 0]);
 
         let ipi_handler_list = IPI_HANDLER_LIST.lock();
         let len = ipi_handler_list.len();
@@ -204,6 +206,7 @@ fn ipi_send(target_id: usize, msg: IpiMessage) -> bool {
         return false;
     }
 
+    trace_event(TraceKind::IpiSend, [target_id, msg.ipi_type as usize, 0, 0]);
     let mut cpu_if_list = CPU_IF_LIST.lock();
     cpu_if_list[target_id].msg_queue.push(msg);
     interrupt_cpu_ipi_send(target_id, INTERRUPT_IRQ_IPI);
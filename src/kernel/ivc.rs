@@ -8,13 +8,22 @@
 // MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
 use spin::Mutex;
 
 use crate::arch::PAGE_SIZE;
 use crate::arch::PTE_S2_NORMAL;
+use crate::config::{vm_cfg_entry, vm_id_list, VmHealthConfig};
 use crate::kernel::{
-    active_vm, current_cpu, mem_pages_alloc, Vm, vm_if_set_ivc_arg, vm_if_set_ivc_arg_ptr, vm_ipa2pa, VM_NUM_MAX,
+    active_vm, active_vm_id, current_cpu, hvc_send_msg_to_vm, HvcGuestMsg, HvcIvcMsg, mem_pages_alloc, vm,
+    vm_health_report, Vm, VmHealthEvent, vm_if_ivc_arg, vm_if_set_ivc_arg, vm_if_set_ivc_arg_ptr, vm_ipa2pa, HVC_IVC,
+    HVC_IVC_SEND_MSG, IVC_MSG_MAX_LEN, VM_NUM_MAX, vm_if_get_state, VmState,
 };
+use crate::kernel::{live_update_decode_with, live_update_encode_with, live_update_entry_codec, StreamReader, StreamWriter};
+use crate::kernel::{LiveUpdatable, LiveUpdateEntry, LiveUpdateFrom, LiveUpdateStage};
+use crate::lib::{memcpy_safe, time_current_us};
 use crate::mm::PageFrame;
 
 // todo: need to rewrite for more vm
@@ -39,9 +48,440 @@ pub fn ivc_update_mq(receive_ipa: usize, cfg_ipa: usize) -> bool {
     let val = vm_id;
     current_cpu().set_gpr(idx, val);
     // println!("VM {} update message", vm_id);
+
+    // messages may arrive before the receiver is ready
+    IVC_MAILBOX_LIST.lock().entry(vm_id).or_insert(IvcMailbox::default());
+    ivc_mailbox_deliver(vm_id);
     true
 }
 
+// max pending messages of a mailbox
+const IVC_MAILBOX_DEPTH: usize = 16;
+
+/*
+ * Each VM has a bounded mailbox. Messages are delivered one by one through the hvc message
+ * ring of the receiver with HVC_IRQ, the next one is delivered after the receiver acks the
+ * current one, so a slow receiver makes HVC_IVC_SEND_MSG fail instead of overwriting the ring.
+ */
+#[derive(Clone)]
+struct IvcMailbox {
+    msgs: Vec<HvcIvcMsg>,
+    next_id: usize,
+    // the head message is in the ring of receiver, waiting for ack
+    delivered: bool,
+}
+
+impl IvcMailbox {
+    const fn default() -> IvcMailbox {
+        IvcMailbox {
+            msgs: Vec::new(),
+            next_id: 1,
+            delivered: false,
+        }
+    }
+
+    // version 1 of IVC_MAILBOX_LIST
+    fn encode(&self, w: &mut StreamWriter) {
+        w.put_usize(self.next_id);
+        w.put_bool(self.delivered);
+        w.put_u32(self.msgs.len() as u32);
+        for msg in self.msgs.iter() {
+            for val in [msg.fid, msg.event, msg.src_vmid, msg.msg_id, msg.len] {
+                w.put_usize(val);
+            }
+            w.put(&msg.data);
+        }
+    }
+
+    fn decode(r: &mut StreamReader, version: usize) -> Result<IvcMailbox, ()> {
+        if version != 1 {
+            return Err(());
+        }
+        let next_id = r.get_usize()?;
+        let delivered = r.get_bool()?;
+        let mut msgs = Vec::new();
+        for _ in 0..r.get_count(IVC_MAILBOX_DEPTH)? {
+            let mut msg = HvcIvcMsg {
+                fid: r.get_usize()?,
+                event: r.get_usize()?,
+                src_vmid: r.get_usize()?,
+                msg_id: r.get_usize()?,
+                len: r.get_usize()?,
+                data: [0; IVC_MSG_MAX_LEN],
+            };
+            msg.data.copy_from_slice(r.get(IVC_MSG_MAX_LEN)?);
+            msgs.push(msg);
+        }
+        Ok(IvcMailbox {
+            msgs,
+            next_id,
+            delivered,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct IvcKeepAlive {
+    interval: usize,
+    // a VM is lost after missing this many heartbeats
+    miss: usize,
+    last: usize,
+    lost: bool,
+}
+
+impl IvcKeepAlive {
+    // version 1 of IVC_KEEP_ALIVE_LIST
+    fn encode(&self, w: &mut StreamWriter) {
+        w.put_usize(self.interval);
+        w.put_usize(self.miss);
+        w.put_usize(self.last);
+        w.put_bool(self.lost);
+    }
+
+    fn decode(r: &mut StreamReader, version: usize) -> Result<IvcKeepAlive, ()> {
+        if version != 1 {
+            return Err(());
+        }
+        Ok(IvcKeepAlive {
+            interval: r.get_usize()?,
+            miss: r.get_usize()?,
+            last: r.get_usize()?,
+            lost: r.get_bool()?,
+        })
+    }
+}
+
+static IVC_MAILBOX_LIST: Mutex<BTreeMap<usize, IvcMailbox>> = Mutex::new(BTreeMap::new());
+static IVC_KEEP_ALIVE_LIST: Mutex<BTreeMap<usize, IvcKeepAlive>> = Mutex::new(BTreeMap::new());
+
+impl LiveUpdatable for Mutex<BTreeMap<usize, IvcMailbox>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        let mut list = self.lock();
+        for (vm_id, mailbox) in src.lock().iter() {
+            list.insert(*vm_id, mailbox.clone());
+        }
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().clear();
+    }
+}
+
+impl LiveUpdatable for Mutex<BTreeMap<usize, IvcKeepAlive>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        let mut list = self.lock();
+        for (vm_id, keep_alive) in src.lock().iter() {
+            list.insert(*vm_id, *keep_alive);
+        }
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().clear();
+    }
+}
+
+// the mailboxes and the keep-alive registry VM by VM, see LiveUpdateEncode
+fn ivc_mailbox_encode(state: *const (), buf: usize, cap: usize) -> Result<usize, ()> {
+    live_update_encode_with(state, buf, cap, |list: &Mutex<BTreeMap<usize, IvcMailbox>>, w| {
+        let list = list.lock();
+        w.put_u32(list.len() as u32);
+        for (vm_id, mailbox) in list.iter() {
+            w.put_usize(*vm_id);
+            mailbox.encode(w);
+        }
+    })
+}
+
+fn ivc_mailbox_decode(state: *const (), data: &[u8], version: usize) -> Result<(), ()> {
+    live_update_decode_with(state, data, |list: &Mutex<BTreeMap<usize, IvcMailbox>>, r| {
+        let mut list = list.lock();
+        for _ in 0..r.get_count(VM_NUM_MAX)? {
+            let vm_id = r.get_usize()?;
+            list.insert(vm_id, IvcMailbox::decode(r, version)?);
+        }
+        Ok(())
+    })
+}
+
+fn ivc_keep_alive_encode(state: *const (), buf: usize, cap: usize) -> Result<usize, ()> {
+    live_update_encode_with(state, buf, cap, |list: &Mutex<BTreeMap<usize, IvcKeepAlive>>, w| {
+        let list = list.lock();
+        w.put_u32(list.len() as u32);
+        for (vm_id, keep_alive) in list.iter() {
+            w.put_usize(*vm_id);
+            keep_alive.encode(w);
+        }
+    })
+}
+
+fn ivc_keep_alive_decode(state: *const (), data: &[u8], version: usize) -> Result<(), ()> {
+    live_update_decode_with(state, data, |list: &Mutex<BTreeMap<usize, IvcKeepAlive>>, r| {
+        let mut list = list.lock();
+        for _ in 0..r.get_count(VM_NUM_MAX)? {
+            let vm_id = r.get_usize()?;
+            list.insert(vm_id, IvcKeepAlive::decode(r, version)?);
+        }
+        Ok(())
+    })
+}
+
+pub static IVC_MAILBOX_UPDATE: LiveUpdateEntry = live_update_entry_codec(
+    "IVC_MAILBOX_LIST",
+    LiveUpdateStage::Fresh,
+    false,
+    1,
+    &IVC_MAILBOX_LIST,
+    ivc_mailbox_encode,
+    ivc_mailbox_decode,
+);
+pub static IVC_KEEP_ALIVE_UPDATE: LiveUpdateEntry = live_update_entry_codec(
+    "IVC_KEEP_ALIVE_LIST",
+    LiveUpdateStage::Fresh,
+    false,
+    1,
+    &IVC_KEEP_ALIVE_LIST,
+    ivc_keep_alive_encode,
+    ivc_keep_alive_decode,
+);
+
+// the mailbox of a VM is set up by its own HVC_IVC_UPDATE_MQ or by the MVM sending to it
+fn ivc_mailbox_push(src_vmid: usize, trgt_vmid: usize, data: &[u8]) -> Result<usize, ()> {
+    if vm(trgt_vmid).is_none() {
+        return Err(());
+    }
+    {
+        let mut mailbox_list = IVC_MAILBOX_LIST.lock();
+        if src_vmid != 0 && !mailbox_list.contains_key(&trgt_vmid) {
+            println!(
+                "ivc_mailbox_push: VM[{}] can not set up the mailbox of VM[{}]",
+                src_vmid, trgt_vmid
+            );
+            return Err(());
+        }
+        let mailbox = mailbox_list.entry(trgt_vmid).or_insert(IvcMailbox::default());
+        if mailbox.msgs.len() >= IVC_MAILBOX_DEPTH {
+            return Err(());
+        }
+        let mut msg = HvcIvcMsg {
+            fid: HVC_IVC,
+            event: HVC_IVC_SEND_MSG,
+            src_vmid,
+            msg_id: mailbox.next_id,
+            len: data.len(),
+            data: [0; IVC_MSG_MAX_LEN],
+        };
+        msg.data[..data.len()].copy_from_slice(data);
+        mailbox.next_id += 1;
+        mailbox.msgs.push(msg);
+    }
+    let msg_id = ivc_mailbox_deliver(trgt_vmid);
+    Ok(msg_id)
+}
+
+// deliver the head message of vm[vm_id]'s mailbox if it is not delivered yet, return the id of the last message
+fn ivc_mailbox_deliver(vm_id: usize) -> usize {
+    let msg = {
+        let mut mailbox_list = IVC_MAILBOX_LIST.lock();
+        let mailbox = match mailbox_list.get_mut(&vm_id) {
+            Some(mailbox) => mailbox,
+            None => return 0,
+        };
+        let last_id = mailbox.next_id - 1;
+        if mailbox.delivered || mailbox.msgs.is_empty() || vm_if_ivc_arg(vm_id) == 0 {
+            return last_id;
+        }
+        mailbox.delivered = true;
+        (mailbox.msgs[0], last_id)
+    };
+    if !hvc_send_msg_to_vm(vm_id, &HvcGuestMsg::Ivc(msg.0)) {
+        println!("ivc_mailbox_deliver: failed to notify VM[{}]", vm_id);
+        if let Some(mailbox) = IVC_MAILBOX_LIST.lock().get_mut(&vm_id) {
+            mailbox.delivered = false;
+        }
+    }
+    msg.1
+}
+
+fn ivc_copy_msg(msg_ipa: usize, len: usize) -> Option<([u8; IVC_MSG_MAX_LEN], usize)> {
+    if len > IVC_MSG_MAX_LEN {
+        println!("ivc: message length {} exceeds {}", len, IVC_MSG_MAX_LEN);
+        return None;
+    }
+    // the message may cross a page, it has to lie in one memory region of the sender
+    let vm = active_vm().unwrap();
+    let msg_pa = vm_ipa2pa(vm.clone(), msg_ipa);
+    if msg_pa == 0 || (len != 0 && vm_ipa2pa(vm, msg_ipa + len - 1) != msg_pa + len - 1) {
+        println!("ivc: illegal msg ipa {:x}", msg_ipa);
+        return None;
+    }
+    let mut data = [0_u8; IVC_MSG_MAX_LEN];
+    memcpy_safe(data.as_mut_ptr(), msg_pa as *const u8, len);
+    Some((data, len))
+}
+
+/* Send a message to vm[trgt_vmid], return the message id which is acked by the receiver */
+pub fn ivc_send_msg(trgt_vmid: usize, msg_ipa: usize, len: usize) -> Result<usize, ()> {
+    let (data, len) = match ivc_copy_msg(msg_ipa, len) {
+        Some(msg) => msg,
+        None => return Err(()),
+    };
+    match ivc_mailbox_push(active_vm_id(), trgt_vmid, &data[..len]) {
+        Ok(msg_id) => Ok(msg_id),
+        Err(_) => {
+            println!(
+                "ivc_send_msg: VM[{}] failed to send msg to VM[{}]",
+                active_vm_id(),
+                trgt_vmid
+            );
+            Err(())
+        }
+    }
+}
+
+/* Send a message to all the other VMs, return the number of receivers */
+pub fn ivc_broadcast_msg(msg_ipa: usize, len: usize) -> Result<usize, ()> {
+    let (data, len) = match ivc_copy_msg(msg_ipa, len) {
+        Some(msg) => msg,
+        None => return Err(()),
+    };
+    let src_vmid = active_vm_id();
+    let mut count = 0;
+    for vm_id in vm_id_list() {
+        if vm_id != src_vmid && ivc_mailbox_push(src_vmid, vm_id, &data[..len]).is_ok() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/* Ack the message being received by current VM, then the next one is delivered */
+pub fn ivc_ack(msg_id: usize) -> Result<usize, ()> {
+    let vm_id = active_vm_id();
+    {
+        let mut mailbox_list = IVC_MAILBOX_LIST.lock();
+        let mailbox = match mailbox_list.get_mut(&vm_id) {
+            Some(mailbox) => mailbox,
+            None => return Err(()),
+        };
+        if !mailbox.delivered || mailbox.msgs[0].msg_id != msg_id {
+            println!("ivc_ack: VM[{}] acks msg {} which is not delivered", vm_id, msg_id);
+            return Err(());
+        }
+        mailbox.msgs.remove(0);
+        mailbox.delivered = false;
+    }
+    ivc_mailbox_deliver(vm_id);
+    Ok(0)
+}
+
+/*
+ * Register vm[vm_id] to the keep-alive registry with interval in ms, 0 unregisters it.
+ * Only the MVM manages the registry, a VM could not leave it to escape the health monitor.
+ */
+pub fn ivc_init_keep_alive(vm_id: usize, interval: usize) -> Result<usize, ()> {
+    if active_vm_id() != 0 {
+        println!(
+            "ivc_init_keep_alive: VM[{}] can not manage the keep-alive registry",
+            active_vm_id()
+        );
+        return Err(());
+    }
+    if vm(vm_id).is_none() {
+        println!("ivc_init_keep_alive: VM[{}] not exist", vm_id);
+        return Err(());
+    }
+    // the timeout interval * miss in us has to fit in usize as well
+    let interval_us = match interval.checked_mul(1000) {
+        Some(interval_us) => interval_us,
+        None => {
+            println!("ivc_init_keep_alive: illegal interval {}ms", interval);
+            return Err(());
+        }
+    };
+    let mut keep_alive_list = IVC_KEEP_ALIVE_LIST.lock();
+    if interval == 0 {
+        keep_alive_list.remove(&vm_id);
+    } else {
+        keep_alive_list.insert(
+            vm_id,
+            IvcKeepAlive {
+                interval: interval_us,
+                miss: match vm_cfg_entry(vm_id) {
+                    Some(vm_cfg) => vm_cfg.health_cfg().keep_alive_miss,
+                    None => VmHealthConfig::default().keep_alive_miss,
+                },
+                last: time_current_us(),
+                lost: false,
+            },
+        );
+    }
+    info!("VM[{}] keep alive interval {}ms", vm_id, interval);
+    Ok(0)
+}
+
+pub fn ivc_keep_alive() -> Result<usize, ()> {
+    let vm_id = active_vm_id();
+    let mut keep_alive_list = IVC_KEEP_ALIVE_LIST.lock();
+    match keep_alive_list.get_mut(&vm_id) {
+        Some(keep_alive) => {
+            keep_alive.last = time_current_us();
+            if keep_alive.lost {
+                keep_alive.lost = false;
+                info!("VM[{}] is alive again", vm_id);
+            }
+            Ok(0)
+        }
+        None => {
+            println!("ivc_keep_alive: VM[{}] is not registered", vm_id);
+            Err(())
+        }
+    }
+}
+
+pub fn ivc_keep_alive_lost(vm_id: usize) -> bool {
+    match IVC_KEEP_ALIVE_LIST.lock().get(&vm_id) {
+        Some(keep_alive) => keep_alive.lost,
+        None => false,
+    }
+}
+
+// flag the VMs which stop heartbeating, called periodically by the health monitor
+pub fn ivc_keep_alive_check() {
+    let mut lost_list = Vec::new();
+    {
+        let mut keep_alive_list = match IVC_KEEP_ALIVE_LIST.try_lock() {
+            Some(list) => list,
+            None => return,
+        };
+        if keep_alive_list.is_empty() {
+            return;
+        }
+        let now = time_current_us();
+        for (vm_id, keep_alive) in keep_alive_list.iter_mut() {
+            // a paused VM can not heartbeat, its interval starts over on resume
+            if let VmState::VmPaused = vm_if_get_state(*vm_id) {
+                keep_alive.last = now;
+                continue;
+            }
+            if !keep_alive.lost
+                && now.saturating_sub(keep_alive.last) > keep_alive.interval.saturating_mul(keep_alive.miss)
+            {
+                keep_alive.lost = true;
+                lost_list.push(*vm_id);
+            }
+        }
+    }
+    for vm_id in lost_list {
+        warn!("VM[{}] stops heartbeating", vm_id);
+        vm_health_report(vm_id, VmHealthEvent::KeepAliveLost);
+    }
+}
+
+pub fn ivc_vm_remove(vm_id: usize) {
+    IVC_MAILBOX_LIST.lock().remove(&vm_id);
+    IVC_KEEP_ALIVE_LIST.lock().remove(&vm_id);
+}
+
 pub fn mem_shared_mem_init() {
     let mut shared_mem = SHARED_MEM.lock();
     if shared_mem.is_none() {
@@ -13,18 +13,21 @@ use alloc::collections::{BTreeMap, BTreeSet, LinkedList};
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use spin::{Mutex, RwLock};
 
 use crate::arch::{
     emu_intc_handler, emu_smmu_handler, GIC_LRS_NUM, gic_maintenance_handler, gicc_clear_current_irq, INTERRUPT_EN_SET,
-    PageTable, partial_passthrough_intc_handler, psci_ipi_handler, SMMU_V2, SmmuV2, TIMER_FREQ, TIMER_SLICE, Vgic,
-    vgic_ipi_handler,
+    PAGE_SIZE, PageTable, partial_passthrough_intc_handler, psci_ipi_handler, SMMU_V2, SmmuV2, TIMER_FREQ, TIMER_SLICE,
+    Vgic, vgic_ipi_handler,
 };
-use crate::board::PLAT_DESC;
+use crate::board::{PLAT_DESC, PlatOperation, Platform};
 use crate::config::{
-    DEF_VM_CONFIG_TABLE, vm_cfg_entry, VmConfigEntry, VmConfigTable, VmDtbDevConfig, VMDtbDevConfigList,
-    VmEmulatedDeviceConfig, VmEmulatedDeviceConfigList, VmMemoryConfig, VmPassthroughDeviceConfig,
+    DEF_VM_CONFIG_TABLE, SchedPartitionConfig, vm_cfg_entry, VmConfigEntry, VmConfigTable, VmDtbDevConfig,
+    VMDtbDevConfigList, VmEmulatedDeviceConfig, VmEmulatedDeviceConfigList, VmMemoryConfig, VmPassthroughDeviceConfig,
+    VmSmcConfig,
 };
 use crate::device::{
     BlkIov, EMU_DEVS_LIST, emu_virtio_mmio_handler, EmuDevEntry, EmuDeviceType, EmuDevs, ethernet_ipi_rev_handler,
@@ -34,13 +37,19 @@ use crate::device::{
 use crate::kernel::{
     async_blk_io_req, ASYNC_EXE_STATUS, ASYNC_IO_TASK_LIST, async_ipi_req, ASYNC_IPI_TASK_LIST, ASYNC_USED_INFO_LIST,
     AsyncExeStatus, AsyncTask, AsyncTaskData, CPU, Cpu, cpu_idle, CPU_IF_LIST, CpuIf, CpuState, current_cpu, FairQueue,
-    HEAP_REGION, HeapRegion, hvc_ipi_handler, INTERRUPT_GLB_BITMAP, INTERRUPT_HANDLERS, INTERRUPT_HYPER_BITMAP,
-    interrupt_inject_ipi_handler, InterruptHandler, IoAsyncMsg, IPI_HANDLER_LIST, ipi_irq_handler, ipi_register,
-    ipi_send_msg, IpiHandler, IpiInnerMsg, IpiMediatedMsg, IpiMessage, IpiType, mem_heap_region_init, SchedType,
-    SchedulerUpdate, SHARE_MEM_LIST, timer_irq_handler, UsedInfo, Vcpu, VCPU_LIST, VcpuInner, vm, Vm, VM_IF_LIST,
-    vm_ipa2pa, VM_LIST, VM_NUM_MAX, VM_REGION, VmInterface, VmRegion, logger_init,
+    HEAP_REGION, HeapRegion, hot_patch_ipi_handler, hvc_ipi_handler, INTERRUPT_GLB_BITMAP, INTERRUPT_HANDLERS,
+    INTERRUPT_HYPER_BITMAP, interrupt_inject_ipi_handler, InterruptHandler, IoAsyncMsg, IPI_HANDLER_LIST,
+    ipi_irq_handler, ipi_register, ipi_send_msg, IpiHandler, IpiInnerMsg, IpiMediatedMsg, IpiMessage, IpiType,
+    mem_heap_region_init, SchedType, SchedulerUpdate, SHARE_MEM_LIST, timer_irq_handler, UsedInfo, Vcpu, VCPU_LIST,
+    VcpuInner, VcpuState, vm, Vm, VM_IF_LIST, vm_ipa2pa, VM_LIST, VM_NUM_MAX, VM_REGION, VmInner, VmInterface,
+    VmRegion, logger_init,
 };
-use crate::lib::{BitAlloc256, BitMap, FlexBitmap, time_current_us};
+use crate::kernel::{live_update_entry, LiveUpdatable, LiveUpdateEntry, LiveUpdateFrom, LiveUpdateStage};
+use crate::kernel::{
+    DIRTY_LOG_UPDATE, IVC_KEEP_ALIVE_UPDATE, IVC_MAILBOX_UPDATE, MIGRATE_POSTCOPY_UPDATE, MIGRATE_PROGRESS_UPDATE,
+    SNAPSHOT_RESTORE_PENDING_UPDATE, SNAPSHOT_SAVE_PENDING_UPDATE, TRACE_BUFFER_UPDATE, VM_HEALTH_UPDATE,
+};
+use crate::lib::{BitAlloc256, BitMap, FlexBitmap, HmacSha256, mac_eq, SHA256_LEN, time_current_us};
 use crate::mm::{heap_init, PageFrame};
 use crate::vmm::vmm_ipi_handler;
 
@@ -75,175 +84,355 @@ pub const UPDATE_IMG_BASE_ADDR: usize = 0x8a000000;
 #[cfg(feature = "update")]
 pub const UPDATE_IMG_BASE_ADDR: usize = 0x83000000;
 
+// offset of the LiveUpdateHeader in an image, see the .boot section of the linker scripts
+pub const UPDATE_HEADER_OFFSET: usize = 0x1000;
+// offset of LiveUpdateHeader::mac in an image
+const UPDATE_MAC_OFFSET: usize = UPDATE_HEADER_OFFSET + 3 * size_of::<usize>();
+// "SHYPUPDT"
+const UPDATE_HEADER_MAGIC: usize = 0x5444_5055_5059_4853;
+const UPDATE_HEADER_VERSION: usize = 3;
+// bump it if a transferred structure changes in a way its size does not tell
+const UPDATE_LAYOUT_VERSION: usize = 4;
+const UPDATE_LAYOUT_NUM: usize = 15;
+pub const UPDATE_BUILD_LEN: usize = 128;
+// limit of the encoding of a global migrated by live_update_migrate
+const UPDATE_MIGRATE_BUF_MAX: usize = 0x100000;
+// time for the new image to get every core and vcpu running again, or the old image is restored
+const UPDATE_HEALTH_DEADLINE_US: usize = 1_000_000;
+
+// UPDATE_KEY, the key the images are signed with, provisioned by build.rs
+include!(concat!(env!("OUT_DIR"), "/update_key.rs"));
+
+/*
+ * Header of a hypervisor image for live update, at UPDATE_HEADER_OFFSET of the image. The image
+ * size and mac are filled in after the build by tools/live_update_image.py, the mac is the
+ * HMAC-SHA256 with UPDATE_KEY of the image with the mac field zeroed. Layout is the size of the structures
+ * inside the globals of the registry, both images have to agree on all of them, the globals
+ * themselves are versioned by their LiveUpdateEntry.
+ */
+#[repr(C)]
+pub struct LiveUpdateHeader {
+    magic: usize,
+    version: usize,
+    image_size: usize,
+    mac: [u8; SHA256_LEN],
+    build: [u8; UPDATE_BUILD_LEN],
+    layout: [usize; UPDATE_LAYOUT_NUM],
+    entry: Option<extern "C" fn(&HypervisorAddr, bool)>,
+    registry: &'static [&'static LiveUpdateEntry],
+}
+
+const UPDATE_LAYOUT: [usize; UPDATE_LAYOUT_NUM] = [
+    UPDATE_LAYOUT_VERSION,
+    size_of::<HypervisorAddr>(),
+    size_of::<LiveUpdateEntry>(),
+    size_of::<VmInner>(),
+    size_of::<VcpuInner>(),
+    size_of::<Cpu>(),
+    size_of::<CpuIf>(),
+    size_of::<VmConfigEntry>(),
+    size_of::<EmuDevEntry>(),
+    size_of::<InterruptHandler>(),
+    size_of::<IpiHandler>(),
+    size_of::<MediatedBlk>(),
+    size_of::<AsyncTask>(),
+    size_of::<UsedInfo>(),
+    size_of::<Vgic>(),
+];
+
+const fn update_build_id() -> [u8; UPDATE_BUILD_LEN] {
+    let id = env!("UPDATE_BUILD_ID").as_bytes();
+    let mut build = [0; UPDATE_BUILD_LEN];
+    let mut i = 0;
+    while i < id.len() && i < UPDATE_BUILD_LEN {
+        build[i] = id[i];
+        i += 1;
+    }
+    build
+}
+
+#[used]
+#[link_section = ".update_header"]
+static UPDATE_HEADER: LiveUpdateHeader = LiveUpdateHeader {
+    magic: UPDATE_HEADER_MAGIC,
+    version: UPDATE_HEADER_VERSION,
+    image_size: 0,
+    mac: [0; SHA256_LEN],
+    build: update_build_id(),
+    layout: UPDATE_LAYOUT,
+    entry: Some(rust_shyper_update),
+    registry: LIVE_UPDATE_REGISTRY,
+};
+
+// (header, rollback) of the image update_request switches to
+static UPDATE_TARGET: Mutex<Option<(usize, bool)>> = Mutex::new(None);
+
+struct LiveUpdateCheck {
+    // header of the image updated from
+    prev: usize,
+    // this image is restored by a rollback, there is nothing left to roll back to
+    rollback: bool,
+    start: usize,
+    deadline: usize,
+    // bitmap of the cores not switched to this image yet
+    cores: usize,
+    // (vm id, vcpu id) of the vcpus running or ready at the update, not scheduled since
+    vcpus: BTreeSet<(usize, usize)>,
+}
+
+static LIVE_UPDATE_CHECK: Mutex<Option<LiveUpdateCheck>> = Mutex::new(None);
+// spares the scheduler the lock once the check is over
+static LIVE_UPDATE_CHECKING: AtomicBool = AtomicBool::new(false);
+
 #[repr(C)]
 pub struct HypervisorAddr {
     cpu_id: usize,
-    vm_list: usize,
-    vm_config_table: usize,
-    vcpu_list: usize,
     cpu: usize,
-    emu_dev_list: usize,
-    interrupt_hyper_bitmap: usize,
-    interrupt_glb_bitmap: usize,
-    interrupt_en_set: usize,
-    interrupt_handlers: usize,
-    vm_region: usize,
-    heap_region: usize,
-    vm_if_list: usize,
-    gic_lrs_num: usize,
-    // address for ipi
-    cpu_if_list: usize,
-    ipi_handler_list: usize,
-    // arch time
-    time_freq: usize,
-    time_slice: usize,
-    // mediated blk
-    mediated_blk_list: usize,
-    // async task
-    async_exe_status: usize,
-    async_ipi_task_list: usize,
-    async_io_task_list: usize,
-    async_used_info_list: usize,
-    // shared mem
-    shared_mem_list: usize,
-    // smmu_v2
-    smmu_v2: usize,
+    // header of the image the request comes from
+    image_header: usize,
+    // the request restores the image updated from
+    rollback: usize,
 }
 
+/*
+ * The globals carried over a live update, see LiveUpdatable. The hooks of each stage are called in
+ * the order here. A global private to its module is registered by the LiveUpdateEntry of it.
+ */
+static LIVE_UPDATE_REGISTRY: &[&LiveUpdateEntry] = &[
+    &live_update_entry(
+        "DEF_VM_CONFIG_TABLE",
+        LiveUpdateStage::Alloc,
+        true,
+        1,
+        &DEF_VM_CONFIG_TABLE,
+    ),
+    &live_update_entry("VM_LIST", LiveUpdateStage::Vm, true, 1, &VM_LIST),
+    &live_update_entry("VCPU_LIST", LiveUpdateStage::Vcpu, true, 1, &VCPU_LIST),
+    &live_update_entry("CPU_IF_LIST", LiveUpdateStage::Fresh, true, 1, &CPU_IF_LIST),
+    &live_update_entry("IPI_HANDLER_LIST", LiveUpdateStage::Alloc, true, 1, &IPI_HANDLER_LIST),
+    &live_update_entry("TIMER_FREQ", LiveUpdateStage::Alloc, true, 1, &TIMER_FREQ),
+    &live_update_entry("TIMER_SLICE", LiveUpdateStage::Alloc, true, 1, &TIMER_SLICE),
+    &live_update_entry(
+        "INTERRUPT_HYPER_BITMAP",
+        LiveUpdateStage::Alloc,
+        true,
+        1,
+        &INTERRUPT_HYPER_BITMAP,
+    ),
+    &live_update_entry(
+        "INTERRUPT_GLB_BITMAP",
+        LiveUpdateStage::Alloc,
+        true,
+        1,
+        &INTERRUPT_GLB_BITMAP,
+    ),
+    &live_update_entry("INTERRUPT_EN_SET", LiveUpdateStage::Alloc, true, 1, &INTERRUPT_EN_SET),
+    &live_update_entry(
+        "INTERRUPT_HANDLERS",
+        LiveUpdateStage::Alloc,
+        true,
+        1,
+        &INTERRUPT_HANDLERS,
+    ),
+    &live_update_entry("EMU_DEVS_LIST", LiveUpdateStage::Alloc, true, 1, &EMU_DEVS_LIST),
+    &live_update_entry("GIC_LRS_NUM", LiveUpdateStage::Alloc, true, 1, &GIC_LRS_NUM),
+    &live_update_entry("VM_REGION", LiveUpdateStage::Fresh, true, 1, &VM_REGION),
+    &live_update_entry("HEAP_REGION", LiveUpdateStage::Fresh, true, 1, &HEAP_REGION),
+    &live_update_entry("VM_IF_LIST", LiveUpdateStage::Fresh, true, 1, &VM_IF_LIST),
+    &live_update_entry("MEDIATED_BLK_LIST", LiveUpdateStage::Fresh, true, 1, &MEDIATED_BLK_LIST),
+    &live_update_entry("SHARE_MEM_LIST", LiveUpdateStage::Fresh, true, 1, &SHARE_MEM_LIST),
+    &live_update_entry("ASYNC_EXE_STATUS", LiveUpdateStage::Fresh, true, 1, &ASYNC_EXE_STATUS),
+    &live_update_entry(
+        "ASYNC_IPI_TASK_LIST",
+        LiveUpdateStage::Fresh,
+        true,
+        1,
+        &ASYNC_IPI_TASK_LIST,
+    ),
+    &live_update_entry(
+        "ASYNC_IO_TASK_LIST",
+        LiveUpdateStage::Fresh,
+        true,
+        1,
+        &ASYNC_IO_TASK_LIST,
+    ),
+    &live_update_entry(
+        "ASYNC_USED_INFO_LIST",
+        LiveUpdateStage::Fresh,
+        true,
+        1,
+        &ASYNC_USED_INFO_LIST,
+    ),
+    &live_update_entry("SMMU_V2", LiveUpdateStage::Fresh, true, 1, &SMMU_V2),
+    &DIRTY_LOG_UPDATE,
+    &MIGRATE_PROGRESS_UPDATE,
+    &MIGRATE_POSTCOPY_UPDATE,
+    &VM_HEALTH_UPDATE,
+    &IVC_MAILBOX_UPDATE,
+    &IVC_KEEP_ALIVE_UPDATE,
+    &SNAPSHOT_SAVE_PENDING_UPDATE,
+    &SNAPSHOT_RESTORE_PENDING_UPDATE,
+    &TRACE_BUFFER_UPDATE,
+];
+
 pub fn hyper_fresh_ipi_handler(_msg: &IpiMessage) {
     update_request();
 }
 
+pub fn update_build_str(build: &[u8]) -> &str {
+    let len = build.iter().position(|c| *c == 0).unwrap_or(build.len());
+    core::str::from_utf8(&build[..len]).unwrap_or("unknown")
+}
+
+// build id and the leading word of the mac of the running image, for the objects built against it, see hot_patch
+pub fn update_image_id() -> (&'static [u8], usize) {
+    // the mac is filled in after the build, it is 0 to the compiler
+    let mac = unsafe { core::ptr::read_volatile(&UPDATE_HEADER.mac) };
+    let mut id = [0; size_of::<usize>()];
+    id.copy_from_slice(&mac[..size_of::<usize>()]);
+    (&UPDATE_HEADER.build, usize::from_le_bytes(id))
+}
+
+// the key the images and the hot patch objects are signed with, empty if none is provisioned
+pub fn update_key() -> &'static [u8] {
+    UPDATE_KEY
+}
+
+/*
+ * Check the image loaded at base before switching to it by update_request. The image has to be
+ * signed by tools/live_update_image.py and built the same way as this one, so that the structures
+ * rust_shyper_update transfers are laid out the same in both.
+ */
+pub fn live_update_validate(base: usize, size: usize) -> Result<(), ()> {
+    if LIVE_UPDATE_CHECKING.load(Ordering::Relaxed) {
+        println!("live_update_validate: the last update is not checked yet");
+        return Err(());
+    }
+    if UPDATE_KEY.is_empty() {
+        println!("live_update_validate: no update key is provisioned");
+        return Err(());
+    }
+    if size < UPDATE_HEADER_OFFSET + size_of::<LiveUpdateHeader>() {
+        println!("live_update_validate: image size {:x} too small", size);
+        return Err(());
+    }
+    let header = unsafe { &*((base + UPDATE_HEADER_OFFSET) as *const LiveUpdateHeader) };
+    if header.magic != UPDATE_HEADER_MAGIC || header.version != UPDATE_HEADER_VERSION {
+        println!(
+            "live_update_validate: illegal header, magic {:x} version {}",
+            header.magic, header.version
+        );
+        return Err(());
+    }
+    if header.build != UPDATE_HEADER.build {
+        println!(
+            "live_update_validate: image built by \"{}\", not \"{}\"",
+            update_build_str(&header.build),
+            update_build_str(&UPDATE_HEADER.build)
+        );
+        return Err(());
+    }
+    for (idx, size) in header.layout.iter().enumerate() {
+        if *size != UPDATE_LAYOUT[idx] {
+            println!(
+                "live_update_validate: layout {} is {}, not {}",
+                idx, size, UPDATE_LAYOUT[idx]
+            );
+            return Err(());
+        }
+    }
+    match header.entry {
+        Some(entry) if entry as usize >= base && (entry as usize) < base + size => {}
+        _ => {
+            println!("live_update_validate: illegal entry");
+            return Err(());
+        }
+    }
+    if header.image_size != size {
+        println!(
+            "live_update_validate: image size {:x}, signed {:x}",
+            size, header.image_size
+        );
+        return Err(());
+    }
+    let image = unsafe { core::slice::from_raw_parts(base as *const u8, size) };
+    let mut mac = HmacSha256::new(UPDATE_KEY);
+    mac.update(&image[..UPDATE_MAC_OFFSET]);
+    mac.update(&[0; SHA256_LEN]);
+    mac.update(&image[UPDATE_MAC_OFFSET + SHA256_LEN..]);
+    if !mac_eq(&mac.finish(), &header.mac) {
+        println!("live_update_validate: image is not signed with the update key");
+        return Err(());
+    }
+    // both ways, the image may be rolled back to this one
+    for (registry, other) in [
+        (LIVE_UPDATE_REGISTRY, header.registry),
+        (header.registry, LIVE_UPDATE_REGISTRY),
+    ] {
+        for entry in registry.iter().filter(|entry| entry.required) {
+            if !other
+                .iter()
+                .any(|other| other.name == entry.name && other.compatible(entry))
+            {
+                println!(
+                    "live_update_validate: {} version {} can not be carried over",
+                    entry.name, entry.version
+                );
+                return Err(());
+            }
+        }
+    }
+    *UPDATE_TARGET.lock() = Some((base + UPDATE_HEADER_OFFSET, false));
+    info!("live update image at {:x} validated, {} bytes", base, size);
+    Ok(())
+}
+
 pub fn update_request() {
     // println!("Src Hypervisor Core[{}] send update request", current_cpu().id);
-    extern "C" {
-        pub fn update_request(address_list: &HypervisorAddr, alloc: bool);
-    }
-    // VM_STATE_FLAG UNILIB_FS_LIST SYSTEM_FDT
-    let vm_config_table = &DEF_VM_CONFIG_TABLE as *const _ as usize;
-    let emu_dev_list = &EMU_DEVS_LIST as *const _ as usize;
-    let interrupt_hyper_bitmap = &INTERRUPT_HYPER_BITMAP as *const _ as usize;
-    let interrupt_glb_bitmap = &INTERRUPT_GLB_BITMAP as *const _ as usize;
-    let interrupt_en_set = &INTERRUPT_EN_SET as *const _ as usize;
-    let interrupt_handlers = &INTERRUPT_HANDLERS as *const _ as usize;
-    let vm_region = &VM_REGION as *const _ as usize;
-    let heap_region = &HEAP_REGION as *const _ as usize;
-    let vm_list = &VM_LIST as *const _ as usize;
-    let vm_if_list = &VM_IF_LIST as *const _ as usize;
-    let vcpu_list = &VCPU_LIST as *const _ as usize;
-    let cpu = unsafe { &CPU as *const _ as usize };
-    let cpu_if_list = &CPU_IF_LIST as *const _ as usize;
-    let gic_lrs_num = &GIC_LRS_NUM as *const _ as usize;
-    let ipi_handler_list = &IPI_HANDLER_LIST as *const _ as usize;
-    let time_freq = &TIMER_FREQ as *const _ as usize;
-    let time_slice = &TIMER_SLICE as *const _ as usize;
-    let mediated_blk_list = &MEDIATED_BLK_LIST as *const _ as usize;
-    let async_exe_status = &ASYNC_EXE_STATUS as *const _ as usize;
-    let async_ipi_task_list = &ASYNC_IPI_TASK_LIST as *const _ as usize;
-    let async_io_task_list = &ASYNC_IO_TASK_LIST as *const _ as usize;
-    let async_used_info_list = &ASYNC_USED_INFO_LIST as *const _ as usize;
-    let shared_mem_list = &SHARE_MEM_LIST as *const _ as usize;
-    let smmu_v2 = &SMMU_V2 as *const _ as usize;
-
+    let (header, rollback) = match *UPDATE_TARGET.lock() {
+        Some(target) => target,
+        None => {
+            println!("update_request: no image to update to");
+            return;
+        }
+    };
+    let entry = unsafe { (*(header as *const LiveUpdateHeader)).entry.unwrap() };
     let addr_list = HypervisorAddr {
         cpu_id: current_cpu().id,
-        vm_config_table,
-        emu_dev_list,
-        interrupt_hyper_bitmap,
-        interrupt_glb_bitmap,
-        interrupt_en_set,
-        interrupt_handlers,
-        vm_region,
-        heap_region,
-        vm_list,
-        vm_if_list,
-        vcpu_list,
-        cpu,
-        cpu_if_list,
-        gic_lrs_num,
-        ipi_handler_list,
-        time_freq,
-        time_slice,
-        mediated_blk_list,
-        async_exe_status,
-        async_ipi_task_list,
-        async_io_task_list,
-        async_used_info_list,
-        shared_mem_list,
-        smmu_v2,
+        cpu: unsafe { &CPU as *const _ as usize },
+        image_header: &UPDATE_HEADER as *const _ as usize,
+        rollback: rollback as usize,
     };
     if current_cpu().id == 0 {
-        unsafe {
-            update_request(&addr_list, true);
-        }
+        entry(&addr_list, true);
         for cpu_id in 0..PLAT_DESC.cpu_desc.num {
             if cpu_id != current_cpu().id {
                 ipi_send_msg(cpu_id, IpiType::IpiTHyperFresh, IpiInnerMsg::HyperFreshMsg());
             }
         }
     }
-    unsafe {
-        update_request(&addr_list, false);
-    }
+    entry(&addr_list, false);
 }
 
 #[no_mangle]
 pub extern "C" fn rust_shyper_update(address_list: &HypervisorAddr, alloc: bool) {
     // TODO: vm0_dtb?
-    // let mut time0 = 0;
-    // let mut time1 = 0;
+    let from = LiveUpdateFrom {
+        registry: unsafe { (*(address_list.image_header as *const LiveUpdateHeader)).registry },
+    };
     if alloc {
         // cpu id is 0
-        heap_init();
-        mem_heap_region_init();
-        // alloc and pre_copy
-        unsafe {
-            // DEF_VM_CONFIG_TABLE
-            let vm_config_table = &*(address_list.vm_config_table as *const Mutex<VmConfigTable>);
-            vm_config_table_update(vm_config_table);
-
-            // VM_LIST
-            let vm_list = &*(address_list.vm_list as *const Mutex<Vec<Vm>>);
-            vm_list_alloc(vm_list);
-
-            // VCPU_LIST
-            let vcpu_list = &*(address_list.vcpu_list as *const Mutex<Vec<Vcpu>>);
-            vcpu_list_alloc(vcpu_list);
-
-            // CPU_IF
-            let cpu_if = &*(address_list.cpu_if_list as *const Mutex<Vec<CpuIf>>);
-            cpu_if_alloc(cpu_if);
-
-            // IPI_HANDLER_LIST
-            let ipi_handler_list = &*(address_list.ipi_handler_list as *const Mutex<Vec<IpiHandler>>);
-            ipi_handler_list_update(ipi_handler_list);
-
-            // TIMER_FREQ & TIMER_SLICE
-            let time_freq = &*(address_list.time_freq as *const Mutex<usize>);
-            let time_slice = &*(address_list.time_slice as *const Mutex<usize>);
-            arch_time_update(time_freq, time_slice);
-
-            // INTERRUPT_HYPER_BITMAP, INTERRUPT_GLB_BITMAP, INTERRUPT_HANDLERS
-            let interrupt_hyper_bitmap = &*(address_list.interrupt_hyper_bitmap as *const Mutex<BitMap<BitAlloc256>>);
-            let interrupt_glb_bitmap = &*(address_list.interrupt_glb_bitmap as *const Mutex<BitMap<BitAlloc256>>);
-            let interrutp_en_set = &*(address_list.interrupt_en_set as *const Mutex<BTreeSet<usize>>);
-            let interrupt_handlers =
-                &*(address_list.interrupt_handlers as *const Mutex<BTreeMap<usize, InterruptHandler>>);
-            interrupt_update(
-                interrupt_hyper_bitmap,
-                interrupt_glb_bitmap,
-                interrutp_en_set,
-                interrupt_handlers,
-            );
-
-            // EMU_DEVS_LIST
-            let emu_dev_list = &*(address_list.emu_dev_list as *const Mutex<Vec<EmuDevEntry>>);
-            emu_dev_list_update(emu_dev_list);
-
-            // GIC_LRS_NUM
-            let gic_lrs_num = &*(address_list.gic_lrs_num as *const Mutex<usize>);
-            gic_lrs_num_update(gic_lrs_num);
+        set_fresh_status(FreshStatus::Start);
+        if address_list.rollback == 0 {
+            heap_init();
+            mem_heap_region_init();
+        } else {
+            live_update_rollback_reset();
         }
+        live_update_registry_check(&from);
+        // alloc and pre_copy
+        live_update_stage(LiveUpdateStage::Alloc, &from);
+        live_update_check_start(address_list.image_header, address_list.rollback != 0, &from);
         println!("Finish Alloc VM / VCPU / CPU_IF");
         return;
     }
@@ -252,85 +441,41 @@ pub extern "C" fn rust_shyper_update(address_list: &HypervisorAddr, alloc: bool)
         let lock0 = FRESH_LOGIC_LOCK.lock();
         let lock1 = FRESH_IRQ_LOGIC_LOCK.lock();
         // set_fresh_status(FreshStatus::Start);
-        unsafe {
-            // VM_LIST
-            let time0 = time_current_us();
-            let vm_list = &*(address_list.vm_list as *const Mutex<Vec<Vm>>);
-            vm_list_update(vm_list);
-            set_fresh_status(FreshStatus::FreshVM);
-            let time1 = time_current_us();
-
-            // VCPU_LIST (add vgic)
-            let vcpu_list = &*(address_list.vcpu_list as *const Mutex<Vec<Vcpu>>);
-            vcpu_update(vcpu_list, vm_list);
-            let time2 = time_current_us();
-            drop(lock1);
-            set_fresh_status(FreshStatus::FreshVCPU);
-            let time3 = time_current_us();
-
-            // CPU: Must update after vcpu and vm
-            let cpu = &*(address_list.cpu as *const Cpu);
-            current_cpu_update(cpu);
-
-            // VM_REGION
-            let vm_region = &*(address_list.vm_region as *const Mutex<VmRegion>);
-            vm_region_update(vm_region);
-
-            // HEAP_REGION
-            let heap_region = &*(address_list.heap_region as *const Mutex<HeapRegion>);
-            heap_region_update(heap_region);
-
-            // VM_IF_LIST
-            let vm_if_list = &*(address_list.vm_if_list as *const [Mutex<VmInterface>; VM_NUM_MAX]);
-            vm_if_list_update(vm_if_list);
-
-            // MEDIATED_BLK_LIST
-            let mediated_blk_list = &*(address_list.mediated_blk_list as *const Mutex<Vec<MediatedBlk>>);
-            mediated_blk_list_update(mediated_blk_list);
-
-            // SHARED_MEM_LIST
-            let shared_mem_list = &*(address_list.shared_mem_list as *const Mutex<BTreeMap<usize, usize>>);
-            shared_mem_list_update(shared_mem_list);
-
-            // cpu_if_list
-            let cpu_if = &*(address_list.cpu_if_list as *const Mutex<Vec<CpuIf>>);
-            cpu_if_update(cpu_if);
-
-            // ASYNC_EXE_STATUS、ASYNC_IPI_TASK_LIST、ASYNC_IO_TASK_LIST、ASYNC_USED_INFO_LIST
-            let async_exe_status = &*(address_list.async_exe_status as *const Mutex<AsyncExeStatus>);
-            let async_ipi_task_list = &*(address_list.async_ipi_task_list as *const Mutex<LinkedList<AsyncTask>>);
-            let async_io_task_list = &*(address_list.async_io_task_list as *const Mutex<FairQueue<AsyncTask>>);
-            let async_used_info_list =
-                &*(address_list.async_used_info_list as *const Mutex<BTreeMap<usize, LinkedList<UsedInfo>>>);
-            async_task_update(
-                async_exe_status,
-                async_ipi_task_list,
-                async_io_task_list,
-                async_used_info_list,
-            );
+        let time0 = time_current_us();
+        live_update_stage(LiveUpdateStage::Vm, &from);
+        set_fresh_status(FreshStatus::FreshVM);
+        let time1 = time_current_us();
 
-            // SMMU_V2
-            let smmu_v2 = &*(address_list.smmu_v2 as *const Mutex<SmmuV2>);
-            smmu_update(smmu_v2);
-            // LOGGER
-            let _ = logger_init();
-            set_fresh_status(FreshStatus::Finish);
-            drop(lock0);
-            println!(
-                "handle VM {} us, handle VCPU {} us, free lock {} us",
-                time1 - time0,
-                time2 - time1,
-                time3 - time2
-            );
-            println!("Finish Update VM and VCPU_LIST");
-            println!("Update CPU[{}]", cpu.id);
-            println!("Update {} region for VM_REGION", VM_REGION.lock().region.len());
-            println!("Update HEAP_REGION");
-            println!("Update VM_IF_LIST");
-            println!("Update {} Mediated BLK", MEDIATED_BLK_LIST.lock().len());
-            println!("Update {} SHARE_MEM_LIST", SHARE_MEM_LIST.lock().len());
-            println!("Update CPU_IF_LIST");
-        }
+        // add vgic
+        live_update_stage(LiveUpdateStage::Vcpu, &from);
+        let time2 = time_current_us();
+        drop(lock1);
+        set_fresh_status(FreshStatus::FreshVCPU);
+        let time3 = time_current_us();
+
+        // CPU: Must update after vcpu and vm
+        let cpu = unsafe { &*(address_list.cpu as *const Cpu) };
+        current_cpu_update(cpu);
+
+        live_update_stage(LiveUpdateStage::Fresh, &from);
+        // LOGGER
+        let _ = logger_init();
+        set_fresh_status(FreshStatus::Finish);
+        drop(lock0);
+        println!(
+            "handle VM {} us, handle VCPU {} us, free lock {} us",
+            time1 - time0,
+            time2 - time1,
+            time3 - time2
+        );
+        println!("Finish Update VM and VCPU_LIST");
+        println!("Update CPU[{}]", cpu.id);
+        println!("Update {} region for VM_REGION", VM_REGION.lock().region.len());
+        println!("Update HEAP_REGION");
+        println!("Update VM_IF_LIST");
+        println!("Update {} Mediated BLK", MEDIATED_BLK_LIST.lock().len());
+        println!("Update {} SHARE_MEM_LIST", SHARE_MEM_LIST.lock().len());
+        println!("Update CPU_IF_LIST");
     } else {
         let cpu = unsafe { &*(address_list.cpu as *const Cpu) };
         // let time0 = time_current_us();
@@ -346,14 +491,90 @@ pub extern "C" fn rust_shyper_update(address_list: &HypervisorAddr, alloc: bool)
     fresh_hyper();
 }
 
+// report the globals in one of the images only, they are not carried over
+fn live_update_registry_check(from: &LiveUpdateFrom) {
+    for entry in LIVE_UPDATE_REGISTRY.iter() {
+        if from.entry(entry.name).is_none() {
+            warn!("live update: {} is new, not carried over", entry.name);
+        }
+    }
+    for src in from.registry.iter() {
+        if LIVE_UPDATE_REGISTRY.iter().all(|entry| entry.name != src.name) {
+            warn!("live update: {} is dropped", src.name);
+        }
+    }
+}
+
+/*
+ * Carry over the globals of the stage. The alloc pass also calls live_update_alloc of the globals
+ * of the later stages. A global of another version or layout is left to live_update_migrate.
+ */
+fn live_update_stage(stage: LiveUpdateStage, from: &LiveUpdateFrom) {
+    for entry in LIVE_UPDATE_REGISTRY.iter() {
+        let src = match from.entry(entry.name) {
+            Some(src) => src,
+            None => continue,
+        };
+        if !src.compatible(entry) {
+            if stage == entry.stage && live_update_migrate(entry, src).is_err() {
+                warn!(
+                    "live update: {} version {} size {} can not be carried over to version {} size {}",
+                    entry.name, src.version, src.size, entry.version, entry.size
+                );
+            }
+            continue;
+        }
+        if stage == entry.stage {
+            (entry.update)(entry.state, src.state, from);
+        } else if stage == LiveUpdateStage::Alloc {
+            (entry.alloc)(entry.state, src.state, from);
+        }
+    }
+}
+
+/*
+ * Migrate a global through the codec of its entry, see LiveUpdateEncode. The buffer grows until
+ * the encoding of the image updated from fits in it.
+ */
+fn live_update_migrate(entry: &LiveUpdateEntry, src: &LiveUpdateEntry) -> Result<(), ()> {
+    let (encode, decode) = match (src.encode, entry.decode) {
+        (Some(encode), Some(decode)) => (encode, decode),
+        _ => return Err(()),
+    };
+    let mut cap = PAGE_SIZE;
+    loop {
+        let mut buf = vec![0_u8; cap];
+        match encode(src.state, buf.as_mut_ptr() as usize, cap) {
+            Ok(len) => {
+                decode(entry.state, &buf[..len], src.version)?;
+                info!(
+                    "live update: {} migrated from version {} to {}",
+                    entry.name, src.version, entry.version
+                );
+                return Ok(());
+            }
+            Err(_) if cap < UPDATE_MIGRATE_BUF_MAX => cap *= 2,
+            Err(_) => return Err(()),
+        }
+    }
+}
+
+// the entry of a global in this image
+pub fn live_update_own_entry(name: &str) -> Option<&'static LiveUpdateEntry> {
+    LIVE_UPDATE_REGISTRY.iter().find(|entry| entry.name == name).copied()
+}
+
 pub fn fresh_hyper() {
     extern "C" {
         pub fn fresh_cpu();
         pub fn fresh_hyper(ctx: usize);
     }
+    live_update_check_in();
     if current_cpu().id == 0 {
         let ctx = current_cpu().ctx.unwrap();
         println!("CPU[{}] ctx {:x}", current_cpu().id, ctx);
+        // a rollback is requested in the timer irq
+        gicc_clear_current_irq(true);
         current_cpu().clear_ctx();
         unsafe { fresh_hyper(ctx) };
     } else {
@@ -388,247 +609,106 @@ pub fn fresh_hyper() {
     }
 }
 
-pub fn shared_mem_list_update(src_shared_mem_list: &Mutex<BTreeMap<usize, usize>>) {
-    let mut shared_mem_list = SHARE_MEM_LIST.lock();
-    for (key, val) in src_shared_mem_list.lock().iter() {
-        shared_mem_list.insert(*key, *val);
+/*
+ * Rolling back, the state of this image is left from before the update. Clear the globals of the
+ * registry before they are carried over from the image rolled back from. What they hold on the
+ * heap of this image is freed, the pages behind them are still in use by the other image and are
+ * forgotten by the reset hooks instead.
+ */
+fn live_update_rollback_reset() {
+    for entry in LIVE_UPDATE_REGISTRY.iter() {
+        (entry.reset)(entry.state);
     }
+    println!("Reset for rollback");
 }
 
-pub fn async_task_update(
-    src_async_exe_status: &Mutex<AsyncExeStatus>,
-    src_async_ipi_task_list: &Mutex<LinkedList<AsyncTask>>,
-    src_async_io_task_list: &Mutex<FairQueue<AsyncTask>>,
-    src_async_used_info_list: &Mutex<BTreeMap<usize, LinkedList<UsedInfo>>>,
-) {
-    let mut async_exe_status = ASYNC_EXE_STATUS.lock();
-    let mut async_ipi_task_list = ASYNC_IPI_TASK_LIST.lock();
-    let mut async_io_task_list = ASYNC_IO_TASK_LIST.lock();
-    let mut async_used_info_list = ASYNC_USED_INFO_LIST.lock();
-    assert_eq!(async_ipi_task_list.len(), 0);
-    assert_eq!(async_io_task_list.len(), 0);
-    assert_eq!(async_used_info_list.len(), 0);
-    *async_exe_status = *src_async_exe_status.lock();
-    for ipi_task in src_async_ipi_task_list.lock().iter() {
-        let vm_id = ipi_task.src_vmid;
-        let vm = vm(vm_id).unwrap();
-        let task_data = match &ipi_task.task_data {
-            AsyncTaskData::AsyncIpiTask(mediated_msg) => {
-                assert_eq!(mediated_msg.src_id, vm_id);
-                let mmio_id = mediated_msg.blk.id();
-                let vq_idx = mediated_msg.vq.vq_indx();
-                match vm.emu_dev(mmio_id) {
-                    EmuDevs::VirtioBlk(blk) => {
-                        let new_vq = blk.vq(vq_idx).clone().unwrap();
-                        AsyncTaskData::AsyncIpiTask(IpiMediatedMsg {
-                            src_id: vm_id,
-                            vq: new_vq.clone(),
-                            blk: blk.clone(),
-                        })
-                    }
-                    _ => panic!("illegal mmio dev type in async_task_update"),
-                }
-            }
-            AsyncTaskData::AsyncIoTask(_) => panic!("Find an IO Task in IPI task list"),
-            AsyncTaskData::AsyncNoneTask(_) => panic!("Find an IO None Task in IPI task list"),
-        };
-        async_ipi_task_list.push_back(AsyncTask {
-            task_data,
-            src_vmid: vm_id,
-            priority: vm.priority(),
-            state: Arc::new(Mutex::new(*ipi_task.state.lock())),
-            task: Arc::new(Mutex::new(Box::pin(async_ipi_req()))),
-        })
-    }
-    // for io_task in src_async_io_task_list.lock().iter() {
-    while !src_async_io_task_list.lock().is_empty() {
-        let io_task = src_async_io_task_list.lock().pop_front().unwrap();
-        let vm_id = io_task.src_vmid;
-        let vm = vm(vm_id).unwrap();
-        let task_data = match &io_task.task_data {
-            AsyncTaskData::AsyncIpiTask(_) => panic!("Find an IPI Task in IO task list"),
-            AsyncTaskData::AsyncIoTask(io_msg) => {
-                assert_eq!(vm_id, io_msg.src_vmid);
-                let vq_idx = io_msg.vq.vq_indx();
-                match vm.emu_blk_dev() {
-                    EmuDevs::VirtioBlk(blk) => {
-                        let new_vq = blk.vq(vq_idx).clone().unwrap();
-                        AsyncTaskData::AsyncIoTask(IoAsyncMsg {
-                            src_vmid: vm_id,
-                            vq: new_vq.clone(),
-                            dev: blk.clone(),
-                            io_type: io_msg.io_type,
-                            blk_id: io_msg.blk_id,
-                            sector: io_msg.sector,
-                            count: io_msg.count,
-                            cache: io_msg.cache,
-                            iov_list: Arc::new({
-                                let mut list = vec![];
-                                for iov in io_msg.iov_list.iter() {
-                                    list.push(BlkIov {
-                                        data_bg: iov.data_bg,
-                                        len: iov.len,
-                                    });
-                                }
-                                list
-                            }),
-                        })
-                    }
-                    _ => panic!("illegal mmio dev type in async_task_update"),
-                }
-            }
-            _ => {
-                todo!()
-            }
-        };
-        async_io_task_list.push_back(AsyncTask {
-            task_data,
-            src_vmid: vm_id,
-            priority: vm.priority(),
-            state: Arc::new(Mutex::new(*io_task.state.lock())),
-            task: Arc::new(Mutex::new(Box::pin(async_blk_io_req()))),
-        });
-    }
-    for (key, used_info) in src_async_used_info_list.lock().iter() {
-        let mut new_used_info = LinkedList::new();
-        for info in used_info.iter() {
-            new_used_info.push_back(UsedInfo {
-                desc_chain_head_idx: info.desc_chain_head_idx,
-                used_len: info.used_len,
-            });
+fn live_update_check_start(prev: usize, rollback: bool, from: &LiveUpdateFrom) {
+    let mut vcpus = BTreeSet::new();
+    for vcpu in from.state::<Mutex<Vec<Vcpu>>>("VCPU_LIST").unwrap().lock().iter() {
+        if matches!(vcpu.state(), VcpuState::VcpuAct | VcpuState::VcpuPend) {
+            vcpus.insert((vcpu.vm_id(), vcpu.id()));
         }
-        async_used_info_list.insert(*key, new_used_info);
-    }
-    // println!("Update {} ipi task for ASYNC_IPI_TASK_LIST", async_ipi_task_list.len());
-    // println!("Update {} io task for ASYNC_IO_TASK_LIST", async_io_task_list.len());
-    // println!(
-    //     "Update {} used info for ASYNC_USED_INFO_LIST",
-    //     async_used_info_list.len()
-    // );
-}
-
-pub fn mediated_blk_list_update(src_mediated_blk_list: &Mutex<Vec<MediatedBlk>>) {
-    let mut mediated_blk_list = MEDIATED_BLK_LIST.lock();
-    assert_eq!(mediated_blk_list.len(), 0);
-    mediated_blk_list.clear();
-    for blk in src_mediated_blk_list.lock().iter() {
-        mediated_blk_list.push(MediatedBlk {
-            base_addr: blk.base_addr,
-            avail: blk.avail,
-        });
     }
+    let now = time_current_us();
+    *LIVE_UPDATE_CHECK.lock() = Some(LiveUpdateCheck {
+        prev,
+        rollback,
+        start: now,
+        deadline: now + UPDATE_HEALTH_DEADLINE_US,
+        cores: (1 << PLAT_DESC.cpu_desc.num) - 1,
+        vcpus,
+    });
+    LIVE_UPDATE_CHECKING.store(true, Ordering::Relaxed);
 }
 
-pub fn arch_time_update(src_time_freq: &Mutex<usize>, src_time_slice: &Mutex<usize>) {
-    *TIMER_FREQ.lock() = *src_time_freq.lock();
-    *TIMER_SLICE.lock() = *src_time_slice.lock();
-}
-
-pub fn cpu_if_alloc(src_cpu_if: &Mutex<Vec<CpuIf>>) {
-    let mut cpu_if_list = CPU_IF_LIST.lock();
-    for _ in 0..src_cpu_if.lock().len() {
-        cpu_if_list.push(CpuIf::default());
+// called by each core when it switches to this image
+fn live_update_check_in() {
+    if !LIVE_UPDATE_CHECKING.load(Ordering::Relaxed) {
+        return;
     }
-}
-
-pub fn cpu_if_update(src_cpu_if: &Mutex<Vec<CpuIf>>) {
-    let mut cpu_if_list = CPU_IF_LIST.lock();
-    assert_eq!(cpu_if_list.len(), src_cpu_if.lock().len());
-    for (idx, cpu_if) in src_cpu_if.lock().iter().enumerate() {
-        for (msg_idx, msg) in cpu_if.msg_queue.iter().enumerate() {
-            // Copy ipi msg
-            let new_ipi_msg = match msg.ipi_message.clone() {
-                IpiInnerMsg::Initc(initc) => IpiInnerMsg::Initc(initc),
-                IpiInnerMsg::Power(power) => IpiInnerMsg::Power(power),
-                IpiInnerMsg::EnternetMsg(eth_msg) => IpiInnerMsg::EnternetMsg(eth_msg),
-                IpiInnerMsg::VmmMsg(vmm_msg) => IpiInnerMsg::VmmMsg(vmm_msg),
-                IpiInnerMsg::MediatedMsg(mediated_msg) => {
-                    let mmio_id = mediated_msg.blk.id();
-                    let vm_id = mediated_msg.src_id;
-                    let vq_idx = mediated_msg.vq.vq_indx();
-
-                    let vm = vm(vm_id).unwrap();
-                    match vm.emu_dev(mmio_id) {
-                        EmuDevs::VirtioBlk(blk) => {
-                            let new_vq = blk.vq(vq_idx).clone().unwrap();
-                            IpiInnerMsg::MediatedMsg(IpiMediatedMsg {
-                                src_id: vm_id,
-                                vq: new_vq.clone(),
-                                blk: blk.clone(),
-                            })
-                        }
-                        _ => {
-                            panic!("illegal mmio dev type in cpu_if_update");
-                        }
-                    }
-                }
-                IpiInnerMsg::MediatedNotifyMsg(notify_msg) => IpiInnerMsg::MediatedNotifyMsg(notify_msg),
-                IpiInnerMsg::HvcMsg(hvc_msg) => IpiInnerMsg::HvcMsg(hvc_msg),
-                IpiInnerMsg::IntInjectMsg(inject_msg) => IpiInnerMsg::IntInjectMsg(inject_msg),
-                IpiInnerMsg::HyperFreshMsg() => IpiInnerMsg::HyperFreshMsg(),
-                IpiInnerMsg::None => IpiInnerMsg::None,
-            };
-            cpu_if_list[idx].msg_queue.insert(
-                msg_idx,
-                IpiMessage {
-                    ipi_type: msg.ipi_type,
-                    ipi_message: new_ipi_msg,
-                },
-            );
+    if let Some(check) = LIVE_UPDATE_CHECK.lock().as_mut() {
+        check.cores &= !(1 << current_cpu().id);
+        if let Some(vcpu) = &current_cpu().active_vcpu {
+            check.vcpus.remove(&(vcpu.vm_id(), vcpu.id()));
         }
-        // println!(
-        //     "Update {} ipi msg for CpuIf[{}], after update len is {}",
-        //     cpu_if.msg_queue.len(),
-        //     idx,
-        //     cpu_if_list[idx].msg_queue.len()
-        // );
     }
 }
 
-pub fn ipi_handler_list_update(src_ipi_handler_list: &Mutex<Vec<IpiHandler>>) {
-    for ipi_handler in src_ipi_handler_list.lock().iter() {
-        let handler = match ipi_handler.ipi_type {
-            IpiType::IpiTIntc => vgic_ipi_handler,
-            IpiType::IpiTPower => psci_ipi_handler,
-            IpiType::IpiTEthernetMsg => ethernet_ipi_rev_handler,
-            IpiType::IpiTHvc => hvc_ipi_handler,
-            IpiType::IpiTVMM => vmm_ipi_handler,
-            IpiType::IpiTMediatedDev => mediated_ipi_handler,
-            IpiType::IpiTIntInject => interrupt_inject_ipi_handler,
-            IpiType::IpiTHyperFresh => hyper_fresh_ipi_handler,
-        };
-        ipi_register(ipi_handler.ipi_type, handler);
+pub fn live_update_vcpu_scheduled(vcpu: &Vcpu) {
+    if !LIVE_UPDATE_CHECKING.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(check) = LIVE_UPDATE_CHECK.lock().as_mut() {
+        check.vcpus.remove(&(vcpu.vm_id(), vcpu.id()));
     }
-    println!("Update IPI_HANDLER_LIST");
 }
 
-pub fn vm_if_list_update(src_vm_if_list: &[Mutex<VmInterface>; VM_NUM_MAX]) {
-    for (idx, vm_if_lock) in src_vm_if_list.iter().enumerate() {
-        let vm_if = vm_if_lock.lock();
-        let mut cur_vm_if = VM_IF_LIST[idx].lock();
-        cur_vm_if.master_cpu_id = vm_if.master_cpu_id;
-        cur_vm_if.state = vm_if.state;
-        cur_vm_if.vm_type = vm_if.vm_type;
-        cur_vm_if.mac = vm_if.mac;
-        cur_vm_if.ivc_arg = vm_if.ivc_arg;
-        cur_vm_if.ivc_arg_ptr = vm_if.ivc_arg_ptr;
-        cur_vm_if.mem_map = match &vm_if.mem_map {
-            None => None,
-            Some(mem_map) => Some(FlexBitmap {
-                len: mem_map.len,
-                map: {
-                    let mut map = vec![];
-                    for v in mem_map.map.iter() {
-                        map.push(*v);
-                    }
-                    map
-                },
-            }),
-        };
-        cur_vm_if.mem_map_cache = match &vm_if.mem_map_cache {
-            None => None,
-            Some(cache) => Some(Arc::new(PageFrame::new(cache.pa, cache.page_num))),
-        };
+/*
+ * Health check after a live update, by the timer of core 0. The update is done once every core
+ * switched to this image and every vcpu running or ready at the update is scheduled again. If it
+ * is not done by the deadline, the image updated from, which is kept resident, is restored. A
+ * rollback is checked the same way, core 0 does not wait for the other cores to come back, it
+ * resets the platform at the deadline if they do not.
+ */
+pub fn live_update_check() {
+    if current_cpu().id != 0 || !LIVE_UPDATE_CHECKING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut lock = match LIVE_UPDATE_CHECK.try_lock() {
+        Some(lock) => lock,
+        None => return,
+    };
+    let check = match lock.as_mut() {
+        Some(check) => check,
+        None => return,
+    };
+    // the vcpus of a VM removed meanwhile do not come back
+    check.vcpus.retain(|(vm_id, _)| vm(*vm_id).is_some());
+    let now = time_current_us();
+    let rollback = if check.cores == 0 && check.vcpus.is_empty() {
+        info!("live update health check passed in {}us", now - check.start);
+        false
+    } else if now >= check.deadline && check.rollback {
+        warn!(
+            "live update rollback failed, cores {:#x} vcpus {:?} not back, reset the platform",
+            check.cores, check.vcpus
+        );
+        Platform::sys_reboot();
+    } else if now >= check.deadline {
+        warn!(
+            "live update health check failed, cores {:#x} vcpus {:?} not back, roll back",
+            check.cores, check.vcpus
+        );
+        *UPDATE_TARGET.lock() = Some((check.prev, true));
+        true
+    } else {
+        return;
+    };
+    *lock = None;
+    LIVE_UPDATE_CHECKING.store(false, Ordering::Relaxed);
+    drop(lock);
+    if rollback {
+        update_request();
     }
 }
 
@@ -636,14 +716,14 @@ pub fn current_cpu_update(src_cpu: &Cpu) {
     let cpu = current_cpu();
     // only need to alloc a new VcpuPool from heap, other props all map at 0x400000000
     // current_cpu().sched = src_cpu.sched;
-    match &src_cpu.sched {
-        SchedType::SchedRR(rr) => {
-            cpu.sched = SchedType::SchedRR(rr.update());
-        }
-        SchedType::None => {
-            cpu.sched = SchedType::None;
-        }
-    }
+    let sched = match &src_cpu.sched {
+        SchedType::SchedRR(rr) => SchedType::SchedRR(rr.update()),
+        SchedType::SchedRT(rt) => SchedType::SchedRT(rt.update()),
+        SchedType::SchedTP(tp) => SchedType::SchedTP(tp.update()),
+        SchedType::None => SchedType::None,
+    };
+    // the old scheduler belongs to the heap of the other image, which is kept for rollback
+    core::mem::forget(core::mem::replace(&mut cpu.sched, sched));
 
     assert_eq!(cpu.id, src_cpu.id);
     assert_eq!(cpu.ctx, src_cpu.ctx);
@@ -654,455 +734,825 @@ pub fn current_cpu_update(src_cpu: &Cpu) {
     println!("Update CPU[{}]", cpu.id);
 }
 
-pub fn gic_lrs_num_update(src_gic_lrs_num: &Mutex<usize>) {
-    let gic_lrs_num = *src_gic_lrs_num.lock();
-    *GIC_LRS_NUM.lock() = gic_lrs_num;
-    println!("Update GIC_LRS_NUM");
-}
-
-// alloc vm_list
-pub fn vm_list_alloc(src_vm_list: &Mutex<Vec<Vm>>) {
-    let mut vm_list = VM_LIST.lock();
-    for vm in src_vm_list.lock().iter() {
-        let new_vm = Vm::new(vm.id());
-        vm_list.push(new_vm.clone());
-        let mut dst_inner = new_vm.inner.lock();
-        let src_inner = vm.inner.lock();
-        let pt = match &src_inner.pt {
-            None => None,
-            Some(page_table) => {
-                let new_page_table = PageTable {
-                    directory: Arc::new(PageFrame::new(page_table.directory.pa, page_table.directory.page_num)),
-                    pages: Arc::new(Mutex::new(vec![])),
-                };
-                for page in page_table.pages.lock().iter() {
-                    new_page_table.pages.lock().push(PageFrame::new(page.pa, page.page_num));
-                }
-                Some(new_page_table)
+impl LiveUpdatable for Mutex<VmConfigTable> {
+    fn live_update(&self, src_vm_config_table: &Self, _from: &LiveUpdateFrom) {
+        let mut vm_config_table = self.lock();
+        let src_config_table = src_vm_config_table.lock();
+        vm_config_table.name = src_config_table.name;
+        vm_config_table.vm_bitmap = src_config_table.vm_bitmap;
+        vm_config_table.vm_num = src_config_table.vm_num;
+        vm_config_table.sched_partitions.clear();
+        for (cpu_id, partition) in src_config_table.sched_partitions.iter() {
+            vm_config_table.sched_partitions.insert(
+                *cpu_id,
+                SchedPartitionConfig {
+                    major_frame: partition.major_frame,
+                    windows: partition.windows.clone(),
+                    spare_to_mvm: partition.spare_to_mvm,
+                },
+            );
+        }
+        assert_eq!(vm_config_table.entries.len(), 0);
+        vm_config_table.entries.clear();
+        for entry in src_config_table.entries.iter() {
+            let image = *entry.image.lock();
+            let memory = VmMemoryConfig {
+                region: {
+                    let mut region = vec![];
+                    for mem in entry.memory.lock().region.iter() {
+                        region.push(*mem);
+                    }
+                    assert_eq!(region, entry.memory.lock().region);
+                    region
+                },
+            };
+            let cpu = *entry.cpu.lock();
+            // emu dev config
+            let mut vm_emu_dev_confg = VmEmulatedDeviceConfigList { emu_dev_list: vec![] };
+            let src_emu_dev_confg_list = entry.vm_emu_dev_confg.lock();
+            for emu_config in &src_emu_dev_confg_list.emu_dev_list {
+                vm_emu_dev_confg.emu_dev_list.push(VmEmulatedDeviceConfig {
+                    name: Some(String::from(emu_config.name.as_ref().unwrap())),
+                    base_ipa: emu_config.base_ipa,
+                    length: emu_config.length,
+                    irq_id: emu_config.irq_id,
+                    cfg_list: {
+                        let mut cfg_list = vec![];
+                        for cfg in emu_config.cfg_list.iter() {
+                            cfg_list.push(*cfg);
+                        }
+                        assert_eq!(cfg_list, emu_config.cfg_list);
+                        cfg_list
+                    },
+                    emu_type: emu_config.emu_type,
+                    mediated: emu_config.mediated,
+                })
             }
-        };
-        dst_inner.ready = src_inner.ready;
-        dst_inner.config = vm_cfg_entry(src_inner.id);
-        dst_inner.pt = pt;
-        dst_inner.mem_region_num = src_inner.mem_region_num;
-        dst_inner.pa_region = {
-            let mut pa_region = vec![];
-            for region in src_inner.pa_region.iter() {
-                pa_region.push(*region);
+            // passthrough dev config
+            let src_pt = entry.vm_pt_dev_confg.lock();
+            let mut vm_pt_dev_confg = VmPassthroughDeviceConfig {
+                regions: vec![],
+                irqs: vec![],
+                streams_ids: vec![],
+            };
+            for region in src_pt.regions.iter() {
+                vm_pt_dev_confg.regions.push(*region);
             }
-            pa_region
-        };
-        dst_inner.entry_point = src_inner.entry_point;
-        dst_inner.has_master = src_inner.has_master;
-        dst_inner.cpu_num = src_inner.cpu_num;
-        dst_inner.ncpu = src_inner.ncpu;
-        dst_inner.intc_dev_id = src_inner.intc_dev_id;
-        dst_inner.int_bitmap = src_inner.int_bitmap;
-        dst_inner.share_mem_base = src_inner.share_mem_base;
-        dst_inner.migrate_save_pf = {
-            let mut pf = vec![];
-            for page in src_inner.migrate_save_pf.iter() {
-                pf.push(PageFrame::new(page.pa, page.page_num));
+            for irq in src_pt.irqs.iter() {
+                vm_pt_dev_confg.irqs.push(*irq);
             }
-            pf
-        };
-        dst_inner.migrate_restore_pf = {
-            let mut pf = vec![];
-            for page in src_inner.migrate_restore_pf.iter() {
-                pf.push(PageFrame::new(page.pa, page.page_num));
+            for streams_id in src_pt.streams_ids.iter() {
+                vm_pt_dev_confg.streams_ids.push(*streams_id);
             }
-            pf
-        };
-        dst_inner.med_blk_id = src_inner.med_blk_id;
+            assert_eq!(vm_pt_dev_confg.regions, src_pt.regions);
+            assert_eq!(vm_pt_dev_confg.irqs, src_pt.irqs);
+            assert_eq!(vm_pt_dev_confg.streams_ids, src_pt.streams_ids);
+
+            // dtb config
+            let mut vm_dtb_devs = VMDtbDevConfigList {
+                dtb_device_list: vec![],
+            };
+            let src_dtb_confg_list = entry.vm_dtb_devs.lock();
+            for dtb_config in src_dtb_confg_list.dtb_device_list.iter() {
+                vm_dtb_devs.dtb_device_list.push(VmDtbDevConfig {
+                    name: String::from(&dtb_config.name),
+                    dev_type: dtb_config.dev_type,
+                    irqs: {
+                        let mut irqs = vec![];
+                        for irq in dtb_config.irqs.iter() {
+                            irqs.push(*irq);
+                        }
+                        assert_eq!(irqs, dtb_config.irqs);
+                        irqs
+                    },
+                    addr_region: dtb_config.addr_region,
+                });
+            }
+
+            vm_config_table.entries.push(VmConfigEntry {
+                id: entry.id,
+                name: Some(String::from(entry.name.as_ref().unwrap())),
+                os_type: entry.os_type,
+                cmdline: String::from(&entry.cmdline),
+                image: Arc::new(Mutex::new(image)),
+                memory: Arc::new(Mutex::new(memory)),
+                cpu: Arc::new(Mutex::new(cpu)),
+                vm_emu_dev_confg: Arc::new(Mutex::new(vm_emu_dev_confg)),
+                vm_pt_dev_confg: Arc::new(Mutex::new(vm_pt_dev_confg)),
+                vm_dtb_devs: Arc::new(Mutex::new(vm_dtb_devs)),
+                health: Arc::new(Mutex::new(entry.health_cfg())),
+                smc: Arc::new(Mutex::new(VmSmcConfig {
+                    ranges: entry.smc.lock().ranges.iter().copied().collect(),
+                })),
+            });
+        }
+        assert_eq!(vm_config_table.entries.len(), src_config_table.entries.len());
+        assert_eq!(vm_config_table.vm_num, src_config_table.vm_num);
+        assert_eq!(vm_config_table.vm_bitmap, src_config_table.vm_bitmap);
+        assert_eq!(vm_config_table.name, src_config_table.name);
+        println!("Update {} VM to DEF_VM_CONFIG_TABLE", vm_config_table.vm_num);
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().entries.clear();
     }
-    assert_eq!(vm_list.len(), src_vm_list.lock().len());
-    println!("Alloc {} VM in VM_LIST", vm_list.len());
 }
 
-// Set vm.vcpu_list in vcpu_update
-pub fn vm_list_update(src_vm_list: &Mutex<Vec<Vm>>) {
-    // let mut vm_list = VM_LIST.lock();
-    assert_eq!(VM_LIST.lock().len(), src_vm_list.lock().len());
-    // vm_list.clear();
-    // drop(vm_list);
-    for (idx, vm) in src_vm_list.lock().iter().enumerate() {
-        let emu_devs = {
-            let mut emu_devs = vec![];
-            // drop(old_inner);
-            let old_emu_devs = vm.inner.lock().emu_devs.clone();
-            for dev in old_emu_devs.iter() {
-                // TODO: wip
-                let new_dev = match dev {
-                    EmuDevs::Vgic(_) => {
-                        // set vgic after vcpu update
-                        EmuDevs::None
-                    }
-                    EmuDevs::VirtioBlk(blk) => {
-                        let mmio = VirtioMmio::new(0);
-                        assert_eq!(
-                            (blk.vq(0).unwrap().desc_table()),
-                            vm_ipa2pa(vm.clone(), blk.vq(0).unwrap().desc_table_addr())
-                        );
-                        assert_eq!(
-                            (blk.vq(0).unwrap().used()),
-                            vm_ipa2pa(vm.clone(), blk.vq(0).unwrap().used_addr())
-                        );
-                        assert_eq!(
-                            (blk.vq(0).unwrap().avail()),
-                            vm_ipa2pa(vm.clone(), blk.vq(0).unwrap().avail_addr())
-                        );
-                        mmio.save_mmio(
-                            blk.clone(),
-                            if blk.dev().mediated() {
-                                Some(virtio_mediated_blk_notify_handler)
-                            } else {
-                                Some(virtio_blk_notify_handler)
-                            },
-                        );
-                        EmuDevs::VirtioBlk(mmio)
+impl LiveUpdatable for Mutex<Vec<Vm>> {
+    fn live_update_alloc(&self, src_vm_list: &Self, _from: &LiveUpdateFrom) {
+        let mut vm_list = self.lock();
+        for vm in src_vm_list.lock().iter() {
+            let new_vm = Vm::new(vm.id());
+            vm_list.push(new_vm.clone());
+            let mut dst_inner = new_vm.inner.lock();
+            let src_inner = vm.inner.lock();
+            let pt = match &src_inner.pt {
+                None => None,
+                Some(page_table) => {
+                    let new_page_table = PageTable {
+                        directory: Arc::new(PageFrame::new(page_table.directory.pa, page_table.directory.page_num)),
+                        pages: Arc::new(Mutex::new(vec![])),
+                    };
+                    for page in page_table.pages.lock().iter() {
+                        new_page_table.pages.lock().push(PageFrame::new(page.pa, page.page_num));
                     }
-                    EmuDevs::VirtioNet(net) => {
-                        let mmio = VirtioMmio::new(0);
-                        assert_eq!(
-                            (net.vq(0).unwrap().desc_table()),
-                            vm_ipa2pa(vm.clone(), net.vq(0).unwrap().desc_table_addr())
-                        );
-                        assert_eq!(
-                            (net.vq(0).unwrap().used()),
-                            vm_ipa2pa(vm.clone(), net.vq(0).unwrap().used_addr())
-                        );
-                        assert_eq!(
-                            (net.vq(0).unwrap().avail()),
-                            vm_ipa2pa(vm.clone(), net.vq(0).unwrap().avail_addr())
-                        );
-                        println!("VirtioNet save handler {:x}", unsafe {
-                            *(&virtio_net_notify_handler as *const _ as *const usize)
-                        });
-                        mmio.save_mmio(net.clone(), Some(virtio_net_notify_handler));
-                        EmuDevs::VirtioNet(mmio)
-                    }
-                    EmuDevs::VirtioConsole(console) => {
-                        let mmio = VirtioMmio::new(0);
-                        assert_eq!(
-                            (console.vq(0).unwrap().desc_table()),
-                            vm_ipa2pa(vm.clone(), console.vq(0).unwrap().desc_table_addr())
-                        );
-                        assert_eq!(
-                            (console.vq(0).unwrap().used()),
-                            vm_ipa2pa(vm.clone(), console.vq(0).unwrap().used_addr())
-                        );
-                        assert_eq!(
-                            (console.vq(0).unwrap().avail()),
-                            vm_ipa2pa(vm.clone(), console.vq(0).unwrap().avail_addr())
-                        );
-                        println!("VirtioConsole save handler {:x}", unsafe {
-                            *(&virtio_console_notify_handler as *const _ as *const usize)
-                        });
-                        mmio.save_mmio(console.clone(), Some(virtio_console_notify_handler));
-                        EmuDevs::VirtioConsole(mmio)
+                    Some(new_page_table)
+                }
+            };
+            dst_inner.ready = src_inner.ready;
+            dst_inner.config = vm_cfg_entry(src_inner.id);
+            dst_inner.pt = pt;
+            dst_inner.mem_region_num = src_inner.mem_region_num;
+            dst_inner.pa_region = {
+                let mut pa_region = vec![];
+                for region in src_inner.pa_region.iter() {
+                    pa_region.push(*region);
+                }
+                pa_region
+            };
+            dst_inner.entry_point = src_inner.entry_point;
+            dst_inner.has_master = src_inner.has_master;
+            dst_inner.cpu_num = src_inner.cpu_num;
+            dst_inner.ncpu = src_inner.ncpu;
+            dst_inner.intc_dev_id = src_inner.intc_dev_id;
+            dst_inner.int_bitmap = src_inner.int_bitmap;
+            dst_inner.share_mem_base = src_inner.share_mem_base;
+            dst_inner.migrate_save_pf = {
+                let mut pf = vec![];
+                for page in src_inner.migrate_save_pf.iter() {
+                    pf.push(PageFrame::new(page.pa, page.page_num));
+                }
+                pf
+            };
+            dst_inner.migrate_restore_pf = {
+                let mut pf = vec![];
+                for page in src_inner.migrate_restore_pf.iter() {
+                    pf.push(PageFrame::new(page.pa, page.page_num));
+                }
+                pf
+            };
+            dst_inner.med_blk_id = src_inner.med_blk_id;
+            dst_inner.stats = src_inner.stats.clone();
+        }
+        assert_eq!(vm_list.len(), src_vm_list.lock().len());
+        println!("Alloc {} VM in VM_LIST", vm_list.len());
+    }
+
+    // Set vm.vcpu_list in the Vcpu stage
+    fn live_update(&self, src_vm_list: &Self, _from: &LiveUpdateFrom) {
+        // let mut vm_list = VM_LIST.lock();
+        assert_eq!(self.lock().len(), src_vm_list.lock().len());
+        // vm_list.clear();
+        // drop(vm_list);
+        for (idx, vm) in src_vm_list.lock().iter().enumerate() {
+            let emu_devs = {
+                let mut emu_devs = vec![];
+                // drop(old_inner);
+                let old_emu_devs = vm.inner.lock().emu_devs.clone();
+                for dev in old_emu_devs.iter() {
+                    // TODO: wip
+                    let new_dev = match dev {
+                        EmuDevs::Vgic(_) => {
+                            // set vgic after vcpu update
+                            EmuDevs::None
+                        }
+                        EmuDevs::VirtioBlk(blk) => {
+                            let mmio = VirtioMmio::new(0);
+                            assert_eq!(
+                                (blk.vq(0).unwrap().desc_table()),
+                                vm_ipa2pa(vm.clone(), blk.vq(0).unwrap().desc_table_addr())
+                            );
+                            assert_eq!(
+                                (blk.vq(0).unwrap().used()),
+                                vm_ipa2pa(vm.clone(), blk.vq(0).unwrap().used_addr())
+                            );
+                            assert_eq!(
+                                (blk.vq(0).unwrap().avail()),
+                                vm_ipa2pa(vm.clone(), blk.vq(0).unwrap().avail_addr())
+                            );
+                            mmio.save_mmio(
+                                blk.clone(),
+                                if blk.dev().mediated() {
+                                    Some(virtio_mediated_blk_notify_handler)
+                                } else {
+                                    Some(virtio_blk_notify_handler)
+                                },
+                            );
+                            EmuDevs::VirtioBlk(mmio)
+                        }
+                        EmuDevs::VirtioNet(net) => {
+                            let mmio = VirtioMmio::new(0);
+                            assert_eq!(
+                                (net.vq(0).unwrap().desc_table()),
+                                vm_ipa2pa(vm.clone(), net.vq(0).unwrap().desc_table_addr())
+                            );
+                            assert_eq!(
+                                (net.vq(0).unwrap().used()),
+                                vm_ipa2pa(vm.clone(), net.vq(0).unwrap().used_addr())
+                            );
+                            assert_eq!(
+                                (net.vq(0).unwrap().avail()),
+                                vm_ipa2pa(vm.clone(), net.vq(0).unwrap().avail_addr())
+                            );
+                            println!("VirtioNet save handler {:x}", unsafe {
+                                *(&virtio_net_notify_handler as *const _ as *const usize)
+                            });
+                            mmio.save_mmio(net.clone(), Some(virtio_net_notify_handler));
+                            EmuDevs::VirtioNet(mmio)
+                        }
+                        EmuDevs::VirtioConsole(console) => {
+                            let mmio = VirtioMmio::new(0);
+                            assert_eq!(
+                                (console.vq(0).unwrap().desc_table()),
+                                vm_ipa2pa(vm.clone(), console.vq(0).unwrap().desc_table_addr())
+                            );
+                            assert_eq!(
+                                (console.vq(0).unwrap().used()),
+                                vm_ipa2pa(vm.clone(), console.vq(0).unwrap().used_addr())
+                            );
+                            assert_eq!(
+                                (console.vq(0).unwrap().avail()),
+                                vm_ipa2pa(vm.clone(), console.vq(0).unwrap().avail_addr())
+                            );
+                            println!("VirtioConsole save handler {:x}", unsafe {
+                                *(&virtio_console_notify_handler as *const _ as *const usize)
+                            });
+                            mmio.save_mmio(console.clone(), Some(virtio_console_notify_handler));
+                            EmuDevs::VirtioConsole(mmio)
+                        }
+                        EmuDevs::None => EmuDevs::None,
+                    };
+                    emu_devs.push(new_dev);
+                }
+                emu_devs
+            };
+            let dst_vm = self.lock()[idx].clone();
+            let mut dst_inner = dst_vm.inner.lock();
+            let src_inner = vm.inner.lock();
+            assert_eq!(dst_inner.id, src_inner.id);
+            dst_inner.emu_devs = emu_devs;
+        }
+        // println!("Update VM_LIST");
+    }
+
+    fn live_update_reset(&self) {
+        // the page tables and the memory of the VMs are kept by the image rolled back from
+        core::mem::forget(core::mem::take(&mut *self.lock()));
+    }
+}
+
+impl LiveUpdatable for Mutex<Vec<Vcpu>> {
+    fn live_update_alloc(&self, src_vcpu_list: &Self, _from: &LiveUpdateFrom) {
+        let mut vcpu_list = self.lock();
+        for vcpu in src_vcpu_list.lock().iter() {
+            let src_inner = vcpu.inner.lock();
+            let src_vm_option = src_inner.vm.clone();
+            let vm = match src_vm_option {
+                None => None,
+                Some(src_vm) => {
+                    let vm_id = src_vm.id();
+                    vm(vm_id)
+                }
+            };
+            let mut vcpu_inner = VcpuInner::default();
+            vcpu_inner.vm = vm.clone();
+            vcpu_inner.id = src_inner.id;
+            vcpu_inner.phys_id = src_inner.phys_id;
+            let vcpu = Vcpu {
+                inner: Arc::new(Mutex::new(vcpu_inner)),
+            };
+            vm.unwrap().push_vcpu(vcpu.clone());
+            vcpu_list.push(vcpu);
+        }
+        assert_eq!(vcpu_list.len(), src_vcpu_list.lock().len());
+        println!("Alloc {} VCPU to VCPU_LIST", vcpu_list.len());
+    }
+
+    fn live_update(&self, src_vcpu_list: &Self, from: &LiveUpdateFrom) {
+        let src_vm_list = from.state::<Mutex<Vec<Vm>>>("VM_LIST").unwrap();
+        let vcpu_list = self.lock();
+        // assert_eq!(vcpu_list.len(), src_vcpu_list.lock().len());
+        for (idx, vcpu) in src_vcpu_list.lock().iter().enumerate() {
+            let src_inner = vcpu.inner.lock();
+            let mut dst_inner = vcpu_list[idx].inner.lock();
+
+            // assert_eq!(dst_inner.id, src_inner.id);
+            // assert_eq!(dst_inner.phys_id, src_inner.phys_id);
+            dst_inner.state = src_inner.state;
+            dst_inner.power_on_pending = src_inner.power_on_pending;
+            dst_inner.reboot_pending = src_inner.reboot_pending;
+            dst_inner.throttle_debt = src_inner.throttle_debt;
+            dst_inner.throttle_until = src_inner.throttle_until;
+            dst_inner.postcopy_page = src_inner.postcopy_page;
+            dst_inner.int_list = {
+                let mut int_list = vec![];
+                for int in src_inner.int_list.iter() {
+                    int_list.push(*int);
+                }
+                int_list
+            };
+            dst_inner.vcpu_ctx = src_inner.vcpu_ctx;
+            dst_inner.vm_ctx = src_inner.vm_ctx;
+            dst_inner.stats = src_inner.stats;
+            // assert_eq!(dst_inner.int_list, src_inner.int_list);
+        }
+
+        // Add vgic emu dev for vm
+        for src_vm in src_vm_list.lock().iter() {
+            let src_vgic = src_vm.vgic();
+            let new_vgic = Vgic::default();
+            new_vgic.save_vgic(src_vgic.clone());
+
+            let vm = vm(src_vm.id()).unwrap();
+            if let EmuDevs::None = vm.emu_dev(vm.intc_dev_id()) {
+                vm.set_emu_devs(vm.intc_dev_id(), EmuDevs::Vgic(Arc::new(new_vgic)));
+            } else {
+                panic!("illegal vgic emu dev idx in vm.emu_devs");
+            }
+        }
+        // println!("Update {} Vcpu to VCPU_LIST", vcpu_list.len());
+    }
+
+    fn live_update_reset(&self) {
+        // each vcpu holds its VM, see VM_LIST
+        core::mem::forget(core::mem::take(&mut *self.lock()));
+    }
+}
+
+impl LiveUpdatable for Mutex<Vec<CpuIf>> {
+    fn live_update_alloc(&self, src_cpu_if: &Self, _from: &LiveUpdateFrom) {
+        let mut cpu_if_list = self.lock();
+        for _ in 0..src_cpu_if.lock().len() {
+            cpu_if_list.push(CpuIf::default());
+        }
+    }
+
+    fn live_update(&self, src_cpu_if: &Self, _from: &LiveUpdateFrom) {
+        let mut cpu_if_list = self.lock();
+        assert_eq!(cpu_if_list.len(), src_cpu_if.lock().len());
+        for (idx, cpu_if) in src_cpu_if.lock().iter().enumerate() {
+            for (msg_idx, msg) in cpu_if.msg_queue.iter().enumerate() {
+                // Copy ipi msg
+                let new_ipi_msg = match msg.ipi_message.clone() {
+                    IpiInnerMsg::Initc(initc) => IpiInnerMsg::Initc(initc),
+                    IpiInnerMsg::Power(power) => IpiInnerMsg::Power(power),
+                    IpiInnerMsg::EnternetMsg(eth_msg) => IpiInnerMsg::EnternetMsg(eth_msg),
+                    IpiInnerMsg::VmmMsg(vmm_msg) => IpiInnerMsg::VmmMsg(vmm_msg),
+                    IpiInnerMsg::MediatedMsg(mediated_msg) => {
+                        let mmio_id = mediated_msg.blk.id();
+                        let vm_id = mediated_msg.src_id;
+                        let vq_idx = mediated_msg.vq.vq_indx();
+
+                        let vm = vm(vm_id).unwrap();
+                        match vm.emu_dev(mmio_id) {
+                            EmuDevs::VirtioBlk(blk) => {
+                                let new_vq = blk.vq(vq_idx).clone().unwrap();
+                                IpiInnerMsg::MediatedMsg(IpiMediatedMsg {
+                                    src_id: vm_id,
+                                    vq: new_vq.clone(),
+                                    blk: blk.clone(),
+                                })
+                            }
+                            _ => {
+                                panic!("illegal mmio dev type in cpu_if_update");
+                            }
+                        }
                     }
-                    EmuDevs::None => EmuDevs::None,
+                    IpiInnerMsg::MediatedNotifyMsg(notify_msg) => IpiInnerMsg::MediatedNotifyMsg(notify_msg),
+                    IpiInnerMsg::HvcMsg(hvc_msg) => IpiInnerMsg::HvcMsg(hvc_msg),
+                    IpiInnerMsg::IntInjectMsg(inject_msg) => IpiInnerMsg::IntInjectMsg(inject_msg),
+                    IpiInnerMsg::HyperFreshMsg() => IpiInnerMsg::HyperFreshMsg(),
+                    IpiInnerMsg::None => IpiInnerMsg::None,
                 };
-                emu_devs.push(new_dev);
+                cpu_if_list[idx].msg_queue.insert(
+                    msg_idx,
+                    IpiMessage {
+                        ipi_type: msg.ipi_type,
+                        ipi_message: new_ipi_msg,
+                    },
+                );
             }
-            emu_devs
-        };
-        let dst_vm = VM_LIST.lock()[idx].clone();
-        let mut dst_inner = dst_vm.inner.lock();
-        let src_inner = vm.inner.lock();
-        assert_eq!(dst_inner.id, src_inner.id);
-        dst_inner.emu_devs = emu_devs;
+            // println!(
+            //     "Update {} ipi msg for CpuIf[{}], after update len is {}",
+            //     cpu_if.msg_queue.len(),
+            //     idx,
+            //     cpu_if_list[idx].msg_queue.len()
+            // );
+        }
+    }
+
+    fn live_update_reset(&self) {
+        // the pending messages may hold a VM or a virtio device, see VM_LIST
+        core::mem::forget(core::mem::take(&mut *self.lock()));
+    }
+}
+
+impl LiveUpdatable for Mutex<Vec<IpiHandler>> {
+    fn live_update(&self, src_ipi_handler_list: &Self, _from: &LiveUpdateFrom) {
+        for ipi_handler in src_ipi_handler_list.lock().iter() {
+            let handler = match ipi_handler.ipi_type {
+                IpiType::IpiTIntc => vgic_ipi_handler,
+                IpiType::IpiTPower => psci_ipi_handler,
+                IpiType::IpiTEthernetMsg => ethernet_ipi_rev_handler,
+                IpiType::IpiTHvc => hvc_ipi_handler,
+                IpiType::IpiTVMM => vmm_ipi_handler,
+                IpiType::IpiTMediatedDev => mediated_ipi_handler,
+                IpiType::IpiTIntInject => interrupt_inject_ipi_handler,
+                IpiType::IpiTHyperFresh => hyper_fresh_ipi_handler,
+                IpiType::IpiTHotPatch => hot_patch_ipi_handler,
+            };
+            ipi_register(ipi_handler.ipi_type, handler);
+        }
+        println!("Update IPI_HANDLER_LIST");
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().clear();
     }
-    // println!("Update VM_LIST");
 }
 
-pub fn heap_region_update(src_heap_region: &Mutex<HeapRegion>) {
-    let mut heap_region = HEAP_REGION.lock();
-    let src_region = src_heap_region.lock();
-    heap_region.map = src_region.map;
-    heap_region.region = src_region.region;
-    assert_eq!(heap_region.region, src_region.region);
+impl LiveUpdatable for Mutex<usize> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        *self.lock() = *src.lock();
+    }
 }
 
-pub fn vm_region_update(src_vm_region: &Mutex<VmRegion>) {
-    let mut vm_region = VM_REGION.lock();
-    assert_eq!(vm_region.region.len(), 0);
-    vm_region.region.clear();
-    for mem_region in src_vm_region.lock().region.iter() {
-        vm_region.region.push(*mem_region);
+impl LiveUpdatable for Mutex<BitMap<BitAlloc256>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        *self.lock() = *src.lock();
     }
-    assert_eq!(vm_region.region, src_vm_region.lock().region);
 }
 
-pub fn interrupt_update(
-    src_hyper_bitmap: &Mutex<BitMap<BitAlloc256>>,
-    src_glb_bitmap: &Mutex<BitMap<BitAlloc256>>,
-    src_en_set: &Mutex<BTreeSet<usize>>,
-    src_handlers: &Mutex<BTreeMap<usize, InterruptHandler>>,
-) {
-    let mut hyper_bitmap = INTERRUPT_HYPER_BITMAP.lock();
-    *hyper_bitmap = *src_hyper_bitmap.lock();
-    let mut glb_bitmap = INTERRUPT_GLB_BITMAP.lock();
-    *glb_bitmap = *src_glb_bitmap.lock();
-    let mut handlers = INTERRUPT_HANDLERS.lock();
-    for (int_id, handler) in src_handlers.lock().iter() {
-        match handler {
-            InterruptHandler::IpiIrqHandler(_) => {
-                handlers.insert(*int_id, InterruptHandler::IpiIrqHandler(ipi_irq_handler));
-            }
-            InterruptHandler::GicMaintenanceHandler(_) => {
-                handlers.insert(
-                    *int_id,
-                    InterruptHandler::GicMaintenanceHandler(gic_maintenance_handler),
-                );
-            }
-            InterruptHandler::TimeIrqHandler(_) => {
-                handlers.insert(*int_id, InterruptHandler::TimeIrqHandler(timer_irq_handler));
-            }
-            InterruptHandler::None => {
-                handlers.insert(*int_id, InterruptHandler::None);
-            }
-        }
+impl LiveUpdatable for Mutex<BTreeSet<usize>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        self.lock().extend(&*src.lock());
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().clear();
     }
-    let mut en_set = INTERRUPT_EN_SET.lock();
-    (*en_set).extend(&*src_en_set.lock());
-    println!("Update INTERRUPT_GLB_BITMAP / INTERRUPT_HYPER_BITMAP / INTERRUPT_EN_SET / INTERRUPT_HANDLERS");
 }
 
-pub fn emu_dev_list_update(src_emu_dev_list: &Mutex<Vec<EmuDevEntry>>) {
-    let mut emu_dev_list = EMU_DEVS_LIST.lock();
-    assert_eq!(emu_dev_list.len(), 0);
-    emu_dev_list.clear();
-    for emu_dev_entry in src_emu_dev_list.lock().iter() {
-        let emu_handler = match emu_dev_entry.emu_type {
-            EmuDeviceType::EmuDeviceTGicd => emu_intc_handler,
-            EmuDeviceType::EmuDeviceTGPPT => partial_passthrough_intc_handler,
-            EmuDeviceType::EmuDeviceTVirtioBlk => emu_virtio_mmio_handler,
-            EmuDeviceType::EmuDeviceTVirtioNet => emu_virtio_mmio_handler,
-            EmuDeviceType::EmuDeviceTVirtioConsole => emu_virtio_mmio_handler,
-            EmuDeviceType::EmuDeviceTIOMMU => emu_smmu_handler,
-            _ => {
-                panic!("not support emu dev entry type {}", emu_dev_entry.emu_type);
+impl LiveUpdatable for Mutex<BTreeMap<usize, InterruptHandler>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        let mut handlers = self.lock();
+        for (int_id, handler) in src.lock().iter() {
+            match handler {
+                InterruptHandler::IpiIrqHandler(_) => {
+                    handlers.insert(*int_id, InterruptHandler::IpiIrqHandler(ipi_irq_handler));
+                }
+                InterruptHandler::GicMaintenanceHandler(_) => {
+                    handlers.insert(
+                        *int_id,
+                        InterruptHandler::GicMaintenanceHandler(gic_maintenance_handler),
+                    );
+                }
+                InterruptHandler::TimeIrqHandler(_) => {
+                    handlers.insert(*int_id, InterruptHandler::TimeIrqHandler(timer_irq_handler));
+                }
+                InterruptHandler::None => {
+                    handlers.insert(*int_id, InterruptHandler::None);
+                }
             }
-        };
-        emu_dev_list.push(EmuDevEntry {
-            emu_type: emu_dev_entry.emu_type,
-            vm_id: emu_dev_entry.vm_id,
-            id: emu_dev_entry.id,
-            ipa: emu_dev_entry.ipa,
-            size: emu_dev_entry.size,
-            handler: emu_handler,
-        });
-    }
-    println!("Update {} emu dev for EMU_DEVS_LIST", emu_dev_list.len());
+        }
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().clear();
+    }
 }
 
-pub fn vm_config_table_update(src_vm_config_table: &Mutex<VmConfigTable>) {
-    let mut vm_config_table = DEF_VM_CONFIG_TABLE.lock();
-    let src_config_table = src_vm_config_table.lock();
-    vm_config_table.name = src_config_table.name;
-    vm_config_table.vm_bitmap = src_config_table.vm_bitmap;
-    vm_config_table.vm_num = src_config_table.vm_num;
-    assert_eq!(vm_config_table.entries.len(), 0);
-    vm_config_table.entries.clear();
-    for entry in src_config_table.entries.iter() {
-        let image = *entry.image.lock();
-        let memory = VmMemoryConfig {
-            region: {
-                let mut region = vec![];
-                for mem in entry.memory.lock().region.iter() {
-                    region.push(*mem);
+impl LiveUpdatable for Mutex<Vec<EmuDevEntry>> {
+    fn live_update(&self, src_emu_dev_list: &Self, _from: &LiveUpdateFrom) {
+        let mut emu_dev_list = self.lock();
+        assert_eq!(emu_dev_list.len(), 0);
+        emu_dev_list.clear();
+        for emu_dev_entry in src_emu_dev_list.lock().iter() {
+            let emu_handler = match emu_dev_entry.emu_type {
+                EmuDeviceType::EmuDeviceTGicd => emu_intc_handler,
+                EmuDeviceType::EmuDeviceTGPPT => partial_passthrough_intc_handler,
+                EmuDeviceType::EmuDeviceTVirtioBlk => emu_virtio_mmio_handler,
+                EmuDeviceType::EmuDeviceTVirtioNet => emu_virtio_mmio_handler,
+                EmuDeviceType::EmuDeviceTVirtioConsole => emu_virtio_mmio_handler,
+                EmuDeviceType::EmuDeviceTIOMMU => emu_smmu_handler,
+                #[cfg(feature = "gicv3")]
+                EmuDeviceType::EmuDeviceTGicr => crate::arch::emu_vgicr_handler,
+                _ => {
+                    panic!("not support emu dev entry type {}", emu_dev_entry.emu_type);
                 }
-                assert_eq!(region, entry.memory.lock().region);
-                region
-            },
-        };
-        let cpu = *entry.cpu.lock();
-        // emu dev config
-        let mut vm_emu_dev_confg = VmEmulatedDeviceConfigList { emu_dev_list: vec![] };
-        let src_emu_dev_confg_list = entry.vm_emu_dev_confg.lock();
-        for emu_config in &src_emu_dev_confg_list.emu_dev_list {
-            vm_emu_dev_confg.emu_dev_list.push(VmEmulatedDeviceConfig {
-                name: Some(String::from(emu_config.name.as_ref().unwrap())),
-                base_ipa: emu_config.base_ipa,
-                length: emu_config.length,
-                irq_id: emu_config.irq_id,
-                cfg_list: {
-                    let mut cfg_list = vec![];
-                    for cfg in emu_config.cfg_list.iter() {
-                        cfg_list.push(*cfg);
-                    }
-                    assert_eq!(cfg_list, emu_config.cfg_list);
-                    cfg_list
-                },
-                emu_type: emu_config.emu_type,
-                mediated: emu_config.mediated,
-            })
+            };
+            emu_dev_list.push(EmuDevEntry {
+                emu_type: emu_dev_entry.emu_type,
+                vm_id: emu_dev_entry.vm_id,
+                id: emu_dev_entry.id,
+                ipa: emu_dev_entry.ipa,
+                size: emu_dev_entry.size,
+                handler: emu_handler,
+            });
         }
-        // passthrough dev config
-        let src_pt = entry.vm_pt_dev_confg.lock();
-        let mut vm_pt_dev_confg = VmPassthroughDeviceConfig {
-            regions: vec![],
-            irqs: vec![],
-            streams_ids: vec![],
-        };
-        for region in src_pt.regions.iter() {
-            vm_pt_dev_confg.regions.push(*region);
+        println!("Update {} emu dev for EMU_DEVS_LIST", emu_dev_list.len());
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().clear();
+    }
+}
+
+impl LiveUpdatable for Mutex<VmRegion> {
+    fn live_update(&self, src_vm_region: &Self, _from: &LiveUpdateFrom) {
+        let mut vm_region = self.lock();
+        assert_eq!(vm_region.region.len(), 0);
+        vm_region.region.clear();
+        for mem_region in src_vm_region.lock().region.iter() {
+            vm_region.region.push(*mem_region);
         }
-        for irq in src_pt.irqs.iter() {
-            vm_pt_dev_confg.irqs.push(*irq);
+        assert_eq!(vm_region.region, src_vm_region.lock().region);
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().region.clear();
+    }
+}
+
+impl LiveUpdatable for Mutex<HeapRegion> {
+    fn live_update(&self, src_heap_region: &Self, _from: &LiveUpdateFrom) {
+        let mut heap_region = self.lock();
+        let src_region = src_heap_region.lock();
+        heap_region.map = src_region.map;
+        heap_region.region = src_region.region;
+        assert_eq!(heap_region.region, src_region.region);
+    }
+}
+
+impl LiveUpdatable for [Mutex<VmInterface>; VM_NUM_MAX] {
+    fn live_update(&self, src_vm_if_list: &Self, _from: &LiveUpdateFrom) {
+        for (idx, vm_if_lock) in src_vm_if_list.iter().enumerate() {
+            let vm_if = vm_if_lock.lock();
+            let mut cur_vm_if = self[idx].lock();
+            cur_vm_if.master_cpu_id = vm_if.master_cpu_id;
+            cur_vm_if.state = vm_if.state;
+            cur_vm_if.vm_type = vm_if.vm_type;
+            cur_vm_if.mac = vm_if.mac;
+            cur_vm_if.ivc_arg = vm_if.ivc_arg;
+            cur_vm_if.ivc_arg_ptr = vm_if.ivc_arg_ptr;
+            cur_vm_if.pause_counter = vm_if.pause_counter;
+            cur_vm_if.mem_map = match &vm_if.mem_map {
+                None => None,
+                Some(mem_map) => Some(FlexBitmap {
+                    len: mem_map.len,
+                    map: {
+                        let mut map = vec![];
+                        for v in mem_map.map.iter() {
+                            map.push(*v);
+                        }
+                        map
+                    },
+                }),
+            };
+            cur_vm_if.mem_map_cache = match &vm_if.mem_map_cache {
+                None => None,
+                Some(cache) => Some(Arc::new(PageFrame::new(cache.pa, cache.page_num))),
+            };
         }
-        for streams_id in src_pt.streams_ids.iter() {
-            vm_pt_dev_confg.streams_ids.push(*streams_id);
+    }
+
+    fn live_update_reset(&self) {
+        // dropped before the heap region is carried over, the cache page is kept by the image rolled back from
+        for vm_if in self.iter() {
+            let mut vm_if = vm_if.lock();
+            vm_if.mem_map = None;
+            core::mem::forget(vm_if.mem_map_cache.take());
         }
-        assert_eq!(vm_pt_dev_confg.regions, src_pt.regions);
-        assert_eq!(vm_pt_dev_confg.irqs, src_pt.irqs);
-        assert_eq!(vm_pt_dev_confg.streams_ids, src_pt.streams_ids);
+    }
+}
 
-        // dtb config
-        let mut vm_dtb_devs = VMDtbDevConfigList {
-            dtb_device_list: vec![],
-        };
-        let src_dtb_confg_list = entry.vm_dtb_devs.lock();
-        for dtb_config in src_dtb_confg_list.dtb_device_list.iter() {
-            vm_dtb_devs.dtb_device_list.push(VmDtbDevConfig {
-                name: String::from(&dtb_config.name),
-                dev_type: dtb_config.dev_type,
-                irqs: {
-                    let mut irqs = vec![];
-                    for irq in dtb_config.irqs.iter() {
-                        irqs.push(*irq);
-                    }
-                    assert_eq!(irqs, dtb_config.irqs);
-                    irqs
-                },
-                addr_region: dtb_config.addr_region,
+impl LiveUpdatable for Mutex<Vec<MediatedBlk>> {
+    fn live_update(&self, src_mediated_blk_list: &Self, _from: &LiveUpdateFrom) {
+        let mut mediated_blk_list = self.lock();
+        assert_eq!(mediated_blk_list.len(), 0);
+        mediated_blk_list.clear();
+        for blk in src_mediated_blk_list.lock().iter() {
+            mediated_blk_list.push(MediatedBlk {
+                base_addr: blk.base_addr,
+                avail: blk.avail,
             });
         }
+    }
 
-        vm_config_table.entries.push(VmConfigEntry {
-            id: entry.id,
-            name: Some(String::from(entry.name.as_ref().unwrap())),
-            os_type: entry.os_type,
-            cmdline: String::from(&entry.cmdline),
-            image: Arc::new(Mutex::new(image)),
-            memory: Arc::new(Mutex::new(memory)),
-            cpu: Arc::new(Mutex::new(cpu)),
-            vm_emu_dev_confg: Arc::new(Mutex::new(vm_emu_dev_confg)),
-            vm_pt_dev_confg: Arc::new(Mutex::new(vm_pt_dev_confg)),
-            vm_dtb_devs: Arc::new(Mutex::new(vm_dtb_devs)),
-        });
-    }
-    assert_eq!(vm_config_table.entries.len(), src_config_table.entries.len());
-    assert_eq!(vm_config_table.vm_num, src_config_table.vm_num);
-    assert_eq!(vm_config_table.vm_bitmap, src_config_table.vm_bitmap);
-    assert_eq!(vm_config_table.name, src_config_table.name);
-    println!("Update {} VM to DEF_VM_CONFIG_TABLE", vm_config_table.vm_num);
+    fn live_update_reset(&self) {
+        self.lock().clear();
+    }
 }
 
-pub fn vcpu_list_alloc(src_vcpu_list: &Mutex<Vec<Vcpu>>) {
-    let mut vcpu_list = VCPU_LIST.lock();
-    for vcpu in src_vcpu_list.lock().iter() {
-        let src_inner = vcpu.inner.lock();
-        let src_vm_option = src_inner.vm.clone();
-        let vm = match src_vm_option {
-            None => None,
-            Some(src_vm) => {
-                let vm_id = src_vm.id();
-                vm(vm_id)
-            }
-        };
-        let mut vcpu_inner = VcpuInner::default();
-        vcpu_inner.vm = vm.clone();
-        vcpu_inner.id = src_inner.id;
-        vcpu_inner.phys_id = src_inner.phys_id;
-        let vcpu = Vcpu {
-            inner: Arc::new(Mutex::new(vcpu_inner)),
-        };
-        vm.unwrap().push_vcpu(vcpu.clone());
-        vcpu_list.push(vcpu);
+impl LiveUpdatable for Mutex<BTreeMap<usize, usize>> {
+    fn live_update(&self, src_shared_mem_list: &Self, _from: &LiveUpdateFrom) {
+        let mut shared_mem_list = self.lock();
+        for (key, val) in src_shared_mem_list.lock().iter() {
+            shared_mem_list.insert(*key, *val);
+        }
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().clear();
     }
-    assert_eq!(vcpu_list.len(), src_vcpu_list.lock().len());
-    println!("Alloc {} VCPU to VCPU_LIST", vcpu_list.len());
 }
 
-pub fn vcpu_update(src_vcpu_list: &Mutex<Vec<Vcpu>>, src_vm_list: &Mutex<Vec<Vm>>) {
-    let vcpu_list = VCPU_LIST.lock();
-    // assert_eq!(vcpu_list.len(), src_vcpu_list.lock().len());
-    for (idx, vcpu) in src_vcpu_list.lock().iter().enumerate() {
-        let src_inner = vcpu.inner.lock();
-        let mut dst_inner = vcpu_list[idx].inner.lock();
-
-        // assert_eq!(dst_inner.id, src_inner.id);
-        // assert_eq!(dst_inner.phys_id, src_inner.phys_id);
-        dst_inner.state = src_inner.state;
-        dst_inner.int_list = {
-            let mut int_list = vec![];
-            for int in src_inner.int_list.iter() {
-                int_list.push(*int);
-            }
-            int_list
-        };
-        dst_inner.vcpu_ctx = src_inner.vcpu_ctx;
-        dst_inner.vm_ctx = src_inner.vm_ctx;
-        // assert_eq!(dst_inner.int_list, src_inner.int_list);
+impl LiveUpdatable for Mutex<AsyncExeStatus> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        *self.lock() = *src.lock();
+    }
+}
+
+impl LiveUpdatable for Mutex<LinkedList<AsyncTask>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        let mut async_ipi_task_list = self.lock();
+        assert_eq!(async_ipi_task_list.len(), 0);
+        for ipi_task in src.lock().iter() {
+            let vm_id = ipi_task.src_vmid;
+            let vm = vm(vm_id).unwrap();
+            let task_data = match &ipi_task.task_data {
+                AsyncTaskData::AsyncIpiTask(mediated_msg) => {
+                    assert_eq!(mediated_msg.src_id, vm_id);
+                    let mmio_id = mediated_msg.blk.id();
+                    let vq_idx = mediated_msg.vq.vq_indx();
+                    match vm.emu_dev(mmio_id) {
+                        EmuDevs::VirtioBlk(blk) => {
+                            let new_vq = blk.vq(vq_idx).clone().unwrap();
+                            AsyncTaskData::AsyncIpiTask(IpiMediatedMsg {
+                                src_id: vm_id,
+                                vq: new_vq.clone(),
+                                blk: blk.clone(),
+                            })
+                        }
+                        _ => panic!("illegal mmio dev type in async_task_update"),
+                    }
+                }
+                AsyncTaskData::AsyncIoTask(_) => panic!("Find an IO Task in IPI task list"),
+                AsyncTaskData::AsyncNoneTask(_) => panic!("Find an IO None Task in IPI task list"),
+            };
+            async_ipi_task_list.push_back(AsyncTask {
+                task_data,
+                src_vmid: vm_id,
+                priority: vm.priority(),
+                state: Arc::new(Mutex::new(*ipi_task.state.lock())),
+                task: Arc::new(Mutex::new(Box::pin(async_ipi_req()))),
+            })
+        }
     }
 
-    // Add vgic emu dev for vm
-    for src_vm in src_vm_list.lock().iter() {
-        let src_vgic = src_vm.vgic();
-        let new_vgic = Vgic::default();
-        new_vgic.save_vgic(src_vgic.clone());
+    fn live_update_reset(&self) {
+        // the tasks hold the virtio devices and queues of the VMs, see VM_LIST
+        core::mem::forget(core::mem::take(&mut *self.lock()));
+    }
+}
 
-        let vm = vm(src_vm.id()).unwrap();
-        if let EmuDevs::None = vm.emu_dev(vm.intc_dev_id()) {
-            vm.set_emu_devs(vm.intc_dev_id(), EmuDevs::Vgic(Arc::new(new_vgic)));
-        } else {
-            panic!("illegal vgic emu dev idx in vm.emu_devs");
+impl LiveUpdatable for Mutex<FairQueue<AsyncTask>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        let mut async_io_task_list = self.lock();
+        assert_eq!(async_io_task_list.len(), 0);
+        // for io_task in src.lock().iter() {
+        while !src.lock().is_empty() {
+            let io_task = src.lock().pop_front().unwrap();
+            let vm_id = io_task.src_vmid;
+            let vm = vm(vm_id).unwrap();
+            let task_data = match &io_task.task_data {
+                AsyncTaskData::AsyncIpiTask(_) => panic!("Find an IPI Task in IO task list"),
+                AsyncTaskData::AsyncIoTask(io_msg) => {
+                    assert_eq!(vm_id, io_msg.src_vmid);
+                    let vq_idx = io_msg.vq.vq_indx();
+                    match vm.emu_blk_dev() {
+                        EmuDevs::VirtioBlk(blk) => {
+                            let new_vq = blk.vq(vq_idx).clone().unwrap();
+                            AsyncTaskData::AsyncIoTask(IoAsyncMsg {
+                                src_vmid: vm_id,
+                                vq: new_vq.clone(),
+                                dev: blk.clone(),
+                                io_type: io_msg.io_type,
+                                blk_id: io_msg.blk_id,
+                                sector: io_msg.sector,
+                                count: io_msg.count,
+                                cache: io_msg.cache,
+                                iov_list: Arc::new({
+                                    let mut list = vec![];
+                                    for iov in io_msg.iov_list.iter() {
+                                        list.push(BlkIov {
+                                            data_bg: iov.data_bg,
+                                            len: iov.len,
+                                        });
+                                    }
+                                    list
+                                }),
+                            })
+                        }
+                        _ => panic!("illegal mmio dev type in async_task_update"),
+                    }
+                }
+                _ => {
+                    todo!()
+                }
+            };
+            async_io_task_list.push_back(AsyncTask {
+                task_data,
+                src_vmid: vm_id,
+                priority: vm.priority(),
+                state: Arc::new(Mutex::new(*io_task.state.lock())),
+                task: Arc::new(Mutex::new(Box::pin(async_blk_io_req()))),
+            });
         }
     }
-    // println!("Update {} Vcpu to VCPU_LIST", vcpu_list.len());
+
+    fn live_update_reset(&self) {
+        // the tasks hold the virtio devices and queues of the VMs, see VM_LIST
+        core::mem::forget(core::mem::replace(&mut *self.lock(), FairQueue::new()));
+    }
 }
 
-fn smmu_update(src_smmu_v2: &Mutex<SmmuV2>) {
-    let mut smmu_v2 = SMMU_V2.lock();
-    let src_smmu = src_smmu_v2.lock();
-    smmu_v2.glb_rs0 = src_smmu.glb_rs0;
-    smmu_v2.glb_rs1 = src_smmu.glb_rs1;
-    smmu_v2.context_s2_idx = src_smmu.context_s2_idx;
-    for ctx_bank in src_smmu.context_bank.iter() {
-        smmu_v2.context_bank.push(*ctx_bank);
-    }
-    smmu_v2.context_alloc_bitmap = match &src_smmu.context_alloc_bitmap {
-        Some(ctx_bitmap) => {
-            let mut bitmap = FlexBitmap::new(ctx_bitmap.len);
-            for v in ctx_bitmap.map.iter() {
-                bitmap.map.push(*v);
+impl LiveUpdatable for Mutex<BTreeMap<usize, LinkedList<UsedInfo>>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        let mut async_used_info_list = self.lock();
+        assert_eq!(async_used_info_list.len(), 0);
+        for (key, used_info) in src.lock().iter() {
+            let mut new_used_info = LinkedList::new();
+            for info in used_info.iter() {
+                new_used_info.push_back(UsedInfo {
+                    desc_chain_head_idx: info.desc_chain_head_idx,
+                    used_len: info.used_len,
+                });
             }
-            Some(bitmap)
+            async_used_info_list.insert(*key, new_used_info);
         }
-        None => None,
-    };
+    }
 
-    smmu_v2.smr_num = src_smmu.smr_num;
-    smmu_v2.smr_alloc_bitmap = match &src_smmu.smr_alloc_bitmap {
-        Some(smr_bitmap) => {
-            let mut bitmap = FlexBitmap::new(smr_bitmap.len);
-            for v in smr_bitmap.map.iter() {
-                bitmap.map.push(*v);
-            }
-            Some(bitmap)
+    fn live_update_reset(&self) {
+        self.lock().clear();
+    }
+}
+
+impl LiveUpdatable for Mutex<SmmuV2> {
+    fn live_update(&self, src_smmu_v2: &Self, _from: &LiveUpdateFrom) {
+        let mut smmu_v2 = self.lock();
+        let src_smmu = src_smmu_v2.lock();
+        smmu_v2.glb_rs0 = src_smmu.glb_rs0;
+        smmu_v2.glb_rs1 = src_smmu.glb_rs1;
+        smmu_v2.context_s2_idx = src_smmu.context_s2_idx;
+        for ctx_bank in src_smmu.context_bank.iter() {
+            smmu_v2.context_bank.push(*ctx_bank);
         }
-        None => None,
-    };
-    smmu_v2.group_alloc_bitmap = match &src_smmu.group_alloc_bitmap {
-        Some(group_bitmap) => {
-            let mut bitmap = FlexBitmap::new(group_bitmap.len);
-            for v in group_bitmap.map.iter() {
-                bitmap.map.push(*v);
+        smmu_v2.context_alloc_bitmap = match &src_smmu.context_alloc_bitmap {
+            Some(ctx_bitmap) => {
+                let mut bitmap = FlexBitmap::new(ctx_bitmap.len);
+                for v in ctx_bitmap.map.iter() {
+                    bitmap.map.push(*v);
+                }
+                Some(bitmap)
             }
-            Some(bitmap)
-        }
-        None => None,
-    };
+            None => None,
+        };
+
+        smmu_v2.smr_num = src_smmu.smr_num;
+        smmu_v2.smr_alloc_bitmap = match &src_smmu.smr_alloc_bitmap {
+            Some(smr_bitmap) => {
+                let mut bitmap = FlexBitmap::new(smr_bitmap.len);
+                for v in smr_bitmap.map.iter() {
+                    bitmap.map.push(*v);
+                }
+                Some(bitmap)
+            }
+            None => None,
+        };
+        smmu_v2.group_alloc_bitmap = match &src_smmu.group_alloc_bitmap {
+            Some(group_bitmap) => {
+                let mut bitmap = FlexBitmap::new(group_bitmap.len);
+                for v in group_bitmap.map.iter() {
+                    bitmap.map.push(*v);
+                }
+                Some(bitmap)
+            }
+            None => None,
+        };
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().context_bank.clear();
+    }
 }
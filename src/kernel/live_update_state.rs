@@ -0,0 +1,176 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use core::mem::size_of;
+
+use crate::kernel::{live_update_own_entry, StreamReader, StreamWriter};
+
+/*
+ * A global of the hypervisor carried over a live update. The hooks of the new image are called
+ * with the same global of the image updated from, the code of both images is resident meanwhile,
+ * so src can be read as is. Nothing of src may be kept or freed: its Vec, Arc and PageFrame belong
+ * to the heap of the other image, the hooks copy what they need to their own heap.
+ * A global takes part in live update once its LiveUpdateEntry is in LIVE_UPDATE_REGISTRY, the
+ * version is the one of the entry, globals of the same type are versioned apart.
+ */
+pub trait LiveUpdatable {
+    // in the alloc pass, while the other cores still run the image updated from
+    fn live_update_alloc(&self, _src: &Self, _from: &LiveUpdateFrom) {}
+
+    // at the stage of the entry
+    fn live_update(&self, src: &Self, from: &LiveUpdateFrom);
+
+    /*
+     * Rolling back to an image, clear the state left from before the update, see
+     * live_update_rollback_reset. The image rolled back from carries over what this state points
+     * to, the pages it owns have to be forgotten instead of freed.
+     */
+    fn live_update_reset(&self) {}
+}
+
+/*
+ * Codec of a global carried over a live update with another version or layout, see
+ * live_update_migrate. The image updated from encodes its global at buf of cap bytes in the
+ * format of the version of its entry, returns the length or an error if it does not fit. The new
+ * image decodes it into its own global, keeping the code for every older version it supports.
+ */
+pub type LiveUpdateEncode = fn(*const (), usize, usize) -> Result<usize, ()>;
+pub type LiveUpdateDecode = fn(*const (), &[u8], usize) -> Result<(), ()>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LiveUpdateStage {
+    // in the alloc pass
+    Alloc,
+    // the other cores are held by FRESH_LOGIC_LOCK and FRESH_IRQ_LOGIC_LOCK, see FreshStatus
+    Vm,
+    Vcpu,
+    Fresh,
+}
+
+#[repr(C)]
+pub struct LiveUpdateEntry {
+    pub name: &'static str,
+    pub stage: LiveUpdateStage,
+    // an update is refused unless both images carry the global over with the same version
+    pub required: bool,
+    // bump it when the layout of the global changes, a global of another version is migrated by the codec
+    pub version: usize,
+    pub size: usize,
+    pub state: *const (),
+    // hooks of LiveUpdatable, never called on the entries of the other image
+    pub alloc: fn(*const (), *const (), &LiveUpdateFrom),
+    pub update: fn(*const (), *const (), &LiveUpdateFrom),
+    pub reset: fn(*const ()),
+    // the encode hook is called on the entry of the image updated from
+    pub encode: Option<LiveUpdateEncode>,
+    pub decode: Option<LiveUpdateDecode>,
+}
+
+unsafe impl Sync for LiveUpdateEntry {}
+
+impl LiveUpdateEntry {
+    pub fn compatible(&self, other: &LiveUpdateEntry) -> bool {
+        self.version == other.version && self.size == other.size
+    }
+}
+
+fn live_update_alloc_hook<T: LiveUpdatable>(state: *const (), src: *const (), from: &LiveUpdateFrom) {
+    unsafe { (*(state as *const T)).live_update_alloc(&*(src as *const T), from) }
+}
+
+fn live_update_hook<T: LiveUpdatable>(state: *const (), src: *const (), from: &LiveUpdateFrom) {
+    unsafe { (*(state as *const T)).live_update(&*(src as *const T), from) }
+}
+
+fn live_update_reset_hook<T: LiveUpdatable>(state: *const ()) {
+    unsafe { (*(state as *const T)).live_update_reset() }
+}
+
+pub const fn live_update_entry<T: LiveUpdatable>(
+    name: &'static str,
+    stage: LiveUpdateStage,
+    required: bool,
+    version: usize,
+    state: &'static T,
+) -> LiveUpdateEntry {
+    LiveUpdateEntry {
+        name,
+        stage,
+        required,
+        version,
+        size: size_of::<T>(),
+        state: state as *const T as *const (),
+        alloc: live_update_alloc_hook::<T>,
+        update: live_update_hook::<T>,
+        reset: live_update_reset_hook::<T>,
+        encode: None,
+        decode: None,
+    }
+}
+
+// an entry whose global can be migrated from another version, see LiveUpdateEncode
+pub const fn live_update_entry_codec<T: LiveUpdatable>(
+    name: &'static str,
+    stage: LiveUpdateStage,
+    required: bool,
+    version: usize,
+    state: &'static T,
+    encode: LiveUpdateEncode,
+    decode: LiveUpdateDecode,
+) -> LiveUpdateEntry {
+    let mut entry = live_update_entry(name, stage, required, version, state);
+    entry.encode = Some(encode);
+    entry.decode = Some(decode);
+    entry
+}
+
+/*
+ * The global behind the state of an entry and the writer or reader of a codec, for the codec
+ * hooks, which are typed by the global of their entry.
+ */
+pub fn live_update_encode_with<T, F: FnOnce(&T, &mut StreamWriter)>(
+    state: *const (),
+    buf: usize,
+    cap: usize,
+    encode: F,
+) -> Result<usize, ()> {
+    let mut w = StreamWriter::new(buf, cap);
+    encode(unsafe { &*(state as *const T) }, &mut w);
+    w.written()
+}
+
+pub fn live_update_decode_with<T, F: FnOnce(&T, &mut StreamReader) -> Result<(), ()>>(
+    state: *const (),
+    data: &[u8],
+    decode: F,
+) -> Result<(), ()> {
+    decode(unsafe { &*(state as *const T) }, &mut StreamReader::new(data))
+}
+
+/* The registry of the image updated from */
+pub struct LiveUpdateFrom {
+    pub registry: &'static [&'static LiveUpdateEntry],
+}
+
+impl LiveUpdateFrom {
+    pub fn entry(&self, name: &str) -> Option<&'static LiveUpdateEntry> {
+        self.registry.iter().find(|entry| entry.name == name).copied()
+    }
+
+    // another global of the image updated from, for the hooks that need more than their own
+    pub fn state<T: LiveUpdatable>(&self, name: &str) -> Option<&'static T> {
+        match (self.entry(name), live_update_own_entry(name)) {
+            (Some(entry), Some(own)) if entry.compatible(own) && own.size == size_of::<T>() => {
+                Some(unsafe { &*(entry.state as *const T) })
+            }
+            _ => None,
+        }
+    }
+}
@@ -9,7 +9,7 @@
 // See the Mulan PSL v2 for more details.
 
 use crate::arch::{
-    Aarch64ContextFrame, GIC_LIST_REGS_NUM, GIC_PRIVINT_NUM, GIC_SGIS_NUM, GIC_SPI_MAX, GicContext, IrqState,
+    Aarch64ContextFrame, GIC_LIST_REGS_NUM, GIC_PRIVINT_NUM, GIC_SGIS_NUM, GIC_SPI_MAX, GicContext, GicState, IrqState,
     PAGE_SIZE, PTE_S2_FIELD_AP_RW, PTE_S2_NORMAL, PTE_S2_RO, Sgis, VmContext,
 };
 use crate::arch::tlb_invalidate_guest_all;
@@ -20,6 +20,13 @@ use crate::kernel::{
     mem_pages_alloc, MIGRATE_BITMAP, MIGRATE_COPY, MIGRATE_FINISH, MIGRATE_SEND, vm, Vm, vm_if_copy_mem_map,
     vm_if_mem_map_cache, vm_if_mem_map_page_num, vm_if_set_mem_map, vm_if_set_mem_map_cache,
 };
+use crate::kernel::{migrate_compress_dirty, migrate_compress_exit, migrate_compress_send_init, MIGRATE_COMPRESS_NONE};
+use crate::kernel::{migrate_progress_finish, migrate_progress_stage, migrate_progress_start, migrate_throttle};
+use crate::kernel::{dirty_log_logged, dirty_log_write_fault};
+use crate::kernel::{
+    migrate_stream_size, send_hvc_ipi, vm_if_clear_mem_map, vm_if_clear_mem_map_cache, MigrateStage,
+    HVC_VMM_MIGRATE_CANCEL, VM_CONTEXT_SEND, VM_STATE_FLAG,
+};
 
 pub struct VMData {
     pub vm_ctx: [VmContext; PLATFORM_VCPU_NUM_MAX],
@@ -27,6 +34,24 @@ pub struct VMData {
     pub gic_ctx: [GicContext; PLATFORM_VCPU_NUM_MAX],
     pub vgic_ctx: VgicMigData,
     pub emu_devs: [EmuDevData; EMU_DEV_NUM_MAX],
+    // MIGRATE_COMPRESS_NONE or MIGRATE_COMPRESS_XBZRLE, the mode the memory was sent in
+    pub compress: u32,
+}
+
+impl VMData {
+    // VMData lives in page frames, every field must be set before use
+    pub fn reset(&mut self) {
+        for vcpu_id in 0..PLATFORM_VCPU_NUM_MAX {
+            self.vm_ctx[vcpu_id] = VmContext::default();
+            self.vcpu_ctx[vcpu_id] = Aarch64ContextFrame::default();
+            self.gic_ctx[vcpu_id] = GicContext::default();
+        }
+        self.vgic_ctx = VgicMigData::default();
+        for dev in self.emu_devs.iter_mut() {
+            *dev = EmuDevData::None;
+        }
+        self.compress = MIGRATE_COMPRESS_NONE;
+    }
 }
 
 pub enum EmuDevData {
@@ -51,6 +76,46 @@ impl VgicMigData {
             cpu_priv: [VgicCpuPrivData::default(); 4], // TODO: 4 is hardcode for vm cpu num max
         }
     }
+
+    /*
+     * The list registers of the sender build are not migrated: take the state of the interrupts
+     * a vcpu holds in them back into the vgic lists, the receiver refills its own from there.
+     */
+    pub fn fold_lrs(&mut self, vcpu_id: usize, gic_state: &GicState) {
+        if vcpu_id >= usize::min(self.cpu_priv_num, self.cpu_priv.len()) {
+            return;
+        }
+        for id in 0..GIC_PRIVINT_NUM + GIC_SPI_MAX {
+            let int = if id < GIC_PRIVINT_NUM {
+                &mut self.cpu_priv[vcpu_id].interrupts[id]
+            } else {
+                &mut self.vgicd.interrupts[id - GIC_PRIVINT_NUM]
+            };
+            if !int.in_lr || int.owner != Some(vcpu_id) {
+                continue;
+            }
+            // GICv2 layout: vID [9:0], cpuid [12:10], state [29:28]
+            let lr = gic_state.saved_lr(int.lr as usize) as usize;
+            let state = if lr & 0x3ff == id { (lr >> 28) & 0b11 } else { 0 };
+            int.in_lr = false;
+            int.state = IrqState::num_to_state(state);
+            let (in_pend, in_act) = (int.in_pend, int.in_act);
+            int.in_pend = state & 1 != 0;
+            int.in_act = state & 2 != 0;
+
+            let cpu = &mut self.cpu_priv[vcpu_id];
+            if id < GIC_SGIS_NUM {
+                let src = ((lr >> 10) & 0b111) as u8;
+                if state & 2 != 0 {
+                    cpu.sgis[id].act = src;
+                } else if state & 1 != 0 {
+                    cpu.sgis[id].pend |= 1 << src;
+                }
+            }
+            cpu.update_list(id, true, in_pend, state & 1 != 0);
+            cpu.update_list(id, false, in_act, state & 2 != 0);
+        }
+    }
 }
 
 pub struct VgicdData {
@@ -95,6 +160,23 @@ impl VgicCpuPrivData {
             act_list: [0; 16],
         }
     }
+
+    fn update_list(&mut self, id: usize, is_pend: bool, listed: bool, want: bool) {
+        let (list, num) = if is_pend {
+            (&mut self.pend_list, &mut self.pend_num)
+        } else {
+            (&mut self.act_list, &mut self.act_num)
+        };
+        if want && !listed && *num < list.len() {
+            list[*num] = id;
+            *num += 1;
+        } else if !want && listed {
+            if let Some(pos) = list[..*num].iter().position(|irq| *irq == id) {
+                list.copy_within(pos + 1..*num, pos);
+                *num -= 1;
+            }
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -108,7 +190,8 @@ pub struct VgicIntData {
     pub enabled: bool,
     pub state: IrqState,
     pub prio: u8,
-    pub targets: u8,
+    // mask of vcpus
+    pub targets: usize,
     pub cfg: u8,
 
     pub in_pend: bool,
@@ -254,6 +337,8 @@ pub fn migrate_ready(vmid: usize) {
                     true,
                 );
                 vm_if_set_mem_map_cache(vmid, pf);
+                migrate_compress_send_init(vmid);
+                migrate_progress_start(vmid);
             }
             Err(_) => {
                 panic!("migrate_ready: mem_pages_alloc failed");
@@ -310,6 +395,7 @@ pub fn migrate_finish_ipi_handler(vm_id: usize) {
     // }
     // tlb_invalidate_guest_all();
     vm_if_copy_mem_map(vm_id);
+    migrate_compress_dirty(vm_id);
 
     hvc_send_msg_to_vm(
         0,
@@ -323,6 +409,71 @@ pub fn migrate_finish_ipi_handler(vm_id: usize) {
     );
 }
 
+/*
+ * Abort the migration of the source VM on behalf of the MVM. The share memory of the migration is
+ * unmapped from the MVM and the VM gets write access to its memory again. A VM stopped for the
+ * last copy runs again from where it stopped on each core.
+ */
+pub fn migrate_cancel(vm_id: usize) -> Result<usize, ()> {
+    let stage = migrate_progress_stage(vm_id);
+    let vm = match vm(vm_id) {
+        Some(vm) if !matches!(stage, MigrateStage::Idle) => vm,
+        _ => {
+            println!("migrate_cancel: VM[{}] is not migrating", vm_id);
+            return Err(());
+        }
+    };
+    if let MigrateStage::PostCopy = stage {
+        // the receiver may run the VM already
+        println!("migrate_cancel: VM[{}] is in post-copy", vm_id);
+        return Err(());
+    }
+    if matches!(stage, MigrateStage::StopCopy) && vm.inner().lock().migrate_save_pf.is_empty() {
+        // the vcpus are stopping, the MVM should try again
+        println!("migrate_cancel: VM[{}] context is not saved yet", vm_id);
+        return Err(());
+    }
+    migrate_progress_finish(vm_id);
+
+    let mvm = active_vm().unwrap();
+    unmap_migrate_vm_mem(vm.clone(), get_share_mem(MIGRATE_SEND));
+    mvm.pt_unmap_range(
+        get_share_mem(MIGRATE_BITMAP),
+        PAGE_SIZE * vm_if_mem_map_page_num(vm_id),
+        true,
+    );
+    migrate_compress_exit(vm_id);
+    vm_if_clear_mem_map_cache(vm_id);
+
+    // the stale read only entries in the TLB fault once more at most, see migrate_data_abort_handler
+    // the pages under dirty logging stay read only
+    for i in 0..vm.region_num() {
+        let ipa_start = vm.pa_start(i).wrapping_add(vm.pa_offset(i));
+        let mut ipa = ipa_start;
+        while ipa < ipa_start + vm.pa_length(i) {
+            let len = if dirty_log_logged(vm_id, ipa) {
+                PAGE_SIZE
+            } else {
+                vm.pt_set_access_permission(ipa, PTE_S2_FIELD_AP_RW).1
+            };
+            ipa += usize::max(len, PAGE_SIZE);
+        }
+    }
+    vm_if_clear_mem_map(vm_id);
+
+    if let MigrateStage::StopCopy = stage {
+        mvm.pt_unmap_range(get_share_mem(VM_CONTEXT_SEND), migrate_stream_size(), true);
+        vm.inner().lock().migrate_save_pf.clear();
+        *VM_STATE_FLAG.lock() = 0;
+        for vcpu_id in 0..vm.cpu_num() {
+            let cpu_trgt = vm.vcpuid_to_pcpuid(vcpu_id).unwrap();
+            send_hvc_ipi(0, vm_id, HVC_VMM, HVC_VMM_MIGRATE_CANCEL, cpu_trgt);
+        }
+    }
+    info!("VM[{}] migration cancelled", vm_id);
+    Ok(0)
+}
+
 pub fn migrate_data_abort_handler(emu_ctx: &EmuContext) {
     if emu_ctx.write {
         // ptr_read_write(emu_ctx.address, emu_ctx.width, val, false);
@@ -330,7 +481,7 @@ pub fn migrate_data_abort_handler(emu_ctx: &EmuContext) {
         // vm.show_pagetable(emu_ctx.address);
         let vm_id = vm.id();
 
-        let (pa, len) = vm.pt_set_access_permission(emu_ctx.address, PTE_S2_FIELD_AP_RW);
+        let (pa, len) = dirty_log_write_fault(&vm, emu_ctx.address);
         // println!(
         //     "migrate_data_abort_handler: emu_ctx addr 0x{:x}, write pa {:x}, len 0x{:x}",
         //     emu_ctx.address, pa, len
@@ -354,6 +505,7 @@ pub fn migrate_data_abort_handler(emu_ctx: &EmuContext) {
         }
         // flush tlb for updating page table
         tlb_invalidate_guest_all();
+        migrate_throttle(vm_id);
     } else {
         panic!("migrate_data_abort_handler: permission should be read only");
     }
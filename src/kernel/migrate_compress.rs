@@ -0,0 +1,597 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use spin::Mutex;
+
+use crate::arch::{PAGE_SIZE, PTE_S2_NORMAL, PTE_S2_RO};
+use crate::kernel::{active_vm, find_share_mem, mem_page_alloc, mem_pages_alloc, vm, vm_if_mem_map_cache};
+use crate::kernel::{vm_if_mem_map_page_num, Vm, MIGRATE_XBZRLE, MIGRATE_ZERO_BITMAP};
+use crate::lib::{memcpy_safe, memset_safe};
+use crate::mm::PageFrame;
+
+// pages of the sender cache that delta records are encoded against
+pub const MIGRATE_XBZRLE_CACHE_PAGES: usize = 1024;
+// size of the record buffer shared with the MVM
+pub const MIGRATE_COMPRESS_BUF_PAGES: usize = 512;
+// a delta larger than this is sent as a full page
+const XBZRLE_MAX_LEN: usize = PAGE_SIZE / 2;
+
+pub const MIGRATE_RECORD_XBZRLE: u32 = 0;
+pub const MIGRATE_RECORD_FULL: u32 = 1;
+
+// the compression mode of a migration, the sender and the receiver must agree on it
+pub const MIGRATE_COMPRESS_NONE: u32 = 0;
+pub const MIGRATE_COMPRESS_XBZRLE: u32 = 1;
+
+/*
+ * Every pre-copy round, the dirty pages the hypervisor can handle are removed from the bitmap
+ * at MIGRATE_BITMAP before the MVM sees it:
+ * - all-zero pages are set in the bitmap at MIGRATE_ZERO_BITMAP instead;
+ * - pages sent before are delta-encoded (XBZRLE) against the cached copy into the buffer at MIGRATE_XBZRLE;
+ * - other pages are copied into the buffer as full records while the cache has room, so a later
+ *   round can send them as deltas.
+ * The receiver MVM copies the zero bitmap and the buffer of the round into its own share memory
+ * and calls HVC_VMM_MIGRATE_APPLY, after the pages left in the bitmap have been copied.
+ * The buffer starts with MigrateCompressHeader, followed by the records, each one is a
+ * MigrateCompressRecord and len bytes of data, aligned to 8.
+ * Compression is on only if the MVM maps both buffers, the mode of the sender goes with the
+ * migration stream and the receiver rejects the stream if its own mode is different.
+ */
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct MigrateCompressHeader {
+    pub records: usize,
+    // bytes of records after the header
+    pub len: usize,
+    pub zero_pages: usize,
+    pub xbzrle_pages: usize,
+    pub full_pages: usize,
+    pub bytes_saved: usize,
+}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct MigrateCompressRecord {
+    pub page: usize,
+    pub len: u32,
+    pub kind: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MigrateCompressStats {
+    pub rounds: usize,
+    pub zero_pages: usize,
+    pub xbzrle_pages: usize,
+    // pages unchanged since they were sent, they need no record
+    pub same_pages: usize,
+    pub full_pages: usize,
+    pub bytes_saved: usize,
+}
+
+struct MigrateCompress {
+    // (first page, page num, pa) of each memory region of the VM
+    regions: Vec<(usize, usize, usize)>,
+    page_num: usize,
+    zero_map: PageFrame,
+    buf: PageFrame,
+    scratch: Option<PageFrame>,
+    // page idx -> the content last sent by the hypervisor
+    cache: BTreeMap<usize, PageFrame>,
+    offset: usize,
+    header: MigrateCompressHeader,
+    stats: MigrateCompressStats,
+}
+
+static MIGRATE_COMPRESS_LIST: Mutex<BTreeMap<usize, MigrateCompress>> = Mutex::new(BTreeMap::new());
+
+const HEADER_SIZE: usize = size_of::<MigrateCompressHeader>();
+const RECORD_SIZE: usize = size_of::<MigrateCompressRecord>();
+
+fn uleb128_encode(mut val: usize, out: &mut [u8], mut pos: usize) -> Option<usize> {
+    loop {
+        if pos >= out.len() {
+            return None;
+        }
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            out[pos] = byte;
+            return Some(pos + 1);
+        }
+        out[pos] = byte | 0x80;
+        pos += 1;
+    }
+}
+
+fn uleb128_decode(data: &[u8], mut pos: usize) -> Result<(usize, usize), ()> {
+    let mut val = 0;
+    let mut shift = 0;
+    while pos < data.len() && shift < 64 {
+        let byte = data[pos];
+        val |= ((byte & 0x7f) as usize) << shift;
+        pos += 1;
+        if byte & 0x80 == 0 {
+            return Ok((val, pos));
+        }
+        shift += 7;
+    }
+    Err(())
+}
+
+/*
+ * Encode new against old as pairs of (unchanged run, changed run, changed bytes), the run lengths
+ * in uleb128. The unchanged tail is not encoded, so a page without changes encodes to nothing.
+ */
+fn xbzrle_encode(old: &[u8], new: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut i = 0;
+    while i < new.len() {
+        let zrun_start = i;
+        while i < new.len() && old[i] == new[i] {
+            i += 1;
+        }
+        if i == new.len() {
+            break;
+        }
+        let nzrun_start = i;
+        while i < new.len() && old[i] != new[i] {
+            i += 1;
+        }
+        pos = uleb128_encode(nzrun_start - zrun_start, out, pos)?;
+        pos = uleb128_encode(i - nzrun_start, out, pos)?;
+        if pos + i - nzrun_start > out.len() {
+            return None;
+        }
+        out[pos..pos + i - nzrun_start].copy_from_slice(&new[nzrun_start..i]);
+        pos += i - nzrun_start;
+    }
+    Some(pos)
+}
+
+fn xbzrle_decode(data: &[u8], page: &mut [u8]) -> Result<(), ()> {
+    let mut pos = 0;
+    let mut i = 0;
+    while pos < data.len() {
+        let (zrun, next) = uleb128_decode(data, pos)?;
+        let (nzrun, next) = uleb128_decode(data, next)?;
+        pos = next;
+        if zrun > page.len() - i || nzrun > page.len() - i - zrun || nzrun > data.len() - pos {
+            return Err(());
+        }
+        i += zrun;
+        page[i..i + nzrun].copy_from_slice(&data[pos..pos + nzrun]);
+        i += nzrun;
+        pos += nzrun;
+    }
+    Ok(())
+}
+
+fn page_bytes(pa: usize) -> &'static mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(pa as *mut u8, PAGE_SIZE) }
+}
+
+impl MigrateCompress {
+    fn new(vm: &Vm) -> Option<MigrateCompress> {
+        let map_page_num = vm_if_mem_map_page_num(vm.id());
+        let (zero_map, buf) = match (
+            mem_pages_alloc(map_page_num),
+            mem_pages_alloc(MIGRATE_COMPRESS_BUF_PAGES),
+        ) {
+            (Ok(zero_map), Ok(buf)) => (zero_map, buf),
+            _ => return None,
+        };
+        let mut regions = Vec::new();
+        let mut page_num = 0;
+        for idx in 0..vm.region_num() {
+            regions.push((page_num, vm.pa_length(idx) / PAGE_SIZE, vm.pa_start(idx)));
+            page_num += vm.pa_length(idx) / PAGE_SIZE;
+        }
+        // pages out of the bitmap shared with the MVM are never reported
+        page_num = usize::min(page_num, map_page_num * PAGE_SIZE * 8);
+        memset_safe(zero_map.pa() as *mut u8, 0, map_page_num * PAGE_SIZE);
+        Some(MigrateCompress {
+            regions,
+            page_num,
+            zero_map,
+            buf,
+            scratch: mem_page_alloc().ok(),
+            cache: BTreeMap::new(),
+            offset: HEADER_SIZE,
+            header: MigrateCompressHeader::default(),
+            stats: MigrateCompressStats::default(),
+        })
+    }
+
+    fn page_pa(&self, page: usize) -> Option<usize> {
+        for (first, num, pa) in self.regions.iter() {
+            if page >= *first && page < *first + *num {
+                return Some(*pa + (page - *first) * PAGE_SIZE);
+            }
+        }
+        None
+    }
+
+    fn map_len(&self) -> usize {
+        self.zero_map.page_num * PAGE_SIZE
+    }
+
+    fn buf_bytes(&self) -> &'static mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.buf.pa() as *mut u8, self.buf.page_num * PAGE_SIZE) }
+    }
+
+    fn reset_round(&mut self) {
+        memset_safe(self.zero_map.pa() as *mut u8, 0, self.map_len());
+        self.header = MigrateCompressHeader::default();
+        self.offset = HEADER_SIZE;
+    }
+
+    fn push_record(&mut self, page: usize, kind: u32, len: usize) {
+        let record = MigrateCompressRecord {
+            page,
+            len: len as u32,
+            kind,
+        };
+        unsafe {
+            *((self.buf.pa() + self.offset) as *mut MigrateCompressRecord) = record;
+        }
+        let size = (RECORD_SIZE + len + 7) & !7;
+        self.offset += size;
+        self.header.records += 1;
+        self.header.len += size;
+    }
+
+    // returns true if the page is sent by the hypervisor, it must not be copied by the MVM then
+    fn compress_page(&mut self, page: usize, pa: usize) -> bool {
+        let scratch = match &self.scratch {
+            Some(scratch) => page_bytes(scratch.pa()),
+            None => return false,
+        };
+        // the guest may write the page meanwhile, take one copy and work on it
+        memcpy_safe(scratch.as_mut_ptr(), pa as *const u8, PAGE_SIZE);
+
+        if scratch.iter().all(|byte| *byte == 0) {
+            let zero_map =
+                unsafe { core::slice::from_raw_parts_mut(self.zero_map.pa() as *mut usize, self.map_len() / 8) };
+            zero_map[page / 64] |= 1 << (page % 64);
+            self.cache.remove(&page);
+            self.header.zero_pages += 1;
+            self.header.bytes_saved += PAGE_SIZE;
+            return true;
+        }
+
+        let room = self.buf_bytes().len() - self.offset;
+        if let Some(cached) = self.cache.get(&page) {
+            let cached = page_bytes(cached.pa());
+            if room > RECORD_SIZE {
+                let data_start = self.offset + RECORD_SIZE;
+                let cap = usize::min(XBZRLE_MAX_LEN, room - RECORD_SIZE);
+                let out = &mut self.buf_bytes()[data_start..data_start + cap];
+                match xbzrle_encode(cached, scratch, out) {
+                    Some(0) => {
+                        self.stats.same_pages += 1;
+                        self.header.bytes_saved += PAGE_SIZE;
+                        return true;
+                    }
+                    Some(len) => {
+                        cached.copy_from_slice(scratch);
+                        self.push_record(page, MIGRATE_RECORD_XBZRLE, len);
+                        self.header.xbzrle_pages += 1;
+                        self.header.bytes_saved += PAGE_SIZE.saturating_sub(RECORD_SIZE + len);
+                        return true;
+                    }
+                    None => {}
+                }
+            }
+        } else if self.cache.len() < MIGRATE_XBZRLE_CACHE_PAGES {
+            if let Ok(pf) = mem_page_alloc() {
+                self.cache.insert(page, pf);
+            }
+        }
+
+        // the receiver must hold what is cached here, send the cached copy in full
+        match self.cache.get(&page) {
+            Some(cached) if room >= RECORD_SIZE + PAGE_SIZE => {
+                page_bytes(cached.pa()).copy_from_slice(scratch);
+                let data_start = self.offset + RECORD_SIZE;
+                self.buf_bytes()[data_start..data_start + PAGE_SIZE].copy_from_slice(scratch);
+                self.push_record(page, MIGRATE_RECORD_FULL, PAGE_SIZE);
+                self.header.full_pages += 1;
+                true
+            }
+            _ => {
+                self.cache.remove(&page);
+                false
+            }
+        }
+    }
+
+    fn apply_records(&self) -> Result<(), ()> {
+        let zero_map = unsafe { core::slice::from_raw_parts(self.zero_map.pa() as *const usize, self.map_len() / 8) };
+        for (word_idx, word) in zero_map.iter().enumerate() {
+            let mut word = *word;
+            while word != 0 {
+                let page = word_idx * 64 + word.trailing_zeros() as usize;
+                word &= word - 1;
+                match self.page_pa(page) {
+                    Some(pa) => {
+                        memset_safe(pa as *mut u8, 0, PAGE_SIZE);
+                    }
+                    None => return Err(()),
+                }
+            }
+        }
+
+        let buf = self.buf_bytes();
+        let header = unsafe { *(buf.as_ptr() as *const MigrateCompressHeader) };
+        if header.len > buf.len() - HEADER_SIZE {
+            return Err(());
+        }
+        let end = HEADER_SIZE + header.len;
+        let mut offset = HEADER_SIZE;
+        for _ in 0..header.records {
+            if end - offset < RECORD_SIZE {
+                return Err(());
+            }
+            let record = unsafe { *((buf.as_ptr() as usize + offset) as *const MigrateCompressRecord) };
+            let len = record.len as usize;
+            let data_start = offset + RECORD_SIZE;
+            if len > end - data_start {
+                return Err(());
+            }
+            let page = match self.page_pa(record.page) {
+                Some(pa) => page_bytes(pa),
+                None => return Err(()),
+            };
+            let data = &buf[data_start..data_start + len];
+            match record.kind {
+                MIGRATE_RECORD_XBZRLE => xbzrle_decode(data, page)?,
+                MIGRATE_RECORD_FULL if len == PAGE_SIZE => page.copy_from_slice(data),
+                _ => return Err(()),
+            }
+            offset = usize::min((data_start + len + 7) & !7, end);
+        }
+        Ok(())
+    }
+}
+
+fn migrate_compress_init(vm_id: usize, pte: usize) {
+    let (zero_map_ipa, buf_ipa) = match (find_share_mem(MIGRATE_ZERO_BITMAP), find_share_mem(MIGRATE_XBZRLE)) {
+        (Some(zero_map_ipa), Some(buf_ipa)) => (zero_map_ipa, buf_ipa),
+        // the MVM does not support it, every dirty page is copied by the MVM
+        _ => return,
+    };
+    let vm = vm(vm_id).unwrap();
+    let compress = match MigrateCompress::new(&vm) {
+        Some(compress) => compress,
+        None => {
+            warn!(
+                "migrate_compress_init: VM[{}] failed to alloc pages, compression disabled",
+                vm_id
+            );
+            return;
+        }
+    };
+    let mvm = active_vm().unwrap();
+    mvm.pt_map_range(zero_map_ipa, compress.map_len(), compress.zero_map.pa(), pte, true);
+    mvm.pt_map_range(buf_ipa, compress.buf.page_num * PAGE_SIZE, compress.buf.pa(), pte, true);
+    MIGRATE_COMPRESS_LIST.lock().insert(vm_id, compress);
+}
+
+// for sender, called at the first HVC_VMM_MIGRATE_READY
+pub fn migrate_compress_send_init(vm_id: usize) {
+    migrate_compress_init(vm_id, PTE_S2_RO);
+}
+
+// for receiver, called at HVC_VMM_MIGRATE_INIT_VM
+pub fn migrate_compress_receive_init(vm_id: usize) {
+    migrate_compress_init(vm_id, PTE_S2_NORMAL);
+}
+
+/*
+ * Called after vm_if_copy_mem_map, before the MVM is told to copy the dirty pages of the round.
+ * The list is locked per page, the scan of a large VM must not keep other cores waiting on it.
+ */
+pub fn migrate_compress_dirty(vm_id: usize) {
+    let bitmap_pf = match vm_if_mem_map_cache(vm_id) {
+        Some(pf) => pf,
+        None => return,
+    };
+    let (map_len, page_num) = match MIGRATE_COMPRESS_LIST.lock().get_mut(&vm_id) {
+        Some(compress) => {
+            compress.reset_round();
+            (compress.map_len(), compress.page_num)
+        }
+        None => return,
+    };
+    let bitmap = unsafe { core::slice::from_raw_parts_mut(bitmap_pf.pa() as *mut usize, map_len / 8) };
+    let word_num = (page_num + 63) / 64;
+    for word_idx in 0..word_num {
+        let mut word = bitmap[word_idx];
+        while word != 0 {
+            let bit = word.trailing_zeros() as usize;
+            word &= word - 1;
+            let page = word_idx * 64 + bit;
+            let mut list = MIGRATE_COMPRESS_LIST.lock();
+            let compress = match list.get_mut(&vm_id) {
+                Some(compress) => compress,
+                // the migration is over
+                None => return,
+            };
+            let pa = match compress.page_pa(page) {
+                Some(pa) => pa,
+                None => continue,
+            };
+            if compress.compress_page(page, pa) {
+                bitmap[word_idx] &= !(1 << bit);
+            }
+        }
+    }
+
+    let mut list = MIGRATE_COMPRESS_LIST.lock();
+    let compress = match list.get_mut(&vm_id) {
+        Some(compress) => compress,
+        None => return,
+    };
+    let header = compress.header;
+    unsafe {
+        *(compress.buf.pa() as *mut MigrateCompressHeader) = header;
+    }
+    let stats = &mut compress.stats;
+    stats.rounds += 1;
+    stats.zero_pages += header.zero_pages;
+    stats.xbzrle_pages += header.xbzrle_pages;
+    stats.full_pages += header.full_pages;
+    stats.bytes_saved += header.bytes_saved;
+}
+
+/* For receiver: apply the zero pages and records of a round copied in by the MVM */
+pub fn migrate_compress_apply(vm_id: usize) -> Result<usize, ()> {
+    let list = MIGRATE_COMPRESS_LIST.lock();
+    let compress = match list.get(&vm_id) {
+        Some(compress) => compress,
+        None => {
+            println!("migrate_compress_apply: VM[{}] compression is not enabled", vm_id);
+            return Err(());
+        }
+    };
+    if compress.apply_records().is_err() {
+        println!("migrate_compress_apply: VM[{}] illegal migrate records", vm_id);
+        return Err(());
+    }
+    Ok(0)
+}
+
+pub fn migrate_compress_mode(vm_id: usize) -> u32 {
+    match MIGRATE_COMPRESS_LIST.lock().contains_key(&vm_id) {
+        true => MIGRATE_COMPRESS_XBZRLE,
+        false => MIGRATE_COMPRESS_NONE,
+    }
+}
+
+pub fn migrate_compress_stats(vm_id: usize) -> Option<MigrateCompressStats> {
+    MIGRATE_COMPRESS_LIST.lock().get(&vm_id).map(|compress| compress.stats)
+}
+
+// unmap the share memory of the MVM and free the cache, at the end of migration
+pub fn migrate_compress_exit(vm_id: usize) {
+    let compress = match MIGRATE_COMPRESS_LIST.lock().remove(&vm_id) {
+        Some(compress) => compress,
+        None => return,
+    };
+    let mvm = vm(0).unwrap();
+    if let Some(zero_map_ipa) = find_share_mem(MIGRATE_ZERO_BITMAP) {
+        mvm.pt_unmap_range(zero_map_ipa, compress.map_len(), true);
+    }
+    if let Some(buf_ipa) = find_share_mem(MIGRATE_XBZRLE) {
+        mvm.pt_unmap_range(buf_ipa, compress.buf.page_num * PAGE_SIZE, true);
+    }
+    let stats = compress.stats;
+    if stats.rounds != 0 {
+        info!(
+            "VM[{}] migration compress: {} rounds, zero {} xbzrle {} same {} full {} pages, {} bytes saved",
+            vm_id,
+            stats.rounds,
+            stats.zero_pages,
+            stats.xbzrle_pages,
+            stats.same_pages,
+            stats.full_pages,
+            stats.bytes_saved
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn roundtrip(old: &[u8], new: &[u8]) -> usize {
+        let mut out = vec![0; 2 * PAGE_SIZE];
+        let len = xbzrle_encode(old, new, &mut out).unwrap();
+        let mut page = old.to_vec();
+        assert!(xbzrle_decode(&out[..len], &mut page).is_ok());
+        assert_eq!(page, new);
+        len
+    }
+
+    #[test]
+    fn uleb128() {
+        let mut out = [0; 10];
+        for val in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, PAGE_SIZE, usize::MAX] {
+            let len = uleb128_encode(val, &mut out, 0).unwrap();
+            assert_eq!(uleb128_decode(&out[..len], 0), Ok((val, len)));
+        }
+        assert_eq!(uleb128_encode(0x80, &mut out[..1], 0), None);
+        assert_eq!(uleb128_encode(0x7f, &mut out, 10), None);
+        assert!(uleb128_decode(&[0x80, 0x80], 0).is_err());
+        assert!(uleb128_decode(&[], 0).is_err());
+    }
+
+    #[test]
+    fn xbzrle_same_page() {
+        let old: Vec<u8> = (0..PAGE_SIZE).map(|i| i as u8).collect();
+        assert_eq!(roundtrip(&old, &old), 0);
+    }
+
+    #[test]
+    fn xbzrle_page_edges() {
+        let old = vec![0x5a; PAGE_SIZE];
+        let mut new = old.clone();
+        new[0] = 1;
+        // zero run 0, changed run 1, 1 byte
+        assert_eq!(roundtrip(&old, &new), 3);
+        new[0] = 0x5a;
+        new[PAGE_SIZE - 1] = 2;
+        // zero run 4095 takes 2 bytes
+        assert_eq!(roundtrip(&old, &new), 4);
+        new[0] = 1;
+        roundtrip(&old, &new);
+    }
+
+    #[test]
+    fn xbzrle_whole_page() {
+        let old = vec![0; PAGE_SIZE];
+        let new = vec![0xff; PAGE_SIZE];
+        assert_eq!(roundtrip(&old, &new), 1 + 2 + PAGE_SIZE);
+        let mut out = vec![0; XBZRLE_MAX_LEN];
+        assert_eq!(xbzrle_encode(&old, &new, &mut out), None);
+    }
+
+    #[test]
+    fn xbzrle_scattered() {
+        let old = vec![0; PAGE_SIZE];
+        let new: Vec<u8> = (0..PAGE_SIZE).map(|i| (i % 2) as u8).collect();
+        // every other byte changed, 3 bytes per changed byte
+        assert_eq!(roundtrip(&old, &new), 3 * PAGE_SIZE / 2);
+        let mut out = vec![0; XBZRLE_MAX_LEN];
+        assert_eq!(xbzrle_encode(&old, &new, &mut out), None);
+    }
+
+    #[test]
+    fn xbzrle_illegal() {
+        let mut page = vec![0; PAGE_SIZE];
+        // truncated run length
+        assert!(xbzrle_decode(&[0x80], &mut page).is_err());
+        // no changed run length
+        assert!(xbzrle_decode(&[0x01], &mut page).is_err());
+        // changed bytes missing
+        assert!(xbzrle_decode(&[0x00, 0x04, 1, 2], &mut page).is_err());
+        // runs beyond the page
+        assert!(xbzrle_decode(&[0x80, 0x20, 0x01, 1], &mut page).is_err());
+        assert!(xbzrle_decode(&[0xff, 0x1f, 0x02, 1, 2], &mut page).is_err());
+        assert!(page.iter().all(|byte| *byte == 0));
+    }
+}
@@ -0,0 +1,363 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use alloc::collections::BTreeMap;
+use core::mem::size_of;
+
+use spin::Mutex;
+
+use crate::kernel::{active_vm, current_cpu, migrate_compress_stats, vcpu_throttle, vm, vm_if_mem_map_dirty_sum, vm_ipa2pa};
+use crate::kernel::{live_update_decode_with, live_update_encode_with, live_update_entry_codec, StreamReader, StreamWriter};
+use crate::kernel::{LiveUpdatable, LiveUpdateEntry, LiveUpdateFrom, LiveUpdateStage, VM_NUM_MAX};
+use crate::lib::time_current_us;
+
+// default downtime target of the stop-and-copy phase
+pub const MIGRATE_MAX_DOWNTIME_US: usize = 300_000;
+// stop-and-copy anyway after so many pre-copy rounds
+pub const MIGRATE_MAX_ITERATIONS: usize = 30;
+// vcpus are throttled once pages get dirty faster than this percent of the bandwidth
+const MIGRATE_THROTTLE_TRIGGER: usize = 50;
+const MIGRATE_THROTTLE_INITIAL: usize = 20;
+const MIGRATE_THROTTLE_STEP: usize = 10;
+const MIGRATE_THROTTLE_MAX: usize = 99;
+// run time of a vcpu each write fault is charged for, see migrate_throttle
+const MIGRATE_THROTTLE_SLICE_US: usize = 10;
+// a vcpu is blocked once it owes this much, about a tick of the core timer that wakes it up
+const MIGRATE_THROTTLE_BLOCK_US: usize = 1000;
+
+#[derive(Clone, Copy, Debug)]
+pub enum MigrateStage {
+    Idle = 0,
+    PreCopy = 1,
+    StopCopy = 2,
+    // stopped, the memory left is sent on demand of the receiver
+    PostCopy = 3,
+}
+
+/* Progress of the migration of a VM, read by the MVM through HVC_VMM_MIGRATE_PROGRESS */
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct MigrateProgressInfo {
+    pub stage: usize,
+    pub iterations: usize,
+    // dirty pages per second of the last round
+    pub dirty_rate: usize,
+    // pages per second the MVM copied in the last round
+    pub bandwidth: usize,
+    pub remaining_pages: usize,
+    pub expected_downtime_us: usize,
+    pub max_downtime_us: usize,
+    // percent of time the vcpus are held back
+    pub throttle: usize,
+    pub pages_sent: usize,
+    pub bytes_saved: usize,
+    pub elapsed_us: usize,
+}
+
+#[derive(Clone, Copy)]
+struct MigrateProgress {
+    stage: MigrateStage,
+    start: usize,
+    round_start: usize,
+    round_pages: usize,
+    iterations: usize,
+    dirty_rate: usize,
+    bandwidth: usize,
+    remaining_pages: usize,
+    expected_downtime_us: usize,
+    max_downtime_us: usize,
+    throttle: usize,
+    pages_sent: usize,
+}
+
+impl MigrateProgress {
+    // version 1 of MIGRATE_PROGRESS_LIST
+    fn encode(&self, w: &mut StreamWriter) {
+        w.put_usize(self.stage as usize);
+        for val in [
+            self.start,
+            self.round_start,
+            self.round_pages,
+            self.iterations,
+            self.dirty_rate,
+            self.bandwidth,
+            self.remaining_pages,
+            self.expected_downtime_us,
+            self.max_downtime_us,
+            self.throttle,
+            self.pages_sent,
+        ] {
+            w.put_usize(val);
+        }
+    }
+
+    fn decode(r: &mut StreamReader, version: usize) -> Result<MigrateProgress, ()> {
+        if version != 1 {
+            return Err(());
+        }
+        let stage = match r.get_usize()? {
+            0 => MigrateStage::Idle,
+            1 => MigrateStage::PreCopy,
+            2 => MigrateStage::StopCopy,
+            3 => MigrateStage::PostCopy,
+            _ => return Err(()),
+        };
+        Ok(MigrateProgress {
+            stage,
+            start: r.get_usize()?,
+            round_start: r.get_usize()?,
+            round_pages: r.get_usize()?,
+            iterations: r.get_usize()?,
+            dirty_rate: r.get_usize()?,
+            bandwidth: r.get_usize()?,
+            remaining_pages: r.get_usize()?,
+            expected_downtime_us: r.get_usize()?,
+            max_downtime_us: r.get_usize()?,
+            throttle: r.get_usize()?,
+            pages_sent: r.get_usize()?,
+        })
+    }
+}
+
+static MIGRATE_PROGRESS_LIST: Mutex<BTreeMap<usize, MigrateProgress>> = Mutex::new(BTreeMap::new());
+
+impl LiveUpdatable for Mutex<BTreeMap<usize, MigrateProgress>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        let mut list = self.lock();
+        for (vm_id, progress) in src.lock().iter() {
+            list.insert(*vm_id, *progress);
+        }
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().clear();
+    }
+}
+
+// the progress of the migrations VM by VM, see LiveUpdateEncode
+fn migrate_progress_encode(state: *const (), buf: usize, cap: usize) -> Result<usize, ()> {
+    live_update_encode_with(state, buf, cap, |list: &Mutex<BTreeMap<usize, MigrateProgress>>, w| {
+        let list = list.lock();
+        w.put_u32(list.len() as u32);
+        for (vm_id, progress) in list.iter() {
+            w.put_usize(*vm_id);
+            progress.encode(w);
+        }
+    })
+}
+
+fn migrate_progress_decode(state: *const (), data: &[u8], version: usize) -> Result<(), ()> {
+    live_update_decode_with(state, data, |list: &Mutex<BTreeMap<usize, MigrateProgress>>, r| {
+        let mut list = list.lock();
+        for _ in 0..r.get_count(VM_NUM_MAX)? {
+            let vm_id = r.get_usize()?;
+            list.insert(vm_id, MigrateProgress::decode(r, version)?);
+        }
+        Ok(())
+    })
+}
+
+pub static MIGRATE_PROGRESS_UPDATE: LiveUpdateEntry = live_update_entry_codec(
+    "MIGRATE_PROGRESS_LIST",
+    LiveUpdateStage::Fresh,
+    false,
+    1,
+    &MIGRATE_PROGRESS_LIST,
+    migrate_progress_encode,
+    migrate_progress_decode,
+);
+
+// called at the first HVC_VMM_MIGRATE_READY of the source VM
+pub fn migrate_progress_start(vm_id: usize) {
+    let now = time_current_us();
+    MIGRATE_PROGRESS_LIST.lock().insert(
+        vm_id,
+        MigrateProgress {
+            stage: MigrateStage::PreCopy,
+            start: now,
+            round_start: now,
+            round_pages: 0,
+            iterations: 0,
+            dirty_rate: 0,
+            bandwidth: 0,
+            remaining_pages: 0,
+            expected_downtime_us: 0,
+            max_downtime_us: MIGRATE_MAX_DOWNTIME_US,
+            throttle: 0,
+            pages_sent: 0,
+        },
+    );
+}
+
+// a new round starts, the MVM is going to copy the pages dirty now
+pub fn migrate_progress_round(vm_id: usize) {
+    let pages = vm_if_mem_map_dirty_sum(vm_id);
+    if let Some(vm) = vm(vm_id) {
+        vm.stats_dirty_pages(pages);
+    }
+    if let Some(progress) = MIGRATE_PROGRESS_LIST.lock().get_mut(&vm_id) {
+        progress.round_start = time_current_us();
+        progress.round_pages = pages;
+        progress.pages_sent += pages;
+    }
+}
+
+/*
+ * Called when the MVM finished a round. The pages dirtied meanwhile have to be copied with the
+ * VM stopped, it is time to stop-and-copy if they fit in the downtime target at the bandwidth of
+ * the round. Otherwise the vcpus are throttled harder while they dirty pages faster than the
+ * MVM copies them.
+ */
+pub fn migrate_progress_converged(vm_id: usize) -> bool {
+    let dirty = vm_if_mem_map_dirty_sum(vm_id);
+    let now = time_current_us();
+    let mut list = MIGRATE_PROGRESS_LIST.lock();
+    let progress = match list.get_mut(&vm_id) {
+        Some(progress) => progress,
+        None => return true,
+    };
+    let elapsed = usize::max(now - progress.round_start, 1);
+    progress.iterations += 1;
+    progress.remaining_pages = dirty;
+    progress.dirty_rate = dirty * 1_000_000 / elapsed;
+    if progress.round_pages != 0 {
+        progress.bandwidth = progress.round_pages * 1_000_000 / elapsed;
+    }
+    progress.expected_downtime_us = match progress.bandwidth {
+        0 => usize::MAX,
+        bandwidth => dirty * 1_000_000 / bandwidth,
+    };
+
+    let converged = dirty == 0 || progress.expected_downtime_us <= progress.max_downtime_us;
+    if converged || progress.iterations >= MIGRATE_MAX_ITERATIONS {
+        if !converged {
+            warn!(
+                "VM[{}] migration does not converge after {} rounds, expected downtime {}us",
+                vm_id, progress.iterations, progress.expected_downtime_us
+            );
+        }
+        progress.stage = MigrateStage::StopCopy;
+        progress.throttle = 0;
+        return true;
+    }
+    if progress.bandwidth != 0 && progress.dirty_rate * 100 > progress.bandwidth * MIGRATE_THROTTLE_TRIGGER {
+        progress.throttle = match progress.throttle {
+            0 => MIGRATE_THROTTLE_INITIAL,
+            throttle => usize::min(throttle + MIGRATE_THROTTLE_STEP, MIGRATE_THROTTLE_MAX),
+        };
+        info!(
+            "VM[{}] migration round {}: dirty rate {} bandwidth {} pages/s, throttle {}%",
+            vm_id, progress.iterations, progress.dirty_rate, progress.bandwidth, progress.throttle
+        );
+    }
+    false
+}
+
+pub fn migrate_progress_stage(vm_id: usize) -> MigrateStage {
+    match MIGRATE_PROGRESS_LIST.lock().get(&vm_id) {
+        Some(progress) => progress.stage,
+        None => MigrateStage::Idle,
+    }
+}
+
+// switch a migration in pre-copy to post-copy, the vcpus are stopped by the caller
+pub fn migrate_progress_postcopy(vm_id: usize) -> bool {
+    match MIGRATE_PROGRESS_LIST.lock().get_mut(&vm_id) {
+        Some(progress) if matches!(progress.stage, MigrateStage::PreCopy) => {
+            progress.stage = MigrateStage::PostCopy;
+            progress.throttle = 0;
+            true
+        }
+        _ => false,
+    }
+}
+
+// the migration is done or cancelled
+pub fn migrate_progress_finish(vm_id: usize) {
+    if let Some(progress) = MIGRATE_PROGRESS_LIST.lock().remove(&vm_id) {
+        info!(
+            "VM[{}] migration: {} rounds, {} pages sent in {}us",
+            vm_id,
+            progress.iterations,
+            progress.pages_sent,
+            time_current_us() - progress.start
+        );
+    }
+}
+
+pub fn migrate_set_max_downtime(vm_id: usize, downtime_us: usize) -> Result<usize, ()> {
+    match MIGRATE_PROGRESS_LIST.lock().get_mut(&vm_id) {
+        Some(progress) => {
+            progress.max_downtime_us = downtime_us;
+            Ok(0)
+        }
+        None => {
+            println!("migrate_set_max_downtime: VM[{}] is not migrating", vm_id);
+            Err(())
+        }
+    }
+}
+
+pub fn migrate_progress_get(vm_id: usize, info_ipa: usize) -> Result<usize, ()> {
+    // the struct may cross a page, it has to lie in one memory region of the MVM
+    let mvm = active_vm().unwrap();
+    let info_pa = vm_ipa2pa(mvm.clone(), info_ipa);
+    let last = size_of::<MigrateProgressInfo>() - 1;
+    if info_pa == 0 || vm_ipa2pa(mvm, info_ipa + last) != info_pa + last {
+        println!("migrate_progress_get: illegal ipa {:x}", info_ipa);
+        return Err(());
+    }
+    let info = match MIGRATE_PROGRESS_LIST.lock().get(&vm_id) {
+        Some(progress) => MigrateProgressInfo {
+            stage: progress.stage as usize,
+            iterations: progress.iterations,
+            dirty_rate: progress.dirty_rate,
+            bandwidth: progress.bandwidth,
+            remaining_pages: progress.remaining_pages,
+            expected_downtime_us: progress.expected_downtime_us,
+            max_downtime_us: progress.max_downtime_us,
+            throttle: progress.throttle,
+            pages_sent: progress.pages_sent,
+            bytes_saved: 0,
+            elapsed_us: time_current_us() - progress.start,
+        },
+        None => MigrateProgressInfo::default(),
+    };
+    let bytes_saved = match migrate_compress_stats(vm_id) {
+        Some(stats) => stats.bytes_saved,
+        None => 0,
+    };
+    unsafe {
+        *(info_pa as *mut MigrateProgressInfo) = MigrateProgressInfo { bytes_saved, ..info };
+    }
+    Ok(info.stage)
+}
+
+/*
+ * Called on the write fault of a page under dirty logging. A throttled vcpu owes throttle /
+ * (100 - throttle) of MIGRATE_THROTTLE_SLICE_US for each fault, so the vcpus that dirty pages the
+ * fastest are slowed down the most, and the others are not affected. Once it owes
+ * MIGRATE_THROTTLE_BLOCK_US, it is blocked for that long and the core runs the other vcpus.
+ */
+pub fn migrate_throttle(vm_id: usize) {
+    let throttle = match MIGRATE_PROGRESS_LIST.lock().get(&vm_id) {
+        Some(progress) => progress.throttle,
+        None => return,
+    };
+    if throttle == 0 {
+        return;
+    }
+    let vcpu = current_cpu().active_vcpu.clone().unwrap();
+    let debt = vcpu.throttle_charge(MIGRATE_THROTTLE_SLICE_US * throttle / (100 - throttle));
+    if debt >= MIGRATE_THROTTLE_BLOCK_US {
+        // the faulting write is retried once the vcpu runs again
+        vcpu_throttle(vcpu, debt);
+    }
+}
@@ -0,0 +1,338 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use crate::arch::{PAGE_SIZE, PTE_S2_NORMAL};
+use crate::kernel::{active_vm, current_cpu, hvc_send_msg_to_vm, vm, vm_ipa2pa, HvcGuestMsg, HvcMigrateMsg, Vm};
+use crate::kernel::{ipi_send_msg, vcpu_postcopy_wait, vcpu_postcopy_wakeup, IpiInnerMsg, IpiType, IpiVmmMsg};
+use crate::kernel::{HVC_VMM, HVC_VMM_MIGRATE_START, MIGRATE_PAGE_REQUEST};
+use crate::kernel::{live_update_entry, LiveUpdatable, LiveUpdateEntry, LiveUpdateFrom, LiveUpdateStage};
+use crate::lib::{memcpy_safe, time_current_us, FlexBitmap};
+use crate::vmm::VmmEvent;
+
+/*
+ * Post-copy migration, for receiver. The VM is booted once the vcpu and device state arrived, with
+ * the pages the sender has not sent yet unmapped from stage-2. A guest access to such a page takes
+ * a translation fault, the page is requested from the MVM (MIGRATE_PAGE_REQUEST) and the vcpu is
+ * blocked until the MVM fills the page by HVC_VMM_MIGRATE_PAGE_FILL, then the access is retried.
+ * The MVM pushes the other missing pages the same way meanwhile, the migration is done when none is
+ * left. Device emulation reaches the guest memory by pa and can not wait for a page, so a VM with
+ * virtio devices is not migrated by post-copy.
+ */
+#[derive(Clone)]
+struct MigratePostcopy {
+    // (first page, page num, pa, ipa) of each memory region of the VM
+    regions: Vec<(usize, usize, usize, usize)>,
+    page_num: usize,
+    missing: FlexBitmap,
+    requested: FlexBitmap,
+    remaining: usize,
+    requests: usize,
+    start: usize,
+}
+
+impl MigratePostcopy {
+    fn page_addr(&self, page: usize) -> Option<(usize, usize)> {
+        for (first, num, pa, ipa) in self.regions.iter() {
+            if page >= *first && page < *first + *num {
+                let offset = (page - *first) * PAGE_SIZE;
+                return Some((*pa + offset, *ipa + offset));
+            }
+        }
+        None
+    }
+
+    fn ipa_page(&self, ipa: usize) -> Option<usize> {
+        for (first, num, _, start) in self.regions.iter() {
+            if ipa >= *start && ipa < *start + *num * PAGE_SIZE {
+                return Some(*first + (ipa - *start) / PAGE_SIZE);
+            }
+        }
+        None
+    }
+}
+
+static MIGRATE_POSTCOPY_LIST: Mutex<BTreeMap<usize, MigratePostcopy>> = Mutex::new(BTreeMap::new());
+
+impl LiveUpdatable for Mutex<BTreeMap<usize, MigratePostcopy>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        let mut list = self.lock();
+        for (vm_id, postcopy) in src.lock().iter() {
+            list.insert(*vm_id, postcopy.clone());
+        }
+    }
+
+    fn live_update_reset(&self) {
+        self.lock().clear();
+    }
+}
+
+pub static MIGRATE_POSTCOPY_UPDATE: LiveUpdateEntry = live_update_entry(
+    "MIGRATE_POSTCOPY_LIST",
+    LiveUpdateStage::Fresh,
+    false,
+    1,
+    &MIGRATE_POSTCOPY_LIST,
+);
+
+// checked by the sender before it stops the VM and by the receiver before it boots the VM
+pub fn migrate_postcopy_allowed(vm: &Vm) -> bool {
+    if vm.has_virtio() {
+        println!(
+            "migrate_postcopy: VM[{}] has virtio devices, they can not wait for a missing page",
+            vm.id()
+        );
+        return false;
+    }
+    true
+}
+
+/*
+ * Take the pages set in the bitmap at bitmap_ipa of the MVM away from the VM, the bitmap is laid
+ * out the same as the dirty bitmap of the sender. Called before the VM is booted.
+ */
+pub fn migrate_postcopy_init(vm_id: usize, bitmap_ipa: usize) -> Result<(), ()> {
+    let vm = match vm(vm_id) {
+        Some(vm) if vm_id != 0 => vm,
+        _ => {
+            println!("migrate_postcopy_init: VM[{}] can not be migrated", vm_id);
+            return Err(());
+        }
+    };
+    // read by words, a word never crosses a page then
+    if bitmap_ipa % 8 != 0 {
+        println!("migrate_postcopy_init: unaligned bitmap ipa {:x}", bitmap_ipa);
+        return Err(());
+    }
+    if MIGRATE_POSTCOPY_LIST.lock().contains_key(&vm_id) {
+        println!("migrate_postcopy_init: VM[{}] is in post-copy already", vm_id);
+        return Err(());
+    }
+    if !migrate_postcopy_allowed(&vm) {
+        return Err(());
+    }
+    let mut regions = Vec::new();
+    let mut page_num = 0;
+    for idx in 0..vm.region_num() {
+        let num = vm.pa_length(idx) / PAGE_SIZE;
+        let ipa = vm.pa_start(idx).wrapping_add(vm.pa_offset(idx));
+        regions.push((page_num, num, vm.pa_start(idx), ipa));
+        page_num += num;
+    }
+    let mut postcopy = MigratePostcopy {
+        regions,
+        page_num,
+        missing: FlexBitmap::new(page_num),
+        requested: FlexBitmap::new(page_num),
+        remaining: 0,
+        requests: 0,
+        start: time_current_us(),
+    };
+
+    let mvm = active_vm().unwrap();
+    for word_idx in 0..(page_num + 63) / 64 {
+        let word_pa = vm_ipa2pa(mvm.clone(), bitmap_ipa + word_idx * 8);
+        if word_pa == 0 {
+            println!("migrate_postcopy_init: illegal ipa {:x}", bitmap_ipa + word_idx * 8);
+            return Err(());
+        }
+        let mut word = unsafe { *(word_pa as *const usize) };
+        while word != 0 {
+            let page = word_idx * 64 + word.trailing_zeros() as usize;
+            word &= word - 1;
+            if page >= page_num {
+                break;
+            }
+            postcopy.missing.set(page, true);
+            postcopy.remaining += 1;
+        }
+    }
+    // the memory of a guest VM is mapped by pages, see vmm_init_memory
+    for page in 0..page_num {
+        if postcopy.missing.get(page) != 0 {
+            let (_, ipa) = postcopy.page_addr(page).unwrap();
+            vm.pt_unmap_range(ipa, PAGE_SIZE, false);
+        }
+    }
+    info!(
+        "VM[{}] post-copy migration, {} of {} pages missing",
+        vm_id, postcopy.remaining, page_num
+    );
+    if postcopy.remaining != 0 {
+        MIGRATE_POSTCOPY_LIST.lock().insert(vm_id, postcopy);
+    }
+    Ok(())
+}
+
+/*
+ * Fill count pages of the VM from page on, their content is at data_ipa of the MVM. The pages
+ * received already are skipped, the guest may have written them. Returns the pages still missing.
+ */
+pub fn migrate_postcopy_fill(vm_id: usize, data_ipa: usize, page: usize, count: usize) -> Result<usize, ()> {
+    let vm = match vm(vm_id) {
+        Some(vm) => vm,
+        None => {
+            println!("migrate_postcopy_fill: VM[{}] not exist", vm_id);
+            return Err(());
+        }
+    };
+    // copied by pages, a page of the data never crosses a page of the MVM then
+    if data_ipa % PAGE_SIZE != 0 {
+        println!("migrate_postcopy_fill: unaligned data ipa {:x}", data_ipa);
+        return Err(());
+    }
+    let mvm = active_vm().unwrap();
+    let mut list = MIGRATE_POSTCOPY_LIST.lock();
+    let postcopy = match list.get_mut(&vm_id) {
+        Some(postcopy) => postcopy,
+        None => {
+            println!("migrate_postcopy_fill: VM[{}] is not in post-copy", vm_id);
+            return Err(());
+        }
+    };
+    if page >= postcopy.page_num || count > postcopy.page_num - page {
+        println!("migrate_postcopy_fill: illegal page {} count {}", page, count);
+        return Err(());
+    }
+    for idx in page..page + count {
+        if postcopy.missing.get(idx) == 0 {
+            continue;
+        }
+        let data_pa = vm_ipa2pa(mvm.clone(), data_ipa + (idx - page) * PAGE_SIZE);
+        if data_pa == 0 {
+            println!(
+                "migrate_postcopy_fill: illegal ipa {:x}",
+                data_ipa + (idx - page) * PAGE_SIZE
+            );
+            return Err(());
+        }
+        let (pa, ipa) = postcopy.page_addr(idx).unwrap();
+        memcpy_safe(pa as *const u8, data_pa as *const u8, PAGE_SIZE);
+        vm.pt_map_range(ipa, PAGE_SIZE, pa, PTE_S2_NORMAL, false);
+        postcopy.missing.set(idx, false);
+        postcopy.requested.set(idx, false);
+        postcopy.remaining -= 1;
+    }
+
+    let remaining = postcopy.remaining;
+    if remaining == 0 {
+        info!(
+            "VM[{}] post-copy migration done, {} pages requested by faults in {}us",
+            vm_id,
+            postcopy.requests,
+            time_current_us() - postcopy.start
+        );
+        list.remove(&vm_id);
+    }
+    drop(list);
+    migrate_postcopy_wake(&vm);
+    Ok(remaining)
+}
+
+pub fn migrate_postcopy_missing(vm_id: usize, page: usize) -> bool {
+    match MIGRATE_POSTCOPY_LIST.lock().get(&vm_id) {
+        Some(postcopy) => postcopy.missing.get(page) != 0,
+        None => false,
+    }
+}
+
+// wake up the vcpus blocked on a page which is not missing any more, each one on its own core
+fn migrate_postcopy_wake(vm: &Vm) {
+    for idx in 0..vm.cpu_num() {
+        let vcpu = vm.vcpu(idx).unwrap();
+        match vcpu.postcopy_page() {
+            Some(page) if !migrate_postcopy_missing(vm.id(), page) => {}
+            _ => continue,
+        }
+        if vcpu.phys_id() == current_cpu().id {
+            vcpu_postcopy_wakeup(vm.id());
+            continue;
+        }
+        let m = IpiVmmMsg {
+            vmid: vm.id(),
+            event: VmmEvent::VmmPostcopyWake,
+        };
+        if !ipi_send_msg(vcpu.phys_id(), IpiType::IpiTVMM, IpiInnerMsg::VmmMsg(m)) {
+            warn!("migrate_postcopy_wake: failed to send ipi to Core {}", vcpu.phys_id());
+        }
+    }
+}
+
+/*
+ * Called on a stage-2 translation fault of the active VM. Returns true if ipa is a page still
+ * missing, the vcpu is blocked until the page is filled and the faulting access is retried then.
+ */
+pub fn migrate_postcopy_fault(ipa: usize) -> bool {
+    let vcpu = match current_cpu().active_vcpu.clone() {
+        Some(vcpu) => vcpu,
+        None => return false,
+    };
+    let vm_id = vcpu.vm_id();
+    let (page, request) = {
+        let mut list = MIGRATE_POSTCOPY_LIST.lock();
+        let postcopy = match list.get_mut(&vm_id) {
+            Some(postcopy) => postcopy,
+            None => return false,
+        };
+        let page = match postcopy.ipa_page(ipa) {
+            Some(page) => page,
+            None => return false,
+        };
+        // filled meanwhile
+        if postcopy.missing.get(page) == 0 {
+            return true;
+        }
+        // set before the list is unlocked, so a fill on another core wakes the vcpu up
+        vcpu.set_postcopy_page(Some(page));
+        // on the way
+        if postcopy.requested.get(page) != 0 {
+            (page, false)
+        } else {
+            postcopy.requested.set(page, true);
+            postcopy.requests += 1;
+            (page, true)
+        }
+    };
+    if !request {
+        vcpu_postcopy_wait(vcpu);
+        return true;
+    }
+    let sent = hvc_send_msg_to_vm(
+        0,
+        &HvcGuestMsg::Migrate(HvcMigrateMsg {
+            fid: HVC_VMM,
+            event: HVC_VMM_MIGRATE_START,
+            vm_id,
+            oper: MIGRATE_PAGE_REQUEST,
+            page_num: page,
+        }),
+    );
+    if !sent {
+        // the MVM never sees this request, let the retried access send it again
+        if let Some(postcopy) = MIGRATE_POSTCOPY_LIST.lock().get_mut(&vm_id) {
+            if postcopy.missing.get(page) != 0 {
+                postcopy.requested.set(page, false);
+                postcopy.requests -= 1;
+            }
+        }
+        vcpu.set_postcopy_page(None);
+        return true;
+    }
+    vcpu_postcopy_wait(vcpu);
+    true
+}
+
+pub fn migrate_postcopy_remove(vm_id: usize) {
+    MIGRATE_POSTCOPY_LIST.lock().remove(&vm_id);
+}
@@ -10,40 +10,64 @@
 
 pub use self::async_task::*;
 pub use self::cpu::*;
+pub use self::dirty_log::*;
+pub use self::health::*;
+pub use self::hot_patch::*;
 pub use self::hvc::*;
 pub use self::interrupt::*;
 pub use self::iommu::*;
 pub use self::ipi::*;
 pub use self::ivc::*;
 pub use self::live_update::*;
+pub use self::live_update_state::*;
 pub use self::logger::*;
 pub use self::mem::*;
 pub use self::mem_region::*;
 pub use self::migrate::*;
+pub use self::migrate_compress::*;
+pub use self::migrate_converge::*;
+pub use self::migrate_postcopy::*;
+pub use self::migrate_stream::*;
+pub use self::power::*;
 pub use self::sched::*;
+pub use self::snapshot::*;
 // pub use self::task::*;
 pub use self::timer::*;
+pub use self::trace_ring::*;
 pub use self::vcpu::*;
 // pub use self::vcpu_pool::*;
 pub use self::vcpu_array::*;
 pub use self::vm::*;
+pub use self::vm_stats::*;
 
 mod async_task;
 mod cpu;
+mod dirty_log;
+mod health;
+mod hot_patch;
 mod hvc;
 mod interrupt;
 mod ipi;
 mod ivc;
 mod live_update;
+mod live_update_state;
 mod logger;
 mod mem;
 mod mem_region;
 mod migrate;
+mod migrate_compress;
+mod migrate_converge;
+mod migrate_postcopy;
+mod migrate_stream;
+mod power;
 // mod task;
 mod iommu;
 mod sched;
+mod snapshot;
 mod timer;
+mod trace_ring;
 mod vcpu;
 // mod vcpu_pool;
 mod vcpu_array;
 mod vm;
+mod vm_stats;
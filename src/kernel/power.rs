@@ -0,0 +1,177 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use crate::board::{PlatOperation, Platform};
+use crate::kernel::{
+    active_vm_id, async_task_pending, current_cpu, hvc_send_msg_to_vm, vm_if_get_state, HvcGuestMsg, HvcManageMsg,
+};
+use crate::kernel::{VmState, HVC_SYS, HVC_SYS_REBOOT, HVC_SYS_SHUTDOWN, VM_LIST};
+use crate::lib::time_current_us;
+use crate::vmm::vmm_shutdown_vm;
+
+// time the guests have to power themselves off, then they are stopped by force
+const SYS_POWER_GUEST_TIMEOUT_US: usize = 10_000_000;
+// time the MVM has to finish the mediated block I/O left by the guests
+const SYS_POWER_IO_TIMEOUT_US: usize = 2_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SysPowerEvent {
+    Reboot = 0,
+    Shutdown = 1,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum SysPowerStage {
+    WaitGuest,
+    FlushIo,
+}
+
+struct SysPowerRequest {
+    event: SysPowerEvent,
+    stage: SysPowerStage,
+    deadline: usize,
+    // guests to be stopped by force, out of the timer irq, see sys_power_stop_guests
+    stop: Vec<usize>,
+}
+
+static SYS_POWER_REQUEST: Mutex<Option<SysPowerRequest>> = Mutex::new(None);
+static SYS_POWER_STOP_PENDING: AtomicBool = AtomicBool::new(false);
+
+// the guests not stopped yet, a paused one is still to be stopped
+fn sys_power_active_guests() -> Vec<usize> {
+    VM_LIST
+        .lock()
+        .iter()
+        .map(|vm| vm.id())
+        .filter(|vm_id| *vm_id != 0 && matches!(vm_if_get_state(*vm_id), VmState::VmActive | VmState::VmPaused))
+        .collect()
+}
+
+/*
+ * Reboot or shut down the platform on behalf of the MVM, in order:
+ * 1. every running guest is asked to power itself off, the paused ones and the ones can not be
+ *    notified are stopped at once;
+ * 2. the guests still running after SYS_POWER_GUEST_TIMEOUT_US are stopped by force;
+ * 3. the mediated block I/O they left is completed by the MVM, bounded by SYS_POWER_IO_TIMEOUT_US;
+ * 4. the platform is reset or powered off.
+ * Steps 2 to 4 are driven by the timer of core 0 which never stops, see TimerKeep::Monitor. The
+ * guests of step 2 are stopped on the next trap of the MVM on core 0, not in the timer irq.
+ */
+pub fn sys_power_request(event: SysPowerEvent) -> Result<usize, ()> {
+    {
+        let mut request = SYS_POWER_REQUEST.lock();
+        if request.is_some() {
+            println!("sys_power_request: a system {:?} is in progress", event);
+            return Err(());
+        }
+        *request = Some(SysPowerRequest {
+            event,
+            stage: SysPowerStage::WaitGuest,
+            deadline: time_current_us() + SYS_POWER_GUEST_TIMEOUT_US,
+            stop: Vec::new(),
+        });
+    }
+    info!("System {:?} requested by MVM", event);
+
+    let guest_event = match event {
+        SysPowerEvent::Reboot => HVC_SYS_REBOOT,
+        SysPowerEvent::Shutdown => HVC_SYS_SHUTDOWN,
+    };
+    for vm_id in sys_power_active_guests() {
+        let msg = HvcManageMsg {
+            fid: HVC_SYS,
+            event: guest_event,
+            vm_id,
+        };
+        if let VmState::VmPaused = vm_if_get_state(vm_id) {
+            warn!("sys_power_request: VM[{}] is paused, stop it", vm_id);
+            vmm_shutdown_vm(vm_id);
+        } else if !hvc_send_msg_to_vm(vm_id, &HvcGuestMsg::Manage(msg)) {
+            warn!("sys_power_request: VM[{}] can not be notified, stop it", vm_id);
+            vmm_shutdown_vm(vm_id);
+        }
+    }
+    Ok(0)
+}
+
+// called periodically by the timer of core 0
+pub fn sys_power_check() {
+    if current_cpu().id != 0 {
+        return;
+    }
+    let now = time_current_us();
+    let mut request_lock = match SYS_POWER_REQUEST.try_lock() {
+        Some(request) => request,
+        None => return,
+    };
+    let request = match request_lock.as_mut() {
+        Some(request) => request,
+        None => return,
+    };
+
+    if let SysPowerStage::WaitGuest = request.stage {
+        let guests = sys_power_active_guests();
+        if !guests.is_empty() && now < request.deadline {
+            return;
+        }
+        request.stage = SysPowerStage::FlushIo;
+        request.deadline = now + SYS_POWER_IO_TIMEOUT_US;
+        if !guests.is_empty() {
+            request.stop = guests;
+            SYS_POWER_STOP_PENDING.store(true, Ordering::Release);
+        }
+        return;
+    }
+
+    // the platform goes down anyway if the MVM does not trap in time to stop the guests
+    if (!request.stop.is_empty() || async_task_pending()) && now < request.deadline {
+        return;
+    }
+    if async_task_pending() {
+        warn!("sys_power_check: mediated block I/O left unfinished");
+    }
+    let event = request.event;
+    drop(request_lock);
+    match event {
+        SysPowerEvent::Reboot => Platform::sys_reboot(),
+        SysPowerEvent::Shutdown => Platform::sys_shutdown(),
+    }
+}
+
+/*
+ * Stop the guests sys_power_check gave up waiting for. Called at the end of every trap from a lower
+ * EL, it only acts on core 0 in the context of the MVM, like an HVC_VMM_SHUTDOWN_VM of it, so the
+ * vcpus are not taken off the schedulers from the timer irq.
+ */
+pub fn sys_power_stop_guests() {
+    if !SYS_POWER_STOP_PENDING.load(Ordering::Acquire) || current_cpu().id != 0 || active_vm_id() != 0 {
+        return;
+    }
+    let guests = match SYS_POWER_REQUEST.try_lock() {
+        Some(mut request) => match request.as_mut() {
+            Some(request) => core::mem::take(&mut request.stop),
+            None => Vec::new(),
+        },
+        None => return,
+    };
+    SYS_POWER_STOP_PENDING.store(false, Ordering::Release);
+    for vm_id in guests {
+        warn!(
+            "sys_power_stop_guests: VM[{}] did not power off in time, stop it",
+            vm_id
+        );
+        vmm_shutdown_vm(vm_id);
+    }
+}
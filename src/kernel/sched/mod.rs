@@ -9,17 +9,20 @@
 // See the Mulan PSL v2 for more details.
 
 mod sched_rr;
-// mod sched_rt;
+mod sched_rt;
+mod sched_tp;
 
 pub use self::sched_rr::SchedulerRR;
-// pub use self::sched_rt::SchedulerRT;
+pub use self::sched_rt::SchedulerRT;
+pub use self::sched_tp::SchedulerTP;
 
 use crate::kernel::Vcpu;
 
 // Must Implement SchedulerTrait for inner struct(the real scheduler object)
 pub enum SchedType {
     SchedRR(SchedulerRR),
-    // SchedRT(SchedulerRT),
+    SchedRT(SchedulerRT),
+    SchedTP(SchedulerTP),
     None,
 }
 
@@ -9,7 +9,7 @@
 // See the Mulan PSL v2 for more details.
 
 use alloc::vec::Vec;
-use crate::kernel::{Vcpu, Scheduler, SchedulerUpdate, current_cpu, VcpuState, timer_enable, vm};
+use crate::kernel::{Vcpu, Scheduler, SchedulerUpdate, current_cpu, VcpuState, timer_enable, vcpu_blocked_on_core, vm};
 
 pub struct SchedulerRR {
     queue: Vec<Vcpu>,
@@ -60,8 +60,14 @@ impl Scheduler for SchedulerRR {
     }
 
     fn do_schedule(&mut self) {
-        let next_vcpu = self.next().unwrap();
-        current_cpu().schedule_to(next_vcpu);
+        let prev = current_cpu().active_vcpu.clone();
+        if let Some(next_vcpu) = self.next() {
+            match prev {
+                // schedule_to does not save the context of a vcpu from the same vm
+                Some(prev_vcpu) if prev_vcpu.vm_id() == next_vcpu.vm_id() => {}
+                _ => current_cpu().schedule_to(next_vcpu),
+            }
+        }
     }
 
     fn sleep(&mut self, vcpu: Vcpu) {
@@ -90,7 +96,8 @@ impl Scheduler for SchedulerRR {
                 None => {}
             }
         }
-        if self.queue.len() <= 1 {
+        // blocked vcpus are woken up by the timer, see vcpu_wfi_check
+        if self.queue.len() <= 1 && !vcpu_blocked_on_core() {
             timer_enable(false);
         }
         if need_schedule {
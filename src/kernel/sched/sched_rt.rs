@@ -8,32 +8,329 @@
 // MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use crate::kernel::{Vcpu, Scheduler};
+use alloc::vec::Vec;
 
-pub struct SchedulerRT {}
+use crate::kernel::{current_cpu, timer_enable, vcpu_blocked_on_core, vm, Scheduler, SchedulerUpdate, Vcpu, VcpuState};
+use crate::lib::time_current_us;
+
+/*
+ * EDF scheduler with a periodic server per vcpu.
+ * - a vcpu gets `budget` us of cpu time every `period` us, its deadline is the end of current period;
+ * - vcpus with budget left run first, earliest deadline first, `priority` breaks the tie;
+ * - vcpus with 0 budget (best effort) take the time left, in round robin order;
+ * - if nobody else is runnable, vcpus which used up their budget keep running until the next period.
+ * A vcpu still holding budget when its period ends has missed its deadline.
+ * Budget is accounted at the granularity of the hypervisor timer tick.
+ * A blocked or paused vcpu keeps its server, the budget left and the deadline, so waking up does
+ * not refill it, only a period boundary does.
+ */
+struct RtVcpu {
+    vcpu: Vcpu,
+    budget: usize,
+    period: usize,
+    priority: usize,
+    remaining: usize,
+    deadline: usize,
+    deadline_miss: usize,
+}
+
+impl RtVcpu {
+    fn new(vcpu: Vcpu, now: usize) -> RtVcpu {
+        let (budget, period, priority) = match vcpu.vm() {
+            Some(vm) => {
+                let config = vm.config();
+                (config.cpu_budget(), config.cpu_period(), config.cpu_priority())
+            }
+            None => (0, 0, 0),
+        };
+        // a budget without period can not be replenished, treat it as best effort
+        let budget = if period == 0 { 0 } else { budget };
+        RtVcpu {
+            vcpu,
+            budget,
+            period,
+            priority,
+            remaining: budget,
+            deadline: now + period,
+            deadline_miss: 0,
+        }
+    }
+
+    fn is_rt(&self) -> bool {
+        self.budget != 0
+    }
+
+    fn runnable(&self) -> bool {
+        match self.vcpu.state() {
+            VcpuState::VcpuInv => false,
+            _ => true,
+        }
+    }
+
+    fn replenish(&mut self, now: usize) {
+        if !self.is_rt() || self.deadline > now {
+            return;
+        }
+        if self.remaining != 0 && self.runnable() {
+            self.deadline_miss += 1;
+            // exported to the MVM by HVC_VMM_VM_STATS
+            self.vcpu.stats_deadline_miss();
+            if self.deadline_miss == 1 {
+                warn!(
+                    "SchedulerRT: Core {} VM[{}] vcpu {} missed deadline, {}us budget left",
+                    current_cpu().id,
+                    self.vcpu.vm_id(),
+                    self.vcpu.id(),
+                    self.remaining
+                );
+            }
+        }
+        self.next_period(now);
+    }
+
+    // a vcpu that was not runnable at its deadline did not miss it
+    fn replenish_idle(&mut self, now: usize) {
+        if self.is_rt() && self.deadline <= now {
+            self.next_period(now);
+        }
+    }
+
+    fn next_period(&mut self, now: usize) {
+        let periods = (now - self.deadline) / self.period + 1;
+        self.deadline += periods * self.period;
+        self.remaining = self.budget;
+    }
+
+    fn is_vcpu(&self, vcpu: &Vcpu) -> bool {
+        self.vcpu.vm_id() == vcpu.vm_id() && self.vcpu.id() == vcpu.id()
+    }
+
+    // the same server for the vcpu in the new image, see SchedulerUpdate
+    fn update(&self) -> RtVcpu {
+        let vm = vm(self.vcpu.vm_id()).unwrap();
+        RtVcpu {
+            vcpu: vm.vcpu(self.vcpu.id()).unwrap(),
+            budget: self.budget,
+            period: self.period,
+            priority: self.priority,
+            remaining: self.remaining,
+            deadline: self.deadline,
+            deadline_miss: self.deadline_miss,
+        }
+    }
+}
+
+pub struct SchedulerRT {
+    queue: Vec<RtVcpu>,
+    // servers of the blocked and paused vcpus, taken back when they wake up
+    blocked: Vec<RtVcpu>,
+    active_idx: usize,
+    last_tick: usize,
+}
+
+impl SchedulerRT {
+    pub fn new() -> Self {
+        Self {
+            queue: Vec::new(),
+            blocked: Vec::new(),
+            active_idx: 0,
+            last_tick: time_current_us(),
+        }
+    }
+
+    fn server(&mut self, vcpu: Vcpu, now: usize) -> RtVcpu {
+        match self.blocked.iter().position(|x| x.is_vcpu(&vcpu)) {
+            Some(idx) => {
+                let mut rt_vcpu = self.blocked.remove(idx);
+                rt_vcpu.vcpu = vcpu;
+                rt_vcpu.replenish_idle(now);
+                rt_vcpu
+            }
+            None => RtVcpu::new(vcpu, now),
+        }
+    }
+
+    fn account(&mut self, now: usize) {
+        let elapsed = now - self.last_tick;
+        self.last_tick = now;
+        let active_vm_id = match &current_cpu().active_vcpu {
+            Some(active_vcpu) => active_vcpu.vm_id(),
+            None => return,
+        };
+        if let Some(rt_vcpu) = self.queue.get_mut(self.active_idx) {
+            if rt_vcpu.vcpu.vm_id() == active_vm_id {
+                rt_vcpu.remaining = rt_vcpu.remaining.saturating_sub(elapsed);
+            }
+        }
+    }
+
+    fn pick_edf(&self, with_budget: bool) -> Option<usize> {
+        let mut pick: Option<usize> = None;
+        for (idx, rt_vcpu) in self.queue.iter().enumerate() {
+            if !rt_vcpu.is_rt() || !rt_vcpu.runnable() || (rt_vcpu.remaining != 0) != with_budget {
+                continue;
+            }
+            pick = match pick {
+                None => Some(idx),
+                Some(cur) => {
+                    let cur_vcpu = &self.queue[cur];
+                    if (rt_vcpu.deadline, rt_vcpu.priority) < (cur_vcpu.deadline, cur_vcpu.priority) {
+                        Some(idx)
+                    } else {
+                        Some(cur)
+                    }
+                }
+            };
+        }
+        pick
+    }
+
+    fn pick_best_effort(&self) -> Option<usize> {
+        let len = self.queue.len();
+        for i in 1..=len {
+            let idx = (self.active_idx + i) % len;
+            let rt_vcpu = &self.queue[idx];
+            if !rt_vcpu.is_rt() && rt_vcpu.runnable() {
+                return Some(idx);
+            }
+        }
+        None
+    }
+}
+
+impl Default for SchedulerRT {
+    fn default() -> Self {
+        Self {
+            queue: Default::default(),
+            blocked: Default::default(),
+            active_idx: Default::default(),
+            last_tick: Default::default(),
+        }
+    }
+}
 
 impl Scheduler for SchedulerRT {
     fn init(&mut self) {
-        todo!()
+        self.last_tick = time_current_us();
     }
 
     fn next(&mut self) -> Option<Vcpu> {
-        todo!()
+        let now = time_current_us();
+        self.account(now);
+        for rt_vcpu in self.queue.iter_mut() {
+            rt_vcpu.replenish(now);
+        }
+
+        let pick = self
+            .pick_edf(true)
+            .or_else(|| self.pick_best_effort())
+            .or_else(|| self.pick_edf(false));
+        match pick {
+            Some(idx) => {
+                self.active_idx = idx;
+                Some(self.queue[idx].vcpu.clone())
+            }
+            None => None,
+        }
     }
 
     fn do_schedule(&mut self) {
-        todo!()
+        let prev = current_cpu().active_vcpu.clone();
+        if let Some(next_vcpu) = self.next() {
+            match prev {
+                // schedule_to does not save the context of a vcpu from the same vm
+                Some(prev_vcpu) if prev_vcpu.vm_id() == next_vcpu.vm_id() => {}
+                _ => current_cpu().schedule_to(next_vcpu),
+            }
+        }
     }
 
     fn sleep(&mut self, vcpu: Vcpu) {
-        todo!()
+        let mut need_schedule = false;
+        {
+            let now = time_current_us();
+            self.account(now);
+            // a vcpu leaving for good drops its server
+            self.blocked.retain(|x| !x.is_vcpu(&vcpu));
+            let keep = matches!(vcpu.state(), VcpuState::VcpuBlk | VcpuState::VcpuPause);
+            let queue = &mut self.queue;
+            match queue.iter().position(|x| x.vcpu.vm_id() == vcpu.vm_id()) {
+                Some(idx) => {
+                    let rt_vcpu = queue.remove(idx);
+                    if keep {
+                        self.blocked.push(rt_vcpu);
+                    }
+                    if idx < self.active_idx {
+                        self.active_idx -= 1;
+                    } else if idx == self.active_idx {
+                        // cpu.active_vcpu need remove
+                        current_cpu().set_active_vcpu(None);
+                        if !queue.is_empty() {
+                            need_schedule = true;
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+        // blocked vcpus are woken up by the timer, see vcpu_wfi_check
+        if self.queue.len() <= 1 && !vcpu_blocked_on_core() {
+            timer_enable(false);
+        }
+        if need_schedule {
+            self.do_schedule();
+        }
     }
 
     fn wakeup(&mut self, vcpu: Vcpu) {
-        todo!()
+        let now = time_current_us();
+        vcpu.set_state(VcpuState::VcpuPend);
+        let rt_vcpu = self.server(vcpu, now);
+        self.queue.push(rt_vcpu);
+        if self.queue.len() > 1 {
+            timer_enable(true);
+        }
+        if self.queue.len() == 1 {
+            self.last_tick = now;
+            self.active_idx = 0;
+            let vcpu = self.queue[0].vcpu.clone();
+            current_cpu().schedule_to(vcpu);
+        }
     }
 
     fn yield_to(&mut self, vcpu: Vcpu) {
-        todo!()
+        let now = time_current_us();
+        self.account(now);
+        let rt_vcpu = self.server(vcpu.clone(), now);
+        self.queue.push(rt_vcpu);
+        self.active_idx = self.queue.len() - 1;
+        current_cpu().schedule_to(vcpu);
+        if self.queue.len() > 1 {
+            timer_enable(true);
+        }
+    }
+}
+
+// #[cfg(feature = "update")]
+impl SchedulerUpdate for SchedulerRT {
+    fn update(&self) -> Self {
+        let src_rt = self;
+        let mut new_rt = SchedulerRT::default();
+        for rt_vcpu in src_rt.queue.iter() {
+            new_rt.queue.push(rt_vcpu.update());
+        }
+        for rt_vcpu in src_rt.blocked.iter() {
+            new_rt.blocked.push(rt_vcpu.update());
+        }
+        new_rt.active_idx = src_rt.active_idx;
+        new_rt.last_tick = src_rt.last_tick;
+
+        let active_vcpu = if src_rt.active_idx < new_rt.queue.len() {
+            Some(new_rt.queue[src_rt.active_idx].vcpu.clone())
+        } else {
+            None
+        };
+        current_cpu().set_active_vcpu(active_vcpu);
+        new_rt
     }
 }
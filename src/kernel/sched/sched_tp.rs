@@ -0,0 +1,287 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use alloc::vec::Vec;
+
+use crate::config::{vm_cfg_sched_partition, SchedWindowConfig};
+use crate::kernel::{current_cpu, timer_enable, vcpu_blocked_on_core, vm, Scheduler, SchedulerUpdate, Vcpu, VcpuState};
+use crate::kernel::{trace_enabled, trace_event, TraceKind};
+use crate::lib::time_current_us;
+
+// the hypervisor timer tick in us, a window switch is expected within one tick
+const SCHED_TP_TICK_US: usize = 1000;
+
+/*
+ * ARINC-653 style static time partitioning.
+ * The cpu repeats a major frame, which is split into fixed windows, each window belongs to one VM.
+ * - the plan of this cpu is loaded from the vm config table at every major frame boundary;
+ * - a window whose VM is not runnable and time not covered by any window leave the cpu idle, or go
+ *   to the MVM if the plan explicitly gives it the spare time and it runs on this cpu;
+ * - a partition leaving its window more than one tick late is a window overrun;
+ * - without a plan, the vcpus are scheduled in round robin order.
+ */
+pub struct SchedulerTP {
+    queue: Vec<Vcpu>,
+    active_idx: usize,
+    major_frame: usize,
+    windows: Vec<SchedWindowConfig>,
+    frame_start: usize,
+    spare_to_mvm: bool,
+    // the window being served and its absolute end time
+    window_idx: Option<usize>,
+    window_end: usize,
+}
+
+impl SchedulerTP {
+    pub fn new() -> Self {
+        Self {
+            queue: Vec::new(),
+            active_idx: 0,
+            major_frame: 0,
+            windows: Vec::new(),
+            frame_start: 0,
+            spare_to_mvm: false,
+            window_idx: None,
+            window_end: 0,
+        }
+    }
+
+    fn check_overrun(&mut self, now: usize) {
+        let idx = match self.window_idx {
+            Some(idx) if now >= self.window_end => idx,
+            _ => return,
+        };
+        self.window_idx = None;
+        if now - self.window_end <= SCHED_TP_TICK_US {
+            return;
+        }
+        let vm_id = self.windows[idx].vm_id;
+        // counted in the stats of the VM, exported to the MVM by HVC_VMM_VM_STATS
+        let count = match vm(vm_id) {
+            Some(vm) => vm.stats_window_overrun(),
+            None => return,
+        };
+        if count == 1 {
+            warn!(
+                "SchedulerTP: Core {} VM[{}] overran its window by {}us",
+                current_cpu().id,
+                vm_id,
+                now - self.window_end
+            );
+        }
+    }
+
+    fn reload_plan(&mut self, now: usize) {
+        if self.major_frame != 0 && now < self.frame_start + self.major_frame {
+            return;
+        }
+        let partition = vm_cfg_sched_partition(current_cpu().id);
+        if partition.major_frame == 0 {
+            self.major_frame = 0;
+            self.windows.clear();
+            self.spare_to_mvm = false;
+            return;
+        }
+        if partition.major_frame == self.major_frame {
+            // keep the frames aligned to the first one
+            self.frame_start += (now - self.frame_start) / self.major_frame * self.major_frame;
+        } else {
+            self.frame_start = now;
+        }
+        self.major_frame = partition.major_frame;
+        self.windows = partition.windows;
+        self.spare_to_mvm = partition.spare_to_mvm;
+    }
+
+    fn find_runnable(&self, vm_id: usize) -> Option<usize> {
+        self.queue
+            .iter()
+            .position(|x| x.vm_id() == vm_id && !matches!(x.state(), VcpuState::VcpuInv))
+    }
+
+    fn pick_round_robin(&self) -> Option<usize> {
+        let len = self.queue.len();
+        for i in 1..=len {
+            let idx = (self.active_idx + i) % len;
+            if !matches!(self.queue[idx].state(), VcpuState::VcpuInv) {
+                return Some(idx);
+            }
+        }
+        None
+    }
+
+    // a plan is being served or is set to start at the next tick
+    fn planned(&self) -> bool {
+        self.major_frame != 0 || vm_cfg_sched_partition(current_cpu().id).major_frame != 0
+    }
+
+    fn pick_partition(&mut self, now: usize) -> Option<usize> {
+        let offset = now - self.frame_start;
+        for (idx, window) in self.windows.iter().enumerate() {
+            if offset >= window.offset && offset < window.offset + window.length {
+                if let Some(pick) = self.find_runnable(window.vm_id) {
+                    self.window_idx = Some(idx);
+                    self.window_end = self.frame_start + window.offset + window.length;
+                    return Some(pick);
+                }
+                break;
+            }
+        }
+        // spare time of the plan, no other partition may run in it
+        if self.spare_to_mvm {
+            self.find_runnable(0)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for SchedulerTP {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler for SchedulerTP {
+    fn init(&mut self) {}
+
+    fn next(&mut self) -> Option<Vcpu> {
+        let now = time_current_us();
+        self.check_overrun(now);
+        self.reload_plan(now);
+
+        let pick = if self.major_frame == 0 {
+            self.pick_round_robin()
+        } else {
+            self.pick_partition(now)
+        };
+        match pick {
+            Some(idx) => {
+                self.active_idx = idx;
+                Some(self.queue[idx].clone())
+            }
+            None => None,
+        }
+    }
+
+    fn do_schedule(&mut self) {
+        let prev = current_cpu().active_vcpu.clone();
+        match self.next() {
+            Some(next_vcpu) => match prev {
+                // schedule_to does not save the context of a vcpu from the same vm
+                Some(prev_vcpu) if prev_vcpu.vm_id() == next_vcpu.vm_id() => {}
+                _ => current_cpu().schedule_to(next_vcpu),
+            },
+            // nothing may run now, the core idles on return from the timer irq, see lower_aarch64_irq
+            None => {
+                if let Some(prev_vcpu) = prev {
+                    if trace_enabled(TraceKind::SchedOut) {
+                        trace_event(TraceKind::SchedOut, [prev_vcpu.vm_id(), prev_vcpu.id(), 0, 0]);
+                    }
+                    prev_vcpu.set_state(VcpuState::VcpuPend);
+                    prev_vcpu.context_vm_store();
+                    current_cpu().set_active_vcpu(None);
+                }
+            }
+        }
+    }
+
+    fn sleep(&mut self, vcpu: Vcpu) {
+        let mut need_schedule = false;
+        {
+            let queue = &mut self.queue;
+            match queue.iter().position(|x| x.vm_id() == vcpu.vm_id()) {
+                Some(idx) => {
+                    queue.remove(idx);
+                    if idx < self.active_idx {
+                        self.active_idx -= 1;
+                    } else if idx == self.active_idx {
+                        // cpu.active_vcpu need remove
+                        current_cpu().set_active_vcpu(None);
+                        self.window_idx = None;
+                        if !queue.is_empty() {
+                            need_schedule = true;
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+        // blocked vcpus are woken up by the timer, see vcpu_wfi_check, a plan switches windows by it
+        if self.queue.len() <= 1 && self.major_frame == 0 && !vcpu_blocked_on_core() {
+            timer_enable(false);
+        }
+        if need_schedule {
+            self.do_schedule();
+        }
+    }
+
+    fn wakeup(&mut self, vcpu: Vcpu) {
+        vcpu.set_state(VcpuState::VcpuPend);
+        self.queue.push(vcpu);
+        let planned = self.planned();
+        if self.queue.len() > 1 || planned {
+            timer_enable(true);
+        }
+        if self.queue.len() == 1 {
+            if planned {
+                // the vcpu may run only in a window of its VM
+                self.do_schedule();
+            } else {
+                self.active_idx = 0;
+                let vcpu = self.queue[0].clone();
+                current_cpu().schedule_to(vcpu);
+            }
+        }
+    }
+
+    fn yield_to(&mut self, vcpu: Vcpu) {
+        self.queue.push(vcpu.clone());
+        let planned = self.planned();
+        if planned {
+            // the plan decides, the vcpu runs when a window of its VM comes
+            self.do_schedule();
+        } else {
+            self.active_idx = self.queue.len() - 1;
+            self.window_idx = None;
+            current_cpu().schedule_to(vcpu);
+        }
+        if self.queue.len() > 1 || planned {
+            timer_enable(true);
+        }
+    }
+}
+
+// #[cfg(feature = "update")]
+impl SchedulerUpdate for SchedulerTP {
+    fn update(&self) -> Self {
+        let src_tp = self;
+        let mut new_tp = SchedulerTP::new();
+        for vcpu in src_tp.queue.iter() {
+            let vm = vm(vcpu.vm_id()).unwrap();
+            new_tp.queue.push(vm.vcpu(vcpu.id()).unwrap());
+        }
+        new_tp.active_idx = src_tp.active_idx;
+        new_tp.major_frame = src_tp.major_frame;
+        new_tp.windows = src_tp.windows.clone();
+        new_tp.frame_start = src_tp.frame_start;
+        new_tp.spare_to_mvm = src_tp.spare_to_mvm;
+        new_tp.window_idx = src_tp.window_idx;
+        new_tp.window_end = src_tp.window_end;
+
+        let active_vcpu = if src_tp.active_idx < new_tp.queue.len() {
+            Some(new_tp.queue[src_tp.active_idx].clone())
+        } else {
+            None
+        };
+        current_cpu().set_active_vcpu(active_vcpu);
+        new_tp
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use alloc::collections::BTreeMap;
+use core::mem::{align_of, size_of};
+
+use spin::Mutex;
+
+use crate::arch::PAGE_SIZE;
+use crate::kernel::{active_vm, current_cpu, get_share_mem, hvc_send_msg_to_vm, map_migrate_vm_mem, migrate_stream_size};
+use crate::kernel::{send_hvc_ipi, unmap_migrate_vm_mem, vm, vm_if_get_state, vm_ipa2pa, HvcGuestMsg, HvcMigrateMsg, Vm};
+use crate::kernel::{VcpuState, VmState};
+use crate::kernel::{HVC_VMM, HVC_VMM_MIGRATE_VM_BOOT, HVC_VMM_SNAPSHOT_SAVE, HVC_VMM_SNAPSHOT_SAVE_GIC};
+use crate::kernel::{MIGRATE_RECEIVE, MIGRATE_SEND, VM_CONTEXT_RECEIVE, VM_CONTEXT_SEND};
+use crate::kernel::{live_update_decode_with, live_update_encode_with, live_update_entry_codec, LiveUpdateEntry};
+use crate::kernel::{LiveUpdateStage, VM_NUM_MAX};
+
+// "SHYPSNAP"
+pub const VM_SNAPSHOT_MAGIC: usize = 0x5041_4e53_5059_4853;
+pub const VM_SNAPSHOT_VERSION: usize = 2;
+
+/*
+ * A snapshot image is written and read by the MVM, to a file or a partition, laid out as:
+ * | VmSnapshotHeader | migrate stream (data_size) | memory regions of the VM in order (mem_size) |
+ * The migrate stream is mapped at VM_CONTEXT_SEND/RECEIVE and the memory at MIGRATE_SEND/RECEIVE share
+ * memory of the MVM, the same as migration.
+ */
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct VmSnapshotHeader {
+    pub magic: usize,
+    pub version: usize,
+    pub cpu_num: usize,
+    // vcpus powered on when the snapshot was taken
+    pub cpu_on_mask: usize,
+    pub region_num: usize,
+    pub mem_size: usize,
+    pub data_size: usize,
+}
+
+// vm id -> vcpus whose gic state is not saved yet
+static SNAPSHOT_SAVE_PENDING: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+// vm id -> cpu_on_mask of the snapshot being loaded
+static SNAPSHOT_RESTORE_PENDING: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+// both pending maps VM by VM, version 1, see LiveUpdateEncode
+fn snapshot_pending_encode(state: *const (), buf: usize, cap: usize) -> Result<usize, ()> {
+    live_update_encode_with(state, buf, cap, |pending: &Mutex<BTreeMap<usize, usize>>, w| {
+        let pending = pending.lock();
+        w.put_u32(pending.len() as u32);
+        for (vm_id, mask) in pending.iter() {
+            w.put_usize(*vm_id);
+            w.put_usize(*mask);
+        }
+    })
+}
+
+fn snapshot_pending_decode(state: *const (), data: &[u8], version: usize) -> Result<(), ()> {
+    if version != 1 {
+        return Err(());
+    }
+    live_update_decode_with(state, data, |pending: &Mutex<BTreeMap<usize, usize>>, r| {
+        let mut pending = pending.lock();
+        for _ in 0..r.get_count(VM_NUM_MAX)? {
+            let vm_id = r.get_usize()?;
+            pending.insert(vm_id, r.get_usize()?);
+        }
+        Ok(())
+    })
+}
+
+pub static SNAPSHOT_SAVE_PENDING_UPDATE: LiveUpdateEntry = live_update_entry_codec(
+    "SNAPSHOT_SAVE_PENDING",
+    LiveUpdateStage::Fresh,
+    false,
+    1,
+    &SNAPSHOT_SAVE_PENDING,
+    snapshot_pending_encode,
+    snapshot_pending_decode,
+);
+pub static SNAPSHOT_RESTORE_PENDING_UPDATE: LiveUpdateEntry = live_update_entry_codec(
+    "SNAPSHOT_RESTORE_PENDING",
+    LiveUpdateStage::Fresh,
+    false,
+    1,
+    &SNAPSHOT_RESTORE_PENDING,
+    snapshot_pending_encode,
+    snapshot_pending_decode,
+);
+
+fn vm_snapshot_mem_size(vm: &Vm) -> usize {
+    (0..vm.region_num()).map(|idx| vm.pa_length(idx)).sum()
+}
+
+// the header may cross a page, it has to lie in one memory region of the MVM
+fn vm_snapshot_header(header_ipa: usize) -> Option<&'static mut VmSnapshotHeader> {
+    let mvm = active_vm().unwrap();
+    let header_pa = vm_ipa2pa(mvm.clone(), header_ipa);
+    let last = size_of::<VmSnapshotHeader>() - 1;
+    if header_pa == 0
+        || header_ipa % align_of::<VmSnapshotHeader>() != 0
+        || vm_ipa2pa(mvm, header_ipa + last) != header_pa + last
+    {
+        println!("vm_snapshot_header: illegal ipa {:x}", header_ipa);
+        return None;
+    }
+    Some(unsafe { &mut *(header_pa as *mut VmSnapshotHeader) })
+}
+
+/*
+ * Take a snapshot of a paused VM, the header is filled at header_ipa of the MVM at once. The gic
+ * state of each vcpu is saved on its own core by HVC_VMM_SNAPSHOT_SAVE_GIC, then the MVM is
+ * notified by HVC_VMM_SNAPSHOT_SAVE to write the image out, and calls HVC_VMM_SNAPSHOT_SAVE_DONE
+ * at last.
+ */
+pub fn vm_snapshot_save(vm_id: usize, header_ipa: usize) -> Result<usize, ()> {
+    let vm = match vm(vm_id) {
+        Some(vm) if vm_id != 0 => vm,
+        _ => {
+            println!("vm_snapshot_save: vm[{}] can not be saved", vm_id);
+            return Err(());
+        }
+    };
+    if !matches!(vm_if_get_state(vm_id), VmState::VmPaused) {
+        println!("vm_snapshot_save: vm[{}] is not paused", vm_id);
+        return Err(());
+    }
+    let mut cpu_on_mask = 0;
+    for vcpu_id in 0..vm.cpu_num() {
+        match vm.vcpu(vcpu_id).unwrap().state() {
+            VcpuState::VcpuInv => {}
+            VcpuState::VcpuPause => cpu_on_mask |= 1 << vcpu_id,
+            _ => {
+                // the pause request has not reached this vcpu yet
+                println!("vm_snapshot_save: vm[{}] vcpu {} is not stopped", vm_id, vcpu_id);
+                return Err(());
+            }
+        }
+    }
+    let header = match vm_snapshot_header(header_ipa) {
+        Some(header) => header,
+        None => return Err(()),
+    };
+    {
+        let mut pending = SNAPSHOT_SAVE_PENDING.lock();
+        if pending.contains_key(&vm_id) || !vm.inner().lock().migrate_save_pf.is_empty() {
+            println!("vm_snapshot_save: vm[{}] snapshot is in progress", vm_id);
+            return Err(());
+        }
+        pending.insert(vm_id, vm.cpu_num());
+    }
+    *header = VmSnapshotHeader {
+        magic: VM_SNAPSHOT_MAGIC,
+        version: VM_SNAPSHOT_VERSION,
+        cpu_num: vm.cpu_num(),
+        cpu_on_mask,
+        region_num: vm.region_num(),
+        mem_size: vm_snapshot_mem_size(&vm),
+        data_size: migrate_stream_size(),
+    };
+    info!("VM[{}] take snapshot, cpu on mask {:#x}", vm_id, cpu_on_mask);
+    map_migrate_vm_mem(vm.clone(), get_share_mem(MIGRATE_SEND));
+
+    for vcpu_id in 0..vm.cpu_num() {
+        let cpu_trgt = vm.vcpuid_to_pcpuid(vcpu_id).unwrap();
+        if cpu_trgt == current_cpu().id {
+            vm_snapshot_save_ipi_handler(vm_id);
+        } else {
+            send_hvc_ipi(0, vm_id, HVC_VMM, HVC_VMM_SNAPSHOT_SAVE_GIC, cpu_trgt);
+        }
+    }
+    Ok(0)
+}
+
+// save the gic state of the vm[vm_id] vcpu on this core, the last one builds the migrate stream
+pub fn vm_snapshot_save_ipi_handler(vm_id: usize) {
+    if let Some(vcpu) = current_cpu().vcpu_array.pop_vcpu_through_vmid(vm_id) {
+        vcpu.context_gic_irqs_store();
+    }
+    {
+        let mut pending = SNAPSHOT_SAVE_PENDING.lock();
+        match pending.get_mut(&vm_id) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                return;
+            }
+            Some(_) => {
+                pending.remove(&vm_id);
+            }
+            None => return,
+        }
+    }
+    let vm = vm(vm_id).unwrap();
+    vm.context_vm_migrate_save();
+    hvc_send_msg_to_vm(
+        0,
+        &HvcGuestMsg::Migrate(HvcMigrateMsg {
+            fid: HVC_VMM,
+            event: HVC_VMM_SNAPSHOT_SAVE,
+            vm_id,
+            oper: 0,
+            page_num: migrate_stream_size() / PAGE_SIZE,
+        }),
+    );
+}
+
+/* The image has been written out by the MVM, the VM stays paused. */
+pub fn vm_snapshot_save_done(vm_id: usize) -> Result<usize, ()> {
+    let vm = match vm(vm_id) {
+        Some(vm) => vm,
+        None => {
+            println!("vm_snapshot_save_done: vm[{}] not exist", vm_id);
+            return Err(());
+        }
+    };
+    if SNAPSHOT_SAVE_PENDING.lock().contains_key(&vm_id) || vm.inner().lock().migrate_save_pf.is_empty() {
+        println!("vm_snapshot_save_done: vm[{}] snapshot is not ready", vm_id);
+        return Err(());
+    }
+    let mvm = vm(0).unwrap();
+    mvm.pt_unmap_range(get_share_mem(VM_CONTEXT_SEND), migrate_stream_size(), true);
+    unmap_migrate_vm_mem(vm.clone(), get_share_mem(MIGRATE_SEND));
+    vm.inner().lock().migrate_save_pf.clear();
+    info!("VM[{}] snapshot saved", vm_id);
+    Ok(0)
+}
+
+/*
+ * Prepare a created but not booted VM to be loaded from the snapshot whose header is at
+ * header_ipa of the MVM. The MVM reads the rest of the image into VM_CONTEXT_RECEIVE and
+ * MIGRATE_RECEIVE share memory, then boots the VM by HVC_VMM_SNAPSHOT_BOOT.
+ */
+pub fn vm_snapshot_restore(vm_id: usize, header_ipa: usize) -> Result<usize, ()> {
+    let vm = match vm(vm_id) {
+        Some(vm) if vm_id != 0 => vm,
+        _ => {
+            println!("vm_snapshot_restore: vm[{}] can not be restored", vm_id);
+            return Err(());
+        }
+    };
+    if !matches!(vm_if_get_state(vm_id), VmState::VmInv | VmState::VmPending) {
+        println!("vm_snapshot_restore: vm[{}] is running", vm_id);
+        return Err(());
+    }
+    let header = match vm_snapshot_header(header_ipa) {
+        Some(header) => *header,
+        None => return Err(()),
+    };
+    if header.magic != VM_SNAPSHOT_MAGIC || header.version != VM_SNAPSHOT_VERSION {
+        println!(
+            "vm_snapshot_restore: bad snapshot magic {:#x} version {}",
+            header.magic, header.version
+        );
+        return Err(());
+    }
+    if header.cpu_num != vm.cpu_num()
+        || header.region_num != vm.region_num()
+        || header.mem_size != vm_snapshot_mem_size(&vm)
+        || header.data_size > migrate_stream_size()
+    {
+        println!(
+            "vm_snapshot_restore: snapshot does not match the config of vm[{}]",
+            vm_id
+        );
+        return Err(());
+    }
+    {
+        let mut pending = SNAPSHOT_RESTORE_PENDING.lock();
+        if pending.contains_key(&vm_id) {
+            println!("vm_snapshot_restore: vm[{}] restore is in progress", vm_id);
+            return Err(());
+        }
+        pending.insert(vm_id, header.cpu_on_mask);
+    }
+    info!("VM[{}] restore from snapshot", vm_id);
+    map_migrate_vm_mem(vm.clone(), get_share_mem(MIGRATE_RECEIVE));
+    vm.context_vm_migrate_init();
+    Ok(0)
+}
+
+pub fn vm_snapshot_boot(vm_id: usize) -> Result<usize, ()> {
+    let cpu_on_mask = match SNAPSHOT_RESTORE_PENDING.lock().remove(&vm_id) {
+        Some(mask) => mask,
+        None => {
+            println!("vm_snapshot_boot: vm[{}] has no snapshot loaded", vm_id);
+            return Err(());
+        }
+    };
+    let mvm = vm(0).unwrap();
+    let vm = vm(vm_id).unwrap();
+    mvm.pt_unmap_range(get_share_mem(VM_CONTEXT_RECEIVE), migrate_stream_size(), true);
+    unmap_migrate_vm_mem(vm.clone(), get_share_mem(MIGRATE_RECEIVE));
+
+    let restored = vm.context_vm_migrate_restore();
+    vm.inner().lock().migrate_restore_pf.clear();
+    restored?;
+    for vcpu_id in 0..vm.cpu_num() {
+        // a vcpu powered off in the snapshot waits for PSCI CPU_ON of the guest
+        if cpu_on_mask & (1 << vcpu_id) == 0 {
+            continue;
+        }
+        let cpu_trgt = vm.vcpuid_to_pcpuid(vcpu_id).unwrap();
+        // the same as a migrated VM, restore gic and run on the target core
+        send_hvc_ipi(0, vm_id, HVC_VMM, HVC_VMM_MIGRATE_VM_BOOT, cpu_trgt);
+    }
+    Ok(0)
+}
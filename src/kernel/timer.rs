@@ -8,9 +8,13 @@
 // MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use crate::arch::INTERRUPT_IRQ_HYPERVISOR_TIMER;
-// use crate::board::PLATFORM_CPU_NUM_MAX;
-use crate::kernel::{current_cpu, InterruptHandler, Scheduler};
+use crate::board::PLATFORM_CPU_NUM_MAX;
+use crate::kernel::{
+    current_cpu, InterruptHandler, live_update_check, Scheduler, sys_power_check, vcpu_wfi_check, vm_health_check,
+};
 
 // #[derive(Copy, Clone)]
 // struct Timer(bool);
@@ -28,6 +32,21 @@ use crate::kernel::{current_cpu, InterruptHandler, Scheduler};
 // static TIMER_LIST: Mutex<[Timer; PLATFORM_CPU_NUM_MAX]> =
 //     Mutex::new([Timer::default(); PLATFORM_CPU_NUM_MAX]);
 
+/*
+ * Reasons for a core to keep its timer running, whatever its scheduler asks for.
+ * The periodic checks in timer_irq_handler rely on it, a scheduler with a single vcpu to run
+ * disables the timer otherwise.
+ */
+#[derive(Clone, Copy, Debug)]
+pub enum TimerKeep {
+    // core 0 runs the health monitor and the platform power sequence, see vm_health_check
+    // and sys_power_check
+    Monitor = 1 << 0,
+}
+
+const TIMER_KEEP_INIT: AtomicUsize = AtomicUsize::new(0);
+static TIMER_KEEP_LIST: [AtomicUsize; PLATFORM_CPU_NUM_MAX] = [TIMER_KEEP_INIT; PLATFORM_CPU_NUM_MAX];
+
 pub fn timer_init() {
     crate::arch::timer_arch_init();
     timer_enable(false);
@@ -40,10 +59,25 @@ pub fn timer_init() {
         );
         println!("Timer frequency: {}Hz", crate::arch::timer_arch_get_frequency());
         println!("Timer init ok");
+        timer_keep(TimerKeep::Monitor, true);
+    }
+}
+
+/* Keep (or stop keeping) the timer of current core running for a reason */
+pub fn timer_keep(reason: TimerKeep, keep: bool) {
+    let keep_list = &TIMER_KEEP_LIST[current_cpu().id];
+    if keep {
+        keep_list.fetch_or(reason as usize, Ordering::SeqCst);
+        timer_enable(true);
+    } else {
+        keep_list.fetch_and(!(reason as usize), Ordering::SeqCst);
     }
 }
 
 pub fn timer_enable(val: bool) {
+    if !val && TIMER_KEEP_LIST[current_cpu().id].load(Ordering::SeqCst) != 0 {
+        return;
+    }
     // println!(
     //     "Core {} {} EL2 timer",
     //     current_cpu().id,
@@ -66,7 +100,14 @@ pub fn timer_irq_handler(_arg: usize) {
     use crate::arch::timer_arch_disable_irq;
 
     timer_arch_disable_irq();
+    if current_cpu().id == 0 {
+        vm_health_check();
+    }
+    sys_power_check();
+    vcpu_wfi_check();
     current_cpu().scheduler().do_schedule();
 
     timer_notify_after(1);
+    // may switch to the image updated from, after the timer is set again
+    live_update_check();
 }
@@ -0,0 +1,247 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::arch::{PAGE_SIZE, PTE_S2_RO, timer_arch_get_counter, timer_arch_get_frequency};
+use crate::board::PLAT_DESC;
+use crate::kernel::{active_vm, current_cpu, mem_pages_alloc};
+use crate::kernel::{live_update_entry, LiveUpdatable, LiveUpdateEntry, LiveUpdateFrom, LiveUpdateStage};
+use crate::lib::memset_safe;
+use crate::mm::PageFrame;
+
+/*
+ * Tracing of VM exits and hypervisor events into a ring buffer per physical cpu. The buffer is
+ * mapped read only into the MVM by HVC_VMM_TRACE_MAP, laid out as:
+ *   page 0: TraceInfo
+ *   TraceInfo::ring_offset + cpu_id * TraceInfo::ring_size: TraceRing of the cpu, followed by
+ *   TraceRing::capacity TraceEvent
+ * All fields are little endian. Event idx of a cpu is in slot idx % capacity, a slot is
+ * overwritten once the ring wraps. A reader takes head, reads the events in
+ * [head - capacity, head) and keeps those whose seq is still idx + 1 after the copy, the others
+ * are being written or overwritten. The timestamps of all cpus are ticks of the same generic
+ * timer, so the rings merge into one timeline, tools/trace_ring_json.py turns a dump of the buffer
+ * into a trace Perfetto opens, one track per cpu.
+ */
+const TRACE_INFO_MAGIC: u32 = 0x5254_5953; // "SYTR"
+const TRACE_INFO_VERSION: u32 = 1;
+// pages of the ring of each cpu
+const TRACE_RING_PAGES: usize = 128;
+const TRACE_RING_SIZE: usize = TRACE_RING_PAGES * PAGE_SIZE;
+const TRACE_RING_EVENTS: usize = (TRACE_RING_SIZE - size_of::<TraceRing>()) / size_of::<TraceEvent>();
+
+// args of each kind, the rest are 0
+#[derive(Copy, Clone, Debug)]
+pub enum TraceKind {
+    // vm id, vcpu id, exception class, esr
+    VmExit = 0,
+    // vm id, ipa, width, write
+    MmioEmu = 1,
+    // vm id, vcpu id, int id
+    IrqInject = 2,
+    // int id, handled by the hypervisor
+    IrqEoi = 3,
+    // vm id, vcpu id
+    SchedIn = 4,
+    SchedOut = 5,
+    // target cpu id, ipi type
+    IpiSend = 6,
+    // ipi type
+    IpiRecv = 7,
+    // src vm id, ipi task
+    AsyncStart = 8,
+    AsyncFinish = 9,
+}
+
+#[repr(C)]
+struct TraceInfo {
+    magic: u32,
+    version: u32,
+    cpu_num: u32,
+    event_size: u32,
+    ring_offset: u64,
+    ring_size: u64,
+    // of the timestamps
+    freq: u64,
+}
+
+#[repr(C)]
+struct TraceRing {
+    // number of events written to the ring
+    head: AtomicU64,
+    capacity: u64,
+    cpu_id: u64,
+    _reserved: [u64; 5],
+}
+
+#[repr(C)]
+struct TraceEvent {
+    // idx + 1 of the event, 0 while it is written
+    seq: AtomicU64,
+    timestamp: u64,
+    kind: u32,
+    cpu_id: u32,
+    args: [u64; 4],
+    _reserved: u64,
+}
+
+struct TraceBuffer {
+    frame: PageFrame,
+    page_num: usize,
+    // ipa of the buffer in the MVM, 0 if not mapped
+    ipa: usize,
+    mask: usize,
+}
+
+static TRACE_BUFFER: Mutex<Option<TraceBuffer>> = Mutex::new(None);
+// copies of TRACE_BUFFER for trace_event, which takes no lock
+static TRACE_BASE: AtomicUsize = AtomicUsize::new(0);
+static TRACE_MASK: AtomicUsize = AtomicUsize::new(0);
+
+impl LiveUpdatable for Mutex<Option<TraceBuffer>> {
+    fn live_update(&self, src: &Self, _from: &LiveUpdateFrom) {
+        if let Some(buffer) = src.lock().as_ref() {
+            *self.lock() = Some(TraceBuffer {
+                frame: PageFrame::new(buffer.frame.pa(), buffer.page_num),
+                page_num: buffer.page_num,
+                ipa: buffer.ipa,
+                mask: buffer.mask,
+            });
+            TRACE_BASE.store(buffer.frame.pa(), Ordering::Release);
+            TRACE_MASK.store(buffer.mask, Ordering::Relaxed);
+        }
+    }
+
+    fn live_update_reset(&self) {
+        TRACE_MASK.store(0, Ordering::Relaxed);
+        TRACE_BASE.store(0, Ordering::Relaxed);
+        // the pages are kept by the image rolled back from
+        if let Some(buffer) = self.lock().take() {
+            core::mem::forget(buffer.frame);
+        }
+    }
+}
+
+pub static TRACE_BUFFER_UPDATE: LiveUpdateEntry =
+    live_update_entry("TRACE_BUFFER", LiveUpdateStage::Fresh, false, 1, &TRACE_BUFFER);
+
+fn trace_buffer_init(buffer: &mut Option<TraceBuffer>) -> Result<(), ()> {
+    if buffer.is_some() {
+        return Ok(());
+    }
+    let cpu_num = PLAT_DESC.cpu_desc.num;
+    let page_num = 1 + cpu_num * TRACE_RING_PAGES;
+    let frame = match mem_pages_alloc(page_num) {
+        Ok(frame) => frame,
+        Err(_) => {
+            println!("trace_buffer_init: failed to alloc {} pages", page_num);
+            return Err(());
+        }
+    };
+    let base = frame.pa();
+    memset_safe(base as *mut u8, 0, page_num * PAGE_SIZE);
+    unsafe {
+        *(base as *mut TraceInfo) = TraceInfo {
+            magic: TRACE_INFO_MAGIC,
+            version: TRACE_INFO_VERSION,
+            cpu_num: cpu_num as u32,
+            event_size: size_of::<TraceEvent>() as u32,
+            ring_offset: PAGE_SIZE as u64,
+            ring_size: TRACE_RING_SIZE as u64,
+            freq: timer_arch_get_frequency() as u64,
+        };
+        for cpu_id in 0..cpu_num {
+            let ring = &mut *((base + PAGE_SIZE + cpu_id * TRACE_RING_SIZE) as *mut TraceRing);
+            ring.capacity = TRACE_RING_EVENTS as u64;
+            ring.cpu_id = cpu_id as u64;
+        }
+    }
+    *buffer = Some(TraceBuffer {
+        frame,
+        page_num,
+        ipa: 0,
+        mask: 0,
+    });
+    TRACE_BASE.store(base, Ordering::Release);
+    Ok(())
+}
+
+#[inline(always)]
+pub fn trace_enabled(kind: TraceKind) -> bool {
+    TRACE_MASK.load(Ordering::Relaxed) & (1 << kind as usize) != 0
+}
+
+// lock free, an event in an irq taken while writing another takes the next slot
+pub fn trace_event(kind: TraceKind, args: [usize; 4]) {
+    if !trace_enabled(kind) {
+        return;
+    }
+    let base = TRACE_BASE.load(Ordering::Acquire);
+    if base == 0 {
+        return;
+    }
+    let cpu_id = current_cpu().id;
+    let ring_base = base + PAGE_SIZE + cpu_id * TRACE_RING_SIZE;
+    let ring = unsafe { &*(ring_base as *const TraceRing) };
+    let idx = ring.head.fetch_add(1, Ordering::Relaxed) as usize;
+    let event = unsafe {
+        &mut *((ring_base + size_of::<TraceRing>() + (idx % TRACE_RING_EVENTS) * size_of::<TraceEvent>())
+            as *mut TraceEvent)
+    };
+    event.seq.store(0, Ordering::Relaxed);
+    core::sync::atomic::fence(Ordering::Release);
+    event.timestamp = timer_arch_get_counter() as u64;
+    event.kind = kind as u32;
+    event.cpu_id = cpu_id as u32;
+    for (arg, val) in event.args.iter_mut().zip(args.iter()) {
+        *arg = *val as u64;
+    }
+    event.seq.store(idx as u64 + 1, Ordering::Release);
+}
+
+// bit n of mask enables the TraceKind n, returns the mask before
+pub fn trace_set_mask(mask: usize) -> Result<usize, ()> {
+    let mut buffer = TRACE_BUFFER.lock();
+    if mask != 0 {
+        trace_buffer_init(&mut buffer)?;
+    }
+    let prev = TRACE_MASK.swap(mask, Ordering::Relaxed);
+    if let Some(buffer) = buffer.as_mut() {
+        buffer.mask = mask;
+    }
+    info!("trace mask {:#x}, was {:#x}", mask, prev);
+    Ok(prev)
+}
+
+pub fn trace_set_kind(kind: TraceKind, enable: bool) -> Result<usize, ()> {
+    let mask = TRACE_MASK.load(Ordering::Relaxed);
+    let bit = 1 << kind as usize;
+    trace_set_mask(if enable { mask | bit } else { mask & !bit })
+}
+
+// map the buffer into the MVM, returns its ipa
+pub fn trace_map() -> Result<usize, ()> {
+    let mut lock = TRACE_BUFFER.lock();
+    trace_buffer_init(&mut lock)?;
+    let buffer = lock.as_mut().unwrap();
+    if buffer.ipa == 0 {
+        let mvm = active_vm().unwrap();
+        let len = buffer.page_num * PAGE_SIZE;
+        let ipa = mvm.share_mem_base();
+        mvm.pt_map_range(ipa, len, buffer.frame.pa(), PTE_S2_RO, true);
+        mvm.add_share_mem_base(len);
+        buffer.ipa = ipa;
+        info!("trace buffer mapped to MVM ipa {:#x} len {:#x}", ipa, len);
+    }
+    Ok(buffer.ipa)
+}
@@ -15,12 +15,15 @@ use spin::Mutex;
 
 use crate::arch::{
     ContextFrame, ContextFrameTrait, cpu_interrupt_unmask, GIC_INTS_MAX, GIC_SGI_REGS_NUM, GICC, GicContext, GICD,
-    GICH, VmContext, timer_arch_get_counter,
+    GICH, VmContext, timer_arch_get_counter, sysreg_vm_mdcr,
 };
 use crate::board::{Platform, PlatOperation, PLATFORM_VCPU_NUM_MAX};
-use crate::kernel::{current_cpu, interrupt_vm_inject, vm_if_set_state};
-use crate::kernel::{active_vcpu_id, active_vm_id};
-use crate::lib::memcpy_safe;
+use crate::kernel::{
+    cpu_idle, current_cpu, interrupt_vm_inject, timer_enable, vm_health_vcpu_wfi, vm_if_set_state, Scheduler,
+};
+use crate::kernel::migrate_postcopy_missing;
+use crate::kernel::{active_vcpu_id, active_vm_id, VcpuStats, VM_STATS_EC_NUM};
+use crate::lib::{memcpy_safe, time_current_us};
 
 use super::{CpuState, Vm, VmType};
 
@@ -29,6 +32,18 @@ pub enum VcpuState {
     VcpuInv = 0,
     VcpuPend = 1,
     VcpuAct = 2,
+    // waiting in WFI out of the scheduler, see vcpu_wfi
+    VcpuBlk = 3,
+    // out of the scheduler while its VM is paused, see vmm_pause_vm
+    VcpuPause = 4,
+}
+
+// power state of a vcpu seen by the guest, as PSCI AFFINITY_INFO returns
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VcpuPowerState {
+    On = 0,
+    Off = 1,
+    OnPending = 2,
 }
 
 #[derive(Clone)]
@@ -90,13 +105,16 @@ impl Vcpu {
         );
     }
 
-    pub fn migrate_vm_ctx_restore(&self, cache_pa: usize) {
-        let inner = self.inner.lock();
-        memcpy_safe(
-            &(inner.vm_ctx) as *const _ as *const u8,
-            cache_pa as *const u8,
-            size_of::<VmContext>(),
-        );
+    // refill: the vgic lists hold interrupts for the empty list registers of this build
+    pub fn migrate_vm_ctx_restore(&self, ctx: &VmContext, refill: bool) {
+        let mut inner = self.inner.lock();
+        let host = inner.vm_ctx;
+        inner.vm_ctx = *ctx;
+        inner.vm_ctx.keep_host_state(&host);
+        if refill {
+            // NPIE, the maintenance irq refills the list registers as soon as the vcpu runs
+            inner.vm_ctx.gic_state.hcr |= 1 << 3;
+        }
     }
 
     pub fn migrate_vcpu_ctx_restore(&self, cache_pa: usize) {
@@ -127,17 +145,37 @@ impl Vcpu {
     }
 
     pub fn context_gic_irqs_store(&self) {
+        let active = current_cpu().active_vcpu.as_ref().map(|vcpu| vcpu.vm_id()) == Some(self.vm_id());
         let mut inner = self.inner.lock();
         let vm = inner.vm.clone().unwrap();
-        inner.gic_ctx;
+        // the irqs are collected again on every store
+        inner.gic_ctx.clear_irqs();
         for irq in vm.config().passthrough_device_irqs() {
             inner.gic_ctx.add_irq(irq as u64);
         }
         inner.gic_ctx.add_irq(25);
-        let gicv_ctlr = unsafe { &*((Platform::GICV_BASE + 0x8_0000_0000) as *const u32) };
-        inner.gic_ctx.set_gicv_ctlr(*gicv_ctlr);
-        let gicv_pmr = unsafe { &*((Platform::GICV_BASE + 0x8_0000_0000 + 0x4) as *const u32) };
-        inner.gic_ctx.set_gicv_pmr(*gicv_pmr);
+        if !active {
+            // the virtual cpu interface belongs to another vcpu, take the state saved at switch out
+            let gicv_ctlr = inner.vm_ctx.gic_state.gicv_ctlr();
+            let gicv_pmr = inner.vm_ctx.gic_state.gicv_pmr();
+            inner.gic_ctx.set_gicv_ctlr(gicv_ctlr);
+            inner.gic_ctx.set_gicv_pmr(gicv_pmr);
+            return;
+        }
+        #[cfg(not(feature = "gicv3"))]
+        {
+            let gicv_ctlr = unsafe { &*((Platform::GICV_BASE + 0x8_0000_0000) as *const u32) };
+            inner.gic_ctx.set_gicv_ctlr(*gicv_ctlr);
+            let gicv_pmr = unsafe { &*((Platform::GICV_BASE + 0x8_0000_0000 + 0x4) as *const u32) };
+            inner.gic_ctx.set_gicv_pmr(*gicv_pmr);
+        }
+        // GICv3 has no GICV frame, the virtual cpu interface state is kept in ICH_VMCR_EL2
+        #[cfg(feature = "gicv3")]
+        {
+            let vmcr = crate::arch::GICH.vmcr();
+            inner.gic_ctx.set_gicv_ctlr(vmcr);
+            inner.gic_ctx.set_gicv_pmr(vmcr >> 24);
+        }
     }
 
     pub fn context_gic_irqs_restore(&self) {
@@ -151,11 +189,16 @@ impl Vcpu {
             }
         }
 
-        let gicv_pmr = unsafe { &mut *((Platform::GICV_BASE + 0x8_0000_0000 + 0x4) as *mut u32) };
-        *gicv_pmr = inner.gic_ctx.gicv_pmr();
-        // println!("Core[{}] save gic context", current_cpu().id);
-        let gicv_ctlr = unsafe { &mut *((Platform::GICV_BASE + 0x8_0000_0000) as *mut u32) };
-        *gicv_ctlr = inner.gic_ctx.gicv_ctlr();
+        #[cfg(not(feature = "gicv3"))]
+        {
+            let gicv_pmr = unsafe { &mut *((Platform::GICV_BASE + 0x8_0000_0000 + 0x4) as *mut u32) };
+            *gicv_pmr = inner.gic_ctx.gicv_pmr();
+            // println!("Core[{}] save gic context", current_cpu().id);
+            let gicv_ctlr = unsafe { &mut *((Platform::GICV_BASE + 0x8_0000_0000) as *mut u32) };
+            *gicv_ctlr = inner.gic_ctx.gicv_ctlr();
+        }
+        #[cfg(feature = "gicv3")]
+        crate::arch::GICH.set_vmcr((inner.gic_ctx.gicv_ctlr() & 0xff_ffff) | (inner.gic_ctx.gicv_pmr() << 24));
         // show_vcpu_reg_context();
     }
 
@@ -223,7 +266,7 @@ impl Vcpu {
 
     pub fn set_gich_ctlr(&self, ctlr: u32) {
         let mut inner = self.inner.lock();
-        inner.vm_ctx.gic_state.ctlr = ctlr;
+        inner.vm_ctx.gic_state.set_ctlr(ctlr);
     }
 
     pub fn set_hcr(&self, hcr: u64) {
@@ -241,6 +284,55 @@ impl Vcpu {
         inner.state = state;
     }
 
+    pub fn power_state(&self) -> VcpuPowerState {
+        let inner = self.inner.lock();
+        match inner.state {
+            VcpuState::VcpuInv if inner.power_on_pending => VcpuPowerState::OnPending,
+            VcpuState::VcpuInv => VcpuPowerState::Off,
+            _ => VcpuPowerState::On,
+        }
+    }
+
+    // set by PSCI CPU_ON until the target core powers the vcpu on
+    pub fn set_power_on_pending(&self, pending: bool) {
+        let mut inner = self.inner.lock();
+        inner.power_on_pending = pending;
+    }
+
+    // a forced reboot of the VM waits for this vcpu to be scheduled, see vmm_ipi_handler
+    pub fn set_reboot_pending(&self, pending: bool) {
+        let mut inner = self.inner.lock();
+        inner.reboot_pending = pending;
+    }
+
+    pub fn take_reboot_pending(&self) -> bool {
+        let mut inner = self.inner.lock();
+        core::mem::replace(&mut inner.reboot_pending, false)
+    }
+
+    // charge run time owed to migrate_throttle, returns the total owed
+    pub fn throttle_charge(&self, us: usize) -> usize {
+        let mut inner = self.inner.lock();
+        inner.throttle_debt += us;
+        inner.throttle_debt
+    }
+
+    // blocked by vcpu_throttle and the time is not over
+    fn throttled(&self) -> bool {
+        let inner = self.inner.lock();
+        time_current_us() < inner.throttle_until
+    }
+
+    pub fn set_postcopy_page(&self, page: Option<usize>) {
+        let mut inner = self.inner.lock();
+        inner.postcopy_page = page;
+    }
+
+    pub fn postcopy_page(&self) -> Option<usize> {
+        let inner = self.inner.lock();
+        inner.postcopy_page
+    }
+
     pub fn id(&self) -> usize {
         let inner = self.inner.lock();
         inner.id
@@ -256,6 +348,44 @@ impl Vcpu {
         inner.phys_id
     }
 
+    pub fn stats(&self) -> VcpuStats {
+        let inner = self.inner.lock();
+        inner.stats
+    }
+
+    pub fn stats_exit(&self, ec: usize) {
+        let mut inner = self.inner.lock();
+        inner.stats.exits[ec % VM_STATS_EC_NUM] += 1;
+    }
+
+    pub fn stats_irq_inject(&self) {
+        let mut inner = self.inner.lock();
+        inner.stats.irq_injects += 1;
+    }
+
+    pub fn stats_ipi(&self) {
+        let mut inner = self.inner.lock();
+        inner.stats.ipis += 1;
+    }
+
+    pub fn stats_deadline_miss(&self) {
+        let mut inner = self.inner.lock();
+        inner.stats.deadline_miss += 1;
+    }
+
+    pub fn stats_sched_in(&self, now: usize) {
+        let mut inner = self.inner.lock();
+        inner.stats.sched_in_us = now;
+    }
+
+    pub fn stats_sched_out(&self, now: usize) {
+        let mut inner = self.inner.lock();
+        if inner.stats.sched_in_us != 0 {
+            inner.stats.run_time_us += now - inner.stats.sched_in_us;
+            inner.stats.sched_in_us = 0;
+        }
+    }
+
     pub fn vm_id(&self) -> usize {
         self.vm().unwrap().id()
     }
@@ -274,6 +404,12 @@ impl Vcpu {
         inner.reset_vmpidr();
     }
 
+    // the virtual counter of the vcpu falls behind by delta ticks
+    pub fn add_vtimer_offset(&self, delta: usize) {
+        let mut inner = self.inner.lock();
+        inner.vm_ctx.cntvoff_el2 += delta as u64;
+    }
+
     pub fn reset_vtimer_offset(&self) {
         let mut inner = self.inner.lock();
         inner.reset_vtimer_offset();
@@ -309,6 +445,26 @@ impl Vcpu {
         inner.show_ctx();
     }
 
+    // a vcpu blocked in WFI has something to do
+    fn wfi_wakeup_ready(&self) -> bool {
+        {
+            let inner = self.inner.lock();
+            if !inner.int_list.is_empty() || inner.vm_ctx.vtimer_fired() {
+                return true;
+            }
+        }
+        match self.vm() {
+            Some(vm) if vm.has_vgic() => vm.vgic().vcpu_has_pending(self.clone()),
+            _ => false,
+        }
+    }
+
+    // an interrupt pending in the list registers saved at the last switch out
+    pub fn gic_lr_pending(&self) -> bool {
+        let inner = self.inner.lock();
+        inner.vm_ctx.gic_state.lr_pending()
+    }
+
     pub fn push_int(&self, int: usize) {
         let mut inner = self.inner.lock();
         if !inner.int_list.contains(&int) {
@@ -337,11 +493,19 @@ pub struct VcpuInner {
     pub id: usize,
     pub phys_id: usize,
     pub state: VcpuState,
+    pub power_on_pending: bool,
+    pub reboot_pending: bool,
+    // see vcpu_throttle, in us
+    pub throttle_debt: usize,
+    pub throttle_until: usize,
+    // the post-copy page the vcpu is blocked on, see vcpu_postcopy_wait
+    pub postcopy_page: Option<usize>,
     pub vm: Option<Vm>,
     pub int_list: Vec<usize>,
     pub vcpu_ctx: ContextFrame,
     pub vm_ctx: VmContext,
     pub gic_ctx: GicContext,
+    pub stats: VcpuStats,
 }
 
 impl VcpuInner {
@@ -350,11 +514,17 @@ impl VcpuInner {
             id: 0,
             phys_id: 0,
             state: VcpuState::VcpuInv,
+            power_on_pending: false,
+            reboot_pending: false,
+            throttle_debt: 0,
+            throttle_until: 0,
+            postcopy_page: None,
             vm: None,
             int_list: vec![],
             vcpu_ctx: ContextFrame::default(),
             vm_ctx: VmContext::default(),
             gic_ctx: GicContext::default(),
+            stats: VcpuStats::default(),
         }
     }
 
@@ -373,9 +543,10 @@ impl VcpuInner {
         self.vm_ctx.cntvoff_el2 = 0;
         self.vm_ctx.sctlr_el1 = 0x30C50830;
         self.vm_ctx.cntkctl_el1 = 0;
-        self.vm_ctx.pmcr_el0 = 0;
+        self.vm_ctx.pmu.reset();
         self.vm_ctx.vtcr_el2 = 0x8001355c;
         // }
+        self.vm_ctx.mdcr_el2 = sysreg_vm_mdcr();
         let mut vmpidr = 0;
         vmpidr |= 1 << 31;
 
@@ -455,7 +626,7 @@ impl VcpuInner {
             self.vm_ctx.cntvoff_el2,
             self.vm_ctx.sctlr_el1,
             self.vm_ctx.cntkctl_el1,
-            self.vm_ctx.pmcr_el0,
+            self.vm_ctx.pmu.pmcr_el0,
             self.vm_ctx.vtcr_el2,
             self.vcpu_ctx.gpr(0)
         );
@@ -494,6 +665,140 @@ pub fn vcpu_idle(_vcpu: Vcpu) -> ! {
     }
 }
 
+fn vcpu_others_runnable(vcpu: &Vcpu) -> bool {
+    current_cpu().vcpu_array.iter().any(|x| match x {
+        Some(other) if other.vm_id() != vcpu.vm_id() => {
+            matches!(other.state(), VcpuState::VcpuPend | VcpuState::VcpuAct)
+        }
+        _ => false,
+    })
+}
+
+/*
+ * A vcpu trapped on WFI, its pc already points to the next instruction.
+ * If other vcpus can run on this core, it leaves the scheduler until an interrupt is injected to
+ * it or its virtual timer fires, otherwise the core waits for the next physical interrupt itself.
+ */
+pub fn vcpu_wfi(vcpu: Vcpu) {
+    let vm = vcpu.vm().unwrap();
+    if vm.vgic().vcpu_has_pending(vcpu.clone()) {
+        return;
+    }
+    // idle for the health monitor until an interrupt is injected to it, either way it waits
+    vm_health_vcpu_wfi(vm.id(), vcpu.id(), true);
+    if !vcpu_others_runnable(&vcpu) {
+        cortex_a::asm::wfi();
+        return;
+    }
+    // the scheduler does not save the context of a sleeping vcpu
+    vcpu.context_vm_store();
+    vcpu.set_state(VcpuState::VcpuBlk);
+    // the timer keeps running for blocked vcpus, see vcpu_wfi_check
+    current_cpu().scheduler().sleep(vcpu);
+}
+
+// WFE is a hint of spinning, give the core to the others
+pub fn vcpu_wfe(vcpu: Vcpu) {
+    if vcpu_others_runnable(&vcpu) {
+        current_cpu().scheduler().do_schedule();
+    }
+}
+
+/*
+ * Hold the running vcpu back for us microseconds. It leaves the scheduler like a vcpu in WFI, but
+ * only the timer wakes it up, once the time is over, see vcpu_wfi_check.
+ */
+pub fn vcpu_throttle(vcpu: Vcpu, us: usize) {
+    {
+        let mut inner = vcpu.inner.lock();
+        inner.throttle_debt = 0;
+        inner.throttle_until = time_current_us() + us;
+    }
+    // the scheduler does not save the context of a sleeping vcpu
+    vcpu.context_vm_store();
+    vcpu.set_state(VcpuState::VcpuBlk);
+    current_cpu().scheduler().sleep(vcpu);
+    timer_enable(true);
+    if current_cpu().active_vcpu.is_none() {
+        cpu_idle();
+    }
+}
+
+/*
+ * Block the running vcpu until the post-copy page it faulted on is filled, the caller has set the
+ * page by set_postcopy_page. migrate_postcopy_fill wakes it up by vcpu_postcopy_wakeup, the timer
+ * checks the page too in case the wake up ipi is lost.
+ */
+pub fn vcpu_postcopy_wait(vcpu: Vcpu) {
+    // the scheduler does not save the context of a sleeping vcpu
+    vcpu.context_vm_store();
+    vcpu.set_state(VcpuState::VcpuBlk);
+    current_cpu().scheduler().sleep(vcpu);
+    timer_enable(true);
+    if current_cpu().active_vcpu.is_none() {
+        cpu_idle();
+    }
+}
+
+// wake up the vcpu of the VM on this core if its post-copy page has been filled
+pub fn vcpu_postcopy_wakeup(vm_id: usize) {
+    if let Some(vcpu) = current_cpu().vcpu_array.pop_vcpu_through_vmid(vm_id) {
+        if let (VcpuState::VcpuBlk, Some(page)) = (vcpu.state(), vcpu.postcopy_page()) {
+            if !migrate_postcopy_missing(vm_id, page) {
+                vcpu.set_postcopy_page(None);
+                current_cpu().scheduler().wakeup(vcpu);
+            }
+        }
+    }
+}
+
+pub fn vcpu_wfi_wakeup(vcpu: Vcpu) {
+    if let VcpuState::VcpuBlk = vcpu.state() {
+        // an interrupt for a throttled vcpu or one waiting for a page is taken once it is woken up
+        if vcpu.throttled() || vcpu.postcopy_page().is_some() {
+            return;
+        }
+        current_cpu().scheduler().wakeup(vcpu);
+    }
+}
+
+pub fn vcpu_blocked_on_core() -> bool {
+    current_cpu()
+        .vcpu_array
+        .iter()
+        .flatten()
+        .any(|vcpu| matches!(vcpu.state(), VcpuState::VcpuBlk))
+}
+
+/*
+ * Called by the timer, wake up the blocked vcpus with a pending interrupt or a fired virtual timer,
+ * the throttled vcpus whose time is over and the vcpus whose post-copy page has been filled.
+ */
+pub fn vcpu_wfi_check() {
+    let mut wakeup_list = Vec::new();
+    for vcpu in current_cpu().vcpu_array.iter().flatten() {
+        if let VcpuState::VcpuBlk = vcpu.state() {
+            let throttle_until = vcpu.inner.lock().throttle_until;
+            if let Some(page) = vcpu.postcopy_page() {
+                if !migrate_postcopy_missing(vcpu.vm_id(), page) {
+                    vcpu.set_postcopy_page(None);
+                    wakeup_list.push(vcpu.clone());
+                }
+            } else if throttle_until != 0 {
+                if time_current_us() >= throttle_until {
+                    vcpu.inner.lock().throttle_until = 0;
+                    wakeup_list.push(vcpu.clone());
+                }
+            } else if vcpu.wfi_wakeup_ready() {
+                wakeup_list.push(vcpu.clone());
+            }
+        }
+    }
+    for vcpu in wakeup_list {
+        vcpu_wfi_wakeup(vcpu);
+    }
+}
+
 // WARNING: No Auto `drop` in this function
 pub fn vcpu_run(announce: bool) -> ! {
     {
@@ -607,6 +912,12 @@ pub fn show_vcpu_reg_context() {
     }
 
     println!("GICH_MISR {:x}", GICH.misr());
+    #[cfg(not(feature = "gicv3"))]
+    show_gicv_regs();
+}
+
+#[cfg(not(feature = "gicv3"))]
+fn show_gicv_regs() {
     println!("GICV_CTLR {:x}", unsafe {
         *((Platform::GICV_BASE + 0x8_0000_0000) as *const u32)
     });
@@ -10,7 +10,8 @@
 
 use alloc::slice::{Iter, IterMut};
 use crate::board::{PLAT_DESC, SchedRule};
-use crate::kernel::{current_cpu, SchedType, SchedulerRR, Vcpu, VM_NUM_MAX, interrupt_cpu_enable};
+use crate::kernel::{current_cpu, SchedType, SchedulerRR, SchedulerRT, SchedulerTP, Vcpu, VM_NUM_MAX, interrupt_cpu_enable};
+use crate::kernel::INTERRUPT_IRQ_GUEST_PMU;
 
 pub struct VcpuArray {
     array: [Option<Vcpu>; VM_NUM_MAX],
@@ -71,6 +72,7 @@ impl VcpuArray {
                 if self.len == 0 {
                     // hard code: remove el1 timer interrupt 27
                     interrupt_cpu_enable(27, false);
+                    interrupt_cpu_enable(INTERRUPT_IRQ_GUEST_PMU, false);
                 }
                 Some(vcpu)
             }
@@ -98,6 +100,14 @@ pub fn cpu_sched_init() {
             info!("cpu[{}] init Round Robin Scheduler", current_cpu().id);
             current_cpu().sched = SchedType::SchedRR(SchedulerRR::new(1));
         }
+        SchedRule::RealTime => {
+            info!("cpu[{}] init Real Time Scheduler", current_cpu().id);
+            current_cpu().sched = SchedType::SchedRT(SchedulerRT::new());
+        }
+        SchedRule::TimePartition => {
+            info!("cpu[{}] init Time Partitioning Scheduler", current_cpu().id);
+            current_cpu().sched = SchedType::SchedTP(SchedulerTP::new());
+        }
         _ => {
             todo!();
         }
@@ -17,7 +17,7 @@ use spin::Mutex;
 
 use crate::arch::{PAGE_SIZE, PTE_S2_FIELD_AP_RO, PTE_S2_NORMAL, PTE_S2_RO};
 use crate::arch::{GICC_CTLR_EN_BIT, GICC_CTLR_EOIMODENS_BIT};
-use crate::arch::PageTable;
+use crate::arch::{tlb_invalidate_vm_all, PageTable};
 use crate::arch::Vgic;
 use crate::board::{Platform, PlatOperation};
 use crate::config::VmConfigEntry;
@@ -25,13 +25,19 @@ use crate::device::EmuDevs;
 use crate::kernel::{
     EmuDevData, get_share_mem, mem_pages_alloc, VirtioMmioData, VM_CONTEXT_RECEIVE, VM_CONTEXT_SEND, VMData,
 };
+use crate::kernel::{migrate_compress_mode, migrate_stream_decode, migrate_stream_encode, migrate_stream_size, VmStats};
 use crate::lib::*;
 use crate::mm::PageFrame;
 
 use super::vcpu::Vcpu;
 
-pub const DIRTY_MEM_THRESHOLD: usize = 0x2000;
 pub const VM_NUM_MAX: usize = 8;
+// HCR_EL2.TWI [13] and TWE [14]: trap WFI and WFE to EL2
+const HCR_EL2_TWI: u64 = 1 << 13;
+const HCR_EL2_TWE: u64 = 1 << 14;
+// HCR_EL2.TID3 [18]: trap ID registers, TSW [22]: trap set/way cache maintenance, see sysreg_emu
+const HCR_EL2_TID3: u64 = 1 << 18;
+const HCR_EL2_TSW: u64 = 1 << 22;
 pub static VM_IF_LIST: [Mutex<VmInterface>; VM_NUM_MAX] = [const { Mutex::new(VmInterface::default()) }; VM_NUM_MAX];
 
 pub fn vm_if_reset(vm_id: usize) {
@@ -49,6 +55,17 @@ pub fn vm_if_get_state(vm_id: usize) -> VmState {
     vm_if.state
 }
 
+// the latest physical counter at which a vcpu of the paused VM stopped
+pub fn vm_if_update_pause_counter(vm_id: usize, counter: usize) {
+    let mut vm_if = VM_IF_LIST[vm_id].lock();
+    vm_if.pause_counter = usize::max(vm_if.pause_counter, counter);
+}
+
+pub fn vm_if_take_pause_counter(vm_id: usize) -> usize {
+    let mut vm_if = VM_IF_LIST[vm_id].lock();
+    core::mem::replace(&mut vm_if.pause_counter, 0)
+}
+
 pub fn vm_if_set_type(vm_id: usize, vm_type: VmType) {
     let mut vm_if = VM_IF_LIST[vm_id].lock();
     vm_if.vm_type = vm_type;
@@ -120,6 +137,11 @@ pub fn vm_if_mem_map_cache(vm_id: usize) -> Option<Arc<PageFrame>> {
     vm_if.mem_map_cache.clone()
 }
 
+pub fn vm_if_clear_mem_map_cache(vm_id: usize) {
+    let mut vm_if = VM_IF_LIST[vm_id].lock();
+    vm_if.mem_map_cache = None;
+}
+
 pub fn vm_if_dirty_mem_map(vm_id: usize) {
     let mut vm_if = VM_IF_LIST[vm_id].lock();
     vm_if.mem_map.as_mut().unwrap().init_dirty();
@@ -193,6 +215,7 @@ pub enum VmState {
     VmInv = 0,
     VmPending = 1,
     VmActive = 2,
+    VmPaused = 3,
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -220,6 +243,7 @@ pub struct VmInterface {
     pub ivc_arg_ptr: usize,
     pub mem_map: Option<FlexBitmap>,
     pub mem_map_cache: Option<Arc<PageFrame>>,
+    pub pause_counter: usize,
 }
 
 impl VmInterface {
@@ -233,6 +257,7 @@ impl VmInterface {
             ivc_arg_ptr: 0,
             mem_map: None,
             mem_map_cache: None,
+            pause_counter: 0,
         }
     }
 
@@ -245,6 +270,7 @@ impl VmInterface {
         self.ivc_arg_ptr = 0;
         self.mem_map = None;
         self.mem_map_cache = None;
+        self.pause_counter = 0;
     }
 }
 
@@ -299,10 +325,11 @@ impl Vm {
             );
             if !emu {
                 vcpu.set_gich_ctlr((GICC_CTLR_EN_BIT) as u32);
-                vcpu.set_hcr(0x80080001); // HCR_EL2_GIC_PASSTHROUGH_VAL
+                vcpu.set_hcr(0x80080001 | HCR_EL2_TID3 | HCR_EL2_TSW); // HCR_EL2_GIC_PASSTHROUGH_VAL
             } else {
                 vcpu.set_gich_ctlr((GICC_CTLR_EN_BIT | GICC_CTLR_EOIMODENS_BIT) as u32);
-                vcpu.set_hcr(0x80080019);
+                // trap WFI and WFE, an idle vcpu gives up the core, see vcpu_wfi
+                vcpu.set_hcr(0x80080019 | HCR_EL2_TID3 | HCR_EL2_TSW | HCR_EL2_TWI | HCR_EL2_TWE);
             }
         }
     }
@@ -505,6 +532,34 @@ impl Vm {
         }
     }
 
+    pub fn pt_range_access_permission(&self, ipa: usize, len: usize, ap: usize) {
+        let vm_inner = self.inner.lock();
+        match &vm_inner.pt {
+            Some(pt) => {
+                pt.access_permission(ipa, len, ap);
+            }
+            None => {
+                panic!("pt_range_access_permission: vm{} pt is empty", vm_inner.id);
+            }
+        }
+    }
+
+    // split the 2MB block mapping ipa into 4KB pages, returns false if ipa is not mapped by a block
+    pub fn pt_split_2mb(&self, ipa: usize) -> bool {
+        let vm_inner = self.inner.lock();
+        match &vm_inner.pt {
+            Some(pt) => pt.split_2mb(ipa, (vm_inner.id << 48) | pt.base_pa()),
+            None => {
+                panic!("pt_split_2mb: vm{} pt is empty", vm_inner.id);
+            }
+        }
+    }
+
+    // invalidate the stale TLB entries of the VM, from any core
+    pub fn tlb_invalidate(&self) {
+        tlb_invalidate_vm_all((self.id() << 48) | self.pt_dir());
+    }
+
     pub fn pt_read_only(&self) {
         let vm_inner = self.inner.lock();
         match vm_inner.pt.clone() {
@@ -545,6 +600,35 @@ impl Vm {
         vm_inner.cpu_num
     }
 
+    pub fn stats(&self) -> VmStats {
+        let vm_inner = self.inner.lock();
+        vm_inner.stats.clone()
+    }
+
+    pub fn stats_mmio(&self, dev_id: usize) {
+        let mut vm_inner = self.inner.lock();
+        vm_inner.stats.dev(dev_id).mmio += 1;
+    }
+
+    pub fn stats_virtio_req(&self, dev_id: usize, bytes: usize) {
+        let mut vm_inner = self.inner.lock();
+        let dev = vm_inner.stats.dev(dev_id);
+        dev.requests += 1;
+        dev.bytes += bytes;
+    }
+
+    pub fn stats_dirty_pages(&self, pages: usize) {
+        let mut vm_inner = self.inner.lock();
+        vm_inner.stats.dirty_pages += pages;
+    }
+
+    // returns the overruns so far
+    pub fn stats_window_overrun(&self) -> usize {
+        let mut vm_inner = self.inner.lock();
+        vm_inner.stats.window_overrun += 1;
+        vm_inner.stats.window_overrun
+    }
+
     pub fn id(&self) -> usize {
         let vm_inner = self.inner.lock();
         vm_inner.id
@@ -618,6 +702,16 @@ impl Vm {
         }
     }
 
+    pub fn has_virtio(&self) -> bool {
+        let vm_inner = self.inner.lock();
+        vm_inner.emu_devs.iter().any(|emu| {
+            matches!(
+                emu,
+                EmuDevs::VirtioBlk(_) | EmuDevs::VirtioNet(_) | EmuDevs::VirtioConsole(_)
+            )
+        })
+    }
+
     // TODO: should copy from or copy to addr, not copy from other vm
     pub fn migrate_emu_devs(&self, src_vm: Vm) {
         let mut vm_inner = self.inner.lock();
@@ -754,17 +848,11 @@ impl Vm {
     pub fn context_vm_migrate_init(&self) {
         let mvm = vm(0).unwrap();
         // for i in 0..self.ncpu() {
-        let size = size_of::<VMData>();
+        let size = migrate_stream_size();
         // println!("context_vm_migrate_init: VM Data size 0x{:x}", size);
-        match mem_pages_alloc(round_up(size, PAGE_SIZE) / PAGE_SIZE) {
+        match mem_pages_alloc(size / PAGE_SIZE) {
             Ok(pf) => {
-                mvm.pt_map_range(
-                    get_share_mem(VM_CONTEXT_RECEIVE),
-                    round_up(size, PAGE_SIZE),
-                    pf.pa(),
-                    PTE_S2_NORMAL,
-                    true,
-                );
+                mvm.pt_map_range(get_share_mem(VM_CONTEXT_RECEIVE), size, pf.pa(), PTE_S2_NORMAL, true);
                 let mut inner = self.inner.lock();
                 inner.migrate_restore_pf.push(pf);
             }
@@ -780,10 +868,9 @@ impl Vm {
         let size = size_of::<VMData>();
         match mem_pages_alloc(round_up(size, PAGE_SIZE) / PAGE_SIZE) {
             Ok(pf) => {
-                let mut vm_data = unsafe { &mut *(pf.pa as *mut VMData) };
-                let base = get_share_mem(VM_CONTEXT_SEND);
-                // println!("pt map base 0x{:x} size 0x{:x}", base, size);
-                mvm.pt_map_range(base, round_up(size, PAGE_SIZE), pf.pa(), PTE_S2_RO, true);
+                let vm_data = unsafe { &mut *(pf.pa as *mut VMData) };
+                vm_data.reset();
+                vm_data.compress = migrate_compress_mode(self.id());
 
                 // key: pcpuid, val: vcpuid
                 let mut cpuid_map: BTreeMap<usize, usize> = BTreeMap::new();
@@ -828,21 +915,59 @@ impl Vm {
                         EmuDevs::None => {}
                     }
                 }
-                inner.migrate_save_pf.push(pf);
+                drop(inner);
+                for vcpu_id in 0..self.cpu_num() {
+                    vm_data.vgic_ctx.fold_lrs(vcpu_id, &vm_data.vm_ctx[vcpu_id].gic_state);
+                }
+
+                // the MVM reads the encoded stream, VMData is only kept until then
+                let stream_size = migrate_stream_size();
+                match mem_pages_alloc(stream_size / PAGE_SIZE) {
+                    Ok(stream_pf) => {
+                        if migrate_stream_encode(vm_data, self.cpu_num(), stream_pf.pa(), stream_size).is_err() {
+                            println!(
+                                "context_vm_migrate_save: VM[{}] failed to encode migrate stream",
+                                self.id()
+                            );
+                        }
+                        let base = get_share_mem(VM_CONTEXT_SEND);
+                        mvm.pt_map_range(base, stream_size, stream_pf.pa(), PTE_S2_RO, true);
+                        self.inner.lock().migrate_save_pf.push(stream_pf);
+                    }
+                    Err(_) => {
+                        println!("context_vm_migrate_save: mem_pages_alloc for migrate stream failed");
+                    }
+                }
             }
             Err(_) => {}
         }
     }
 
-    pub fn context_vm_migrate_restore(&self) {
+    pub fn context_vm_migrate_restore(&self) -> Result<(), ()> {
         // key: vcpuid, val: pcpuid
         let mut vcpuid_map: BTreeMap<usize, usize> = BTreeMap::new();
         for vcpu_id in 0..self.cpu_num() {
             vcpuid_map.insert(vcpu_id, self.vcpuid_to_pcpuid(vcpu_id).unwrap());
         }
+        let data_pf = match mem_pages_alloc(round_up(size_of::<VMData>(), PAGE_SIZE) / PAGE_SIZE) {
+            Ok(pf) => pf,
+            Err(_) => {
+                println!("context_vm_migrate_restore: mem_pages_alloc for vm context failed");
+                return Err(());
+            }
+        };
+        let vm_data = unsafe { &mut *(data_pf.pa() as *mut VMData) };
+        vm_data.reset();
+        let stream_pa = self.inner.lock().migrate_restore_pf[0].pa();
+        if migrate_stream_decode(self, stream_pa, migrate_stream_size(), vm_data).is_err() {
+            println!(
+                "context_vm_migrate_restore: VM[{}] migrate stream is rejected",
+                self.id()
+            );
+            return Err(());
+        }
+
         let inner = self.inner.lock();
-        let pa = inner.migrate_restore_pf[0].pa();
-        let vm_data = unsafe { &mut *(pa as *mut VMData) };
         // migrate emu dev
         for (idx, emu) in inner.emu_devs.iter().enumerate() {
             match emu {
@@ -872,11 +997,16 @@ impl Vm {
         drop(inner);
         for vcpu_id in 0..self.cpu_num() {
             let vcpu = self.vcpu(vcpu_id).unwrap();
-            vcpu.migrate_vm_ctx_restore(&vm_data.vm_ctx[vcpu_id] as *const _ as usize);
+            let refill = match vm_data.vgic_ctx.cpu_priv.get(vcpu_id) {
+                Some(cpu) => cpu.pend_num + cpu.act_num != 0,
+                None => false,
+            };
+            vcpu.migrate_vm_ctx_restore(&vm_data.vm_ctx[vcpu_id], refill);
             vcpu.migrate_vcpu_ctx_restore(&vm_data.vcpu_ctx[vcpu_id] as *const _ as usize);
             // cpu gic context
             vcpu.migrate_gic_ctx_restore(&(vm_data.gic_ctx[vcpu_id]) as *const _ as usize);
         }
+        Ok(())
     }
 
     pub fn share_mem_base(&self) -> usize {
@@ -927,6 +1057,8 @@ pub struct VmInner {
     pub emu_devs: Vec<EmuDevs>,
     pub med_blk_id: Option<usize>,
     pub priority: usize,
+
+    pub stats: VmStats,
 }
 
 impl VmInner {
@@ -957,6 +1089,7 @@ impl VmInner {
             emu_devs: Vec::new(),
             med_blk_id: None,
             priority: 0,
+            stats: VmStats::default(),
         }
     }
 
@@ -986,6 +1119,7 @@ impl VmInner {
             emu_devs: Vec::new(),
             med_blk_id: None,
             priority: 0,
+            stats: VmStats::default(),
         }
     }
 }
@@ -0,0 +1,203 @@
+// Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+// Rust-Shyper is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//          http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+// EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+// MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::mem::size_of;
+
+use crate::board::PLATFORM_VCPU_NUM_MAX;
+use crate::device::EMU_DEV_NUM_MAX;
+use crate::kernel::{active_vm, vm, vm_ipa2pa};
+use crate::lib::{memcpy_safe, time_current_us};
+
+// bump it when HvcVmStats changes
+pub const VM_STATS_VERSION: usize = 1;
+// exception classes of ESR_EL2
+pub const VM_STATS_EC_NUM: usize = 64;
+
+// the cost of a vcpu to the hypervisor, in VcpuInner
+#[derive(Copy, Clone)]
+pub struct VcpuStats {
+    // by exception class
+    pub exits: [usize; VM_STATS_EC_NUM],
+    pub irq_injects: usize,
+    // SGIs sent by the vcpu
+    pub ipis: usize,
+    pub run_time_us: usize,
+    // when the vcpu is scheduled in, 0 if it is not running
+    pub sched_in_us: usize,
+    // periods ended with budget left, see SchedulerRT
+    pub deadline_miss: usize,
+}
+
+impl VcpuStats {
+    pub const fn default() -> VcpuStats {
+        VcpuStats {
+            exits: [0; VM_STATS_EC_NUM],
+            irq_injects: 0,
+            ipis: 0,
+            run_time_us: 0,
+            sched_in_us: 0,
+            deadline_miss: 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct EmuDevStats {
+    pub mmio: usize,
+    // virtio requests, and the bytes of their buffers
+    pub requests: usize,
+    pub bytes: usize,
+}
+
+// the cost of a VM to the hypervisor, in VmInner
+#[derive(Clone)]
+pub struct VmStats {
+    // by the id of the emulated device
+    pub devs: BTreeMap<usize, EmuDevStats>,
+    // pages found dirty by the rounds of migrations
+    pub dirty_pages: usize,
+    // windows of the VM it overran, see SchedulerTP
+    pub window_overrun: usize,
+}
+
+impl VmStats {
+    pub const fn default() -> VmStats {
+        VmStats {
+            devs: BTreeMap::new(),
+            dirty_pages: 0,
+            window_overrun: 0,
+        }
+    }
+
+    pub fn dev(&mut self, dev_id: usize) -> &mut EmuDevStats {
+        self.devs.entry(dev_id).or_default()
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct HvcVcpuStats {
+    pub id: usize,
+    pub phys_id: usize,
+    pub exits: [usize; VM_STATS_EC_NUM],
+    pub irq_injects: usize,
+    pub ipis: usize,
+    pub run_time_us: usize,
+    pub deadline_miss: usize,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct HvcEmuDevStats {
+    pub id: usize,
+    pub mmio: usize,
+    pub requests: usize,
+    pub bytes: usize,
+}
+
+/*
+ * Stats of a VM copied to the MVM by HVC_VMM_VM_STATS. Only the first vcpu_num vcpus and dev_num
+ * devices are valid, size is the size of the struct, for the agent to check against its own.
+ */
+#[repr(C)]
+pub struct HvcVmStats {
+    pub version: usize,
+    pub size: usize,
+    pub vm_id: usize,
+    pub dirty_pages: usize,
+    pub window_overrun: usize,
+    pub vcpu_num: usize,
+    pub dev_num: usize,
+    pub vcpus: [HvcVcpuStats; PLATFORM_VCPU_NUM_MAX],
+    pub devs: [HvcEmuDevStats; EMU_DEV_NUM_MAX],
+}
+
+// copy the stats of the VM to stats_ipa of the MVM, returns the size copied
+pub fn vm_stats_get(vm_id: usize, stats_ipa: usize) -> Result<usize, ()> {
+    let vm = match vm(vm_id) {
+        Some(vm) => vm,
+        None => {
+            println!("vm_stats_get: VM[{}] not exist", vm_id);
+            return Err(());
+        }
+    };
+    // the struct takes more than a page, it has to lie in one memory region of the MVM
+    let mvm = active_vm().unwrap();
+    let stats_pa = vm_ipa2pa(mvm.clone(), stats_ipa);
+    let last = size_of::<HvcVmStats>() - 1;
+    if stats_pa == 0 || vm_ipa2pa(mvm, stats_ipa + last) != stats_pa + last {
+        println!("vm_stats_get: illegal ipa {:x}", stats_ipa);
+        return Err(());
+    }
+
+    let mut stats = Box::new(HvcVmStats {
+        version: VM_STATS_VERSION,
+        size: size_of::<HvcVmStats>(),
+        vm_id,
+        dirty_pages: 0,
+        window_overrun: 0,
+        vcpu_num: 0,
+        dev_num: 0,
+        vcpus: [HvcVcpuStats {
+            id: 0,
+            phys_id: 0,
+            exits: [0; VM_STATS_EC_NUM],
+            irq_injects: 0,
+            ipis: 0,
+            run_time_us: 0,
+            deadline_miss: 0,
+        }; PLATFORM_VCPU_NUM_MAX],
+        devs: [HvcEmuDevStats {
+            id: 0,
+            mmio: 0,
+            requests: 0,
+            bytes: 0,
+        }; EMU_DEV_NUM_MAX],
+    });
+    let now = time_current_us();
+    for vcpu_id in 0..usize::min(vm.cpu_num(), PLATFORM_VCPU_NUM_MAX) {
+        let vcpu = vm.vcpu(vcpu_id).unwrap();
+        let vcpu_stats = vcpu.stats();
+        let running = match vcpu_stats.sched_in_us {
+            0 => 0,
+            sched_in => now - sched_in,
+        };
+        stats.vcpus[vcpu_id] = HvcVcpuStats {
+            id: vcpu_id,
+            phys_id: vcpu.phys_id(),
+            exits: vcpu_stats.exits,
+            irq_injects: vcpu_stats.irq_injects,
+            ipis: vcpu_stats.ipis,
+            run_time_us: vcpu_stats.run_time_us + running,
+            deadline_miss: vcpu_stats.deadline_miss,
+        };
+        stats.vcpu_num += 1;
+    }
+    let vm_stats = vm.stats();
+    stats.dirty_pages = vm_stats.dirty_pages;
+    stats.window_overrun = vm_stats.window_overrun;
+    for (dev_id, dev) in vm_stats.devs.iter().take(EMU_DEV_NUM_MAX) {
+        stats.devs[stats.dev_num] = HvcEmuDevStats {
+            id: *dev_id,
+            mmio: dev.mmio,
+            requests: dev.requests,
+            bytes: dev.bytes,
+        };
+        stats.dev_num += 1;
+    }
+    memcpy_safe(
+        stats_pa as *const u8,
+        stats.as_ref() as *const _ as *const u8,
+        size_of::<HvcVmStats>(),
+    );
+    Ok(size_of::<HvcVmStats>())
+}
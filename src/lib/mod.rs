@@ -10,16 +10,20 @@
 
 pub use self::barrier::*;
 pub use self::bitmap::*;
+pub use self::crc::*;
 // pub use self::fatfs::*;
 pub use self::print::*;
+pub use self::sha256::*;
 pub use self::string::*;
 pub use self::time::*;
 pub use self::util::*;
 
 mod barrier;
 mod bitmap;
+mod crc;
 // mod fatfs;
 mod print;
+mod sha256;
 mod string;
 mod time;
 pub mod unilib;
@@ -13,7 +13,7 @@ use alloc::vec::Vec;
 use crate::arch::{
     emu_intc_handler, emu_intc_init, emu_smmu_handler, partial_passthrough_intc_handler, partial_passthrough_intc_init,
 };
-use crate::arch::{PTE_S2_DEVICE, PTE_S2_NORMAL};
+use crate::arch::{PTE_S2_DEVICE, PTE_S2_NORMAL, vpmu_enabled};
 use crate::arch::PAGE_SIZE;
 use crate::board::*;
 use crate::config::vm_cfg_entry;
@@ -27,7 +27,7 @@ use crate::kernel::{
 use crate::kernel::{mem_page_alloc, mem_vm_region_alloc};
 use crate::kernel::{vm, Vm};
 use crate::kernel::{active_vcpu_id, vcpu_run};
-use crate::kernel::interrupt_vm_register;
+use crate::kernel::{interrupt_vm_register, INTERRUPT_IRQ_GUEST_PMU};
 use crate::kernel::VM_NUM_MAX;
 use crate::lib::trace;
 
@@ -220,6 +220,17 @@ fn vmm_init_emulated_device(vm: Vm) -> bool {
                 );
                 emu_intc_init(vm.clone(), idx);
             }
+            #[cfg(feature = "gicv3")]
+            EmuDeviceTGicr => {
+                emu_register_dev(
+                    EmuDeviceTGicr,
+                    vm.id(),
+                    idx,
+                    emu_dev.base_ipa,
+                    emu_dev.length,
+                    crate::arch::emu_vgicr_handler,
+                );
+            }
             EmuDeviceTGPPT => {
                 vm.set_intc_dev_id(idx);
                 emu_register_dev(
@@ -333,6 +344,10 @@ fn vmm_init_passthrough_device(vm: Vm) -> bool {
             return false;
         }
     }
+    // the overflow PPI of the vPMU is linked to the physical one, like the timer
+    if vpmu_enabled(&vm.config()) && !interrupt_vm_register(vm.clone(), INTERRUPT_IRQ_GUEST_PMU) {
+        return false;
+    }
     true
 }
 
@@ -379,7 +394,8 @@ pub unsafe fn vmm_setup_fdt(vm: Vm) {
                 for emu_cfg in config.emulated_device_list() {
                     match emu_cfg.emu_type {
                         EmuDeviceTGicd => {
-                            #[cfg(any(feature = "tx2", feature = "qemu"))]
+                            // the MVM dtb from qemu already describes its GICv3
+                            #[cfg(all(any(feature = "tx2", feature = "qemu"), not(feature = "gicv3")))]
                             fdt_setup_gic(
                                 dtb,
                                 Platform::GICD_BASE as u64,
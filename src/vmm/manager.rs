@@ -10,7 +10,7 @@
 
 use alloc::vec::Vec;
 
-use crate::arch::gicc_clear_current_irq;
+use crate::arch::{gicc_clear_current_irq, timer_arch_get_counter};
 use crate::arch::power_arch_vm_shutdown_secondary_cores;
 use crate::board::PLATFORM_CPU_NUM_MAX;
 use crate::config::{vm_id_list, vm_num};
@@ -22,7 +22,10 @@ use crate::kernel::{
     active_vcpu_id, active_vm, current_cpu, push_vm, vm, Vm, vm_if_get_state, vm_if_set_ivc_arg, vm_if_set_ivc_arg_ptr,
     vm_ipa2pa, VM_NUM_MAX, Scheduler,
 };
-use crate::kernel::{active_vm_id, vm_if_get_cpu_id};
+use crate::kernel::{active_vm_id, cpu_idle, ivc_vm_remove, vm_health_clear, vm_if_get_cpu_id, vm_if_set_state};
+use crate::kernel::{vcpu_wfi_wakeup, vm_health_vcpu_wfi, vm_if_take_pause_counter, vm_if_update_pause_counter};
+use crate::kernel::vcpu_postcopy_wakeup;
+use crate::kernel::{VcpuState, VmState};
 use crate::kernel::{ipi_send_msg, IpiInnerMsg, IpiMessage, IpiType, IpiVmmMsg};
 use crate::kernel::{hvc_send_msg_to_vm, HvcGuestMsg, HvcManageMsg};
 use crate::kernel::HVC_CONFIG;
@@ -40,12 +43,194 @@ pub enum VmmEvent {
     VmmShutdown,
     VmmAssignCpu,
     VmmRemoveCpu,
+    VmmPause,
+    VmmResume,
+    // a post-copy page has been filled, see migrate_postcopy_fill
+    VmmPostcopyWake,
 }
 
 pub fn vmm_shutdown_secondary_vm() {
     println!("Shutting down all VMs...");
 }
 
+/* Stop all vcpus of a GVM, the VM is kept and could be rebooted later.
+ *
+ * @param[in] vm_id : target VM id to shutdown.
+ */
+pub fn vmm_shutdown_vm(vm_id: usize) {
+    if vm_id == 0 {
+        warn!("vmm_shutdown_vm: Rust-Shyper do not support shutdown vm0");
+        return;
+    }
+    let vm = match vm(vm_id) {
+        None => {
+            println!("vmm_shutdown_vm: vm[{}] not exist", vm_id);
+            return;
+        }
+        Some(vm) => vm,
+    };
+    println!("vmm_shutdown VM [{}]", vm_id);
+    vm_if_set_state(vm_id, VmState::VmPending);
+
+    // current core may go idle, so notify the others first
+    let mut local = false;
+    for idx in 0..vm.cpu_num() {
+        let vcpu = vm.vcpu(idx).unwrap();
+        if vcpu.phys_id() == current_cpu().id {
+            local = true;
+            continue;
+        }
+        let m = IpiVmmMsg {
+            vmid: vm_id,
+            event: VmmEvent::VmmShutdown,
+        };
+        if !ipi_send_msg(vcpu.phys_id(), IpiType::IpiTVMM, IpiInnerMsg::VmmMsg(m)) {
+            warn!("vmm_shutdown_vm: failed to send ipi to Core {}", vcpu.phys_id());
+        }
+    }
+    if local {
+        vmm_cpu_shutdown_vcpu(vm_id);
+    }
+}
+
+pub fn vmm_cpu_shutdown_vcpu(vm_id: usize) {
+    if let Some(vcpu) = current_cpu().vcpu_array.pop_vcpu_through_vmid(vm_id) {
+        vcpu.set_state(VcpuState::VcpuInv);
+        // remove vcpu from scheduler
+        current_cpu().scheduler().sleep(vcpu);
+    }
+    if current_cpu().active_vcpu.is_none() {
+        gicc_clear_current_irq(true);
+        cpu_idle();
+    }
+}
+
+fn vmm_vm_ipi(vm: &Vm, event: VmmEvent) -> bool {
+    let mut local = false;
+    for idx in 0..vm.cpu_num() {
+        let vcpu = vm.vcpu(idx).unwrap();
+        if vcpu.phys_id() == current_cpu().id {
+            local = true;
+            continue;
+        }
+        let m = IpiVmmMsg { vmid: vm.id(), event };
+        if !ipi_send_msg(vcpu.phys_id(), IpiType::IpiTVMM, IpiInnerMsg::VmmMsg(m)) {
+            warn!("vmm_vm_ipi: failed to send ipi to Core {}", vcpu.phys_id());
+        }
+    }
+    local
+}
+
+// every vcpu of a pausing VM has stopped, a powered off one stays off
+fn vmm_vm_paused(vm: &Vm) -> bool {
+    (0..vm.cpu_num()).all(|idx| matches!(vm.vcpu(idx).unwrap().state(), VcpuState::VcpuPause | VcpuState::VcpuInv))
+}
+
+/* Freeze a running VM, its memory, devices and pending interrupts are kept.
+ * The pause completes once the pause ipis are handled on every core of the VM, the call returns 0
+ * if it has completed and 1 if it is in progress, the MVM calls it again to poll the pause.
+ *
+ * @param[in] vm_id : target VM id to pause.
+ */
+pub fn vmm_pause_vm(vm_id: usize) -> Result<usize, ()> {
+    let vm = match vm(vm_id) {
+        Some(vm) if vm_id != 0 => vm,
+        _ => {
+            println!("vmm_pause_vm: vm[{}] can not be paused", vm_id);
+            return Err(());
+        }
+    };
+    match vm_if_get_state(vm_id) {
+        VmState::VmActive => {}
+        VmState::VmPaused => return Ok(if vmm_vm_paused(&vm) { 0 } else { 1 }),
+        _ => {
+            println!("vmm_pause_vm: vm[{}] is not running", vm_id);
+            return Err(());
+        }
+    }
+    println!("vmm_pause VM [{}]", vm_id);
+    vm_if_set_state(vm_id, VmState::VmPaused);
+    // current core may go idle, so notify the others first
+    if vmm_vm_ipi(&vm, VmmEvent::VmmPause) {
+        vmm_cpu_pause_vcpu(vm_id);
+    }
+    Ok(if vmm_vm_paused(&vm) { 0 } else { 1 })
+}
+
+/*
+ * Take the vcpu out of the scheduler, a powered off vcpu stays off. The virtual counter must not
+ * count the paused time, the latest stop time of all vcpus is the base of the offset, so the
+ * counter never goes backwards in any vcpu.
+ */
+pub fn vmm_cpu_pause_vcpu(vm_id: usize) {
+    if let Some(vcpu) = current_cpu().vcpu_array.pop_vcpu_through_vmid(vm_id) {
+        let active = current_cpu().active_vcpu.as_ref().map(|x| x.vm_id()) == Some(vm_id);
+        match vcpu.state() {
+            VcpuState::VcpuInv => {}
+            _ => {
+                if active {
+                    // the scheduler does not save the context of a sleeping vcpu
+                    vcpu.context_vm_store();
+                }
+                vcpu.set_state(VcpuState::VcpuPause);
+                vm_health_vcpu_wfi(vm_id, vcpu.id(), false);
+                current_cpu().scheduler().sleep(vcpu);
+            }
+        }
+        vm_if_update_pause_counter(vm_id, timer_arch_get_counter());
+    }
+    if current_cpu().active_vcpu.is_none() {
+        gicc_clear_current_irq(true);
+        cpu_idle();
+    }
+}
+
+/* Let a paused VM run again from where it stopped.
+ *
+ * @param[in] vm_id : target VM id to resume.
+ */
+pub fn vmm_resume_vm(vm_id: usize) -> Result<usize, ()> {
+    let vm = match vm(vm_id) {
+        Some(vm) => vm,
+        None => {
+            println!("vmm_resume_vm: vm[{}] not exist", vm_id);
+            return Err(());
+        }
+    };
+    if !matches!(vm_if_get_state(vm_id), VmState::VmPaused) {
+        println!("vmm_resume_vm: vm[{}] is not paused", vm_id);
+        return Err(());
+    }
+    // the pause counter is final only after every vcpu has stopped
+    if !vmm_vm_paused(&vm) {
+        println!("vmm_resume_vm: vm[{}] is still pausing", vm_id);
+        return Err(());
+    }
+    println!("vmm_resume VM [{}]", vm_id);
+    // 0 means no vcpu has stopped yet
+    let pause_counter = vm_if_take_pause_counter(vm_id);
+    if pause_counter != 0 {
+        let delta = timer_arch_get_counter().saturating_sub(pause_counter);
+        for idx in 0..vm.cpu_num() {
+            vm.vcpu(idx).unwrap().add_vtimer_offset(delta);
+        }
+    }
+    vm_if_set_state(vm_id, VmState::VmActive);
+    if vmm_vm_ipi(&vm, VmmEvent::VmmResume) {
+        vmm_cpu_resume_vcpu(vm_id);
+    }
+    Ok(0)
+}
+
+pub fn vmm_cpu_resume_vcpu(vm_id: usize) {
+    if let Some(vcpu) = current_cpu().vcpu_array.pop_vcpu_through_vmid(vm_id) {
+        // interrupts held by the vgic are delivered once the vcpu runs
+        if let VcpuState::VcpuPause = vcpu.state() {
+            current_cpu().scheduler().wakeup(vcpu);
+        }
+    }
+}
+
 /* Generate VM structure and push it to VM.
  *
  * @param[in]  vm_id: new added VM id.
@@ -237,23 +422,11 @@ pub fn vmm_boot_vm(vm_id: usize) {
 pub fn vmm_reboot_vm(arg: usize) {
     let vm_id = bit_extract(arg, 0, 16);
     let force = bit_extract(arg, 16, 16) != 0;
-    let cur_vm = active_vm().unwrap();
 
     println!("vmm_reboot VM [{}] force:{}", vm_id, force);
 
     if force {
-        if cur_vm.id() == vm_id {
-            vmm_reboot();
-        } else {
-            let cpu_trgt = vm_if_get_cpu_id(vm_id);
-            let m = IpiVmmMsg {
-                vmid: vm_id,
-                event: VmmEvent::VmmReboot,
-            };
-            if !ipi_send_msg(cpu_trgt, IpiType::IpiTVMM, IpiInnerMsg::VmmMsg(m)) {
-                println!("vmm_reboot_vm: failed to send ipi to Core {}", cpu_trgt);
-            }
-        }
+        vmm_force_reboot_vm(vm_id);
         return;
     }
 
@@ -267,12 +440,41 @@ pub fn vmm_reboot_vm(arg: usize) {
     }
 }
 
-/* Reset vm os at current core.
+/* Reset target vm without notifying it. It does not rely on an active vcpu on current core,
+ * e.g. the health monitor reboots a VM from the timer.
+ *
+ * @param[in] vm_id : target VM id to be reboot.
+ */
+pub fn vmm_force_reboot_vm(vm_id: usize) {
+    let vm = match vm(vm_id) {
+        None => {
+            println!("vmm_force_reboot_vm: vm[{}] not exist", vm_id);
+            return;
+        }
+        Some(vm) => vm,
+    };
+    if current_cpu().active_vcpu.as_ref().map(|vcpu| vcpu.vm_id()) == Some(vm_id) {
+        vmm_reboot(vm);
+    } else {
+        let cpu_trgt = vm_if_get_cpu_id(vm_id);
+        let m = IpiVmmMsg {
+            vmid: vm_id,
+            event: VmmEvent::VmmReboot,
+        };
+        if !ipi_send_msg(cpu_trgt, IpiType::IpiTVMM, IpiInnerMsg::VmmMsg(m)) {
+            println!("vmm_force_reboot_vm: failed to send ipi to Core {}", cpu_trgt);
+        }
+    }
+}
+
+/* Reset vm os at current core, a vcpu of it must be active.
  *
  * @param[in] vm : target VM structure to be reboot.
  */
-pub fn vmm_reboot() {
-    let vm = active_vm().unwrap();
+pub fn vmm_reboot(vm: Vm) {
+    if let Some(vcpu) = current_cpu().active_vcpu.as_ref() {
+        vcpu.set_reboot_pending(false);
+    }
     // If running MVM, reboot the whole system.
     if vm.id() == 0 {
         vmm_shutdown_secondary_vm();
@@ -312,6 +514,9 @@ pub fn vmm_reboot() {
     // Reset ivc arg.
     vm_if_set_ivc_arg(vm.id(), 0);
     vm_if_set_ivc_arg_ptr(vm.id(), 0);
+    // Drop pending messages and keep alive, the new instance registers again.
+    ivc_vm_remove(vm.id());
+    vm_health_clear(vm.id());
 
     crate::arch::interrupt_arch_clear();
     crate::arch::vcpu_arch_init(vm.clone(), vm.vcpu(0).unwrap());
@@ -419,7 +624,45 @@ pub fn vmm_ipi_handler(msg: &IpiMessage) {
                 vmm_boot_vm(vmm.vmid);
             }
             VmmEvent::VmmReboot => {
-                vmm_reboot();
+                if current_cpu().active_vcpu.as_ref().map(|vcpu| vcpu.vm_id()) != Some(vmm.vmid) {
+                    match current_cpu().vcpu_array.pop_vcpu_through_vmid(vmm.vmid) {
+                        Some(vcpu) => match vcpu.state() {
+                            VcpuState::VcpuInv => {
+                                // the VM has been shut down, e.g. after a fault, run its vcpu again to reset it
+                                gicc_clear_current_irq(true);
+                                current_cpu().scheduler().yield_to(vcpu);
+                                vm_if_set_state(vmm.vmid, VmState::VmActive);
+                            }
+                            state => {
+                                // reboot once the vcpu is scheduled, see Cpu::schedule_to
+                                info!(
+                                    "vmm_ipi_handler: VM[{}] vcpu is {:?} on core {}, queue reboot",
+                                    vmm.vmid,
+                                    state,
+                                    current_cpu().id
+                                );
+                                vcpu.set_reboot_pending(true);
+                                if let VcpuState::VcpuBlk = state {
+                                    vm_health_vcpu_wfi(vmm.vmid, vcpu.id(), false);
+                                    vcpu_wfi_wakeup(vcpu);
+                                }
+                                return;
+                            }
+                        },
+                        None => {
+                            warn!(
+                                "vmm_ipi_handler: VM[{}] is not running on core {}, skip reboot",
+                                vmm.vmid,
+                                current_cpu().id
+                            );
+                            return;
+                        }
+                    }
+                }
+                match vm(vmm.vmid) {
+                    Some(vm) => vmm_reboot(vm),
+                    None => println!("vmm_ipi_handler: vm[{}] not exist", vmm.vmid),
+                }
             }
             VmmEvent::VmmAssignCpu => {
                 println!(
@@ -429,6 +672,9 @@ pub fn vmm_ipi_handler(msg: &IpiMessage) {
                 );
                 vmm_cpu_assign_vcpu(vmm.vmid);
             }
+            VmmEvent::VmmShutdown => {
+                vmm_cpu_shutdown_vcpu(vmm.vmid);
+            }
             VmmEvent::VmmRemoveCpu => {
                 println!(
                     "vmm_ipi_handler: core {} remove vcpu for vm[{}]",
@@ -437,6 +683,15 @@ pub fn vmm_ipi_handler(msg: &IpiMessage) {
                 );
                 vmm_cpu_remove_vcpu(vmm.vmid);
             }
+            VmmEvent::VmmPause => {
+                vmm_cpu_pause_vcpu(vmm.vmid);
+            }
+            VmmEvent::VmmResume => {
+                vmm_cpu_resume_vcpu(vmm.vmid);
+            }
+            VmmEvent::VmmPostcopyWake => {
+                vcpu_postcopy_wakeup(vmm.vmid);
+            }
             _ => {
                 todo!();
             }
@@ -13,7 +13,8 @@ use crate::config::vm_cfg_remove_vm_entry;
 use crate::device::emu_remove_dev;
 use crate::kernel::{
     current_cpu, interrupt_vm_remove, ipi_send_msg, IpiInnerMsg, IpiType, IpiVmmMsg, mem_vm_region_free,
-    remove_async_used_info, remove_vm, remove_vm_async_task, vcpu_remove, vm, Vm, Scheduler, cpu_idle,
+    remove_async_used_info, remove_vm, remove_vm_async_task, vcpu_remove, vm, Vm, Scheduler, cpu_idle, ivc_vm_remove,
+    vm_health_remove, migrate_postcopy_remove, dirty_log_remove,
 };
 use crate::kernel::vm_if_reset;
 use crate::vmm::VmmEvent;
@@ -50,6 +51,11 @@ pub fn vmm_remove_vm(vm_id: usize) {
     remove_vm_async_task(vm_id);
     // async used info
     remove_async_used_info(vm_id);
+    // ivc mailbox and keep alive
+    ivc_vm_remove(vm_id);
+    vm_health_remove(vm_id);
+    migrate_postcopy_remove(vm_id);
+    dirty_log_remove(vm_id);
     // remove vm: page table / mmio / vgic will be removed with struct vm
     vmm_remove_vm_list(vm_id);
     // remove vm cfg
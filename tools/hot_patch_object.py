@@ -0,0 +1,91 @@
+#!/usr/bin/env python3
+# Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+# Rust-Shyper is licensed under Mulan PSL v2.
+# You can use this software according to the terms and conditions of the Mulan PSL v2.
+# You may obtain a copy of Mulan PSL v2 at:
+#          http://license.coscl.org.cn/MulanPSL2
+# THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+# EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+# MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+# See the Mulan PSL v2 for more details.
+
+# Build a hot patch object against a signed hypervisor image, see src/kernel/hot_patch.rs.
+# code.bin holds the new functions, position independent, each func is old_addr:new_offset in hex,
+# the address of an old function in the image and the offset of the new one in code.bin. The key is
+# the one the image is signed with by live_update_image.py.
+# usage: hot_patch_object.py update.key rust_shyper.bin base code.bin out.bin func...
+
+import hashlib
+import hmac
+import struct
+import sys
+
+UPDATE_HEADER_OFFSET = 0x1000
+UPDATE_HEADER_MAGIC = 0x5444_5055_5059_4853
+UPDATE_BUILD_LEN = 128
+
+HOT_PATCH_MAGIC = 0x5441_5048_5059_4853
+HOT_PATCH_VERSION = 2
+HOT_PATCH_MAC_OFFSET = 24
+SHA256_LEN = 32
+# magic, version, size, mac, build, image checksum, func num, code offset, code size
+HOT_PATCH_HEADER = "<3Q{}s{}s4Q".format(SHA256_LEN, UPDATE_BUILD_LEN)
+HOT_PATCH_FUNC = "<3Q"
+
+
+def main():
+    if len(sys.argv) < 7:
+        print(
+            "usage: {} <update.key> <image.bin> <base> <code.bin> <out.bin> <old_addr:new_offset>...".format(
+                sys.argv[0]
+            )
+        )
+        sys.exit(1)
+    with open(sys.argv[1], "rb") as f:
+        key = f.read()
+    if not key:
+        print("{}: empty key".format(sys.argv[1]))
+        sys.exit(1)
+    with open(sys.argv[2], "rb") as f:
+        image = f.read()
+    base = int(sys.argv[3], 16)
+    with open(sys.argv[4], "rb") as f:
+        code = f.read()
+    (magic,) = struct.unpack_from("<Q", image, UPDATE_HEADER_OFFSET)
+    if magic != UPDATE_HEADER_MAGIC:
+        print("{}: no live update header".format(sys.argv[2]))
+        sys.exit(1)
+    # the leading word of the image mac identifies it, see update_image_id
+    (image_checksum,) = struct.unpack_from("<Q", image, UPDATE_HEADER_OFFSET + 24)
+    if image_checksum == 0:
+        print("{}: not signed by live_update_image.py".format(sys.argv[2]))
+        sys.exit(1)
+    (build,) = struct.unpack_from("{}s".format(UPDATE_BUILD_LEN), image, UPDATE_HEADER_OFFSET + 56)
+
+    funcs = []
+    for func in sys.argv[6:]:
+        old_addr, new_offset = [int(field, 16) for field in func.split(":")]
+        if old_addr < base or old_addr + 4 > base + len(image) or new_offset >= len(code):
+            print("{}: out of the image or the code".format(func))
+            sys.exit(1)
+        (old_insn,) = struct.unpack_from("<I", image, old_addr - base)
+        funcs.append(struct.pack(HOT_PATCH_FUNC, old_addr, new_offset, old_insn))
+
+    code_offset = struct.calcsize(HOT_PATCH_HEADER) + len(funcs) * struct.calcsize(HOT_PATCH_FUNC)
+    code_offset = (code_offset + 15) & ~15
+    size = code_offset + len(code)
+    header = struct.pack(
+        HOT_PATCH_HEADER, HOT_PATCH_MAGIC, HOT_PATCH_VERSION, size, bytes(SHA256_LEN), build, image_checksum, len(funcs),
+        code_offset, len(code)
+    )
+    patch = bytearray(header + b"".join(funcs))
+    patch += bytes(code_offset - len(patch)) + code
+    mac = hmac.new(key, patch, hashlib.sha256).digest()
+    patch[HOT_PATCH_MAC_OFFSET:HOT_PATCH_MAC_OFFSET + SHA256_LEN] = mac
+    with open(sys.argv[5], "wb") as f:
+        f.write(patch)
+    print("{}: {} functions, {} bytes, mac {}".format(sys.argv[5], len(funcs), size, mac.hex()))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,54 @@
+#!/usr/bin/env python3
+# Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+# Rust-Shyper is licensed under Mulan PSL v2.
+# You can use this software according to the terms and conditions of the Mulan PSL v2.
+# You may obtain a copy of Mulan PSL v2 at:
+#          http://license.coscl.org.cn/MulanPSL2
+# THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+# EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+# MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+# See the Mulan PSL v2 for more details.
+
+# Sign a hypervisor binary for live update: fill in the image size and the HMAC-SHA256 of the
+# LiveUpdateHeader, see src/kernel/live_update.rs. The key is the one the running hypervisor is
+# built with, update.key or UPDATE_KEY_FILE of build.rs.
+# usage: live_update_image.py update.key rust_shyper.bin
+
+import hashlib
+import hmac
+import struct
+import sys
+
+UPDATE_HEADER_OFFSET = 0x1000
+UPDATE_HEADER_MAGIC = 0x5444_5055_5059_4853
+SIZE_OFFSET = UPDATE_HEADER_OFFSET + 16
+MAC_OFFSET = UPDATE_HEADER_OFFSET + 24
+MAC_LEN = 32
+
+
+def main():
+    if len(sys.argv) != 3:
+        print("usage: {} <update.key> <image.bin>".format(sys.argv[0]))
+        sys.exit(1)
+    with open(sys.argv[1], "rb") as f:
+        key = f.read()
+    if not key:
+        print("{}: empty key".format(sys.argv[1]))
+        sys.exit(1)
+    with open(sys.argv[2], "rb") as f:
+        image = bytearray(f.read())
+    (magic,) = struct.unpack_from("<Q", image, UPDATE_HEADER_OFFSET)
+    if magic != UPDATE_HEADER_MAGIC:
+        print("{}: no live update header".format(sys.argv[2]))
+        sys.exit(1)
+    struct.pack_into("<Q", image, SIZE_OFFSET, len(image))
+    image[MAC_OFFSET:MAC_OFFSET + MAC_LEN] = bytes(MAC_LEN)
+    mac = hmac.new(key, image, hashlib.sha256).digest()
+    image[MAC_OFFSET:MAC_OFFSET + MAC_LEN] = mac
+    with open(sys.argv[2], "wb") as f:
+        f.write(image)
+    print("{}: {} bytes, mac {}".format(sys.argv[2], len(image), mac.hex()))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,78 @@
+#!/usr/bin/env python3
+# Copyright (c) 2023 Beihang University, Huawei Technologies Co.,Ltd. All rights reserved.
+# Rust-Shyper is licensed under Mulan PSL v2.
+# You can use this software according to the terms and conditions of the Mulan PSL v2.
+# You may obtain a copy of Mulan PSL v2 at:
+#          http://license.coscl.org.cn/MulanPSL2
+# THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY KIND,
+# EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO NON-INFRINGEMENT,
+# MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+# See the Mulan PSL v2 for more details.
+
+# Convert a dump of the trace buffer mapped by HVC_VMM_TRACE_MAP to the JSON trace event format,
+# which Perfetto and chrome://tracing open, one track per physical cpu. See src/kernel/trace_ring.rs.
+# usage: trace_ring_json.py trace.bin trace.json
+
+import json
+import struct
+import sys
+
+TRACE_INFO_MAGIC = 0x5254_5953
+TRACE_INFO = "<4I3Q"
+TRACE_RING = "<3Q"
+TRACE_EVENT = "<2Q2I4Q"
+
+KINDS = [
+    ("vm_exit", ["vm", "vcpu", "ec", "esr"]),
+    ("mmio_emu", ["vm", "ipa", "width", "write"]),
+    ("irq_inject", ["vm", "vcpu", "int_id"]),
+    ("irq_eoi", ["int_id", "by_hypervisor"]),
+    ("sched_in", ["vm", "vcpu"]),
+    ("sched_out", ["vm", "vcpu"]),
+    ("ipi_send", ["target", "type"]),
+    ("ipi_recv", ["type"]),
+    ("async_start", ["src_vm", "ipi"]),
+    ("async_finish", ["src_vm", "ipi"]),
+]
+
+
+def main():
+    if len(sys.argv) != 3:
+        print("usage: {} <trace.bin> <trace.json>".format(sys.argv[0]))
+        sys.exit(1)
+    with open(sys.argv[1], "rb") as f:
+        buf = f.read()
+    magic, version, cpu_num, event_size, ring_offset, ring_size, freq = struct.unpack_from(TRACE_INFO, buf, 0)
+    if magic != TRACE_INFO_MAGIC or version != 1:
+        print("{}: not a trace buffer".format(sys.argv[1]))
+        sys.exit(1)
+
+    events = []
+    for cpu in range(cpu_num):
+        ring = ring_offset + cpu * ring_size
+        head, capacity, _ = struct.unpack_from(TRACE_RING, buf, ring)
+        for idx in range(max(0, head - capacity), head):
+            slot = ring + 64 + (idx % capacity) * event_size
+            seq, timestamp, kind, cpu_id, *args = struct.unpack_from(TRACE_EVENT, buf, slot)
+            if seq != idx + 1 or kind >= len(KINDS):
+                continue
+            name, arg_names = KINDS[kind]
+            events.append({
+                "name": name,
+                "ph": "i",
+                "s": "t",
+                "ts": timestamp * 1_000_000 / freq,
+                "pid": 0,
+                "tid": cpu_id,
+                "args": {arg: "{:#x}".format(val) for arg, val in zip(arg_names, args)},
+            })
+    events.sort(key=lambda event: event["ts"])
+    for cpu in range(cpu_num):
+        events.append({"name": "thread_name", "ph": "M", "pid": 0, "tid": cpu, "args": {"name": "cpu{}".format(cpu)}})
+    with open(sys.argv[2], "w") as f:
+        json.dump({"traceEvents": events, "displayTimeUnit": "ns"}, f)
+    print("{}: {} events of {} cpus".format(sys.argv[2], len(events) - cpu_num, cpu_num))
+
+
+if __name__ == "__main__":
+    main()